@@ -0,0 +1,181 @@
+use rumqttc::QoS;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+use crate::connection::MqttClient;
+
+// --- MANUAL ACKS ---
+// With `manual_ack`, a QoS 1/2 message is only acknowledged once the
+// pipeline is done with it. MQTT wants acknowledgements in the order the
+// messages arrived, and workers finish in any order, so each message takes
+// a ticket on arrival and acknowledgements are released from the front of
+// the line as tickets settle.
+//
+// A message the output rejected stays unacknowledged, and so does
+// everything that arrived after it: the broker redelivers them all in the
+// next session (with `clean_session: false`). Until then the unacknowledged
+// messages use up the inflight window, so delivery pauses rather than
+// running ahead of an output that is down.
+pub struct AckQueue {
+    state: Mutex<State>,
+    // To the task that hands acknowledgements to the client, in order
+    acks: mpsc::UnboundedSender<(QoS, u16)>,
+}
+
+#[derive(Default)]
+struct State {
+    // Bumped on every new session, whose packet IDs mean something else
+    session: u64,
+    next_ticket: u64,
+    // In arrival order; None until the message has been processed, then
+    // whether it may be acknowledged
+    waiting: VecDeque<(u64, QoS, u16, Option<bool>)>,
+    // Set once a rejected message blocks the line, so it's only logged once
+    stalled: bool,
+}
+
+// Travels with the message through the dispatcher to whoever finishes it
+#[derive(Clone)]
+pub struct AckTicket {
+    queue: Arc<AckQueue>,
+    session: u64,
+    ticket: u64,
+}
+
+impl std::fmt::Debug for AckTicket {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "AckTicket({})", self.ticket)
+    }
+}
+
+impl AckQueue {
+    pub fn start(client: MqttClient) -> Arc<Self> {
+        info!("Acknowledging QoS 1/2 messages only once they are processed");
+        let (acks, mut pending) = mpsc::unbounded_channel::<(QoS, u16)>();
+        // Spawned: the client's requests only go out while the event loop is
+        // polled, and that may be waiting for room in the processing queue
+        tokio::spawn(async move {
+            while let Some((qos, pkid)) = pending.recv().await {
+                if let Err(e) = client.ack(qos, pkid).await {
+                    warn!("Failed to acknowledge packet {}: {:#}", pkid, e);
+                }
+            }
+        });
+        Arc::new(AckQueue {
+            state: Mutex::new(State::default()),
+            acks,
+        })
+    }
+
+    // QoS 0 messages have nothing to acknowledge
+    pub fn ticket(self: &Arc<Self>, qos: QoS, pkid: u16) -> Option<AckTicket> {
+        if qos == QoS::AtMostOnce {
+            return None;
+        }
+        let mut state = self.state.lock().expect("ack queue lock poisoned");
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        state.waiting.push_back((ticket, qos, pkid, None));
+        Some(AckTicket {
+            queue: self.clone(),
+            session: state.session,
+            ticket,
+        })
+    }
+
+    // On every ConnAck: whatever was still waiting belongs to the old
+    // session, which the broker redelivers from or has forgotten
+    pub fn new_session(&self) {
+        let mut state = self.state.lock().expect("ack queue lock poisoned");
+        state.session += 1;
+        state.waiting.clear();
+        state.stalled = false;
+    }
+}
+
+impl AckTicket {
+    // `processed` false withholds this acknowledgement and every later one
+    pub fn settle(&self, processed: bool) {
+        let mut state = self.queue.state.lock().expect("ack queue lock poisoned");
+        if state.session != self.session {
+            return;
+        }
+        if let Some(entry) = state.waiting.iter_mut().find(|entry| entry.0 == self.ticket) {
+            entry.3 = Some(processed);
+        }
+        while let Some(&(_, qos, pkid, Some(true))) = state.waiting.front() {
+            state.waiting.pop_front();
+            let _ = self.queue.acks.send((qos, pkid));
+        }
+        if let Some(&(_, _, pkid, Some(false))) = state.waiting.front() {
+            if !state.stalled {
+                state.stalled = true;
+                warn!(
+                    event = "ack_withheld",
+                    "Not acknowledging packet {} or anything after it, so the broker redelivers them in the next session",
+                    pkid
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Without the client task: the acknowledgements it would send
+    fn queue() -> (Arc<AckQueue>, mpsc::UnboundedReceiver<(QoS, u16)>) {
+        let (acks, released) = mpsc::unbounded_channel();
+        let queue = Arc::new(AckQueue {
+            state: Mutex::new(State::default()),
+            acks,
+        });
+        (queue, released)
+    }
+
+    fn released(receiver: &mut mpsc::UnboundedReceiver<(QoS, u16)>) -> Vec<u16> {
+        std::iter::from_fn(|| receiver.try_recv().ok()).map(|(_, pkid)| pkid).collect()
+    }
+
+    #[test]
+    fn acknowledgements_go_out_in_arrival_order() {
+        let (queue, mut receiver) = queue();
+        let tickets: Vec<_> = (1..=3).map(|pkid| queue.ticket(QoS::AtLeastOnce, pkid).unwrap()).collect();
+        tickets[2].settle(true);
+        tickets[1].settle(true);
+        assert!(released(&mut receiver).is_empty());
+        tickets[0].settle(true);
+        assert_eq!(released(&mut receiver), [1, 2, 3]);
+    }
+
+    #[test]
+    fn a_rejected_message_holds_back_everything_after_it() {
+        let (queue, mut receiver) = queue();
+        let tickets: Vec<_> = (1..=3).map(|pkid| queue.ticket(QoS::ExactlyOnce, pkid).unwrap()).collect();
+        tickets[0].settle(true);
+        tickets[1].settle(false);
+        tickets[2].settle(true);
+        assert_eq!(released(&mut receiver), [1]);
+    }
+
+    #[test]
+    fn a_new_session_forgets_the_old_tickets() {
+        let (queue, mut receiver) = queue();
+        let old = queue.ticket(QoS::AtLeastOnce, 1).unwrap();
+        queue.new_session();
+        let new = queue.ticket(QoS::AtLeastOnce, 1).unwrap();
+        old.settle(true);
+        assert!(released(&mut receiver).is_empty());
+        new.settle(true);
+        assert_eq!(released(&mut receiver), [1]);
+    }
+
+    #[test]
+    fn qos_0_has_nothing_to_acknowledge() {
+        let (queue, _receiver) = queue();
+        assert!(queue.ticket(QoS::AtMostOnce, 0).is_none());
+    }
+}
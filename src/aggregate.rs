@@ -0,0 +1,270 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+use tracing::{info, warn};
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+use crate::config::{AggregateFunction, AggregationConfig};
+use crate::emit::Emitter;
+use crate::model::{DrasiElement, ElementOp};
+
+// --- WINDOWED AGGREGATION ---
+// Summarises one property per device over fixed, back-to-back windows.
+// Mapped elements are recorded as they are emitted; when a window closes,
+// each device seen in it gets an element `<device>:<function>:<field>`
+// (e.g. `temp-01:avg:temperature`) through the same output.
+pub struct Aggregator {
+    windows: Vec<Window>,
+    emitter: Arc<dyn Emitter>,
+    tag_snapshots: bool,
+    // Summaries the shutdown flush has yet to emit
+    unflushed: AtomicUsize,
+}
+
+struct Window {
+    config: AggregationConfig,
+    // The pointer without its slashes, for element IDs
+    field: String,
+    state: Mutex<WindowState>,
+}
+
+struct WindowState {
+    started: DateTime<Utc>,
+    // Keyed by element ID
+    devices: HashMap<String, Accumulator>,
+}
+
+#[derive(Default)]
+struct Accumulator {
+    samples: u64,
+    sum: f64,
+    min: f64,
+    max: f64,
+}
+
+impl Aggregator {
+    pub fn new(configs: &[AggregationConfig], emitter: Arc<dyn Emitter>, tag_snapshots: bool) -> Self {
+        let windows = configs
+            .iter()
+            .map(|config| Window {
+                field: config.pointer.trim_start_matches('/').replace('/', "."),
+                config: config.clone(),
+                state: Mutex::new(WindowState {
+                    started: Utc::now(),
+                    devices: HashMap::new(),
+                }),
+            })
+            .collect();
+        Aggregator {
+            windows,
+            emitter,
+            tag_snapshots,
+            unflushed: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn record(&self, element: &DrasiElement) {
+        for window in &self.windows {
+            let Some(value) = element.properties.pointer(&window.config.pointer) else {
+                continue;
+            };
+            let number = value.as_f64();
+            if number.is_none() && window.config.function != AggregateFunction::Count {
+                continue;
+            }
+            let mut state = window.state.lock().expect("aggregation lock poisoned");
+            let accumulator = state.devices.entry(element.id.clone()).or_default();
+            accumulator.add(number.unwrap_or_default());
+        }
+    }
+
+    // One task per aggregation, each closing its window every `window_secs`
+    pub fn start(self: &Arc<Self>) -> Vec<JoinHandle<()>> {
+        (0..self.windows.len())
+            .map(|index| {
+                let aggregator = self.clone();
+                let config = &self.windows[index].config;
+                let period = Duration::from_secs(config.window_secs);
+                info!(
+                    "Emitting the {} of {} per device every {:?} as {}",
+                    config.function.name(),
+                    config.pointer,
+                    period,
+                    config.label
+                );
+                tokio::spawn(async move {
+                    let mut interval = tokio::time::interval(period);
+                    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+                    // The first tick is immediate; the window has only just opened
+                    interval.tick().await;
+                    loop {
+                        interval.tick().await;
+                        aggregator.close(index).await;
+                    }
+                })
+            })
+            .collect()
+    }
+
+    // At shutdown, after the tasks are stopped: emits the partial windows
+    pub async fn flush(&self) {
+        let summaries = self
+            .windows
+            .iter()
+            .map(|window| window.state.lock().expect("aggregation lock poisoned").devices.len())
+            .sum();
+        self.unflushed.store(summaries, Ordering::Relaxed);
+        for index in 0..self.windows.len() {
+            self.close(index).await;
+        }
+    }
+
+    // What a `flush` cut short never emitted
+    pub fn unflushed(&self) -> usize {
+        self.unflushed.load(Ordering::Relaxed)
+    }
+
+    async fn close(&self, index: usize) {
+        let window = &self.windows[index];
+        let now = Utc::now();
+        let (started, devices) = {
+            let mut state = window.state.lock().expect("aggregation lock poisoned");
+            let started = std::mem::replace(&mut state.started, now);
+            (started, std::mem::take(&mut state.devices))
+        };
+        for (device, accumulator) in devices {
+            let element = DrasiElement {
+                id: format!("{}:{}:{}", device, window.config.function.name(), window.field),
+                element_type: None,
+                labels: vec![window.config.label.clone()],
+                properties: json!({
+                    "device_id": device,
+                    "function": window.config.function.name(),
+                    "field": window.config.pointer,
+                    "value": accumulator.result(window.config.function),
+                    "samples": accumulator.samples,
+                    "window_start": started.to_rfc3339_opts(SecondsFormat::Millis, true),
+                    "window_end": now.to_rfc3339_opts(SecondsFormat::Millis, true),
+                }),
+                op: self.tag_snapshots.then_some(ElementOp::Update),
+            };
+            if let Err(e) = self.emitter.emit(element).await {
+                warn!(event = "aggregate_failed", device_id = %device, "Failed to emit aggregate: {:#}", e);
+            }
+            // Only counting down during the flush
+            let _ = self.unflushed.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| left.checked_sub(1));
+        }
+    }
+}
+
+impl Accumulator {
+    fn add(&mut self, value: f64) {
+        if self.samples == 0 {
+            self.min = value;
+            self.max = value;
+        } else {
+            self.min = self.min.min(value);
+            self.max = self.max.max(value);
+        }
+        self.samples += 1;
+        self.sum += value;
+    }
+
+    fn result(&self, function: AggregateFunction) -> serde_json::Value {
+        match function {
+            AggregateFunction::Avg => json!(self.sum / self.samples as f64),
+            AggregateFunction::Min => json!(self.min),
+            AggregateFunction::Max => json!(self.max),
+            AggregateFunction::Count => json!(self.samples),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emit::tests::element;
+    use anyhow::Result;
+    use async_trait::async_trait;
+    use crate::model::{DrasiDelete, DrasiRelation};
+    use serde_json::Value;
+
+    // Keeps the summaries themselves
+    #[derive(Default)]
+    struct Captured(Mutex<Vec<DrasiElement>>);
+
+    #[async_trait]
+    impl Emitter for Captured {
+        async fn emit(&self, element: DrasiElement) -> Result<()> {
+            self.0.lock().unwrap().push(element);
+            Ok(())
+        }
+
+        async fn delete(&self, _delete: DrasiDelete) -> Result<()> {
+            Ok(())
+        }
+
+        async fn emit_relation(&self, _relation: DrasiRelation) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn aggregation(function: AggregateFunction) -> AggregationConfig {
+        AggregationConfig {
+            pointer: "/temperature".to_string(),
+            function,
+            window_secs: 60,
+            label: "Aggregate".to_string(),
+        }
+    }
+
+    fn reading(id: &str, temperature: Value) -> DrasiElement {
+        DrasiElement {
+            properties: json!({ "temperature": temperature }),
+            ..element(id)
+        }
+    }
+
+    #[tokio::test]
+    async fn each_device_gets_a_summary_per_function() {
+        let captured = Arc::new(Captured::default());
+        let configs = [AggregateFunction::Avg, AggregateFunction::Min, AggregateFunction::Max, AggregateFunction::Count].map(aggregation);
+        let aggregator = Aggregator::new(&configs, captured.clone(), false);
+        for temperature in [json!(20), json!(22.5), json!("n/a")] {
+            aggregator.record(&reading("temp-01", temperature));
+        }
+        aggregator.flush().await;
+
+        let summaries = captured.0.lock().unwrap();
+        let values: HashMap<_, _> = summaries
+            .iter()
+            .map(|summary| (summary.id.as_str(), &summary.properties["value"]))
+            .collect();
+        assert_eq!(values["temp-01:avg:temperature"], &json!(21.25));
+        assert_eq!(values["temp-01:min:temperature"], &json!(20.0));
+        assert_eq!(values["temp-01:max:temperature"], &json!(22.5));
+        // Counting takes any value
+        assert_eq!(values["temp-01:count:temperature"], &json!(3));
+        assert!(summaries.iter().all(|summary| summary.labels == ["Aggregate"]));
+        assert_eq!(aggregator.unflushed(), 0);
+    }
+
+    #[tokio::test]
+    async fn a_closed_window_starts_empty() {
+        let captured = Arc::new(Captured::default());
+        let aggregator = Aggregator::new(&[aggregation(AggregateFunction::Count)], captured.clone(), true);
+        aggregator.record(&reading("temp-01", json!(20)));
+        aggregator.record(&DrasiElement {
+            properties: json!({ "humidity": 40 }),
+            ..element("hum-01")
+        });
+        aggregator.flush().await;
+        aggregator.flush().await;
+        let summaries = captured.0.lock().unwrap();
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].op, Some(ElementOp::Update));
+    }
+}
@@ -0,0 +1,82 @@
+use rand::Rng;
+use std::time::Duration;
+
+const INITIAL_DELAY: Duration = Duration::from_millis(500);
+const MAX_DELAY: Duration = Duration::from_secs(30);
+// Each delay is shortened by up to this fraction so that many sources
+// restarted together don't reconnect in lockstep
+const JITTER: f64 = 0.2;
+
+// --- EXPONENTIAL BACKOFF ---
+// Doubles the wait after every failure up to a cap, and starts over once
+// the caller reports success via `reset`.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff::new(INITIAL_DELAY, MAX_DELAY)
+    }
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Backoff {
+            initial,
+            max,
+            current: initial,
+        }
+    }
+
+    // The un-jittered delay for this attempt; advances the sequence
+    pub fn next_base(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = (self.current * 2).min(self.max);
+        delay
+    }
+
+    // What callers should actually sleep for
+    pub fn next_delay(&mut self) -> Duration {
+        let base = self.next_base();
+        base.mul_f64(1.0 - rand::rng().random_range(0.0..JITTER))
+    }
+
+    pub fn reset(&mut self) {
+        self.current = self.initial;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn doubles_from_half_a_second_up_to_the_cap() {
+        let mut backoff = Backoff::default();
+        let delays: Vec<u64> = (0..9).map(|_| backoff.next_base().as_millis() as u64).collect();
+        assert_eq!(delays, [500, 1000, 2000, 4000, 8000, 16000, 30000, 30000, 30000]);
+    }
+
+    #[test]
+    fn reset_starts_over() {
+        let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_secs(1));
+        backoff.next_base();
+        backoff.next_base();
+        backoff.reset();
+        assert_eq!(backoff.next_base(), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn jitter_only_shortens_the_delay() {
+        let mut backoff = Backoff::default();
+        for base in [500, 1000, 2000, 4000] {
+            let delay = backoff.next_delay();
+            let base = Duration::from_millis(base);
+            assert!(delay <= base && delay > base.mul_f64(1.0 - JITTER), "{:?} for {:?}", delay, base);
+        }
+    }
+}
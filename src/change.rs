@@ -0,0 +1,185 @@
+use serde_json::Value;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+
+use crate::config::ChangeDetectionConfig;
+use crate::model::DrasiElement;
+
+// Properties that differ on every message whether or not the reading did
+const VOLATILE_KEYS: [&str; 2] = ["_mqtt", "_pkid"];
+
+// --- CHANGE DETECTION ---
+// Drops an element whose labels and properties are the same as the last
+// ones emitted for its ID, however long ago that was. The ingestion
+// timestamp, `_mqtt`, `_pkid` and any `ignore`d keys are left out of the
+// comparison. Up to `max_entries` IDs are remembered; past that the least
+// recently seen are forgotten, so their next message counts as a change.
+pub struct ChangeDetector {
+    max_entries: usize,
+    ignored: Vec<String>,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    // Bumped on every lookup, to order entries by recency
+    clock: u64,
+    // element ID -> (hash of what was last emitted, when it was last seen)
+    last: HashMap<String, (u64, u64)>,
+}
+
+impl ChangeDetector {
+    pub fn new(config: &ChangeDetectionConfig, timestamp_key: Option<&str>) -> Self {
+        let ignored = config
+            .ignore
+            .iter()
+            .map(String::as_str)
+            .chain(VOLATILE_KEYS)
+            .chain(timestamp_key)
+            .map(str::to_string)
+            .collect();
+        ChangeDetector {
+            max_entries: config.max_entries,
+            ignored,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    // True if the element differs from the last one with its ID (or is the
+    // first); either way it becomes the one to compare the next against
+    pub fn has_changed(&self, element: &DrasiElement) -> bool {
+        let hash = self.fingerprint(element);
+        let mut state = self.state.lock().expect("change detection lock poisoned");
+        state.clock += 1;
+        let now = state.clock;
+
+        if let Some(entry) = state.last.get_mut(&element.id) {
+            let changed = entry.0 != hash;
+            *entry = (hash, now);
+            return changed;
+        }
+        if state.last.len() >= self.max_entries {
+            evict(&mut state.last, self.max_entries);
+        }
+        state.last.insert(element.id.clone(), (hash, now));
+        true
+    }
+
+    // After a delete, the element's next upsert is a change even if it
+    // carries the same values as before
+    pub fn forget(&self, id: &str) {
+        self.state.lock().expect("change detection lock poisoned").last.remove(id);
+    }
+
+    fn fingerprint(&self, element: &DrasiElement) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        element.element_type.hash(&mut hasher);
+        element.labels.hash(&mut hasher);
+        match &element.properties {
+            Value::Object(map) => {
+                let mut keys: Vec<&String> = map.keys().filter(|key| !self.ignored.contains(key)).collect();
+                keys.sort();
+                for key in keys {
+                    key.hash(&mut hasher);
+                    hash_value(&map[key], &mut hasher);
+                }
+            }
+            other => hash_value(other, &mut hasher),
+        }
+        hasher.finish()
+    }
+}
+
+// Object keys are visited in sorted order, so key order in the payload
+// doesn't make an unchanged reading look new
+fn hash_value(value: &Value, hasher: &mut DefaultHasher) {
+    match value {
+        Value::Object(map) => {
+            let mut entries: Vec<(&String, &Value)> = map.iter().collect();
+            entries.sort_by(|a, b| a.0.cmp(b.0));
+            for (key, value) in entries {
+                key.hash(hasher);
+                hash_value(value, hasher);
+            }
+        }
+        Value::Array(items) => {
+            items.len().hash(hasher);
+            for item in items {
+                hash_value(item, hasher);
+            }
+        }
+        other => other.to_string().hash(hasher),
+    }
+}
+
+// Forgets the least recently seen tenth at once, so a full cache doesn't
+// sort itself on every new ID
+fn evict(last: &mut HashMap<String, (u64, u64)>, max_entries: usize) {
+    let mut by_age: Vec<(u64, String)> = last.iter().map(|(id, (_, seen))| (*seen, id.clone())).collect();
+    by_age.sort_unstable();
+    let excess = last.len() + 1 - max_entries;
+    for (_, id) in by_age.into_iter().take(excess.max(max_entries / 10)) {
+        last.remove(&id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emit::tests::element;
+    use serde_json::json;
+
+    fn reading(id: &str, properties: Value) -> DrasiElement {
+        DrasiElement {
+            properties,
+            ..element(id)
+        }
+    }
+
+    fn detector(max_entries: usize) -> ChangeDetector {
+        let config = ChangeDetectionConfig {
+            max_entries,
+            ignore: vec!["battery".to_string()],
+        };
+        ChangeDetector::new(&config, Some("ingested_at"))
+    }
+
+    #[test]
+    fn an_unchanged_reading_is_dropped() {
+        let detector = detector(10);
+        assert!(detector.has_changed(&reading("a", json!({ "t": 21.5, "meta": { "x": 1, "y": 2 } }))));
+        assert!(!detector.has_changed(&reading("a", json!({ "meta": { "y": 2, "x": 1 }, "t": 21.5 }))));
+        assert!(detector.has_changed(&reading("a", json!({ "t": 22.0, "meta": { "x": 1, "y": 2 } }))));
+        // Other IDs are compared on their own
+        assert!(detector.has_changed(&reading("b", json!({ "t": 22.0, "meta": { "x": 1, "y": 2 } }))));
+    }
+
+    #[test]
+    fn ignored_and_volatile_keys_are_left_out() {
+        let detector = detector(10);
+        assert!(detector.has_changed(&reading("a", json!({ "t": 1, "battery": 90, "ingested_at": "10:00", "_pkid": 1 }))));
+        assert!(!detector.has_changed(&reading("a", json!({ "t": 1, "battery": 80, "ingested_at": "10:01", "_pkid": 2 }))));
+    }
+
+    #[test]
+    fn a_forgotten_id_changes_again() {
+        let detector = detector(10);
+        assert!(detector.has_changed(&reading("a", json!({ "t": 1 }))));
+        detector.forget("a");
+        assert!(detector.has_changed(&reading("a", json!({ "t": 1 }))));
+    }
+
+    #[test]
+    fn the_least_recently_seen_are_evicted() {
+        let detector = detector(2);
+        assert!(detector.has_changed(&reading("a", json!({ "t": 1 }))));
+        assert!(detector.has_changed(&reading("b", json!({ "t": 1 }))));
+        assert!(!detector.has_changed(&reading("a", json!({ "t": 1 }))));
+        assert!(detector.has_changed(&reading("c", json!({ "t": 1 }))));
+        // b went to make room; a was seen more recently
+        assert!(!detector.has_changed(&reading("a", json!({ "t": 1 }))));
+        assert!(detector.has_changed(&reading("b", json!({ "t": 1 }))));
+    }
+}
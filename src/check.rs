@@ -0,0 +1,121 @@
+use anyhow::{bail, Result};
+use std::fmt::Display;
+
+use crate::cli::Args;
+use crate::config::{self, Config, TransportKind};
+use crate::relations::TopicHierarchy;
+use crate::schema::Schemas;
+use crate::template::TopicTemplates;
+use crate::tls;
+use crate::transform::ScriptTransform;
+
+// --- CONFIGURATION CHECK ---
+// `--validate`: everything startup would reject, without connecting to a
+// broker or contacting the output. Each check prints a PASS or FAIL line to
+// stdout; any failure makes the run fail, so CI can gate a deployment on it.
+pub fn run(args: &Args) -> Result<()> {
+    let mut report = Report::default();
+
+    let loaded = match &args.config {
+        Some(path) => config::from_yaml(path),
+        None => Config::from_env(),
+    };
+    let source = match &args.config {
+        Some(path) => path.display().to_string(),
+        None => "the environment".to_string(),
+    };
+    let Some(config) = report.check(format!("configuration from {}", source), loaded.and_then(|config| {
+        config.validate()?;
+        Ok(config)
+    })) else {
+        // Nothing else can be checked without it
+        return report.finish();
+    };
+
+    // Topic filters are part of the configuration's own validation
+    for (name, connection) in config.connections() {
+        if connection.tls.enabled || connection.transport == TransportKind::Wss {
+            report.check(format!("TLS files ({})", name), tls::load_tls_configuration(&connection.tls));
+        }
+    }
+    let subscriptions = config.all_subscriptions();
+    if subscriptions.iter().any(|subscription| subscription.schema.is_some()) {
+        report.check("JSON schemas", Schemas::load(&subscriptions));
+    }
+    if !config.mapping.topic_templates.is_empty() {
+        report.check("topic templates", TopicTemplates::compile(&config.mapping.topic_templates));
+    }
+    if !config.mapping.topic_hierarchy.is_empty() {
+        report.check("topic hierarchy", TopicHierarchy::compile(&config.mapping.topic_hierarchy));
+    }
+    if let Some(script) = &config.mapping.script {
+        report.check(format!("mapping script {}", script.path.display()), ScriptTransform::load(script));
+    }
+    report.finish()
+}
+
+#[derive(Default)]
+struct Report {
+    passed: usize,
+    failed: usize,
+}
+
+impl Report {
+    fn check<T, E: Display>(&mut self, name: impl Display, result: Result<T, E>) -> Option<T> {
+        match result {
+            Ok(value) => {
+                println!("PASS  {}", name);
+                self.passed += 1;
+                Some(value)
+            }
+            Err(e) => {
+                println!("FAIL  {}: {:#}", name, e);
+                self.failed += 1;
+                None
+            }
+        }
+    }
+
+    fn finish(self) -> Result<()> {
+        if self.failed > 0 {
+            bail!("{} of {} check(s) failed", self.failed, self.passed + self.failed);
+        }
+        println!("All {} check(s) passed", self.passed);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validate(path: &std::path::Path) -> Result<()> {
+        run(&Args {
+            config: Some(path.to_path_buf()),
+            replay: None,
+            record: None,
+            publish_test: None,
+            dry_run: false,
+            validate: true,
+        })
+    }
+
+    #[test]
+    fn a_failed_check_fails_the_run() {
+        let path = std::env::temp_dir().join(format!("drasi-mqtt-check-{}.yaml", std::process::id()));
+        std::fs::write(&path, "source:\n  broker: localhost\n  subscriptions:\n    - topic: sensors/#\n").unwrap();
+        validate(&path).unwrap();
+
+        std::fs::write(
+            &path,
+            "source:\n  broker: localhost\n  subscriptions:\n    - topic: sensors/#\n      schema: /nonexistent/schema.json\n",
+        )
+        .unwrap();
+        assert_eq!(validate(&path).unwrap_err().to_string(), "1 of 2 check(s) failed");
+
+        // Nothing past the configuration is checked without it
+        std::fs::write(&path, "source:\n  subscriptions: []\n").unwrap();
+        assert_eq!(validate(&path).unwrap_err().to_string(), "1 of 1 check(s) failed");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
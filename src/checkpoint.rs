@@ -0,0 +1,106 @@
+use anyhow::{Context, Result};
+use rumqttc::QoS;
+use tracing::info;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use tokio::sync::Mutex;
+
+use crate::message::Message;
+
+// --- CHECKPOINTS ---
+// Remembers, per topic, the packet identifier of the last QoS 1/2 message
+// that was fully processed, in a small JSON file (`{"<topic>": <pkid>}`).
+// With a persistent session the broker re-sends unacknowledged publishes
+// after a reconnect, with their original packet ID and the DUP flag set; a
+// redelivery whose ID matches the checkpoint was already emitted before the
+// restart and is skipped. Packet IDs are reused, so fresh (non-DUP) messages
+// are never skipped. The file is rewritten after every checkpoint, via a
+// temporary file so a crash mid-write leaves the previous version.
+pub struct CheckpointStore {
+    path: PathBuf,
+    last: Mutex<HashMap<String, u16>>,
+}
+
+impl CheckpointStore {
+    // A missing file just means no checkpoints yet
+    pub async fn open(path: &Path) -> Result<Self> {
+        let last = match tokio::fs::read(path).await {
+            Ok(contents) => serde_json::from_slice(&contents)
+                .with_context(|| format!("{} is not a valid checkpoint file", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read checkpoint file {}", path.display())),
+        };
+        info!("Checkpointing processed messages to {}", path.display());
+        Ok(CheckpointStore {
+            path: path.to_path_buf(),
+            last: Mutex::new(last),
+        })
+    }
+
+    // True if this is a redelivery of the last message processed on its topic
+    pub async fn is_processed(&self, message: &Message) -> bool {
+        if message.qos == QoS::AtMostOnce || !message.dup {
+            return false;
+        }
+        self.last.lock().await.get(&message.topic) == Some(&message.pkid)
+    }
+
+    // QoS 0 messages have no packet ID and are never redelivered
+    pub async fn record(&self, message: &Message) -> Result<()> {
+        if message.qos == QoS::AtMostOnce {
+            return Ok(());
+        }
+        let mut last = self.last.lock().await;
+        if last.get(&message.topic) == Some(&message.pkid) {
+            return Ok(());
+        }
+        last.insert(message.topic.clone(), message.pkid);
+
+        // Written under the lock so concurrent workers can't reorder the file
+        let contents = serde_json::to_vec(&*last)?;
+        let temporary = self.path.with_extension("tmp");
+        tokio::fs::write(&temporary, contents)
+            .await
+            .with_context(|| format!("Failed to write checkpoint file {}", temporary.display()))?;
+        tokio::fs::rename(&temporary, &self.path)
+            .await
+            .with_context(|| format!("Failed to replace checkpoint file {}", self.path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn delivery(pkid: u16, dup: bool) -> Message {
+        let mut publish = rumqttc::Publish::new("sensors/a", QoS::ExactlyOnce, "{}");
+        publish.pkid = pkid;
+        publish.dup = dup;
+        Message::from(publish)
+    }
+
+    #[tokio::test]
+    async fn only_a_redelivery_of_the_last_message_is_skipped() {
+        let path = std::env::temp_dir().join(format!("drasi-mqtt-checkpoint-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let store = CheckpointStore::open(&path).await.unwrap();
+        store.record(&delivery(7, false)).await.unwrap();
+        assert!(store.is_processed(&delivery(7, true)).await);
+        // Packet IDs are reused, so a fresh message with the same one is new
+        assert!(!store.is_processed(&delivery(7, false)).await);
+        assert!(!store.is_processed(&delivery(8, true)).await);
+
+        let reopened = CheckpointStore::open(&path).await.unwrap();
+        assert!(reopened.is_processed(&delivery(7, true)).await);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn qos_0_messages_are_not_checkpointed() {
+        let path = std::env::temp_dir().join(format!("drasi-mqtt-checkpoint-qos0-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let store = CheckpointStore::open(&path).await.unwrap();
+        store.record(&Message::from(rumqttc::Publish::new("sensors/a", QoS::AtMostOnce, "{}"))).await.unwrap();
+        assert!(!path.exists());
+    }
+}
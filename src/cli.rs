@@ -0,0 +1,40 @@
+use clap::Parser;
+use std::path::PathBuf;
+
+// --- COMMAND LINE ---
+// Flags are kept to a minimum: anything describing the broker or the mapping
+// belongs in the YAML config, not on the command line.
+#[derive(Debug, Parser)]
+#[command(name = "drasi-mqtt-poc", about = "Drasi MQTT Source PoC")]
+pub struct Args {
+    /// Path to a YAML source definition. When omitted, the source is
+    /// configured from DRASI_MQTT_* environment variables and defaults.
+    #[arg(long, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// Replay messages from a JSON Lines file instead of connecting to the
+    /// broker. Each line is {"topic": "...", "payload": {...}}.
+    #[arg(long, value_name = "FILE")]
+    pub replay: Option<PathBuf>,
+
+    /// Append every incoming message to a JSON Lines capture (replayable
+    /// with --replay) while processing it as usual.
+    #[arg(long, value_name = "FILE")]
+    pub record: Option<PathBuf>,
+
+    /// Connect with the configured broker settings, publish a sample JSON
+    /// message to TOPIC, wait for the broker to acknowledge it and exit.
+    #[arg(long, value_name = "TOPIC", conflicts_with_all = ["replay", "record"])]
+    pub publish_test: Option<String>,
+
+    /// Run the full pipeline but log every mapped element instead of sending
+    /// it to the configured output, e.g. to try a new mapping on live traffic.
+    #[arg(long, conflicts_with = "publish_test")]
+    pub dry_run: bool,
+
+    /// Check the configuration (schemas, TLS files, topic filters, scripts)
+    /// without connecting to anything, print a PASS/FAIL line per check and
+    /// exit non-zero if any failed.
+    #[arg(long, conflicts_with_all = ["replay", "record", "publish_test", "dry_run"])]
+    pub validate: bool,
+}
@@ -0,0 +1,3228 @@
+use anyhow::{anyhow, bail, Context, Result};
+use rumqttc::QoS;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use crate::redact::REDACTED;
+
+// --- DEFAULTS ---
+// These match the original hardcoded PoC values so that running without any
+// configuration still talks to the public test broker.
+const DEFAULT_BROKER_HOST: &str = "test.mosquitto.org";
+const DEFAULT_BROKER_PORT: u16 = 1883;
+const DEFAULT_TLS_BROKER_PORT: u16 = 8883;
+const DEFAULT_WS_BROKER_PORT: u16 = 80;
+const DEFAULT_WSS_BROKER_PORT: u16 = 443;
+const DEFAULT_WEBSOCKET_PATH: &str = "/mqtt";
+// We listen to a wildcard topic to simulate multiple sensors
+const DEFAULT_TOPIC_PATTERN: &str = "lfx/drasi/sensors/#";
+const DEFAULT_CLIENT_ID_PREFIX: &str = "drasi-poc";
+const DEFAULT_QOS: QoS = QoS::AtLeastOnce;
+const DEFAULT_INFLIGHT: u16 = 100;
+const DEFAULT_KEEP_ALIVE_SECS: u16 = 30;
+const DEFAULT_MAX_SUBSCRIBE_ATTEMPTS: u32 = 5;
+// rumqttc refuses shorter keep-alives on v5
+const MIN_KEEP_ALIVE_SECS: u16 = 5;
+const DEFAULT_MAX_PAYLOAD_BYTES: usize = 1024 * 1024;
+const DEFAULT_MAX_PAYLOAD_DEPTH: usize = 64;
+const DEFAULT_LOG_PAYLOAD_PREVIEW_BYTES: usize = 256;
+// The largest payload an MQTT packet can carry
+pub const MAX_MQTT_PAYLOAD_BYTES: usize = 268_435_455;
+// serde_json refuses anything nested deeper on its own
+const MAX_JSON_DEPTH: usize = 128;
+const DEFAULT_CHANNEL_CAPACITY: usize = 10;
+const DEFAULT_MAX_CONCURRENCY: usize = 100;
+const DEFAULT_QUEUE_CAPACITY: usize = 1000;
+const DEFAULT_DRAIN_TIMEOUT_SECS: u64 = 5;
+const DEFAULT_HTTP_MAX_ATTEMPTS: u32 = 3;
+#[cfg(feature = "kafka")]
+const DEFAULT_KAFKA_MAX_ATTEMPTS: u32 = 3;
+const DEFAULT_DAPR_MAX_ATTEMPTS: u32 = 3;
+const DEFAULT_MQTT_OUTPUT_PORT: u16 = 1883;
+const DEFAULT_MQTT_OUTPUT_TOPIC: &str = "drasi/{label}/{id}";
+const DEFAULT_FILE_FSYNC_INTERVAL_SECS: u64 = 5;
+const DEFAULT_MAX_BATCH_SIZE: usize = 100;
+const DEFAULT_FLUSH_INTERVAL_MS: u64 = 1000;
+const DEFAULT_DEAD_LETTER_TOPIC_PREFIX: &str = "deadletter";
+const DEFAULT_DEDUP_TTL_MS: u64 = 1000;
+const DEFAULT_DEDUP_MAX_ENTRIES: usize = 10_000;
+const DEFAULT_CHANGE_DETECTION_MAX_ENTRIES: usize = 10_000;
+const DEFAULT_MERGE_MAX_ENTRIES: usize = 10_000;
+const DEFAULT_SNAPSHOT_MAX_ENTRIES: usize = 10_000;
+const DEFAULT_WRAP_KEY: &str = "value";
+const DEFAULT_SEQUENCE_KEY: &str = "_seq";
+const DEFAULT_SEQUENCE_MAX_ENTRIES: usize = 10_000;
+const DEFAULT_EXPIRY_TTL_SECS: u64 = 300;
+const DEFAULT_EXPIRY_KEY: &str = "_expires_at";
+const DEFAULT_FRAMING_PREFIX_BYTES: u8 = 4;
+const DEFAULT_REORDER_POINTER: &str = "/timestamp";
+const DEFAULT_REORDER_LATENCY_MS: u64 = 200;
+const DEFAULT_REORDER_CAPACITY: usize = 16;
+const DEFAULT_RETRY_QUEUE_CAPACITY: usize = 1000;
+const DEFAULT_RETRY_MAX_ATTEMPTS: u32 = 5;
+const DEFAULT_RETRY_INITIAL_BACKOFF_MS: u64 = 1000;
+const DEFAULT_RETRY_MAX_BACKOFF_MS: u64 = 60_000;
+const DEFAULT_TOPIC_SUMMARY_INTERVAL_SECS: u64 = 60;
+const DEFAULT_HEARTBEAT_INTERVAL_SECS: u64 = 30;
+const DEFAULT_HEARTBEAT_ID: &str = "drasi-mqtt-source";
+const DEFAULT_HEARTBEAT_LABEL: &str = "SourceHeartbeat";
+const DEFAULT_RECONNECT_NOTICE_ID: &str = "drasi-mqtt-source-reconnect";
+const DEFAULT_RECONNECT_NOTICE_LABEL: &str = "SourceReconnected";
+const DEFAULT_AGGREGATION_WINDOW_SECS: u64 = 60;
+const DEFAULT_AGGREGATION_LABEL: &str = "Aggregate";
+const DEFAULT_BREAKER_FAILURE_THRESHOLD: u32 = 5;
+const DEFAULT_BREAKER_COOLDOWN_MS: u64 = 30_000;
+const DEFAULT_CSV_DELIMITER: char = ',';
+const DEFAULT_COMPOSITE_SEPARATOR: &str = "-";
+const DEFAULT_COMPOSITE_PLACEHOLDER: &str = "unknown";
+const DEFAULT_SCRIPT_TIMEOUT_MS: u64 = 50;
+const DEFAULT_LWT_PAYLOAD: &str = r#"{"status":"offline"}"#;
+const DEFAULT_BIRTH_PAYLOAD: &str = r#"{"status":"online"}"#;
+const DEFAULT_METRICS_ADDR: &str = "0.0.0.0:9090";
+const DEFAULT_HEALTH_ADDR: &str = "0.0.0.0:8080";
+
+// --- SOURCE CONFIGURATION ---
+// Everything needed to point the source at a broker without recompiling.
+// The YAML field names follow the Drasi source manifest (`broker`, `port`),
+// while the Rust names stay descriptive.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    #[serde(rename = "broker")]
+    pub broker_host: String,
+    // Left unset, this follows the transport: 1883 in cleartext, 8883 with
+    // TLS, 80 over WebSockets and 443 over secure WebSockets
+    #[serde(rename = "port")]
+    pub broker_port: Option<u16>,
+    pub subscriptions: Vec<Subscription>,
+    pub client_id_prefix: String,
+    // A fixed client ID, used as-is instead of `<prefix>-<uuid>`
+    pub client_id: Option<String>,
+    // false asks the broker to keep our session (subscriptions and queued
+    // QoS 1/2 messages) across reconnects and restarts. That only helps with
+    // a fixed `client_id`: a random ID starts a new session every run.
+    pub clean_session: bool,
+    // Holds back each QoS 1/2 acknowledgement until the message has been
+    // processed, and withholds it (and, as acks go out in order, every later
+    // one) when the output rejects the message, so the broker sends them
+    // again in the next session (with `clean_session: false`) instead of
+    // them being lost. Not available with `batch` or `retry_queue`.
+    pub manual_ack: bool,
+    // How many QoS 1/2 publishes may await acknowledgement at once. On v5 it
+    // also caps what the broker sends us unacknowledged (receive maximum).
+    pub inflight: u16,
+    // Requests (subscribe, publish, ...) queued for the event loop before
+    // callers have to wait
+    pub channel_capacity: usize,
+    // A subscription the broker refuses is always logged; this also stops
+    // the source with an error
+    pub exit_on_subscribe_failure: bool,
+    // A subscription granted a lower QoS than it asked for is always logged
+    // (and shown in the metrics); this also stops the source with an error,
+    // for when the delivery guarantee matters
+    pub fail_on_qos_downgrade: bool,
+    // Give up (exit non-zero) after this many connection errors in a row
+    // without a successful connect in between; 0 retries forever
+    pub max_reconnect_attempts: u32,
+    // Give up (exit non-zero) when the first connection fails this many
+    // times, before anything was received; 0 leaves it to
+    // `max_reconnect_attempts`. Later outages aren't affected.
+    pub initial_connect_attempts: u32,
+    // No ConnAck this long after startup logs an error, so a wrong host or
+    // port shows up at once rather than as endless retries; unset waits forever
+    pub connect_timeout_secs: Option<u64>,
+    // When `connect_timeout_secs` runs out: stop with an error instead of
+    // carrying on retrying
+    pub exit_on_connect_timeout: bool,
+    // Reconnect when nothing has been received for this long while
+    // connected, for brokers that stop delivering without closing the
+    // connection; keepalive pings don't count. Unset never does.
+    pub idle_timeout_secs: Option<u64>,
+    // How often to try handing the subscriptions to the client, backing off
+    // in between, before giving up; 0 retries forever
+    pub max_subscribe_attempts: u32,
+    // How long to wait after each ConnAck before subscribing, for brokers
+    // that drop subscriptions arriving before the session is fully set up
+    pub subscribe_delay_ms: u64,
+    // The longest we go without talking to the broker before pinging it; a
+    // broker drops us after about 1.5x this without hearing from us
+    pub keep_alive_secs: u16,
+    // v5 adds per-message user properties and a content type
+    pub protocol_version: ProtocolVersion,
+    // tcp (plain or with `tls`), ws or wss for brokers that only speak MQTT
+    // over WebSockets. wss takes its certificates from the `tls` section.
+    pub transport: TransportKind,
+    // HTTP path of the WebSocket endpoint; ignored over tcp
+    pub websocket_path: String,
+    // Last Will and Testament: with `lwt_topic` set, the broker publishes
+    // `lwt_payload` there if we vanish without disconnecting. We publish the
+    // same payload ourselves on a clean shutdown (which suppresses the will).
+    pub lwt_topic: Option<String>,
+    pub lwt_payload: String,
+    #[serde(deserialize_with = "deserialize_qos", serialize_with = "serialize_qos")]
+    pub lwt_qos: QoS,
+    pub lwt_retain: bool,
+    // Birth message: `birth_payload` is published on every (re)connect, to
+    // `birth_topic` and retained, or else to `lwt_topic` with its retain flag
+    // so the will replaces it there.
+    pub birth_topic: Option<String>,
+    pub birth_payload: String,
+    // Subscribing to a topic this source publishes to (status, birth, MQTT
+    // dead letters, an mqtt output on the same broker) is refused at startup
+    // unless this is set
+    pub allow_loopback: bool,
+    pub tls: TlsConfig,
+    pub username: Option<String>,
+    // Prefer DRASI_MQTT_PASSWORD over putting this in YAML; the variable
+    // always overrides the file
+    pub password: Option<String>,
+    // Files holding the username and password instead, as Kubernetes mounts
+    // secrets; read once at startup, less trailing newlines
+    pub username_file: Option<PathBuf>,
+    pub password_file: Option<PathBuf>,
+    // When set, every broker connection is tunnelled through this proxy
+    pub proxy: Option<ProxyConfig>,
+    // Several brokers ingested side by side into one graph, each on its own
+    // connection. Entries take what they leave out from the settings above.
+    pub brokers: Vec<BrokerConfig>,
+    // Number of worker tasks processing payloads, i.e. how many are
+    // processed at the same time
+    pub max_concurrency: usize,
+    // Messages waiting for a worker. When it's full the source either waits
+    // (`block`, which stops reading from the broker) or drops the message.
+    pub queue_capacity: usize,
+    pub queue_full: OverflowPolicy,
+    // With several brokers, gives each its own queue (of `queue_capacity`)
+    // and has the workers take from them in turn, so a busy broker can't
+    // hold up a quiet one behind its backlog
+    pub fair_scheduling: bool,
+    // With several brokers, at most this many are connected (or connecting)
+    // at once. The others wait their turn, which a broker gives up while its
+    // connection is down; unset connects to all of them.
+    pub max_active_connections: Option<usize>,
+    // Hands each topic to the same worker every time, so its messages are
+    // processed in arrival order while other topics still run concurrently
+    pub preserve_order: bool,
+    // How long shutdown waits for in-flight payloads before giving up;
+    // what is still queued or being processed then is dead-lettered
+    pub drain_timeout_secs: u64,
+    // A hard limit on draining and flushing the output at shutdown, for
+    // orchestrators with a termination grace period: past it the process
+    // exits whether or not the output took everything. Unset waits for the
+    // flush however long it takes.
+    pub shutdown_timeout_secs: Option<u64>,
+    // How payloads become graph elements
+    pub mapping: MappingConfig,
+    // Where mapped elements go; `http`, `kafka`, `dapr` and `mqtt` need their
+    // sections below
+    pub output: OutputKind,
+    // How the `log` output renders elements as JSON
+    pub log_output: LogOutput,
+    pub http: Option<HttpConfig>,
+    #[cfg(feature = "kafka")]
+    pub kafka: Option<KafkaConfig>,
+    pub dapr: Option<DaprConfig>,
+    pub mqtt_output: Option<MqttOutputConfig>,
+    pub file: Option<FileOutputConfig>,
+    // Wraps every change in a CloudEvents 1.0 envelope (`type` e.g.
+    // `io.drasi.element.ingested`, `source` the broker URL, `data` the
+    // element); only with a single broker
+    pub cloudevents: bool,
+    // Sorts property keys (recursively) and labels, dropping repeated labels,
+    // so equal elements always serialize to the same bytes
+    pub canonicalize: bool,
+    // Property pointers (e.g. `/owner/email`) whose values the log output
+    // shows as "***"; with `redact_emitted` they are replaced in what is
+    // emitted too
+    pub redact: Vec<String>,
+    pub redact_emitted: bool,
+    // Adds a `_source` property to every node and relation, naming this
+    // source (`source_name`, else the client ID or its prefix)
+    pub stamp_source: bool,
+    // Prefixes every ID with `<source_name>:`, so sources whose devices
+    // reuse IDs don't overwrite each other's nodes
+    pub source_id_prefix: bool,
+    pub source_name: Option<String>,
+    // When set, elements are buffered and handed to the output in batches
+    pub batch: Option<BatchConfig>,
+    // When set, repeated readings within the window are dropped before emission
+    pub dedup: Option<DedupConfig>,
+    // When set, an element identical to the last one emitted for its ID is
+    // dropped, however long ago that was
+    pub change_detection: Option<ChangeDetectionConfig>,
+    // When set, payloads are taken as deltas: each element's properties are
+    // merged onto the last known ones for its ID before anything else
+    pub merge_with_previous: Option<MergeConfig>,
+    // When set, emitted elements carry the time after which they are stale,
+    // and optionally are deleted once that passes without a new message
+    pub expiry: Option<ExpiryConfig>,
+    // When set, elements wait briefly so readings for the same ID that
+    // arrive out of order are emitted in the order of their timestamps
+    pub reorder: Option<ReorderConfig>,
+    // When set, caps how many elements per second reach the output
+    pub throttle: Option<ThrottleConfig>,
+    // When set, only one in every N updates per element ID is emitted (or
+    // logged), to watch high-volume data without flooding the output
+    pub sample: Option<SampleConfig>,
+    // When set, an output that keeps failing is left alone for a while
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+    // When set, message counts per topic are logged periodically
+    pub topic_summary: Option<TopicSummaryConfig>,
+    // When set, a synthetic element is emitted periodically as a liveness
+    // signal, even while no sensor publishes
+    pub heartbeat: Option<HeartbeatConfig>,
+    // When set, a synthetic element announces every reconnect, so consumers
+    // can resync what they may have missed during the outage
+    pub reconnect_notice: Option<ReconnectNoticeConfig>,
+    // When set, every connect and disconnect is announced outside the
+    // pipeline, e.g. `connection_hook: { http: { url: ... } }`
+    #[serde(with = "serde_yaml::with::singleton_map")]
+    pub connection_hook: Option<ConnectionHookConfig>,
+    // When set, the last element emitted for each ID is kept and all of
+    // them are emitted again after every reconnect
+    pub snapshot_on_reconnect: Option<SnapshotConfig>,
+    // When set, emitted elements carry a sequence number that counts up
+    // per element ID
+    pub sequence: Option<SequenceConfig>,
+    // Windowed summaries (e.g. a per-device average temperature per minute),
+    // emitted as elements of their own next to the mapped ones
+    pub aggregations: Vec<AggregationConfig>,
+    // Where messages that fail to map or emit are kept, e.g.
+    // `dead_letter: { file: { path: dlq.jsonl } }`; unset means they are only logged
+    #[serde(with = "serde_yaml::with::singleton_map")]
+    pub dead_letter: Option<DeadLetterConfig>,
+    // When set, messages the output rejects are retried in the background
+    // before they count as failed (and are dead-lettered)
+    pub retry_queue: Option<RetryQueueConfig>,
+    // When set, skips QoS 1/2 redeliveries already processed before a
+    // restart; only useful with a persistent session (`clean_session: false`)
+    pub checkpoint: Option<CheckpointConfig>,
+    // Larger payloads are dropped before decoding (and counted). The MQTT
+    // packet limit is set just above this so the client refuses, and v5
+    // brokers don't send, anything far bigger.
+    pub max_payload_bytes: usize,
+    // JSON payloads nesting objects and arrays deeper than this are rejected
+    // (and counted) before they are parsed, so a hostile one costs a scan
+    pub max_payload_depth: usize,
+    // When set, mapped elements with more properties than this are rejected
+    // (and dead-lettered) or cut down to size
+    pub max_properties: Option<MaxPropertiesConfig>,
+    // Also dead-letter the dropped payloads, which can be large
+    pub dead_letter_oversized: bool,
+    // How much of a payload that fails to decode is quoted in the error (and
+    // so the log); 0 quotes none, for sensitive data
+    pub log_payload_preview_bytes: usize,
+    // Messages that take longer than this to process (map and emit) are
+    // logged with their topic and size; unset means never
+    pub slow_message_ms: Option<u64>,
+    // Where Prometheus scrapes `/metrics`
+    pub metrics_addr: SocketAddr,
+    // Where Kubernetes probes `/healthz` and `/readyz`
+    pub health_addr: SocketAddr,
+}
+
+// --- MAPPING CONFIGURATION ---
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MappingConfig {
+    // Checked in order; the first rule whose prefix matches the topic wins
+    pub label_rules: Vec<LabelRule>,
+    // Used when no rule matches
+    pub default_labels: Vec<String>,
+    // Pointer to a label (a string, or an array of strings) in the payload,
+    // e.g. `/type` for `{"type": "pump"}`. Its labels are added to the
+    // topic's; default_labels only apply when neither yields any.
+    pub label_pointer: Option<String>,
+    // Where the element's primary `type` comes from, e.g. `type_source:
+    // first_label` or `type_source: { json_pointer: /kind }`; unset leaves
+    // elements without one. The type is taken out of the labels, which keep
+    // the rest.
+    #[serde(with = "serde_yaml::with::singleton_map")]
+    pub type_source: Option<TypeSource>,
+    // Extra labels for how a message was delivered, e.g. `retained:
+    // [RetainedState]` or `qos2: [QoS2]`, added to whichever labels the
+    // element otherwise gets
+    pub delivery_labels: DeliveryLabels,
+    // Where the element ID comes from, e.g. `id_source: topic` or
+    // `id_source: { json_pointer: /meta/deviceId }`
+    #[serde(with = "serde_yaml::with::singleton_map_recursive")]
+    pub id_source: IdSource,
+    // With a `json_pointer` ID source, a payload without the field fails
+    // (and is dead-lettered) instead of taking its ID from the topic
+    pub require_id: bool,
+    // Applied to every ID the mapping produces, relation ends included
+    pub id_transform: IdTransformConfig,
+    // How the raw MQTT body is turned into properties, e.g. `payload_format:
+    // cbor` or `payload_format: { csv: { headers: [ts, temp] } }`
+    #[serde(with = "serde_yaml::with::singleton_map")]
+    pub payload_format: PayloadFormat,
+    // Undone before the payload format is decoded, for devices that gzip
+    // or deflate their messages
+    pub compression: Compression,
+    // For bridges that pack several records into one publish, e.g.
+    // `framing: { length_prefixed: { prefix_bytes: 2 } }`: each record is
+    // mapped as if it had arrived on its own
+    #[serde(with = "serde_yaml::with::singleton_map")]
+    pub framing: Framing,
+    // What a payload that decodes to `null` means: `delete` (the default,
+    // like an empty payload), `skip` or `wrap` (`{<wrap_key>: null}`)
+    pub null_payload: NullPayload,
+    // What a payload that decodes to a number, string, boolean or array
+    // becomes: `{<wrap_key>: payload}` (the default), skipped, or kept as the
+    // properties as it is. `raw_string` payloads are strings by design and
+    // always kept; arrays are only seen here without `explode_arrays`.
+    pub non_object_payload: NonObjectPayload,
+    // The key wrapped payloads (and wrapped nulls) go under
+    pub wrap_key: String,
+    // Adds `_mqtt: {topic, filter, qos, retain, dup}` to object properties so queries
+    // can tell retained startup state from live updates
+    pub include_mqtt_metadata: bool,
+    // Drops trailing slashes and collapses doubled ones (`a//b/` -> `a/b`)
+    // before anything looks at the topic's levels, `_mqtt.topic` included,
+    // so the last level is never empty
+    pub normalize_topics: bool,
+    // Adds `_broker`, the name of the broker connection an element came in
+    // on (see `brokers`)
+    pub include_broker: bool,
+    // Adds `_pkid`, the packet identifier of the QoS 1/2 publish an element
+    // came from, to correlate graph changes with wire captures
+    pub include_packet_id: bool,
+    // Also keeps the payload as received in `_raw` (object properties only),
+    // for debugging a mapping or re-decoding it downstream
+    pub preserve_raw: RawEncoding,
+    // Marks elements `op: snapshot` (from retained messages) or `op: update`
+    // so consumers can bootstrap from the snapshot, then stream
+    pub tag_snapshots: bool,
+    pub timestamp: TimestampConfig,
+    // Turns `{"a": {"b": 1}}` into `{"a.b": 1}` (arrays become `a.0`, `a.1`)
+    // so queries can address nested values directly. `_mqtt` and
+    // `_user_props` are added afterwards and stay nested.
+    pub flatten_properties: bool,
+    pub flatten_separator: String,
+    // Source JSON pointer -> target property name, e.g. `/t: temperatureCelsius`.
+    // Pointers resolve against the payload as published; ones that don't
+    // resolve are left out.
+    pub field_map: HashMap<String, String>,
+    // Topic segment index -> property name, e.g. `{1: building, 3: room}`
+    // for `buildings/b1/rooms/r2/temp-01`; negative indices count from the
+    // end. Named template captures (`topic_templates`) need no entry here.
+    pub topic_properties: BTreeMap<isize, String>,
+    // With a field map, keep the fields it doesn't mention (true) or drop them
+    pub passthrough_unmapped: bool,
+    // Property pointer -> type, e.g. `/temperatureCelsius: number`, for
+    // sensors that send `"21.5"` or `"true"`. Pointers address the mapped
+    // properties (after the field map or script, before flattening); a
+    // value that doesn't convert is left as it is.
+    pub coerce: BTreeMap<String, CoerceType>,
+    // Pointers into the same properties as `coerce`, for payloads with bulky
+    // fields (images, raw sample arrays). With `include_fields` only those
+    // values are kept; `exclude_fields` are then removed, so an exclusion
+    // inside an included object wins.
+    pub include_fields: Vec<String>,
+    pub exclude_fields: Vec<String>,
+    // A baseline shape for every element, merged under the properties:
+    // missing keys (nested ones too) are filled in, present ones are kept,
+    // e.g. `{battery: null, location: {floor: 0}}`
+    pub default_properties: BTreeMap<String, Value>,
+    // Static properties added to every element, e.g. `{environment: prod}`
+    pub enrich: EnrichConfig,
+    // Turns a JSON array payload into one element per item, with its ID at
+    // `item_id_pointer` within the item, or else `<topic id>-<index>`
+    pub explode_arrays: bool,
+    pub item_id_pointer: Option<String>,
+    // Only payloads (or exploded items) matching this become elements, e.g.
+    // `filter: { gt: { pointer: /temperature, value: 30 } }`
+    #[serde(with = "serde_yaml::with::singleton_map_recursive")]
+    pub filter: Option<PayloadFilter>,
+    // A Rhai script computing the properties from `payload` and `topic`,
+    // used in place of the field map
+    pub script: Option<ScriptConfig>,
+    // Rules for deriving relations from topic segments; the first rule
+    // whose pattern matches applies
+    pub topic_hierarchy: Vec<HierarchyRule>,
+    // Templates binding topic segments to the element, e.g.
+    // `lfx/drasi/{type}/{id}`; the first one that matches applies
+    pub topic_templates: Vec<TopicTemplateRule>,
+    // Topics whose messages describe an edge rather than a node, e.g.
+    // `links/#`; the first route whose filter matches applies
+    pub relation_routes: Vec<RelationRoute>,
+    // Relations each node brings along, to the entity its properties name,
+    // e.g. `{pointer: /room, label: IN_ROOM}`
+    pub payload_relations: Vec<PayloadRelation>,
+}
+
+// A node whose (mapped) properties have an ID at `pointer` also gets a
+// `label` relation to that ID, or from it with `reverse`, emitted right
+// after it. A node without a value there gets no relation.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct PayloadRelation {
+    pub pointer: String,
+    pub label: String,
+    #[serde(default)]
+    pub reverse: bool,
+}
+
+// A message on `topic` (an MQTT filter) becomes a single `label` relation
+// from the ID at `start_pointer` in its payload to the one at `end_pointer`,
+// carrying the payload's other fields as properties. Empty payloads on
+// these topics are ignored, since there is no payload to name the edge.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RelationRoute {
+    pub topic: String,
+    pub start_pointer: String,
+    pub end_pointer: String,
+    pub label: String,
+}
+
+// `pattern` is a topic template: literal segments, `{name}` captures and the
+// usual `+` / trailing `#` wildcards. Each relation connects two captures.
+// Same pattern syntax as `topic_hierarchy`. `id_from` names the capture used
+// as the element ID and `labels_from` the captures used as labels, replacing
+// `id_source` and the label rules for matching topics. Other captures are
+// added as properties unless the payload already has that key.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct TopicTemplateRule {
+    pub pattern: String,
+    #[serde(default)]
+    pub id_from: Option<String>,
+    #[serde(default)]
+    pub labels_from: Vec<String>,
+}
+
+// The script's last expression is the properties object. Runs longer than
+// `timeout_ms` are aborted; then, as on any script error, the message gets
+// the default mapping.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScriptConfig {
+    pub path: PathBuf,
+    #[serde(default = "default_script_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+fn default_script_timeout_ms() -> u64 {
+    DEFAULT_SCRIPT_TIMEOUT_MS
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct HierarchyRule {
+    pub pattern: String,
+    pub relations: Vec<RelationRule>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RelationRule {
+    pub from: String,
+    pub to: String,
+    pub label: String,
+}
+
+// Stamps every object element with the time the source ingested it, and
+// optionally copies the device's own timestamp into `event_time`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimestampConfig {
+    pub enabled: bool,
+    // Property name for the ingestion time
+    pub key: String,
+    pub format: TimestampFormat,
+    // Pointer to a timestamp already present in the payload
+    pub event_time_pointer: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TimestampFormat {
+    // "2024-05-01T12:00:00.000Z"
+    #[default]
+    Rfc3339,
+    // 1714564800000
+    UnixMillis,
+}
+
+// A payload field of the same name wins unless `force` is set
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EnrichConfig {
+    pub properties: BTreeMap<String, Value>,
+    pub force: bool,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeliveryLabels {
+    // Messages the broker replayed from its retained store
+    pub retained: Vec<String>,
+    // Redeliveries (the DUP flag)
+    pub duplicate: Vec<String>,
+    pub qos0: Vec<String>,
+    pub qos1: Vec<String>,
+    pub qos2: Vec<String>,
+}
+
+impl Default for TimestampConfig {
+    fn default() -> Self {
+        TimestampConfig {
+            enabled: true,
+            key: "ingested_at".to_string(),
+            format: TimestampFormat::default(),
+            event_time_pointer: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum PayloadFormat {
+    // Parse as JSON; anything unparseable fails to map
+    #[default]
+    Json,
+    // UTF-8 text, kept as a JSON string
+    RawString,
+    // Arbitrary bytes, base64-encoded into `{"raw": "..."}`
+    Bytes,
+    // Compact binary JSON (RFC 8949); map keys must be strings
+    Cbor,
+    // MessagePack; map keys must be strings
+    #[serde(rename = "msgpack")]
+    MsgPack,
+    // Sniffed per payload, for topic trees that mix formats: text starting
+    // with `{` or `[` is JSON, other text a raw string, and binary a CBOR or
+    // MessagePack map when its first byte says so, else bytes
+    Auto,
+    // One delimited line per message, zipped with `headers` into an object.
+    // No quoting, so fields can't contain the delimiter. With `infer_types`,
+    // fields that parse as numbers become numbers.
+    Csv {
+        headers: Vec<String>,
+        #[serde(default = "default_csv_delimiter")]
+        delimiter: char,
+        #[serde(default = "default_csv_infer_types")]
+        infer_types: bool,
+    },
+    // A bare value such as `21.5` or `open`, placed into a JSON skeleton
+    // wherever it says "{{value}}", e.g. `template: {temperature: "{{value}}"}`.
+    // A string that is exactly the placeholder takes the value's type
+    // (numbers stay numbers); one that merely contains it gets the text.
+    Template(Value),
+    // A packed struct: each field is read at its byte offset. Fields that
+    // run past the end of the payload are skipped with a warning.
+    Binary {
+        fields: Vec<BinaryField>,
+        // For fields without an `endian` of their own
+        #[serde(default)]
+        endian: Endian,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct BinaryField {
+    pub name: String,
+    pub offset: usize,
+    #[serde(rename = "type")]
+    pub kind: BinaryType,
+    #[serde(default)]
+    pub endian: Option<Endian>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BinaryType {
+    U8,
+    I8,
+    U16,
+    I16,
+    U32,
+    I32,
+    U64,
+    I64,
+    F32,
+    F64,
+    // One byte, anything but 0 is true
+    Bool,
+}
+
+impl BinaryType {
+    pub fn size(self) -> usize {
+        match self {
+            BinaryType::U8 | BinaryType::I8 | BinaryType::Bool => 1,
+            BinaryType::U16 | BinaryType::I16 => 2,
+            BinaryType::U32 | BinaryType::I32 | BinaryType::F32 => 4,
+            BinaryType::U64 | BinaryType::I64 | BinaryType::F64 => 8,
+        }
+    }
+}
+
+// Records follow each other, each preceded by its length in bytes (not
+// counting the prefix) as an unsigned integer of `prefix_bytes` (1, 2 or 4)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum Framing {
+    #[default]
+    None,
+    LengthPrefixed {
+        #[serde(default = "default_prefix_bytes")]
+        prefix_bytes: u8,
+        #[serde(default)]
+        endian: Endian,
+    },
+}
+
+fn default_prefix_bytes() -> u8 {
+    DEFAULT_FRAMING_PREFIX_BYTES
+}
+
+// Network byte order unless told otherwise
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Endian {
+    #[default]
+    Big,
+    Little,
+}
+
+fn default_csv_delimiter() -> char {
+    DEFAULT_CSV_DELIMITER
+}
+
+fn default_csv_infer_types() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+    // zlib-wrapped deflate (RFC 1950), as produced by zlib's `compress`
+    Deflate,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NullPayload {
+    // The element's ID comes from the topic, as for an empty payload
+    #[default]
+    Delete,
+    // Counted as filtered
+    Skip,
+    Wrap,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NonObjectPayload {
+    #[default]
+    Wrap,
+    // Counted as filtered
+    Skip,
+    Keep,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CoerceType {
+    // Integer if the text is one, else floating point
+    Number,
+    // Also accepts floats without a fractional part, e.g. `"3.0"`
+    Integer,
+    // true/false, yes/no, on/off or 1/0, in any case
+    Bool,
+}
+
+impl CoerceType {
+    pub fn name(self) -> &'static str {
+        match self {
+            CoerceType::Number => "number",
+            CoerceType::Integer => "integer",
+            CoerceType::Bool => "bool",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RawEncoding {
+    #[default]
+    Off,
+    // Lowercase, two digits per byte
+    Hex,
+    // Standard alphabet, padded
+    Base64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TypeSource {
+    // Whichever label comes first once topic, payload and delivery labels
+    // are resolved
+    FirstLabel,
+    // A string (or number) in the payload as published, like label_pointer
+    JsonPointer(String),
+    // The topic segment at this index, negative counting from the end
+    TopicSegment(isize),
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IdSource {
+    // Last topic segment: "lfx/drasi/sensors/temp-01" -> "temp-01"
+    #[default]
+    Topic,
+    // RFC 6901 pointer into the payload, falling back to the topic segment
+    // when it doesn't resolve
+    JsonPointer(String),
+    // The topic segment at this index, counting from 0, or from the end when
+    // negative: "site/3/temp-01/state" -> 2: "temp-01", -1: "state"
+    TopicSegment(isize),
+    // The whole topic with its `/` replaced by this separator (`/` keeps it
+    // as it is), so topics ending in the same segment stay distinct:
+    // "b1/floor2/temp" -> ".": "b1.floor2.temp"
+    FullTopic(String),
+    // Topic segments and payload values joined together, for IDs only
+    // unique in combination: "b1/sensors/x" + {"deviceId": "d7"} -> "b1-d7"
+    Composite(CompositeId),
+}
+
+// A part that can't be found is replaced by `placeholder`, with a warning
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct CompositeId {
+    pub parts: Vec<IdPart>,
+    #[serde(default = "default_composite_separator")]
+    pub separator: String,
+    #[serde(default = "default_composite_placeholder")]
+    pub placeholder: String,
+}
+
+fn default_composite_separator() -> String {
+    DEFAULT_COMPOSITE_SEPARATOR.to_string()
+}
+
+fn default_composite_placeholder() -> String {
+    DEFAULT_COMPOSITE_PLACEHOLDER.to_string()
+}
+
+// Same meanings as the `id_source` variants of the same name
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IdPart {
+    TopicSegment(isize),
+    JsonPointer(String),
+}
+
+// Predicates on JSON pointers into the decoded payload, combined with
+// `all` / `any`. `equals` and `gt` never match a pointer that doesn't
+// resolve; `not_equals` is the exact opposite of `equals`, so it does.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PayloadFilter {
+    All(Vec<PayloadFilter>),
+    Any(Vec<PayloadFilter>),
+    Equals(FilterValue),
+    NotEquals(FilterValue),
+    Gt(FilterThreshold),
+    Exists(FilterExists),
+}
+
+// Numbers compare by value, so `30` equals `30.0`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FilterValue {
+    pub pointer: String,
+    pub value: Value,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FilterThreshold {
+    pub pointer: String,
+    pub value: f64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FilterExists {
+    pub pointer: String,
+    #[serde(default = "default_filter_exists")]
+    pub value: bool,
+}
+
+fn default_filter_exists() -> bool {
+    true
+}
+
+impl PayloadFilter {
+    // `all: []` would pass everything and `any: []` nothing, so either is
+    // rejected as a likely mistake
+    fn has_empty_group(&self) -> bool {
+        match self {
+            PayloadFilter::All(filters) | PayloadFilter::Any(filters) => {
+                filters.is_empty() || filters.iter().any(PayloadFilter::has_empty_group)
+            }
+            _ => false,
+        }
+    }
+}
+
+// `sanitize` replaces anything but ASCII letters, digits and `-_.:` with
+// `_`, then `lowercase` folds the case, and `prefix` (e.g. `plant-a:`) is
+// prepended as given to keep this source's IDs apart from others'
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IdTransformConfig {
+    pub prefix: String,
+    pub lowercase: bool,
+    pub sanitize: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct LabelRule {
+    pub topic_prefix: String,
+    pub labels: Vec<String>,
+}
+
+impl Default for MappingConfig {
+    fn default() -> Self {
+        MappingConfig {
+            label_rules: Vec::new(),
+            default_labels: vec!["Sensor".to_string(), "IoTDevice".to_string()],
+            label_pointer: None,
+            type_source: None,
+            delivery_labels: DeliveryLabels::default(),
+            id_source: IdSource::default(),
+            require_id: false,
+            id_transform: IdTransformConfig::default(),
+            payload_format: PayloadFormat::default(),
+            compression: Compression::default(),
+            framing: Framing::default(),
+            null_payload: NullPayload::default(),
+            non_object_payload: NonObjectPayload::default(),
+            wrap_key: DEFAULT_WRAP_KEY.to_string(),
+            include_mqtt_metadata: true,
+            normalize_topics: true,
+            include_broker: false,
+            include_packet_id: false,
+            preserve_raw: RawEncoding::default(),
+            tag_snapshots: true,
+            timestamp: TimestampConfig::default(),
+            flatten_properties: false,
+            flatten_separator: ".".to_string(),
+            field_map: HashMap::new(),
+            topic_properties: BTreeMap::new(),
+            passthrough_unmapped: true,
+            coerce: BTreeMap::new(),
+            include_fields: Vec::new(),
+            exclude_fields: Vec::new(),
+            default_properties: BTreeMap::new(),
+            enrich: EnrichConfig::default(),
+            explode_arrays: false,
+            item_id_pointer: None,
+            filter: None,
+            script: None,
+            topic_hierarchy: Vec::new(),
+            topic_templates: Vec::new(),
+            relation_routes: Vec::new(),
+            payload_relations: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProtocolVersion {
+    // MQTT 3.1.1
+    #[default]
+    V3,
+    V5,
+}
+
+impl ProtocolVersion {
+    fn parse(name: &str, value: &str) -> Result<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "v3" | "3" | "3.1.1" => Ok(ProtocolVersion::V3),
+            "v5" | "5" => Ok(ProtocolVersion::V5),
+            _ => bail!("{} must be v3 or v5; got {:?}", name, value),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransportKind {
+    #[default]
+    Tcp,
+    Ws,
+    Wss,
+}
+
+impl TransportKind {
+    fn parse(name: &str, value: &str) -> Result<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "tcp" => Ok(TransportKind::Tcp),
+            "ws" => Ok(TransportKind::Ws),
+            "wss" => Ok(TransportKind::Wss),
+            _ => bail!("{} must be tcp, ws or wss; got {:?}", name, value),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputKind {
+    #[default]
+    Log,
+    Http,
+    // Only with the `kafka` cargo feature, which builds librdkafka
+    #[cfg(feature = "kafka")]
+    Kafka,
+    Dapr,
+    // JSON messages on another MQTT broker
+    Mqtt,
+    // Newline-delimited JSON on stdout
+    Stdout,
+    // The same, appended to a file
+    File,
+    Null,
+}
+
+impl OutputKind {
+    fn parse(name: &str, value: &str) -> Result<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "log" => Ok(OutputKind::Log),
+            "http" => Ok(OutputKind::Http),
+            #[cfg(feature = "kafka")]
+            "kafka" => Ok(OutputKind::Kafka),
+            #[cfg(not(feature = "kafka"))]
+            "kafka" => bail!("{} is kafka, but this build has no Kafka support (build with --features kafka)", name),
+            "dapr" => Ok(OutputKind::Dapr),
+            "mqtt" => Ok(OutputKind::Mqtt),
+            "stdout" => Ok(OutputKind::Stdout),
+            "file" => Ok(OutputKind::File),
+            "null" => Ok(OutputKind::Null),
+            _ => bail!("{} must be one of log, http, kafka, dapr, mqtt, stdout, file, null; got {:?}", name, value),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogOutput {
+    // One line per element
+    #[default]
+    Compact,
+    // Indented over several lines, for reading during development
+    Pretty,
+}
+
+impl LogOutput {
+    fn parse(name: &str, value: &str) -> Result<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "compact" => Ok(LogOutput::Compact),
+            "pretty" => Ok(LogOutput::Pretty),
+            _ => bail!("{} must be compact or pretty, got {:?}", name, value),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct HttpConfig {
+    pub url: String,
+    // Total tries per element, including the first one
+    #[serde(default = "default_http_max_attempts")]
+    pub max_attempts: u32,
+}
+
+fn default_http_max_attempts() -> u32 {
+    DEFAULT_HTTP_MAX_ATTEMPTS
+}
+
+// Every change is produced to `topic`, keyed by element (or relation) ID so
+// all updates to a node land on the same partition in order
+#[cfg(feature = "kafka")]
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct KafkaConfig {
+    // Comma-separated `host:port` list
+    pub bootstrap_servers: String,
+    pub topic: String,
+    // Total tries per element, on top of librdkafka's own retries
+    #[serde(default = "default_kafka_max_attempts")]
+    pub max_attempts: u32,
+}
+
+#[cfg(feature = "kafka")]
+fn default_kafka_max_attempts() -> u32 {
+    DEFAULT_KAFKA_MAX_ATTEMPTS
+}
+
+// Every change is published to `topic` on the Dapr pub/sub component named
+// `pubsub`, through the sidecar at `localhost:$DAPR_HTTP_PORT` (default 3500)
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct DaprConfig {
+    pub pubsub: String,
+    pub topic: String,
+    // Total tries per element, including the first one
+    #[serde(default = "default_dapr_max_attempts")]
+    pub max_attempts: u32,
+}
+
+fn default_dapr_max_attempts() -> u32 {
+    DEFAULT_DAPR_MAX_ATTEMPTS
+}
+
+// Every change is republished as JSON to another broker, on `topic` with
+// `{id}` and `{label}` filled in per element
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct MqttOutputConfig {
+    pub host: String,
+    #[serde(default = "default_mqtt_output_port")]
+    pub port: u16,
+    #[serde(default = "default_mqtt_output_topic")]
+    pub topic: String,
+    #[serde(default = "default_qos", deserialize_with = "deserialize_qos", serialize_with = "serialize_qos")]
+    pub qos: QoS,
+    // Lets the target keep each node's latest state; deletes clear it
+    #[serde(default)]
+    pub retain: bool,
+    // Default: `<source_name>-bridge`
+    #[serde(default)]
+    pub client_id: Option<String>,
+    #[serde(default)]
+    pub username: Option<String>,
+    // Prefer DRASI_MQTT_OUTPUT_PASSWORD, which overrides this
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default = "default_keep_alive_secs")]
+    pub keep_alive_secs: u16,
+    #[serde(default)]
+    pub tls: TlsConfig,
+}
+
+fn default_mqtt_output_port() -> u16 {
+    DEFAULT_MQTT_OUTPUT_PORT
+}
+
+fn default_mqtt_output_topic() -> String {
+    DEFAULT_MQTT_OUTPUT_TOPIC.to_string()
+}
+
+fn default_keep_alive_secs() -> u16 {
+    DEFAULT_KEEP_ALIVE_SECS
+}
+
+// Every change as a JSON line appended to `path`; with `rotate_daily` the
+// UTC date is added to the file name and a new file started each day
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileOutputConfig {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub rotate_daily: bool,
+    // How often written lines are forced to disk; shutdown always does
+    #[serde(default = "default_file_fsync_interval_secs")]
+    pub fsync_interval_secs: u64,
+}
+
+fn default_file_fsync_interval_secs() -> u64 {
+    DEFAULT_FILE_FSYNC_INTERVAL_SECS
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BatchConfig {
+    pub max_batch_size: usize,
+    pub flush_interval_ms: u64,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        BatchConfig {
+            max_batch_size: DEFAULT_MAX_BATCH_SIZE,
+            flush_interval_ms: DEFAULT_FLUSH_INTERVAL_MS,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum DeadLetterConfig {
+    // Appended to as JSON Lines
+    File { path: PathBuf },
+    // Republished to `<topic_prefix>/<original topic>`. Don't subscribe to a
+    // filter that also matches these, or failures will feed back into the source.
+    Mqtt {
+        #[serde(default = "default_dead_letter_topic_prefix")]
+        topic_prefix: String,
+    },
+}
+
+fn default_dead_letter_topic_prefix() -> String {
+    DEFAULT_DEAD_LETTER_TOPIC_PREFIX.to_string()
+}
+
+// Every `interval_secs`, logs the messages received per topic since the
+// last summary. With `levels`, topics are grouped by their first that many
+// levels, e.g. `levels: 3` counts `lfx/drasi/sensors/...` together.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TopicSummaryConfig {
+    pub interval_secs: u64,
+    pub levels: Option<usize>,
+}
+
+impl Default for TopicSummaryConfig {
+    fn default() -> Self {
+        TopicSummaryConfig {
+            interval_secs: DEFAULT_TOPIC_SUMMARY_INTERVAL_SECS,
+            levels: None,
+        }
+    }
+}
+
+// Every `interval_secs` (and once at startup), an element `id` labelled
+// `label` goes to the output, with the time and message counts so far
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HeartbeatConfig {
+    pub interval_secs: u64,
+    pub id: String,
+    pub label: String,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        HeartbeatConfig {
+            interval_secs: DEFAULT_HEARTBEAT_INTERVAL_SECS,
+            id: DEFAULT_HEARTBEAT_ID.to_string(),
+            label: DEFAULT_HEARTBEAT_LABEL.to_string(),
+        }
+    }
+}
+
+// Told `event` (connected or disconnected), `source`, `broker` and
+// `timestamp` whenever a connection comes up or goes down
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum ConnectionHookConfig {
+    // POSTed as a JSON object
+    Http { url: String },
+    // Run with the details in DRASI_MQTT_HOOK_* environment variables
+    Command {
+        program: PathBuf,
+        #[serde(default)]
+        args: Vec<String>,
+    },
+}
+
+// After each reconnect, an element `id` labelled `label` with
+// `disconnected_at`, `timestamp` (reconnected) and `downtime_ms`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReconnectNoticeConfig {
+    pub id: String,
+    pub label: String,
+}
+
+impl Default for ReconnectNoticeConfig {
+    fn default() -> Self {
+        ReconnectNoticeConfig {
+            id: DEFAULT_RECONNECT_NOTICE_ID.to_string(),
+            label: DEFAULT_RECONNECT_NOTICE_LABEL.to_string(),
+        }
+    }
+}
+
+// At the end of every `window_secs`, each device whose elements had a
+// value at `pointer` during the window gets one element labelled `label`
+// with the `function` of those values
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct AggregationConfig {
+    // Into the mapped element's properties, e.g. /temperature
+    pub pointer: String,
+    pub function: AggregateFunction,
+    #[serde(default = "default_aggregation_window_secs")]
+    pub window_secs: u64,
+    #[serde(default = "default_aggregation_label")]
+    pub label: String,
+}
+
+fn default_aggregation_window_secs() -> u64 {
+    DEFAULT_AGGREGATION_WINDOW_SECS
+}
+
+fn default_aggregation_label() -> String {
+    DEFAULT_AGGREGATION_LABEL.to_string()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AggregateFunction {
+    Avg,
+    Min,
+    Max,
+    // Counts readings with any value at the pointer; the others only
+    // consider numbers
+    Count,
+}
+
+impl AggregateFunction {
+    pub fn name(self) -> &'static str {
+        match self {
+            AggregateFunction::Avg => "avg",
+            AggregateFunction::Min => "min",
+            AggregateFunction::Max => "max",
+            AggregateFunction::Count => "count",
+        }
+    }
+}
+
+// Opens after `failure_threshold` failed output calls in a row; for
+// `cooldown_ms` changes then fail without calling the output, after which
+// one probe decides whether to close again
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CircuitBreakerConfig {
+    pub failure_threshold: u32,
+    pub cooldown_ms: u64,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        CircuitBreakerConfig {
+            failure_threshold: DEFAULT_BREAKER_FAILURE_THRESHOLD,
+            cooldown_ms: DEFAULT_BREAKER_COOLDOWN_MS,
+        }
+    }
+}
+
+// Up to `capacity` messages wait for another go at the output, the first
+// retry after `initial_backoff_ms`, doubling up to `max_backoff_ms`, for
+// `max_attempts` attempts in all (the failed first one included). `path`
+// keeps the queue in a JSON file across restarts.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryQueueConfig {
+    pub capacity: usize,
+    pub max_attempts: u32,
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    pub path: Option<PathBuf>,
+}
+
+impl Default for RetryQueueConfig {
+    fn default() -> Self {
+        RetryQueueConfig {
+            capacity: DEFAULT_RETRY_QUEUE_CAPACITY,
+            max_attempts: DEFAULT_RETRY_MAX_ATTEMPTS,
+            initial_backoff_ms: DEFAULT_RETRY_INITIAL_BACKOFF_MS,
+            max_backoff_ms: DEFAULT_RETRY_MAX_BACKOFF_MS,
+            path: None,
+        }
+    }
+}
+
+// The last processed packet ID per topic, kept in a JSON file at `path`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct CheckpointConfig {
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ThrottleConfig {
+    pub max_per_second: u32,
+    #[serde(default)]
+    pub overflow: OverflowPolicy,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OverflowPolicy {
+    // Wait for capacity, which backs up into the MQTT event loop
+    #[default]
+    Block,
+    // Discard whatever doesn't fit
+    Drop,
+}
+
+impl OverflowPolicy {
+    fn parse(name: &str, value: &str) -> Result<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "block" => Ok(OverflowPolicy::Block),
+            "drop" => Ok(OverflowPolicy::Drop),
+            _ => bail!("{} must be block or drop; got {:?}", name, value),
+        }
+    }
+}
+
+// Counted after mapping, so `_mqtt`, the timestamp and anything else added
+// count too. With `nested`, every key of the objects inside (arrays
+// included) counts as well; otherwise only the top-level keys do.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct MaxPropertiesConfig {
+    pub limit: usize,
+    #[serde(default)]
+    pub mode: PropertyLimitMode,
+    #[serde(default)]
+    pub nested: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PropertyLimitMode {
+    // Fail the message, which is dead-lettered like any mapping failure
+    #[default]
+    Reject,
+    // Keep the first `limit` keys (in key order, depth first) and drop the rest
+    Truncate,
+}
+
+impl PropertyLimitMode {
+    fn parse(name: &str, value: &str) -> Result<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "reject" => Ok(PropertyLimitMode::Reject),
+            "truncate" => Ok(PropertyLimitMode::Truncate),
+            _ => bail!("{} must be reject or truncate; got {:?}", name, value),
+        }
+    }
+}
+
+// Of every `one_in` node updates for an element ID, the first is sampled.
+// `emit` drops the rest (counted, not emitted); `log` emits everything and
+// logs the sampled ones, for watching an output other than log.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SampleConfig {
+    pub one_in: u64,
+    #[serde(default)]
+    pub mode: SampleMode,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SampleMode {
+    #[default]
+    Emit,
+    Log,
+}
+
+impl SampleMode {
+    fn parse(name: &str, value: &str) -> Result<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "emit" => Ok(SampleMode::Emit),
+            "log" => Ok(SampleMode::Log),
+            _ => bail!("{} must be emit or log; got {:?}", name, value),
+        }
+    }
+}
+
+// Elements with the same ID and payload (or `key_pointer` value) within
+// `ttl_ms` of each other are emitted once. At most `max_entries` keys are
+// remembered.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DedupConfig {
+    pub ttl_ms: u64,
+    pub key_pointer: Option<String>,
+    pub max_entries: usize,
+}
+
+impl Default for DedupConfig {
+    fn default() -> Self {
+        DedupConfig {
+            ttl_ms: DEFAULT_DEDUP_TTL_MS,
+            key_pointer: None,
+            max_entries: DEFAULT_DEDUP_MAX_ENTRIES,
+        }
+    }
+}
+
+// Elements whose labels and properties (less the ingestion timestamp,
+// `_mqtt`, `_pkid` and the `ignore`d top-level keys) match the last emitted
+// for their ID are dropped. The last `max_entries` IDs seen are remembered.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChangeDetectionConfig {
+    pub max_entries: usize,
+    pub ignore: Vec<String>,
+}
+
+impl Default for ChangeDetectionConfig {
+    fn default() -> Self {
+        ChangeDetectionConfig {
+            max_entries: DEFAULT_CHANGE_DETECTION_MAX_ENTRIES,
+            ignore: Vec::new(),
+        }
+    }
+}
+
+// The merged state of the last `max_entries` IDs seen is kept in memory
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MergeConfig {
+    pub max_entries: usize,
+}
+
+impl Default for MergeConfig {
+    fn default() -> Self {
+        MergeConfig {
+            max_entries: DEFAULT_MERGE_MAX_ENTRIES,
+        }
+    }
+}
+
+// The last element emitted for up to `max_entries` IDs is kept in memory
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SnapshotConfig {
+    pub max_entries: usize,
+}
+
+impl Default for SnapshotConfig {
+    fn default() -> Self {
+        SnapshotConfig {
+            max_entries: DEFAULT_SNAPSHOT_MAX_ENTRIES,
+        }
+    }
+}
+
+// Elements are stamped `key` with how many times their ID was emitted
+// before. Counts are kept for up to `max_entries` IDs.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SequenceConfig {
+    pub key: String,
+    pub max_entries: usize,
+}
+
+impl Default for SequenceConfig {
+    fn default() -> Self {
+        SequenceConfig {
+            key: DEFAULT_SEQUENCE_KEY.to_string(),
+            max_entries: DEFAULT_SEQUENCE_MAX_ENTRIES,
+        }
+    }
+}
+
+// Elements are stamped `key` (now + `ttl_seconds`, in the mapping
+// timestamp's format). With `reap`, an ID that gets no message for
+// `ttl_seconds` is deleted.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExpiryConfig {
+    pub ttl_seconds: u64,
+    pub key: String,
+    pub reap: bool,
+}
+
+impl Default for ExpiryConfig {
+    fn default() -> Self {
+        ExpiryConfig {
+            ttl_seconds: DEFAULT_EXPIRY_TTL_SECS,
+            key: DEFAULT_EXPIRY_KEY.to_string(),
+            reap: false,
+        }
+    }
+}
+
+// Holds each element up to `latency_ms` and lets those of one ID through in
+// the order of the timestamp at `timestamp_pointer` (into the properties);
+// at most `capacity` wait per ID. One older than an already emitted one is
+// dropped.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReorderConfig {
+    pub timestamp_pointer: String,
+    pub latency_ms: u64,
+    pub capacity: usize,
+}
+
+impl Default for ReorderConfig {
+    fn default() -> Self {
+        ReorderConfig {
+            timestamp_pointer: DEFAULT_REORDER_POINTER.to_string(),
+            latency_ms: DEFAULT_REORDER_LATENCY_MS,
+            capacity: DEFAULT_REORDER_CAPACITY,
+        }
+    }
+}
+
+// TLS is off unless explicitly enabled. Without a `ca_cert` the platform's
+// native root store is used; `client_cert` + `client_key` enable mutual TLS.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    pub enabled: bool,
+    pub ca_cert: Option<PathBuf>,
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+}
+
+// An HTTP CONNECT proxy, reached over plain TCP (`http`) or TLS (`https`,
+// verified against the platform's root certificates). rumqttc has no SOCKS
+// support, so `socks5` is only recognized to be refused with a clear error.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProxyConfig {
+    #[serde(default)]
+    pub kind: ProxyKind,
+    pub host: String,
+    pub port: u16,
+    #[serde(default)]
+    pub username: Option<String>,
+    // Prefer DRASI_MQTT_PROXY_PASSWORD, which overrides this
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyKind {
+    #[default]
+    Http,
+    Https,
+    Socks5,
+}
+
+// One broker of several. `broker` is required; every other field falls back
+// to its top-level counterpart, so shared settings are written once. The name
+// (by default `host:port`) tells the connections apart in logs and `_broker`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct BrokerConfig {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(rename = "broker")]
+    pub broker_host: String,
+    #[serde(default, rename = "port")]
+    pub broker_port: Option<u16>,
+    #[serde(default)]
+    pub client_id: Option<String>,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default)]
+    pub username_file: Option<PathBuf>,
+    #[serde(default)]
+    pub password_file: Option<PathBuf>,
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+    #[serde(default)]
+    pub subscriptions: Option<Vec<Subscription>>,
+}
+
+// A single topic filter the source listens on. The optional mapping fields
+// override their `mapping` counterparts for topics this filter matches (the
+// first matching subscription applies), so one source can serve topic trees
+// shaped differently.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Subscription {
+    pub topic: String,
+    #[serde(default = "default_qos", deserialize_with = "deserialize_qos", serialize_with = "serialize_qos")]
+    pub qos: QoS,
+    // Optional JSON Schema file; non-conforming payloads fail to map
+    #[serde(default)]
+    pub schema: Option<PathBuf>,
+    #[serde(default, with = "serde_yaml::with::singleton_map")]
+    pub payload_format: Option<PayloadFormat>,
+    #[serde(default)]
+    pub compression: Option<Compression>,
+    #[serde(default, with = "serde_yaml::with::singleton_map_recursive")]
+    pub id_source: Option<IdSource>,
+    // Replaces the label rules and default labels
+    #[serde(default)]
+    pub labels: Option<Vec<String>>,
+    // Workers of its own, instead of sharing the `max_concurrency` ones,
+    // with a queue of `queue_capacity` (default: the top-level one)
+    #[serde(default)]
+    pub workers: Option<usize>,
+    #[serde(default)]
+    pub queue_capacity: Option<usize>,
+}
+
+impl Subscription {
+    pub fn new(topic: impl Into<String>) -> Self {
+        Subscription {
+            topic: topic.into(),
+            qos: DEFAULT_QOS,
+            schema: None,
+            payload_format: None,
+            compression: None,
+            id_source: None,
+            labels: None,
+            workers: None,
+            queue_capacity: None,
+        }
+    }
+}
+
+fn default_qos() -> QoS {
+    DEFAULT_QOS
+}
+
+// --- TOPIC FILTERS ---
+// Brokers refuse a malformed filter at best, and at worst accept it and never
+// deliver anything, so it's rejected here instead. `#` must be a whole level
+// and the last one, `+` a whole level; empty levels (`a//b`, `/a`) are
+// legal MQTT. Shared subscriptions (`$share/<group>/<filter>`) are checked
+// on their filter part.
+fn validate_topic_filter(filter: &str) -> Result<()> {
+    let levels = match filter.strip_prefix(SHARE_PREFIX) {
+        Some(shared) => {
+            let Some((group, levels)) = shared.split_once('/') else {
+                bail!("invalid topic filter {:?}: a shared subscription needs a group and a filter", filter);
+            };
+            if group.is_empty() || group.contains(['+', '#']) {
+                bail!("invalid topic filter {:?}: the share group must be a non-empty name without wildcards", filter);
+            }
+            levels
+        }
+        None => filter,
+    };
+    if levels.is_empty() {
+        bail!("invalid topic filter {:?}: it must not be empty", filter);
+    }
+    if filter.len() > u16::MAX as usize {
+        bail!("invalid topic filter: longer than {} bytes", u16::MAX);
+    }
+    if filter.contains('\0') {
+        bail!("invalid topic filter {:?}: it must not contain NUL characters", filter);
+    }
+    let count = levels.split('/').count();
+    for (position, level) in levels.split('/').enumerate() {
+        if level.contains('#') && (level != "#" || position + 1 != count) {
+            bail!("invalid topic filter {:?}: '#' must be the last level, on its own", filter);
+        }
+        if level.contains('+') && level != "+" {
+            bail!("invalid topic filter {:?}: '+' must occupy a whole level", filter);
+        }
+    }
+    Ok(())
+}
+
+const SHARE_PREFIX: &str = "$share/";
+
+// Whether a concrete topic is one the filter subscribes to, by the MQTT
+// rules: `+` is any one level, a trailing `#` any number of them (the
+// parent level included, so `a/#` matches `a`), and topics starting with
+// `$` only match filters that spell out their first level. A shared
+// subscription matches what its filter part does.
+pub fn topic_matches_filter(topic: &str, filter: &str) -> bool {
+    let filter = unshared(filter);
+    if topic.starts_with('$') && filter.starts_with(['+', '#']) {
+        return false;
+    }
+    let mut topic_levels = topic.split('/');
+    for level in filter.split('/') {
+        match (level, topic_levels.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => {}
+            (level, Some(topic_level)) if level == topic_level => {}
+            _ => return false,
+        }
+    }
+    topic_levels.next().is_none()
+}
+
+// The filter part of a shared subscription
+fn unshared(filter: &str) -> &str {
+    match filter.strip_prefix(SHARE_PREFIX) {
+        Some(shared) => shared.split_once('/').map_or("", |(_, levels)| levels),
+        None => filter,
+    }
+}
+
+// Whether some topic matches both filters (a concrete topic being a filter
+// without wildcards)
+fn filters_overlap(a: &str, b: &str) -> bool {
+    let (a, b) = (unshared(a), unshared(b));
+    // `$` topics are out of reach of a leading wildcard
+    if (a.starts_with('$') && b.starts_with(['+', '#'])) || (b.starts_with('$') && a.starts_with(['+', '#'])) {
+        return false;
+    }
+    let (mut a_levels, mut b_levels) = (a.split('/'), b.split('/'));
+    loop {
+        match (a_levels.next(), b_levels.next()) {
+            (Some("#"), _) | (_, Some("#")) => return true,
+            (None, None) => return true,
+            (Some("+"), Some(_)) | (Some(_), Some("+")) => {}
+            (Some(a_level), Some(b_level)) if a_level == b_level => {}
+            _ => return false,
+        }
+    }
+}
+
+// Whether some topic the mqtt output fills `template` into could match
+// `filter`. Placeholders can expand to anything, slashes included, so only
+// the literal text before the first one narrows it down.
+fn template_overlaps(filter: &str, template: &str) -> bool {
+    let Some(start) = template.find('{') else {
+        return filters_overlap(filter, template);
+    };
+    let filter = unshared(filter);
+    let prefix = &template[..start];
+    if prefix.starts_with('$') != filter.starts_with('$') && !prefix.is_empty() {
+        return false;
+    }
+    let (complete, partial) = match prefix.rsplit_once('/') {
+        Some((complete, partial)) => (Some(complete), partial),
+        None => (None, prefix),
+    };
+    let mut levels = filter.split('/');
+    for level in complete.into_iter().flat_map(|complete| complete.split('/')) {
+        match levels.next() {
+            Some("#") => return true,
+            Some("+") => {}
+            Some(filter_level) if filter_level == level => {}
+            _ => return false,
+        }
+    }
+    match levels.next() {
+        Some("#" | "+") => true,
+        Some(filter_level) => filter_level.starts_with(partial),
+        None => false,
+    }
+}
+
+// `localhost` and the loopback addresses all name this machine
+fn same_host(a: &str, b: &str) -> bool {
+    let loopback = |host: &str| matches!(host, "localhost" | "127.0.0.1" | "::1" | "[::1]");
+    let (a, b) = (a.to_ascii_lowercase(), b.to_ascii_lowercase());
+    a == b || (loopback(&a) && loopback(&b))
+}
+
+// --- QOS PARSING ---
+// Accepts the numeric level ("0", "1", "2") or the spec name in any common
+// spelling ("AtLeastOnce", "at_least_once", "at-least-once").
+pub fn parse_qos(s: &str) -> Result<QoS> {
+    let normalized: String = s
+        .trim()
+        .chars()
+        .filter(|c| *c != '_' && *c != '-')
+        .collect::<String>()
+        .to_ascii_lowercase();
+    match normalized.as_str() {
+        "0" | "atmostonce" => Ok(QoS::AtMostOnce),
+        "1" | "atleastonce" => Ok(QoS::AtLeastOnce),
+        "2" | "exactlyonce" => Ok(QoS::ExactlyOnce),
+        _ => bail!(
+            "Invalid QoS {:?}: expected 0, 1, 2, at_most_once, at_least_once or exactly_once",
+            s
+        ),
+    }
+}
+
+// YAML users naturally write `qos: 1`, so both numbers and strings are
+// accepted and funnelled through `parse_qos`.
+pub fn deserialize_qos<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<QoS, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum RawQos {
+        Number(u64),
+        Name(String),
+    }
+
+    let raw = match RawQos::deserialize(deserializer)? {
+        RawQos::Number(n) => n.to_string(),
+        RawQos::Name(name) => name,
+    };
+    parse_qos(&raw).map_err(serde::de::Error::custom)
+}
+
+// Written back as the number, the way it is usually configured
+pub fn serialize_qos<S: Serializer>(qos: &QoS, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+    serializer.serialize_u8(match qos {
+        QoS::AtMostOnce => 0,
+        QoS::AtLeastOnce => 1,
+        QoS::ExactlyOnce => 2,
+    })
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            broker_host: DEFAULT_BROKER_HOST.to_string(),
+            broker_port: None,
+            subscriptions: vec![Subscription::new(DEFAULT_TOPIC_PATTERN)],
+            client_id_prefix: DEFAULT_CLIENT_ID_PREFIX.to_string(),
+            client_id: None,
+            clean_session: true,
+            manual_ack: false,
+            inflight: DEFAULT_INFLIGHT,
+            channel_capacity: DEFAULT_CHANNEL_CAPACITY,
+            exit_on_subscribe_failure: false,
+            fail_on_qos_downgrade: false,
+            max_reconnect_attempts: 0,
+            initial_connect_attempts: 0,
+            connect_timeout_secs: None,
+            exit_on_connect_timeout: false,
+            idle_timeout_secs: None,
+            max_subscribe_attempts: DEFAULT_MAX_SUBSCRIBE_ATTEMPTS,
+            subscribe_delay_ms: 0,
+            keep_alive_secs: DEFAULT_KEEP_ALIVE_SECS,
+            protocol_version: ProtocolVersion::default(),
+            transport: TransportKind::default(),
+            websocket_path: DEFAULT_WEBSOCKET_PATH.to_string(),
+            lwt_topic: None,
+            lwt_payload: DEFAULT_LWT_PAYLOAD.to_string(),
+            lwt_qos: DEFAULT_QOS,
+            lwt_retain: true,
+            birth_topic: None,
+            allow_loopback: false,
+            birth_payload: DEFAULT_BIRTH_PAYLOAD.to_string(),
+            tls: TlsConfig::default(),
+            username: None,
+            password: None,
+            username_file: None,
+            password_file: None,
+            proxy: None,
+            brokers: Vec::new(),
+            max_concurrency: DEFAULT_MAX_CONCURRENCY,
+            queue_capacity: DEFAULT_QUEUE_CAPACITY,
+            queue_full: OverflowPolicy::default(),
+            fair_scheduling: false,
+            max_active_connections: None,
+            preserve_order: false,
+            drain_timeout_secs: DEFAULT_DRAIN_TIMEOUT_SECS,
+            shutdown_timeout_secs: None,
+            mapping: MappingConfig::default(),
+            output: OutputKind::default(),
+            log_output: LogOutput::default(),
+            http: None,
+            #[cfg(feature = "kafka")]
+            kafka: None,
+            dapr: None,
+            mqtt_output: None,
+            file: None,
+            cloudevents: false,
+            canonicalize: false,
+            redact: Vec::new(),
+            redact_emitted: false,
+            stamp_source: false,
+            source_id_prefix: false,
+            source_name: None,
+            batch: None,
+            dedup: None,
+            change_detection: None,
+            merge_with_previous: None,
+            expiry: None,
+            reorder: None,
+            throttle: None,
+            sample: None,
+            circuit_breaker: None,
+            topic_summary: None,
+            heartbeat: None,
+            reconnect_notice: None,
+            connection_hook: None,
+            snapshot_on_reconnect: None,
+            sequence: None,
+            aggregations: Vec::new(),
+            dead_letter: None,
+            retry_queue: None,
+            checkpoint: None,
+            max_payload_bytes: DEFAULT_MAX_PAYLOAD_BYTES,
+            max_payload_depth: DEFAULT_MAX_PAYLOAD_DEPTH,
+            max_properties: None,
+            dead_letter_oversized: false,
+            log_payload_preview_bytes: DEFAULT_LOG_PAYLOAD_PREVIEW_BYTES,
+            slow_message_ms: None,
+            metrics_addr: DEFAULT_METRICS_ADDR.parse().expect("default metrics address is valid"),
+            health_addr: DEFAULT_HEALTH_ADDR.parse().expect("default health address is valid"),
+        }
+    }
+}
+
+impl Config {
+    // Reads the DRASI_MQTT_* environment variables, falling back to the
+    // defaults above for anything that is unset.
+    pub fn from_env() -> Result<Self> {
+        let mut config = Config::default();
+
+        if let Some(host) = read_var("DRASI_MQTT_BROKER_HOST") {
+            config.broker_host = host;
+        }
+        if let Some(port) = read_var("DRASI_MQTT_BROKER_PORT") {
+            config.broker_port = Some(port.parse::<u16>().map_err(|e| {
+                anyhow!("DRASI_MQTT_BROKER_PORT must be a valid port number (0-65535), got {:?}: {}", port, e)
+            })?);
+        }
+        // Several topic trees can be given as a comma-separated list
+        if let Some(topics) = read_var("DRASI_MQTT_TOPIC") {
+            config.subscriptions = topics
+                .split(',')
+                .map(str::trim)
+                .filter(|topic| !topic.is_empty())
+                .map(Subscription::new)
+                .collect();
+        }
+        if let Some(qos) = read_var("DRASI_MQTT_QOS") {
+            let qos = parse_qos(&qos).context("DRASI_MQTT_QOS is invalid")?;
+            for subscription in &mut config.subscriptions {
+                subscription.qos = qos;
+            }
+        }
+        if let Some(prefix) = read_var("DRASI_MQTT_CLIENT_ID_PREFIX") {
+            config.client_id_prefix = prefix;
+        }
+        config.client_id = read_var("DRASI_MQTT_CLIENT_ID");
+        if let Some(clean) = read_var("DRASI_MQTT_CLEAN_SESSION") {
+            config.clean_session = parse_bool("DRASI_MQTT_CLEAN_SESSION", &clean)?;
+        }
+        if let Some(manual) = read_var("DRASI_MQTT_MANUAL_ACK") {
+            config.manual_ack = parse_bool("DRASI_MQTT_MANUAL_ACK", &manual)?;
+        }
+        if let Some(exit) = read_var("DRASI_MQTT_EXIT_ON_SUBSCRIBE_FAILURE") {
+            config.exit_on_subscribe_failure = parse_bool("DRASI_MQTT_EXIT_ON_SUBSCRIBE_FAILURE", &exit)?;
+        }
+        if let Some(fail) = read_var("DRASI_MQTT_FAIL_ON_QOS_DOWNGRADE") {
+            config.fail_on_qos_downgrade = parse_bool("DRASI_MQTT_FAIL_ON_QOS_DOWNGRADE", &fail)?;
+        }
+        if let Some(attempts) = read_var("DRASI_MQTT_MAX_RECONNECT_ATTEMPTS") {
+            config.max_reconnect_attempts = attempts.parse::<u32>().map_err(|e| {
+                anyhow!("DRASI_MQTT_MAX_RECONNECT_ATTEMPTS must be a whole number, got {:?}: {}", attempts, e)
+            })?;
+        }
+        if let Some(attempts) = read_var("DRASI_MQTT_INITIAL_CONNECT_ATTEMPTS") {
+            config.initial_connect_attempts = attempts.parse::<u32>().map_err(|e| {
+                anyhow!("DRASI_MQTT_INITIAL_CONNECT_ATTEMPTS must be a whole number, got {:?}: {}", attempts, e)
+            })?;
+        }
+        if let Some(secs) = read_var("DRASI_MQTT_CONNECT_TIMEOUT_SECS") {
+            config.connect_timeout_secs = Some(secs.parse::<u64>().map_err(|e| {
+                anyhow!("DRASI_MQTT_CONNECT_TIMEOUT_SECS must be a whole number of seconds, got {:?}: {}", secs, e)
+            })?);
+        }
+        if let Some(exit) = read_var("DRASI_MQTT_EXIT_ON_CONNECT_TIMEOUT") {
+            config.exit_on_connect_timeout = parse_bool("DRASI_MQTT_EXIT_ON_CONNECT_TIMEOUT", &exit)?;
+        }
+        if let Some(secs) = read_var("DRASI_MQTT_IDLE_TIMEOUT_SECS") {
+            config.idle_timeout_secs = Some(secs.parse::<u64>().map_err(|e| {
+                anyhow!("DRASI_MQTT_IDLE_TIMEOUT_SECS must be a whole number of seconds, got {:?}: {}", secs, e)
+            })?);
+        }
+        if let Some(attempts) = read_var("DRASI_MQTT_MAX_SUBSCRIBE_ATTEMPTS") {
+            config.max_subscribe_attempts = attempts.parse::<u32>().map_err(|e| {
+                anyhow!("DRASI_MQTT_MAX_SUBSCRIBE_ATTEMPTS must be a whole number, got {:?}: {}", attempts, e)
+            })?;
+        }
+        if let Some(ms) = read_var("DRASI_MQTT_SUBSCRIBE_DELAY_MS") {
+            config.subscribe_delay_ms = ms.parse::<u64>().map_err(|e| {
+                anyhow!("DRASI_MQTT_SUBSCRIBE_DELAY_MS must be a whole number of milliseconds, got {:?}: {}", ms, e)
+            })?;
+        }
+        if let Some(secs) = read_var("DRASI_MQTT_KEEP_ALIVE_SECS") {
+            config.keep_alive_secs = secs.parse::<u16>().map_err(|e| {
+                anyhow!("DRASI_MQTT_KEEP_ALIVE_SECS must be a whole number of seconds (5-65535), got {:?}: {}", secs, e)
+            })?;
+        }
+        if let Some(inflight) = read_var("DRASI_MQTT_INFLIGHT") {
+            config.inflight = inflight.parse::<u16>().map_err(|e| {
+                anyhow!("DRASI_MQTT_INFLIGHT must be an integer between 1 and 65535, got {:?}: {}", inflight, e)
+            })?;
+        }
+        if let Some(capacity) = read_var("DRASI_MQTT_CHANNEL_CAPACITY") {
+            config.channel_capacity = capacity.parse::<usize>().map_err(|e| {
+                anyhow!("DRASI_MQTT_CHANNEL_CAPACITY must be a positive integer, got {:?}: {}", capacity, e)
+            })?;
+        }
+        config.lwt_topic = read_var("DRASI_MQTT_LWT_TOPIC");
+        if let Some(payload) = read_var("DRASI_MQTT_LWT_PAYLOAD") {
+            config.lwt_payload = payload;
+        }
+        if let Some(qos) = read_var("DRASI_MQTT_LWT_QOS") {
+            config.lwt_qos = parse_qos(&qos).context("DRASI_MQTT_LWT_QOS is invalid")?;
+        }
+        if let Some(retain) = read_var("DRASI_MQTT_LWT_RETAIN") {
+            config.lwt_retain = parse_bool("DRASI_MQTT_LWT_RETAIN", &retain)?;
+        }
+        config.birth_topic = read_var("DRASI_MQTT_BIRTH_TOPIC");
+        if let Some(payload) = read_var("DRASI_MQTT_BIRTH_PAYLOAD") {
+            config.birth_payload = payload;
+        }
+        if let Some(allow) = read_var("DRASI_MQTT_ALLOW_LOOPBACK") {
+            config.allow_loopback = parse_bool("DRASI_MQTT_ALLOW_LOOPBACK", &allow)?;
+        }
+        if let Some(version) = read_var("DRASI_MQTT_PROTOCOL_VERSION") {
+            config.protocol_version = ProtocolVersion::parse("DRASI_MQTT_PROTOCOL_VERSION", &version)?;
+        }
+        if let Some(transport) = read_var("DRASI_MQTT_TRANSPORT") {
+            config.transport = TransportKind::parse("DRASI_MQTT_TRANSPORT", &transport)?;
+        }
+        if let Some(path) = read_var("DRASI_MQTT_WEBSOCKET_PATH") {
+            config.websocket_path = path;
+        }
+        if let Some(proxy) = read_var("DRASI_MQTT_PROXY") {
+            let (kind, address) = match proxy.split_once("://") {
+                None | Some(("http", _)) => (ProxyKind::Http, proxy.trim_start_matches("http://")),
+                Some(("https", address)) => (ProxyKind::Https, address),
+                Some(("socks5" | "socks5h", address)) => (ProxyKind::Socks5, address),
+                Some((scheme, _)) => bail!("DRASI_MQTT_PROXY has an unknown scheme {:?}; use http:// or https://", scheme),
+            };
+            let (host, port) = address
+                .rsplit_once(':')
+                .and_then(|(host, port)| Some((host.to_string(), port.parse::<u16>().ok()?)))
+                .ok_or_else(|| anyhow!("DRASI_MQTT_PROXY must be host:port, got {:?}", proxy))?;
+            config.proxy = Some(ProxyConfig {
+                kind,
+                host,
+                port,
+                username: read_var("DRASI_MQTT_PROXY_USERNAME"),
+                password: None,
+            });
+        }
+        if let Some(enabled) = read_var("DRASI_MQTT_TLS") {
+            config.tls.enabled = parse_bool("DRASI_MQTT_TLS", &enabled)?;
+        }
+        config.tls.ca_cert = read_var("DRASI_MQTT_TLS_CA_CERT").map(PathBuf::from);
+        config.tls.client_cert = read_var("DRASI_MQTT_TLS_CLIENT_CERT").map(PathBuf::from);
+        config.tls.client_key = read_var("DRASI_MQTT_TLS_CLIENT_KEY").map(PathBuf::from);
+        config.username = read_var("DRASI_MQTT_USERNAME");
+        if let Some(limit) = read_var("DRASI_MQTT_MAX_CONCURRENCY") {
+            config.max_concurrency = limit.parse::<usize>().map_err(|e| {
+                anyhow!("DRASI_MQTT_MAX_CONCURRENCY must be a positive integer, got {:?}: {}", limit, e)
+            })?;
+        }
+        if let Some(capacity) = read_var("DRASI_MQTT_QUEUE_CAPACITY") {
+            config.queue_capacity = capacity.parse::<usize>().map_err(|e| {
+                anyhow!("DRASI_MQTT_QUEUE_CAPACITY must be a positive integer, got {:?}: {}", capacity, e)
+            })?;
+        }
+        if let Some(policy) = read_var("DRASI_MQTT_QUEUE_FULL") {
+            config.queue_full = OverflowPolicy::parse("DRASI_MQTT_QUEUE_FULL", &policy)?;
+        }
+        if let Some(enabled) = read_var("DRASI_MQTT_PRESERVE_ORDER") {
+            config.preserve_order = parse_bool("DRASI_MQTT_PRESERVE_ORDER", &enabled)?;
+        }
+        if let Some(enabled) = read_var("DRASI_MQTT_FAIR_SCHEDULING") {
+            config.fair_scheduling = parse_bool("DRASI_MQTT_FAIR_SCHEDULING", &enabled)?;
+        }
+        if let Some(max) = read_var("DRASI_MQTT_MAX_ACTIVE_CONNECTIONS") {
+            config.max_active_connections = Some(max.parse::<usize>().map_err(|e| {
+                anyhow!("DRASI_MQTT_MAX_ACTIVE_CONNECTIONS must be a positive integer, got {:?}: {}", max, e)
+            })?);
+        }
+        // Giving an endpoint implies sending to it unless told otherwise
+        if let Some(secs) = read_var("DRASI_MQTT_DRAIN_TIMEOUT_SECS") {
+            config.drain_timeout_secs = secs.parse::<u64>().map_err(|e| {
+                anyhow!("DRASI_MQTT_DRAIN_TIMEOUT_SECS must be a whole number of seconds, got {:?}: {}", secs, e)
+            })?;
+        }
+        if let Some(secs) = read_var("DRASI_MQTT_SHUTDOWN_TIMEOUT_SECS") {
+            config.shutdown_timeout_secs = Some(secs.parse::<u64>().map_err(|e| {
+                anyhow!("DRASI_MQTT_SHUTDOWN_TIMEOUT_SECS must be a whole number of seconds, got {:?}: {}", secs, e)
+            })?);
+        }
+        if let Some(url) = read_var("DRASI_MQTT_HTTP_URL") {
+            config.http = Some(HttpConfig {
+                url,
+                max_attempts: DEFAULT_HTTP_MAX_ATTEMPTS,
+            });
+            config.output = OutputKind::Http;
+        }
+        #[cfg(not(feature = "kafka"))]
+        if read_var("DRASI_MQTT_KAFKA_BOOTSTRAP_SERVERS").is_some() {
+            bail!("DRASI_MQTT_KAFKA_BOOTSTRAP_SERVERS is set, but this build has no Kafka support (build with --features kafka)");
+        }
+        #[cfg(feature = "kafka")]
+        if let Some(bootstrap_servers) = read_var("DRASI_MQTT_KAFKA_BOOTSTRAP_SERVERS") {
+            let topic = read_var("DRASI_MQTT_KAFKA_TOPIC")
+                .context("DRASI_MQTT_KAFKA_TOPIC is required with DRASI_MQTT_KAFKA_BOOTSTRAP_SERVERS")?;
+            config.kafka = Some(KafkaConfig {
+                bootstrap_servers,
+                topic,
+                max_attempts: DEFAULT_KAFKA_MAX_ATTEMPTS,
+            });
+            config.output = OutputKind::Kafka;
+        }
+        if let Some(pubsub) = read_var("DRASI_MQTT_DAPR_PUBSUB") {
+            let topic = read_var("DRASI_MQTT_DAPR_TOPIC")
+                .context("DRASI_MQTT_DAPR_TOPIC is required with DRASI_MQTT_DAPR_PUBSUB")?;
+            config.dapr = Some(DaprConfig {
+                pubsub,
+                topic,
+                max_attempts: DEFAULT_DAPR_MAX_ATTEMPTS,
+            });
+            config.output = OutputKind::Dapr;
+        }
+        if let Some(host) = read_var("DRASI_MQTT_OUTPUT_BROKER") {
+            let port = match read_var("DRASI_MQTT_OUTPUT_PORT") {
+                Some(port) => port
+                    .parse::<u16>()
+                    .map_err(|e| anyhow!("DRASI_MQTT_OUTPUT_PORT must be a valid port number, got {:?}: {}", port, e))?,
+                None => DEFAULT_MQTT_OUTPUT_PORT,
+            };
+            config.mqtt_output = Some(MqttOutputConfig {
+                host,
+                port,
+                topic: read_var("DRASI_MQTT_OUTPUT_TOPIC").unwrap_or_else(default_mqtt_output_topic),
+                qos: DEFAULT_QOS,
+                retain: false,
+                client_id: None,
+                username: None,
+                password: None,
+                keep_alive_secs: DEFAULT_KEEP_ALIVE_SECS,
+                tls: TlsConfig::default(),
+            });
+            config.output = OutputKind::Mqtt;
+        }
+        if let Some(path) = read_var("DRASI_MQTT_FILE_PATH") {
+            let rotate_daily = match read_var("DRASI_MQTT_FILE_ROTATE_DAILY") {
+                Some(rotate) => parse_bool("DRASI_MQTT_FILE_ROTATE_DAILY", &rotate)?,
+                None => false,
+            };
+            config.file = Some(FileOutputConfig {
+                path: PathBuf::from(path),
+                rotate_daily,
+                fsync_interval_secs: DEFAULT_FILE_FSYNC_INTERVAL_SECS,
+            });
+            config.output = OutputKind::File;
+        }
+        if let Some(output) = read_var("DRASI_MQTT_OUTPUT") {
+            config.output = OutputKind::parse("DRASI_MQTT_OUTPUT", &output)?;
+        }
+        if let Some(format) = read_var("DRASI_MQTT_LOG_OUTPUT") {
+            config.log_output = LogOutput::parse("DRASI_MQTT_LOG_OUTPUT", &format)?;
+        }
+        if let Some(enabled) = read_var("DRASI_MQTT_CLOUDEVENTS") {
+            config.cloudevents = parse_bool("DRASI_MQTT_CLOUDEVENTS", &enabled)?;
+        }
+        if let Some(enabled) = read_var("DRASI_MQTT_CANONICALIZE") {
+            config.canonicalize = parse_bool("DRASI_MQTT_CANONICALIZE", &enabled)?;
+        }
+        if let Some(pointers) = read_var("DRASI_MQTT_REDACT") {
+            config.redact = pointers
+                .split(',')
+                .map(str::trim)
+                .filter(|pointer| !pointer.is_empty())
+                .map(str::to_string)
+                .collect();
+        }
+        if let Some(enabled) = read_var("DRASI_MQTT_REDACT_EMITTED") {
+            config.redact_emitted = parse_bool("DRASI_MQTT_REDACT_EMITTED", &enabled)?;
+        }
+        if let Some(enabled) = read_var("DRASI_MQTT_STAMP_SOURCE") {
+            config.stamp_source = parse_bool("DRASI_MQTT_STAMP_SOURCE", &enabled)?;
+        }
+        if let Some(enabled) = read_var("DRASI_MQTT_SOURCE_ID_PREFIX") {
+            config.source_id_prefix = parse_bool("DRASI_MQTT_SOURCE_ID_PREFIX", &enabled)?;
+        }
+        config.source_name = read_var("DRASI_MQTT_SOURCE_NAME");
+        if let Some(path) = read_var("DRASI_MQTT_DEAD_LETTER_FILE") {
+            config.dead_letter = Some(DeadLetterConfig::File { path: PathBuf::from(path) });
+        }
+        if let Some(topic_prefix) = read_var("DRASI_MQTT_DEAD_LETTER_TOPIC") {
+            config.dead_letter = Some(DeadLetterConfig::Mqtt { topic_prefix });
+        }
+        if let Some(path) = read_var("DRASI_MQTT_CHECKPOINT_FILE") {
+            config.checkpoint = Some(CheckpointConfig { path: PathBuf::from(path) });
+        }
+        if let Some(limit) = read_var("DRASI_MQTT_MAX_PAYLOAD_BYTES") {
+            config.max_payload_bytes = limit.parse::<usize>().map_err(|e| {
+                anyhow!("DRASI_MQTT_MAX_PAYLOAD_BYTES must be a positive integer, got {:?}: {}", limit, e)
+            })?;
+        }
+        if let Some(depth) = read_var("DRASI_MQTT_MAX_PAYLOAD_DEPTH") {
+            config.max_payload_depth = depth.parse::<usize>().map_err(|e| {
+                anyhow!("DRASI_MQTT_MAX_PAYLOAD_DEPTH must be a positive integer, got {:?}: {}", depth, e)
+            })?;
+        }
+        if let Some(enabled) = read_var("DRASI_MQTT_DEAD_LETTER_OVERSIZED") {
+            config.dead_letter_oversized = parse_bool("DRASI_MQTT_DEAD_LETTER_OVERSIZED", &enabled)?;
+        }
+        if let Some(bytes) = read_var("DRASI_MQTT_LOG_PAYLOAD_PREVIEW_BYTES") {
+            config.log_payload_preview_bytes = bytes.parse::<usize>().map_err(|e| {
+                anyhow!("DRASI_MQTT_LOG_PAYLOAD_PREVIEW_BYTES must be a whole number of bytes, got {:?}: {}", bytes, e)
+            })?;
+        }
+        if let Some(ms) = read_var("DRASI_MQTT_SLOW_MESSAGE_MS") {
+            config.slow_message_ms = Some(ms.parse::<u64>().map_err(|e| {
+                anyhow!("DRASI_MQTT_SLOW_MESSAGE_MS must be a whole number of milliseconds, got {:?}: {}", ms, e)
+            })?);
+        }
+        if let Some(secs) = read_var("DRASI_MQTT_TOPIC_SUMMARY_SECS") {
+            config.topic_summary = Some(TopicSummaryConfig {
+                interval_secs: secs.parse::<u64>().map_err(|e| {
+                    anyhow!("DRASI_MQTT_TOPIC_SUMMARY_SECS must be a whole number of seconds, got {:?}: {}", secs, e)
+                })?,
+                levels: None,
+            });
+        }
+        if let Some(secs) = read_var("DRASI_MQTT_HEARTBEAT_SECS") {
+            config.heartbeat = Some(HeartbeatConfig {
+                interval_secs: secs.parse::<u64>().map_err(|e| {
+                    anyhow!("DRASI_MQTT_HEARTBEAT_SECS must be a whole number of seconds, got {:?}: {}", secs, e)
+                })?,
+                ..HeartbeatConfig::default()
+            });
+        }
+        if let Some(secs) = read_var("DRASI_MQTT_TTL_SECONDS") {
+            config.expiry = Some(ExpiryConfig {
+                ttl_seconds: secs.parse::<u64>().map_err(|e| {
+                    anyhow!("DRASI_MQTT_TTL_SECONDS must be a whole number of seconds, got {:?}: {}", secs, e)
+                })?,
+                ..ExpiryConfig::default()
+            });
+        }
+        if let Some(enabled) = read_var("DRASI_MQTT_REAP_EXPIRED") {
+            if parse_bool("DRASI_MQTT_REAP_EXPIRED", &enabled)? {
+                config.expiry.get_or_insert_with(ExpiryConfig::default).reap = true;
+            }
+        }
+        if let Some(ms) = read_var("DRASI_MQTT_REORDER_LATENCY_MS") {
+            config.reorder = Some(ReorderConfig {
+                latency_ms: ms.parse::<u64>().map_err(|e| {
+                    anyhow!("DRASI_MQTT_REORDER_LATENCY_MS must be a whole number of milliseconds, got {:?}: {}", ms, e)
+                })?,
+                ..ReorderConfig::default()
+            });
+        }
+        if let Some(pointer) = read_var("DRASI_MQTT_REORDER_POINTER") {
+            config.reorder.get_or_insert_with(ReorderConfig::default).timestamp_pointer = pointer;
+        }
+        if let Some(limit) = read_var("DRASI_MQTT_MAX_PROPERTIES") {
+            config.max_properties = Some(MaxPropertiesConfig {
+                limit: limit.parse::<usize>().map_err(|e| {
+                    anyhow!("DRASI_MQTT_MAX_PROPERTIES must be a positive integer, got {:?}: {}", limit, e)
+                })?,
+                mode: PropertyLimitMode::default(),
+                nested: false,
+            });
+        }
+        if let Some(mode) = read_var("DRASI_MQTT_MAX_PROPERTIES_MODE") {
+            let mode = PropertyLimitMode::parse("DRASI_MQTT_MAX_PROPERTIES_MODE", &mode)?;
+            match config.max_properties.as_mut() {
+                Some(max_properties) => max_properties.mode = mode,
+                None => bail!("DRASI_MQTT_MAX_PROPERTIES_MODE needs DRASI_MQTT_MAX_PROPERTIES"),
+            }
+        }
+        if let Some(n) = read_var("DRASI_MQTT_SAMPLE_ONE_IN") {
+            config.sample = Some(SampleConfig {
+                one_in: n.parse::<u64>().map_err(|e| {
+                    anyhow!("DRASI_MQTT_SAMPLE_ONE_IN must be a whole number, got {:?}: {}", n, e)
+                })?,
+                mode: SampleMode::default(),
+            });
+        }
+        if let Some(mode) = read_var("DRASI_MQTT_SAMPLE_MODE") {
+            let mode = SampleMode::parse("DRASI_MQTT_SAMPLE_MODE", &mode)?;
+            match config.sample.as_mut() {
+                Some(sample) => sample.mode = mode,
+                None => bail!("DRASI_MQTT_SAMPLE_MODE needs DRASI_MQTT_SAMPLE_ONE_IN"),
+            }
+        }
+        if let Some(enabled) = read_var("DRASI_MQTT_RECONNECT_NOTICE") {
+            if parse_bool("DRASI_MQTT_RECONNECT_NOTICE", &enabled)? {
+                config.reconnect_notice = Some(ReconnectNoticeConfig::default());
+            }
+        }
+        if let Some(url) = read_var("DRASI_MQTT_CONNECTION_HOOK_URL") {
+            config.connection_hook = Some(ConnectionHookConfig::Http { url });
+        }
+        if let Some(program) = read_var("DRASI_MQTT_CONNECTION_HOOK_COMMAND") {
+            if config.connection_hook.is_some() {
+                bail!("Set either DRASI_MQTT_CONNECTION_HOOK_URL or DRASI_MQTT_CONNECTION_HOOK_COMMAND, not both");
+            }
+            config.connection_hook = Some(ConnectionHookConfig::Command {
+                program: PathBuf::from(program),
+                args: Vec::new(),
+            });
+        }
+        if let Some(enabled) = read_var("DRASI_MQTT_SNAPSHOT_ON_RECONNECT") {
+            if parse_bool("DRASI_MQTT_SNAPSHOT_ON_RECONNECT", &enabled)? {
+                config.snapshot_on_reconnect = Some(SnapshotConfig::default());
+            }
+        }
+        if let Some(enabled) = read_var("DRASI_MQTT_SEQUENCE") {
+            if parse_bool("DRASI_MQTT_SEQUENCE", &enabled)? {
+                config.sequence = Some(SequenceConfig::default());
+            }
+        }
+        if let Some(addr) = read_var("DRASI_MQTT_METRICS_ADDR") {
+            config.metrics_addr = parse_addr("DRASI_MQTT_METRICS_ADDR", &addr)?;
+        }
+        if let Some(addr) = read_var("DRASI_MQTT_HEALTH_ADDR") {
+            config.health_addr = parse_addr("DRASI_MQTT_HEALTH_ADDR", &addr)?;
+        }
+        config.apply_secret_env()?;
+
+        Ok(config)
+    }
+
+    // Secrets are read from the environment regardless of where the rest of
+    // the configuration came from, so they never need to be committed. The
+    // secret files (from the configuration, or named by DRASI_MQTT_*_FILE)
+    // are read here too; DRASI_MQTT_PASSWORD still beats them.
+    fn apply_secret_env(&mut self) -> Result<()> {
+        let top = std::iter::once(("", &mut self.username, &mut self.username_file, &mut self.password, &mut self.password_file));
+        let brokers = self.brokers.iter_mut().map(|broker| {
+            ("brokers: ", &mut broker.username, &mut broker.username_file, &mut broker.password, &mut broker.password_file)
+        });
+        for (scope, username, username_file, password, password_file) in top.chain(brokers) {
+            if username.is_some() && username_file.is_some() {
+                bail!("{}set username or username_file, not both", scope);
+            }
+            if password.is_some() && password_file.is_some() {
+                bail!("{}set password or password_file, not both", scope);
+            }
+        }
+        if let Some(path) = read_var("DRASI_MQTT_USERNAME_FILE") {
+            self.username_file = Some(PathBuf::from(path));
+        }
+        if let Some(path) = read_var("DRASI_MQTT_PASSWORD_FILE") {
+            self.password_file = Some(PathBuf::from(path));
+        }
+        let top = std::iter::once((&mut self.username, &self.username_file, &mut self.password, &self.password_file));
+        let brokers = self
+            .brokers
+            .iter_mut()
+            .map(|broker| (&mut broker.username, &broker.username_file, &mut broker.password, &broker.password_file));
+        for (username, username_file, password, password_file) in top.chain(brokers) {
+            if let Some(path) = username_file {
+                *username = Some(read_secret("username", path)?);
+            }
+            if let Some(path) = password_file {
+                *password = Some(read_secret("password", path)?);
+            }
+        }
+        if let Some(password) = read_var("DRASI_MQTT_PASSWORD") {
+            self.password = Some(password);
+        }
+        if let (Some(proxy), Some(password)) = (&mut self.proxy, read_var("DRASI_MQTT_PROXY_PASSWORD")) {
+            proxy.password = Some(password);
+        }
+        if let (Some(output), Some(password)) = (&mut self.mqtt_output, read_var("DRASI_MQTT_OUTPUT_PASSWORD")) {
+            output.password = Some(password);
+        }
+        Ok(())
+    }
+
+    // The settings in effect, as served on `/config`: passwords (top-level,
+    // per broker, the proxy's and the MQTT output's) read "***"
+    pub fn redacted(&self) -> Value {
+        let mut config = self.clone();
+        let passwords = std::iter::once(&mut config.password)
+            .chain(config.brokers.iter_mut().map(|broker| &mut broker.password))
+            .chain(config.proxy.iter_mut().map(|proxy| &mut proxy.password))
+            .chain(config.mqtt_output.iter_mut().map(|output| &mut output.password));
+        for password in passwords.filter(|password| password.is_some()) {
+            *password = Some(REDACTED.to_string());
+        }
+        serde_json::to_value(&config).unwrap_or_default()
+    }
+
+    // What `stamp_source` and `source_id_prefix` call this source. The
+    // prefix stands in for a random client ID, which would differ every run.
+    pub fn source_name(&self) -> &str {
+        self.source_name
+            .as_deref()
+            .or(self.client_id.as_deref())
+            .unwrap_or(&self.client_id_prefix)
+    }
+
+    // The port actually dialed, respecting an explicit override
+    pub fn port(&self) -> u16 {
+        match (self.broker_port, self.transport) {
+            (Some(port), _) => port,
+            (None, TransportKind::Ws) => DEFAULT_WS_BROKER_PORT,
+            (None, TransportKind::Wss) => DEFAULT_WSS_BROKER_PORT,
+            (None, TransportKind::Tcp) if self.tls.enabled => DEFAULT_TLS_BROKER_PORT,
+            (None, TransportKind::Tcp) => DEFAULT_BROKER_PORT,
+        }
+    }
+
+    // One (name, config) per broker connection: this config as it is, or
+    // with `brokers`, each entry layered over it
+    pub fn connections(&self) -> Vec<(String, Config)> {
+        if self.brokers.is_empty() {
+            return vec![(format!("{}:{}", self.broker_host, self.port()), self.clone())];
+        }
+        self.brokers
+            .iter()
+            .map(|broker| {
+                let mut config = self.clone();
+                config.brokers = Vec::new();
+                config.broker_host = broker.broker_host.clone();
+                config.broker_port = broker.broker_port.or(self.broker_port);
+                config.client_id = broker.client_id.clone().or_else(|| self.client_id.clone());
+                config.username = broker.username.clone().or_else(|| self.username.clone());
+                config.password = broker.password.clone().or_else(|| self.password.clone());
+                if let Some(tls) = &broker.tls {
+                    config.tls = tls.clone();
+                }
+                if let Some(subscriptions) = &broker.subscriptions {
+                    config.subscriptions = subscriptions.clone();
+                }
+                let name = broker
+                    .name
+                    .clone()
+                    .unwrap_or_else(|| format!("{}:{}", config.broker_host, config.port()));
+                (name, config)
+            })
+            .collect()
+    }
+
+    // Every connection's subscriptions, for the per-subscription mapping
+    // overrides and schemas
+    pub fn all_subscriptions(&self) -> Vec<Subscription> {
+        self.connections()
+            .into_iter()
+            .flat_map(|(_, connection)| connection.subscriptions)
+            .collect()
+    }
+
+    // A dead letter received back fails again and is republished, forever;
+    // status messages would turn up as elements
+    fn check_loopback(&self) -> Result<()> {
+        let dead_letters = match &self.dead_letter {
+            Some(DeadLetterConfig::Mqtt { topic_prefix }) => {
+                Some(("dead letters", format!("{}/#", topic_prefix.trim_end_matches('/'))))
+            }
+            _ => None,
+        };
+        let published = self
+            .lwt_topic
+            .iter()
+            .map(|topic| ("the status message", topic.clone()))
+            .chain(self.birth_topic.iter().map(|topic| ("the birth message", topic.clone())))
+            .chain(dead_letters);
+        for (what, published) in published {
+            for subscription in &self.subscriptions {
+                if filters_overlap(&subscription.topic, &published) {
+                    bail!(
+                        "the subscription {:?} also matches {:?}, where this source publishes {}; narrow the filter or set allow_loopback",
+                        subscription.topic,
+                        published,
+                        what
+                    );
+                }
+            }
+        }
+        // Republishing to the broker we read from would feed every change
+        // back in
+        let output = self.mqtt_output.as_ref().filter(|output| {
+            self.output == OutputKind::Mqtt
+                && output.port == self.port()
+                && same_host(&output.host, &self.broker_host)
+        });
+        if let Some(output) = output {
+            for subscription in &self.subscriptions {
+                if template_overlaps(&subscription.topic, &output.topic) {
+                    bail!(
+                        "the subscription {:?} also matches {:?}, where mqtt_output republishes on the same broker; narrow the filter, use another topic or set allow_loopback",
+                        subscription.topic,
+                        output.topic
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Catches configurations that would connect fine but never do anything
+    // useful, so they fail at startup instead of silently idling.
+    pub fn validate(&self) -> Result<()> {
+        if self.max_active_connections == Some(0) {
+            bail!("max_active_connections must be greater than 0, otherwise no broker would ever be connected");
+        }
+        // Every event names one source, and the output doesn't know which
+        // connection a change arrived on
+        if self.cloudevents && !self.brokers.is_empty() {
+            bail!("cloudevents can't be combined with a brokers list: each event's source names a single broker");
+        }
+        // Each connection is checked as the config it runs with
+        if !self.brokers.is_empty() {
+            let mut names = HashSet::new();
+            for (name, connection) in self.connections() {
+                if !names.insert(name.clone()) {
+                    bail!("broker names must be unique, but {:?} is used twice", name);
+                }
+                connection.validate().with_context(|| format!("Invalid settings for broker {}", name))?;
+            }
+            return Ok(());
+        }
+        if self.subscriptions.is_empty() {
+            bail!("At least one subscription must be configured, otherwise the source would receive nothing");
+        }
+        for subscription in &self.subscriptions {
+            validate_topic_filter(&subscription.topic)?;
+            if subscription.workers == Some(0) || subscription.queue_capacity == Some(0) {
+                bail!("workers and queue_capacity of subscription {:?} must be greater than 0", subscription.topic);
+            }
+            if subscription.queue_capacity.is_some() && subscription.workers.is_none() {
+                bail!("subscription {:?} sets queue_capacity but has no workers of its own", subscription.topic);
+            }
+        }
+        if !self.allow_loopback {
+            self.check_loopback()?;
+        }
+        if self.keep_alive_secs < MIN_KEEP_ALIVE_SECS {
+            bail!("keep_alive_secs must be at least {}, got {}", MIN_KEEP_ALIVE_SECS, self.keep_alive_secs);
+        }
+        if self.inflight == 0 || self.channel_capacity == 0 {
+            bail!("inflight and channel_capacity must both be greater than 0");
+        }
+        if self.connect_timeout_secs == Some(0) {
+            bail!("connect_timeout_secs must be greater than 0");
+        }
+        if self.shutdown_timeout_secs == Some(0) {
+            bail!("shutdown_timeout_secs must be greater than 0");
+        }
+        if self.idle_timeout_secs == Some(0) {
+            bail!("idle_timeout_secs must be greater than 0");
+        }
+        if self.slow_message_ms == Some(0) {
+            bail!("slow_message_ms must be greater than 0");
+        }
+        if self.max_payload_bytes == 0 || self.max_payload_bytes > MAX_MQTT_PAYLOAD_BYTES {
+            bail!("max_payload_bytes must be between 1 and {}", MAX_MQTT_PAYLOAD_BYTES);
+        }
+        if self.max_payload_depth == 0 || self.max_payload_depth > MAX_JSON_DEPTH {
+            bail!("max_payload_depth must be between 1 and {}", MAX_JSON_DEPTH);
+        }
+        if self.max_properties.as_ref().is_some_and(|max_properties| max_properties.limit == 0) {
+            bail!("max_properties.limit must be greater than 0");
+        }
+        if let Some(proxy) = &self.proxy {
+            if proxy.kind == ProxyKind::Socks5 {
+                bail!("SOCKS5 proxies are not supported: the MQTT client can only tunnel through an HTTP CONNECT proxy (http or https)");
+            }
+            if proxy.host.trim().is_empty() {
+                bail!("proxy.host must not be empty");
+            }
+            if proxy.password.is_some() && proxy.username.is_none() {
+                bail!("proxy.password is set but proxy.username is not");
+            }
+        }
+        if self.max_concurrency == 0 {
+            bail!("max_concurrency must be greater than 0");
+        }
+        if self.queue_capacity == 0 {
+            bail!("queue_capacity must be greater than 0");
+        }
+        if self.output == OutputKind::Http && self.http.is_none() {
+            bail!("output is http but no http section (or DRASI_MQTT_HTTP_URL) is configured");
+        }
+        #[cfg(feature = "kafka")]
+        if self.output == OutputKind::Kafka && self.kafka.is_none() {
+            bail!("output is kafka but no kafka section (or DRASI_MQTT_KAFKA_BOOTSTRAP_SERVERS) is configured");
+        }
+        if self.output == OutputKind::Dapr && self.dapr.is_none() {
+            bail!("output is dapr but no dapr section (or DRASI_MQTT_DAPR_PUBSUB) is configured");
+        }
+        if self.output == OutputKind::Mqtt && self.mqtt_output.is_none() {
+            bail!("output is mqtt but no mqtt_output section (or DRASI_MQTT_OUTPUT_BROKER) is configured");
+        }
+        if let Some(output) = &self.mqtt_output {
+            if output.topic.is_empty() {
+                bail!("mqtt_output.topic must not be empty");
+            }
+            if output.keep_alive_secs < MIN_KEEP_ALIVE_SECS {
+                bail!("mqtt_output.keep_alive_secs must be at least {}, got {}", MIN_KEEP_ALIVE_SECS, output.keep_alive_secs);
+            }
+        }
+        if self.output == OutputKind::File && self.file.is_none() {
+            bail!("output is file but no file section (or DRASI_MQTT_FILE_PATH) is configured");
+        }
+        if self.file.as_ref().is_some_and(|file| file.fsync_interval_secs == 0) {
+            bail!("file.fsync_interval_secs must be greater than 0");
+        }
+        if let Some(batch) = &self.batch {
+            if batch.max_batch_size == 0 || batch.flush_interval_ms == 0 {
+                bail!("batch.max_batch_size and batch.flush_interval_ms must both be greater than 0");
+            }
+        }
+        if let Some(breaker) = &self.circuit_breaker {
+            if breaker.failure_threshold == 0 || breaker.cooldown_ms == 0 {
+                bail!("circuit_breaker.failure_threshold and circuit_breaker.cooldown_ms must both be greater than 0");
+            }
+        }
+        if let Some(retry) = &self.retry_queue {
+            if retry.capacity == 0 || retry.max_attempts < 2 || retry.initial_backoff_ms == 0 {
+                bail!("retry_queue needs a capacity and initial_backoff_ms above 0 and max_attempts of at least 2");
+            }
+            if retry.max_backoff_ms < retry.initial_backoff_ms {
+                bail!("retry_queue.max_backoff_ms must be at least initial_backoff_ms");
+            }
+        }
+        // Both take a message off the worker before the output has it, so it
+        // would be acknowledged while it can still be lost
+        if self.manual_ack && self.batch.is_some() {
+            bail!("manual_ack can't be combined with batch: a message would be acknowledged once buffered, before its batch is sent");
+        }
+        if self.manual_ack && self.retry_queue.is_some() {
+            bail!("manual_ack can't be combined with retry_queue: a message would be acknowledged once queued for retry, before it is delivered");
+        }
+        // The batcher takes every change, so nothing would ever fail in
+        // time to be parked
+        if self.batch.is_some() && self.retry_queue.is_some() {
+            bail!("batch can't be combined with retry_queue: a change is done with once buffered, so a failed batch can't be retried");
+        }
+        if self.source_name.as_ref().is_some_and(|name| name.trim().is_empty()) {
+            bail!("source_name must not be empty");
+        }
+        if self.throttle.as_ref().is_some_and(|throttle| throttle.max_per_second == 0) {
+            bail!("throttle.max_per_second must be greater than 0");
+        }
+        if self.sample.as_ref().is_some_and(|sample| sample.one_in == 0) {
+            bail!("sample.one_in must be greater than 0");
+        }
+        if let Some(summary) = &self.topic_summary {
+            if summary.interval_secs == 0 || summary.levels == Some(0) {
+                bail!("topic_summary.interval_secs and topic_summary.levels must be greater than 0");
+            }
+        }
+        for route in &self.mapping.relation_routes {
+            if route.label.is_empty() {
+                bail!("mapping.relation_routes label must not be empty (topic {:?})", route.topic);
+            }
+            for pointer in [&route.start_pointer, &route.end_pointer] {
+                if !pointer.starts_with('/') {
+                    bail!("mapping.relation_routes pointers must start with '/', got {:?}", pointer);
+                }
+            }
+        }
+        if let Framing::LengthPrefixed { prefix_bytes, .. } = self.mapping.framing {
+            if ![1, 2, 4].contains(&prefix_bytes) {
+                bail!("mapping.framing prefix_bytes must be 1, 2 or 4, got {}", prefix_bytes);
+            }
+        }
+        if self.mapping.wrap_key.is_empty() {
+            bail!("mapping.wrap_key must not be empty");
+        }
+        for relation in &self.mapping.payload_relations {
+            if relation.label.is_empty() {
+                bail!("mapping.payload_relations label must not be empty (pointer {:?})", relation.pointer);
+            }
+            if !relation.pointer.starts_with('/') {
+                bail!("mapping.payload_relations pointers must start with '/', got {:?}", relation.pointer);
+            }
+        }
+        let id_sources = std::iter::once(&self.mapping.id_source)
+            .chain(self.subscriptions.iter().filter_map(|subscription| subscription.id_source.as_ref()));
+        for id_source in id_sources {
+            if let IdSource::Composite(composite) = id_source {
+                if composite.parts.is_empty() {
+                    bail!("id_source composite needs at least one part");
+                }
+                for part in &composite.parts {
+                    if let IdPart::JsonPointer(pointer) = part {
+                        if !pointer.is_empty() && !pointer.starts_with('/') {
+                            bail!("id_source composite json_pointer must start with '/', got {:?}", pointer);
+                        }
+                    }
+                }
+            }
+        }
+        if let Some(pointer) = self.redact.iter().find(|pointer| !pointer.starts_with('/')) {
+            bail!("redact entries must be JSON pointers starting with '/', got {:?}", pointer);
+        }
+        if let Some(pointer) = self.mapping.coerce.keys().find(|pointer| !pointer.starts_with('/')) {
+            bail!("mapping.coerce keys must be JSON pointers starting with '/', got {:?}", pointer);
+        }
+        let fields = self.mapping.include_fields.iter().chain(&self.mapping.exclude_fields);
+        if let Some(pointer) = fields.into_iter().find(|pointer| !pointer.starts_with('/')) {
+            bail!("mapping.include_fields and exclude_fields must be JSON pointers starting with '/', got {:?}", pointer);
+        }
+        for aggregation in &self.aggregations {
+            if !aggregation.pointer.starts_with('/') {
+                bail!(
+                    "aggregations pointer must be a JSON pointer starting with '/', got {:?}",
+                    aggregation.pointer
+                );
+            }
+            if aggregation.window_secs == 0 {
+                bail!("aggregations window_secs must be greater than 0");
+            }
+            if aggregation.label.is_empty() {
+                bail!("aggregations label must not be empty");
+            }
+        }
+        if self.snapshot_on_reconnect.as_ref().is_some_and(|snapshot| snapshot.max_entries == 0) {
+            bail!("snapshot_on_reconnect.max_entries must be greater than 0");
+        }
+        if let Some(sequence) = &self.sequence {
+            if sequence.key.is_empty() {
+                bail!("sequence.key must not be empty");
+            }
+            if sequence.max_entries == 0 {
+                bail!("sequence.max_entries must be greater than 0");
+            }
+        }
+        if self.reconnect_notice.as_ref().is_some_and(|notice| notice.id.is_empty()) {
+            bail!("reconnect_notice.id must not be empty");
+        }
+        match &self.connection_hook {
+            Some(ConnectionHookConfig::Http { url }) => {
+                let parsed = reqwest::Url::parse(url).with_context(|| format!("Invalid connection_hook.http.url {:?}", url))?;
+                if !matches!(parsed.scheme(), "http" | "https") {
+                    bail!("connection_hook.http.url must be http or https, got {:?}", url);
+                }
+            }
+            Some(ConnectionHookConfig::Command { program, .. }) if program.as_os_str().is_empty() => {
+                bail!("connection_hook.command.program must not be empty");
+            }
+            _ => {}
+        }
+        if let Some(heartbeat) = &self.heartbeat {
+            if heartbeat.interval_secs == 0 {
+                bail!("heartbeat.interval_secs must be greater than 0");
+            }
+            if heartbeat.id.is_empty() {
+                bail!("heartbeat.id must not be empty");
+            }
+        }
+        if let Some(dedup) = &self.dedup {
+            if dedup.ttl_ms == 0 || dedup.max_entries == 0 {
+                bail!("dedup.ttl_ms and dedup.max_entries must both be greater than 0");
+            }
+        }
+        if let Some(merge) = &self.merge_with_previous {
+            if merge.max_entries == 0 {
+                bail!("merge_with_previous.max_entries must be greater than 0");
+            }
+        }
+        if let Some(reorder) = &self.reorder {
+            if reorder.latency_ms == 0 || reorder.capacity == 0 {
+                bail!("reorder.latency_ms and reorder.capacity must both be greater than 0");
+            }
+            if !reorder.timestamp_pointer.starts_with('/') {
+                bail!("reorder.timestamp_pointer must start with '/', got {:?}", reorder.timestamp_pointer);
+            }
+        }
+        if let Some(expiry) = &self.expiry {
+            if expiry.ttl_seconds == 0 {
+                bail!("expiry.ttl_seconds must be greater than 0");
+            }
+            if expiry.key.is_empty() {
+                bail!("expiry.key must not be empty");
+            }
+        }
+        if let Some(change_detection) = &self.change_detection {
+            if change_detection.max_entries == 0 {
+                bail!("change_detection.max_entries must be greater than 0");
+            }
+        }
+        if self.mapping.filter.as_ref().is_some_and(PayloadFilter::has_empty_group) {
+            bail!("mapping.filter: every `all` and `any` needs at least one condition");
+        }
+        if self.transport == TransportKind::Ws && self.tls.enabled {
+            bail!("tls.enabled has no effect with transport: ws; use transport: wss for WebSockets over TLS");
+        }
+        if self.transport != TransportKind::Tcp && !self.websocket_path.starts_with('/') {
+            bail!("websocket_path must start with '/', got {:?}", self.websocket_path);
+        }
+        if self.tls.client_cert.is_some() != self.tls.client_key.is_some() {
+            bail!("tls.client_cert and tls.client_key must be given together for mutual TLS");
+        }
+        Ok(())
+    }
+}
+
+// --- YAML LOADING ---
+// A mounted secret usually ends in a newline the broker wouldn't expect
+fn read_secret(what: &str, path: &Path) -> Result<String> {
+    let text = fs::read_to_string(path).with_context(|| format!("Failed to read the {} from {}", what, path.display()))?;
+    Ok(text.trim_end_matches(['\r', '\n']).to_string())
+}
+
+// The file mirrors a Drasi Source definition: everything lives under a
+// top-level `source` key, e.g.
+//
+//   source:
+//     broker: test.mosquitto.org
+//     port: 1883
+//     subscriptions:
+//       - topic: lfx/drasi/sensors/#
+//         qos: 1
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    source: Config,
+}
+
+pub fn from_yaml(path: &Path) -> Result<Config> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read config file {}", path.display()))?;
+    parse_yaml(&text).with_context(|| format!("Invalid config file {}", path.display()))
+}
+
+fn parse_yaml(text: &str) -> Result<Config> {
+    let text = interpolate_env(text)?;
+    let file: ConfigFile = serde_yaml::from_str(&text)?;
+    let mut config = file.source;
+    config.apply_secret_env()?;
+    Ok(config)
+}
+
+// Expands `${VAR}` and `${VAR:-default}` in the YAML text before it is
+// parsed. Empty variables count as unset, like everywhere else. The value is
+// inserted verbatim, so quote placeholders that may expand to YAML syntax
+// (`password: "${MQTT_PASSWORD}"`). Whole-line comments are left alone so
+// commented-out examples don't need their variables.
+fn interpolate_env(text: &str) -> Result<String> {
+    let mut out = String::with_capacity(text.len());
+    for (number, line) in text.split_inclusive('\n').enumerate() {
+        if line.trim_start().starts_with('#') {
+            out.push_str(line);
+            continue;
+        }
+        let mut rest = line;
+        while let Some(start) = rest.find("${") {
+            out.push_str(&rest[..start]);
+            let Some(len) = rest[start..].find('}') else {
+                bail!("line {}: unterminated ${{ placeholder", number + 1);
+            };
+            let placeholder = &rest[start + 2..start + len];
+            let (name, default) = match placeholder.split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (placeholder, None),
+            };
+            let valid = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+            if !valid {
+                bail!("line {}: {:?} is not a valid environment variable name", number + 1, name);
+            }
+            match (read_var(name), default) {
+                (Some(value), _) => out.push_str(&value),
+                (None, Some(default)) => out.push_str(default),
+                (None, None) => bail!(
+                    "line {}: environment variable {} is not set (use ${{{}:-default}} for a fallback)",
+                    number + 1,
+                    name,
+                    name
+                ),
+            }
+            rest = &rest[start + len + 1..];
+        }
+        out.push_str(rest);
+    }
+    Ok(out)
+}
+
+fn parse_addr(name: &str, value: &str) -> Result<SocketAddr> {
+    value
+        .parse()
+        .map_err(|e| anyhow!("{} must be a socket address like 0.0.0.0:9090, got {:?}: {}", name, value, e))
+}
+
+fn parse_bool(name: &str, value: &str) -> Result<bool> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Ok(true),
+        "0" | "false" | "no" | "off" => Ok(false),
+        _ => bail!("{} must be a boolean (true/false), got {:?}", name, value),
+    }
+}
+
+// Treats empty variables the same as unset ones, so `FOO= cargo run` doesn't
+// wipe out a default.
+fn read_var(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(yaml: &str) -> Config {
+        parse_yaml(yaml).expect("config should parse")
+    }
+
+    // Enough to pass validation; tests add what they are about
+    const MINIMAL: &str = "source:\n  broker: localhost\n  subscriptions:\n    - topic: sensors/#\n";
+
+    fn validation_error(config: &Config) -> String {
+        format!("{:#}", config.validate().expect_err("config should be refused"))
+    }
+
+    #[test]
+    fn yaml_round_trips_into_config() {
+        let config = parse(
+            "source:
+  broker: broker.example.com
+  port: 8884
+  subscriptions:
+    - topic: lfx/drasi/sensors/#
+      qos: 1
+    - topic: lfx/drasi/alerts
+      qos: 2
+",
+        );
+        assert_eq!(config.broker_host, "broker.example.com");
+        assert_eq!(config.port(), 8884);
+        let topics: Vec<_> = config.subscriptions.iter().map(|s| (s.topic.as_str(), s.qos)).collect();
+        assert_eq!(topics, [("lfx/drasi/sensors/#", QoS::AtLeastOnce), ("lfx/drasi/alerts", QoS::ExactlyOnce)]);
+
+        let yaml = serde_yaml::to_string(&ConfigFile { source: config }).unwrap();
+        let again = parse(&yaml);
+        assert_eq!(again.broker_host, "broker.example.com");
+        assert_eq!(again.port(), 8884);
+        assert_eq!(again.subscriptions.len(), 2);
+        assert_eq!(again.subscriptions[1].qos, QoS::ExactlyOnce);
+    }
+
+    #[test]
+    fn unset_fields_take_the_defaults() {
+        let config = parse(MINIMAL);
+        assert_eq!(config.port(), 1883);
+        assert_eq!(config.subscriptions[0].qos, default_qos());
+        assert_eq!(config.max_concurrency, DEFAULT_MAX_CONCURRENCY);
+        assert_eq!(config.output, OutputKind::Log);
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn example_config_loads_and_validates() {
+        parse(include_str!("../config.example.yaml")).validate().unwrap();
+    }
+
+    #[test]
+    fn unknown_fields_are_refused() {
+        let error = parse_yaml("source:\n  broker: localhost\n  subscription: []\n").unwrap_err();
+        assert!(format!("{:#}", error).contains("unknown field `subscription`"), "{:#}", error);
+    }
+
+    #[test]
+    fn placeholders_fall_back_to_their_default() {
+        let config = parse("source:\n  broker: ${DRASI_MQTT_TEST_UNSET_HOST:-fallback.local}\n  subscriptions: []\n");
+        assert_eq!(config.broker_host, "fallback.local");
+
+        let error = parse_yaml("source:\n  broker: ${DRASI_MQTT_TEST_UNSET_HOST}\n").unwrap_err();
+        assert!(error.to_string().contains("DRASI_MQTT_TEST_UNSET_HOST is not set"), "{}", error);
+    }
+
+    #[test]
+    fn placeholders_in_comments_are_left_alone() {
+        let config = parse("source:\n  # broker: ${DRASI_MQTT_TEST_UNSET_HOST}\n  broker: localhost\n  subscriptions: []\n");
+        assert_eq!(config.broker_host, "localhost");
+    }
+
+    #[test]
+    fn a_source_without_subscriptions_is_refused() {
+        let config = parse("source:\n  broker: localhost\n  subscriptions: []\n");
+        assert!(validation_error(&config).contains("At least one subscription"));
+    }
+
+    #[test]
+    fn invalid_topic_filters_are_refused() {
+        let mut config = parse(MINIMAL);
+        config.subscriptions[0].topic = "sensors/#/temperature".to_string();
+        config.validate().unwrap_err();
+    }
+
+    #[test]
+    fn tls_moves_the_default_port_unless_one_is_given() {
+        let mut config = parse(MINIMAL);
+        config.tls.enabled = true;
+        assert_eq!(config.port(), 8883);
+        config.broker_port = Some(1884);
+        assert_eq!(config.port(), 1884);
+    }
+
+    #[test]
+    fn qos_parses_levels_and_names() {
+        assert_eq!(parse_qos("0").unwrap(), QoS::AtMostOnce);
+        assert_eq!(parse_qos("1").unwrap(), QoS::AtLeastOnce);
+        assert_eq!(parse_qos("2").unwrap(), QoS::ExactlyOnce);
+        assert_eq!(parse_qos("AtLeastOnce").unwrap(), QoS::AtLeastOnce);
+        assert_eq!(parse_qos("at_most_once").unwrap(), QoS::AtMostOnce);
+        assert_eq!(parse_qos(" exactly-once ").unwrap(), QoS::ExactlyOnce);
+    }
+
+    #[test]
+    fn invalid_qos_is_an_error() {
+        assert!(parse_qos("3").is_err());
+        assert!(parse_qos("high").is_err());
+        let error = parse_yaml("source:\n  broker: localhost\n  subscriptions:\n    - topic: a\n      qos: 5\n").unwrap_err();
+        assert!(format!("{:#}", error).contains("5"), "{:#}", error);
+    }
+
+    #[test]
+    fn qos_is_read_as_a_number_or_a_string() {
+        let config = parse("source:\n  broker: localhost\n  subscriptions:\n    - topic: a\n      qos: 2\n    - topic: b\n      qos: at_least_once\n");
+        assert_eq!(config.subscriptions[0].qos, QoS::ExactlyOnce);
+        assert_eq!(config.subscriptions[1].qos, QoS::AtLeastOnce);
+    }
+
+    #[test]
+    fn subscriptions_matching_our_own_publishes_are_refused() {
+        let mut config = parse(MINIMAL);
+        config.lwt_topic = Some("sensors/source/status".to_string());
+        assert!(validation_error(&config).contains("where this source publishes the status message"));
+        config.allow_loopback = true;
+        config.validate().unwrap();
+
+        let mut config = parse(MINIMAL);
+        config.dead_letter = Some(DeadLetterConfig::Mqtt {
+            topic_prefix: "sensors/dead/".to_string(),
+        });
+        assert!(validation_error(&config).contains("sensors/dead/#"));
+    }
+
+    #[test]
+    fn republishing_into_our_own_subscription_is_refused() {
+        let yaml = "source:
+  broker: localhost
+  subscriptions:
+    - topic: drasi/+/x
+  output: mqtt
+  mqtt_output:
+    host: 127.0.0.1
+    port: 1883
+    topic: \"drasi/{label}/{id}\"
+";
+        let mut config = parse(yaml);
+        assert!(validation_error(&config).contains("where mqtt_output republishes on the same broker"));
+        // Another broker can't feed it back
+        config.mqtt_output.as_mut().unwrap().host = "bridge.example.com".to_string();
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn output_templates_overlap_by_their_literal_prefix() {
+        assert!(template_overlaps("drasi/#", "drasi/{label}/{id}"));
+        assert!(template_overlaps("drasi/+/x", "drasi/{label}/{id}"));
+        assert!(template_overlaps("drasi/Sens+/x", "drasi/Sens{label}"));
+        assert!(template_overlaps("drasi/Sensor/x", "drasi/Sens{label}"));
+        assert!(!template_overlaps("sensors/#", "drasi/{label}/{id}"));
+        assert!(!template_overlaps("drasi", "drasi/{id}"));
+        assert!(!template_overlaps("#", "$SYS/{id}"));
+        // Without placeholders it's a plain topic
+        assert!(template_overlaps("out/+", "out/all"));
+    }
+
+    #[test]
+    fn manual_ack_is_refused_with_batching_or_retries() {
+        let mut config = parse(MINIMAL);
+        config.manual_ack = true;
+        config.validate().unwrap();
+        config.batch = Some(BatchConfig::default());
+        assert!(validation_error(&config).contains("manual_ack can't be combined with batch"));
+        config.batch = None;
+        config.retry_queue = Some(RetryQueueConfig::default());
+        assert!(validation_error(&config).contains("manual_ack can't be combined with retry_queue"));
+    }
+
+    #[test]
+    fn socks5_proxies_are_refused() {
+        let mut config = parse(&format!("{}  proxy:\n    kind: socks5\n    host: proxy.internal\n    port: 1080\n", MINIMAL));
+        assert!(validation_error(&config).contains("SOCKS5 proxies are not supported"));
+        config.proxy.as_mut().unwrap().kind = ProxyKind::Http;
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn max_active_connections_must_allow_one() {
+        let yaml = "source:
+  max_active_connections: 0
+  brokers:
+    - name: site-a
+      broker: mqtt.site-a.example
+      subscriptions:
+        - topic: sensors/#
+";
+        let mut config = parse(yaml);
+        assert!(validation_error(&config).contains("max_active_connections must be greater than 0"));
+        config.max_active_connections = Some(1);
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn topics_match_filters_by_the_mqtt_rules() {
+        assert!(topic_matches_filter("sensors/b1/temp", "sensors/+/temp"));
+        assert!(!topic_matches_filter("sensors/b1/x/temp", "sensors/+/temp"));
+        assert!(topic_matches_filter("sensors", "sensors/#"));
+        assert!(topic_matches_filter("sensors/a/b", "sensors/#"));
+        assert!(!topic_matches_filter("sensors/a", "sensors"));
+        // `$` topics only match filters naming their first level
+        assert!(!topic_matches_filter("$SYS/broker/load", "#"));
+        assert!(topic_matches_filter("$SYS/broker/load", "$SYS/#"));
+        assert!(topic_matches_filter("sensors/a", "$share/workers/sensors/+"));
+    }
+
+    #[test]
+    fn credentials_can_be_read_from_mounted_files() {
+        let dir = std::env::temp_dir();
+        let username = dir.join(format!("drasi-mqtt-username-{}", std::process::id()));
+        let password = dir.join(format!("drasi-mqtt-password-{}", std::process::id()));
+        std::fs::write(&username, "bridge\n").unwrap();
+        std::fs::write(&password, "s3cret\r\n").unwrap();
+        let config = parse(&format!(
+            "{}  username_file: {}\n  password_file: {}\n",
+            MINIMAL,
+            username.display(),
+            password.display()
+        ));
+        assert_eq!(config.username.as_deref(), Some("bridge"));
+        assert_eq!(config.password.as_deref(), Some("s3cret"));
+
+        let both = parse_yaml(&format!("{}  password: inline\n  password_file: {}\n", MINIMAL, password.display()));
+        assert!(format!("{:#}", both.unwrap_err()).contains("set password or password_file, not both"));
+        std::fs::remove_file(&username).unwrap();
+        std::fs::remove_file(&password).unwrap();
+    }
+
+    #[test]
+    fn a_zero_connect_timeout_is_refused() {
+        let mut config = parse(MINIMAL);
+        config.connect_timeout_secs = Some(0);
+        assert!(validation_error(&config).contains("connect_timeout_secs must be greater than 0"));
+        config.connect_timeout_secs = Some(10);
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn a_zero_idle_timeout_is_refused() {
+        let mut config = parse(MINIMAL);
+        config.idle_timeout_secs = Some(0);
+        assert!(validation_error(&config).contains("idle_timeout_secs must be greater than 0"));
+        config.idle_timeout_secs = Some(300);
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn batching_is_refused_with_retries() {
+        let mut config = parse(MINIMAL);
+        config.batch = Some(BatchConfig::default());
+        config.validate().unwrap();
+        config.retry_queue = Some(RetryQueueConfig::default());
+        assert!(validation_error(&config).contains("batch can't be combined with retry_queue"));
+    }
+
+    #[test]
+    fn cloudevents_need_a_single_broker() {
+        let yaml = "source:
+  cloudevents: true
+  brokers:
+    - name: site-a
+      broker: mqtt.site-a.example
+      subscriptions:
+        - topic: sensors/#
+";
+        let mut config = parse(yaml);
+        assert!(validation_error(&config).contains("cloudevents can't be combined with a brokers list"));
+        config.cloudevents = false;
+        config.validate().unwrap();
+    }
+}
@@ -0,0 +1,873 @@
+use anyhow::Result;
+use tracing::warn;
+use rumqttc::{v5, QoS, SubscribeFilter, Transport};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+
+use crate::config::{self, Config, ProtocolVersion, ProxyKind, Subscription, TransportKind};
+use crate::message::{self, Message};
+use crate::tls;
+
+// Room for the topic and v5 properties on top of `max_payload_bytes`
+const PACKET_OVERHEAD_BYTES: usize = 64 * 1024;
+
+// --- MQTT CONNECTION ---
+// rumqttc has separate client and event loop types per protocol version.
+// These wrappers give the rest of the source one API over both, built from
+// our Config so every connection setting lives in one place.
+#[derive(Clone)]
+pub enum MqttClient {
+    V3(rumqttc::AsyncClient),
+    V5(v5::AsyncClient),
+}
+
+pub enum MqttEventLoop {
+    // Boxed: both event loops are large
+    V3(Box<rumqttc::EventLoop>),
+    V5(Box<v5::EventLoop>),
+}
+
+// The events the source acts on; everything else (pings, acks) is `Other`
+pub enum SourceEvent {
+    Message(Message),
+    // With `session_present` the broker kept our subscriptions from before
+    Connected { session_present: bool },
+    // Per filter, in the order they were sent: the granted QoS, or why the
+    // broker refused it
+    SubAck(Vec<Result<QoS, String>>),
+    // Acknowledgement of one of our QoS 1 publishes: its packet ID, or why a
+    // v5 broker rejected it
+    PubAck(Result<u16, String>),
+    DisconnectSent,
+    Other,
+}
+
+// Where the broker session stands. The source publishes it on a watch
+// channel so health, metrics and anything else can follow it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    // Until the first ConnAck
+    Connecting,
+    Connected,
+    // Lost and waiting out the backoff, given up on, or shut down
+    Disconnected,
+    // Polling again after losing the connection
+    Reconnecting,
+}
+
+// Follows several connections as one: Connected only while all of them are,
+// otherwise the state of the first that isn't
+pub fn combine_states(states: Vec<watch::Receiver<ConnectionState>>) -> watch::Receiver<ConnectionState> {
+    if let [state] = states.as_slice() {
+        return state.clone();
+    }
+    let current = |states: &[watch::Receiver<ConnectionState>]| {
+        states
+            .iter()
+            .map(|state| *state.borrow())
+            .find(|state| *state != ConnectionState::Connected)
+            .unwrap_or(ConnectionState::Connected)
+    };
+    let combined = Arc::new(watch::Sender::new(current(&states)));
+    let receiver = combined.subscribe();
+    for mut state in states.clone() {
+        let states = states.clone();
+        let combined = combined.clone();
+        tokio::spawn(async move {
+            while state.changed().await.is_ok() {
+                combined.send_replace(current(&states));
+            }
+        });
+    }
+    receiver
+}
+
+pub fn create_client(config: &Config) -> Result<(MqttClient, MqttEventLoop)> {
+    // Without a fixed ID we use a random one to prevent collisions on the
+    // public broker
+    let client_id = match &config.client_id {
+        Some(client_id) => client_id.clone(),
+        None => {
+            if !config.clean_session {
+                warn!("clean_session is false but no client_id is set; the session can't be resumed after a restart");
+            }
+            format!("{}-{}", config.client_id_prefix, uuid::Uuid::new_v4())
+        }
+    };
+    let transport = match config.transport {
+        TransportKind::Tcp if config.tls.enabled => Transport::tls_with_config(tls::load_tls_configuration(&config.tls)?),
+        TransportKind::Tcp => Transport::tcp(),
+        TransportKind::Ws => Transport::Ws,
+        TransportKind::Wss => Transport::wss_with_config(tls::load_tls_configuration(&config.tls)?),
+    };
+    let broker_addr = broker_addr(config);
+    let keep_alive = Duration::from_secs(config.keep_alive_secs.into());
+    let credentials = credentials(config);
+    let proxy = proxy(config);
+    if config.manual_ack && config.clean_session {
+        warn!("manual_ack is set but clean_session is true; unacknowledged messages are lost on reconnect rather than redelivered");
+    }
+    let max_packet_size = (config.max_payload_bytes + PACKET_OVERHEAD_BYTES).min(config::MAX_MQTT_PAYLOAD_BYTES);
+
+    match config.protocol_version {
+        ProtocolVersion::V3 => {
+            let mut mqttoptions = rumqttc::MqttOptions::new(client_id, broker_addr, config.port());
+            mqttoptions
+                .set_keep_alive(keep_alive)
+                .set_transport(transport)
+                .set_clean_session(config.clean_session)
+                .set_manual_acks(config.manual_ack)
+                .set_inflight(config.inflight)
+                .set_max_packet_size(max_packet_size, max_packet_size);
+            if let Some(topic) = &config.lwt_topic {
+                mqttoptions.set_last_will(rumqttc::LastWill::new(
+                    topic,
+                    config.lwt_payload.as_str(),
+                    config.lwt_qos,
+                    config.lwt_retain,
+                ));
+            }
+            if let Some((username, password)) = credentials {
+                mqttoptions.set_credentials(username, password);
+            }
+            if let Some(proxy) = proxy {
+                mqttoptions.set_proxy(proxy);
+            }
+            let (client, eventloop) = rumqttc::AsyncClient::new(mqttoptions, config.channel_capacity);
+            Ok((MqttClient::V3(client), MqttEventLoop::V3(Box::new(eventloop))))
+        }
+        ProtocolVersion::V5 => {
+            let mut mqttoptions = v5::MqttOptions::new(client_id, broker_addr, config.port());
+            // v5 calls it clean start
+            mqttoptions
+                .set_keep_alive(keep_alive)
+                .set_transport(transport)
+                .set_clean_start(config.clean_session)
+                .set_manual_acks(config.manual_ack)
+                .set_outgoing_inflight_upper_limit(config.inflight)
+                .set_receive_maximum(Some(config.inflight))
+                .set_max_packet_size(u32::try_from(max_packet_size).ok());
+            if let Some(topic) = &config.lwt_topic {
+                mqttoptions.set_last_will(v5::mqttbytes::v5::LastWill::new(
+                    topic,
+                    config.lwt_payload.as_str(),
+                    message::to_v5_qos(config.lwt_qos),
+                    config.lwt_retain,
+                    None,
+                ));
+            }
+            if let Some((username, password)) = credentials {
+                mqttoptions.set_credentials(username, password);
+            }
+            if let Some(proxy) = proxy {
+                mqttoptions.set_proxy(proxy);
+            }
+            let (client, eventloop) = v5::AsyncClient::new(mqttoptions, config.channel_capacity);
+            Ok((MqttClient::V5(client), MqttEventLoop::V5(Box::new(eventloop))))
+        }
+    }
+}
+
+// Over WebSockets rumqttc dials the URL in the broker address and ignores
+// the separate port, so the port goes into the URL
+fn broker_addr(config: &Config) -> String {
+    match config.transport {
+        TransportKind::Tcp => config.broker_host.clone(),
+        TransportKind::Ws => format!("ws://{}:{}{}", config.broker_host, config.port(), config.websocket_path),
+        TransportKind::Wss => format!("wss://{}:{}{}", config.broker_host, config.port(), config.websocket_path),
+    }
+}
+
+fn credentials(config: &Config) -> Option<(String, String)> {
+    match (&config.username, &config.password) {
+        (Some(username), Some(password)) => Some((username.clone(), password.clone())),
+        // Some brokers authenticate on the username alone
+        (Some(username), None) => {
+            warn!("MQTT username given without a password; connecting with username only");
+            Some((username.clone(), String::new()))
+        }
+        // MQTT has no way to send a password without a username
+        (None, Some(_)) => {
+            warn!("MQTT password given without a username; ignoring it and connecting anonymously");
+            None
+        }
+        (None, None) => None,
+    }
+}
+
+// The broker is reached through the proxy with an HTTP CONNECT; the MQTT
+// transport (TLS, WebSocket) then runs inside that tunnel as usual
+fn proxy(config: &Config) -> Option<rumqttc::Proxy> {
+    let proxy = config.proxy.as_ref()?;
+    let ty = match proxy.kind {
+        ProxyKind::Http => rumqttc::ProxyType::Http,
+        ProxyKind::Https => rumqttc::ProxyType::Https(rumqttc::TlsConfiguration::default()),
+        ProxyKind::Socks5 => unreachable!("SOCKS5 proxies are refused by Config::validate"),
+    };
+    let auth = match &proxy.username {
+        Some(username) => rumqttc::ProxyAuth::Basic {
+            username: username.clone(),
+            password: proxy.password.clone().unwrap_or_default(),
+        },
+        None => rumqttc::ProxyAuth::None,
+    };
+    Some(rumqttc::Proxy {
+        ty,
+        auth,
+        addr: proxy.host.clone(),
+        port: proxy.port,
+    })
+}
+
+impl MqttClient {
+    // All filters go out in a single SUBSCRIBE packet instead of one round-trip per topic
+    pub async fn subscribe(&self, subscriptions: &[Subscription]) -> Result<()> {
+        match self {
+            MqttClient::V3(client) => {
+                let filters = subscriptions
+                    .iter()
+                    .map(|subscription| SubscribeFilter::new(subscription.topic.clone(), subscription.qos));
+                client.subscribe_many(filters).await?;
+            }
+            MqttClient::V5(client) => {
+                let filters = subscriptions.iter().map(|subscription| {
+                    v5::mqttbytes::v5::Filter::new(subscription.topic.clone(), message::to_v5_qos(subscription.qos))
+                });
+                client.subscribe_many(filters).await?;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn publish(&self, topic: String, qos: QoS, retain: bool, payload: Vec<u8>) -> Result<()> {
+        match self {
+            MqttClient::V3(client) => client.publish(topic, qos, retain, payload).await?,
+            MqttClient::V5(client) => client.publish(topic, message::to_v5_qos(qos), retain, payload).await?,
+        }
+        Ok(())
+    }
+
+    // Acknowledges a publish received with manual acks on; only its QoS and
+    // packet ID matter. Nothing is sent for QoS 0.
+    pub async fn ack(&self, qos: QoS, pkid: u16) -> Result<()> {
+        match self {
+            MqttClient::V3(client) => {
+                let mut publish = rumqttc::Publish::new("", qos, Vec::new());
+                publish.pkid = pkid;
+                client.ack(&publish).await?;
+            }
+            MqttClient::V5(client) => {
+                let mut publish = v5::mqttbytes::v5::Publish::new("", message::to_v5_qos(qos), Vec::new(), None);
+                publish.pkid = pkid;
+                client.ack(&publish).await?;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn disconnect(&self) -> Result<()> {
+        match self {
+            MqttClient::V3(client) => client.disconnect().await?,
+            MqttClient::V5(client) => client.disconnect().await?,
+        }
+        Ok(())
+    }
+}
+
+impl MqttEventLoop {
+    // Errors mean the connection dropped; polling again reconnects
+    pub async fn poll(&mut self) -> Result<SourceEvent> {
+        let event = match self {
+            MqttEventLoop::V3(eventloop) => match eventloop.poll().await? {
+                rumqttc::Event::Incoming(rumqttc::Packet::Publish(publish)) => SourceEvent::Message(publish.into()),
+                rumqttc::Event::Incoming(rumqttc::Packet::ConnAck(connack)) => SourceEvent::Connected {
+                    session_present: connack.session_present,
+                },
+                rumqttc::Event::Incoming(rumqttc::Packet::SubAck(suback)) => SourceEvent::SubAck(
+                    suback
+                        .return_codes
+                        .into_iter()
+                        .map(|code| match code {
+                            rumqttc::SubscribeReasonCode::Success(qos) => Ok(qos),
+                            rumqttc::SubscribeReasonCode::Failure => Err("Failure".to_string()),
+                        })
+                        .collect(),
+                ),
+                rumqttc::Event::Incoming(rumqttc::Packet::PubAck(puback)) => SourceEvent::PubAck(Ok(puback.pkid)),
+                rumqttc::Event::Outgoing(rumqttc::Outgoing::Disconnect) => SourceEvent::DisconnectSent,
+                _ => SourceEvent::Other,
+            },
+            MqttEventLoop::V5(eventloop) => match eventloop.poll().await? {
+                v5::Event::Incoming(v5::Incoming::Publish(publish)) => SourceEvent::Message(publish.into()),
+                v5::Event::Incoming(v5::Incoming::ConnAck(connack)) => SourceEvent::Connected {
+                    session_present: connack.session_present,
+                },
+                v5::Event::Incoming(v5::Incoming::SubAck(suback)) => {
+                    // v5 brokers may explain themselves
+                    let reason = suback.properties.and_then(|properties| properties.reason_string);
+                    SourceEvent::SubAck(
+                        suback
+                            .return_codes
+                            .into_iter()
+                            .map(|code| match (code, &reason) {
+                                (v5::mqttbytes::v5::SubscribeReasonCode::Success(qos), _) => {
+                                    Ok(message::from_v5_qos(qos))
+                                }
+                                (code, Some(reason)) => Err(format!("{:?} ({})", code, reason)),
+                                (code, None) => Err(format!("{:?}", code)),
+                            })
+                            .collect(),
+                    )
+                }
+                v5::Event::Incoming(v5::Incoming::PubAck(puback)) => {
+                    use v5::mqttbytes::v5::PubAckReason;
+                    SourceEvent::PubAck(match puback.reason {
+                        // Accepted; nobody happens to be listening
+                        PubAckReason::Success | PubAckReason::NoMatchingSubscribers => Ok(puback.pkid),
+                        reason => match puback.properties.and_then(|properties| properties.reason_string) {
+                            Some(explanation) => Err(format!("{:?} ({})", reason, explanation)),
+                            None => Err(format!("{:?}", reason)),
+                        },
+                    })
+                }
+                v5::Event::Outgoing(rumqttc::Outgoing::Disconnect) => SourceEvent::DisconnectSent,
+                _ => SourceEvent::Other,
+            },
+        };
+        Ok(event)
+    }
+
+    // Drops the connection without waiting for the broker to close it; the
+    // next poll connects afresh
+    pub fn drop_connection(&mut self) {
+        match self {
+            MqttEventLoop::V3(eventloop) => eventloop.clean(),
+            MqttEventLoop::V5(eventloop) => eventloop.clean(),
+        }
+    }
+}
+
+// --- SOURCE STATUS ---
+// Publishes our own online (birth) and offline state, complementing the will
+// the broker sends when we drop off without saying goodbye.
+#[derive(Clone)]
+pub struct Status {
+    client: MqttClient,
+    qos: QoS,
+    birth: Option<Announcement>,
+    offline: Option<Announcement>,
+}
+
+#[derive(Clone)]
+struct Announcement {
+    topic: String,
+    payload: String,
+    retain: bool,
+}
+
+impl Status {
+    // None when neither a birth nor an LWT topic is configured
+    pub fn new(client: &MqttClient, config: &Config) -> Option<Self> {
+        let birth = match (&config.birth_topic, &config.lwt_topic) {
+            (Some(topic), _) => Some((topic, true)),
+            (None, Some(topic)) => Some((topic, config.lwt_retain)),
+            (None, None) => None,
+        }
+        .map(|(topic, retain)| Announcement {
+            topic: topic.clone(),
+            payload: config.birth_payload.clone(),
+            retain,
+        });
+        let offline = config.lwt_topic.as_ref().map(|topic| Announcement {
+            topic: topic.clone(),
+            payload: config.lwt_payload.clone(),
+            retain: config.lwt_retain,
+        });
+        if birth.is_none() && offline.is_none() {
+            return None;
+        }
+        Some(Status {
+            client: client.clone(),
+            qos: config.lwt_qos,
+            birth,
+            offline,
+        })
+    }
+
+    pub async fn publish_birth(&self) -> Result<()> {
+        self.publish(self.birth.as_ref()).await
+    }
+
+    pub async fn publish_offline(&self) -> Result<()> {
+        self.publish(self.offline.as_ref()).await
+    }
+
+    async fn publish(&self, announcement: Option<&Announcement>) -> Result<()> {
+        let Some(announcement) = announcement else {
+            return Ok(());
+        };
+        self.client
+            .publish(
+                announcement.topic.clone(),
+                self.qos,
+                announcement.retain,
+                announcement.payload.clone().into_bytes(),
+            )
+            .await
+    }
+}
+
+// --- ERROR CLASSIFICATION ---
+// Most connection errors clear up on their own (broker restarting, network
+// blip), so the source keeps retrying. Some never will: the broker rejected
+// our credentials or client ID, or the TLS handshake failed. Retrying those
+// just spins, so they are reported and end the process instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    Transient,
+    Fatal,
+}
+
+// Takes the error returned by `MqttEventLoop::poll`
+pub fn classify(error: &anyhow::Error) -> ErrorKind {
+    if let Some(error) = error.downcast_ref::<rumqttc::ConnectionError>() {
+        return classify_error(error);
+    }
+    if let Some(error) = error.downcast_ref::<v5::ConnectionError>() {
+        return classify_v5_error(error);
+    }
+    ErrorKind::Transient
+}
+
+pub fn classify_error(error: &rumqttc::ConnectionError) -> ErrorKind {
+    use rumqttc::{ConnectReturnCode, ConnectionError};
+    match error {
+        ConnectionError::ConnectionRefused(
+            ConnectReturnCode::RefusedProtocolVersion
+            | ConnectReturnCode::BadClientId
+            | ConnectReturnCode::BadUserNamePassword
+            | ConnectReturnCode::NotAuthorized,
+        ) => ErrorKind::Fatal,
+        ConnectionError::Tls(error) => classify_tls_error(error),
+        // A malformed WebSocket URL or an endpoint that doesn't speak MQTT
+        // won't fix itself
+        ConnectionError::InvalidUrl(_) | ConnectionError::WsConnect(_) | ConnectionError::ResponseValidation(_) => {
+            ErrorKind::Fatal
+        }
+        // Every client handle is gone, so nothing can be sent any more
+        ConnectionError::RequestsDone => ErrorKind::Fatal,
+        _ => ErrorKind::Transient,
+    }
+}
+
+fn classify_v5_error(error: &v5::ConnectionError) -> ErrorKind {
+    use v5::mqttbytes::v5::ConnectReturnCode;
+    use v5::ConnectionError;
+    match error {
+        ConnectionError::ConnectionRefused(
+            ConnectReturnCode::RefusedProtocolVersion
+            | ConnectReturnCode::UnsupportedProtocolVersion
+            | ConnectReturnCode::BadClientId
+            | ConnectReturnCode::ClientIdentifierNotValid
+            | ConnectReturnCode::BadUserNamePassword
+            | ConnectReturnCode::BadAuthenticationMethod
+            | ConnectReturnCode::NotAuthorized
+            | ConnectReturnCode::Banned,
+        ) => ErrorKind::Fatal,
+        ConnectionError::Tls(error) => classify_tls_error(error),
+        ConnectionError::InvalidUrl(_) | ConnectionError::WsConnect(_) | ConnectionError::ResponseValidation(_) => {
+            ErrorKind::Fatal
+        }
+        ConnectionError::RequestsDone => ErrorKind::Fatal,
+        ConnectionError::MqttState(v5::StateError::ServerDisconnect { reason_code, .. }) => {
+            classify_disconnect(*reason_code)
+        }
+        _ => ErrorKind::Transient,
+    }
+}
+
+// A v5 broker may close the connection with a DISCONNECT saying why. Most
+// reasons (shutting down, busy, keep-alive timeout) pass, but reconnecting
+// won't change our permissions or subscriptions, and after a session
+// takeover it would just kick out the other client using our ID.
+fn classify_disconnect(reason: v5::mqttbytes::v5::DisconnectReasonCode) -> ErrorKind {
+    use v5::mqttbytes::v5::DisconnectReasonCode;
+    match reason {
+        DisconnectReasonCode::NotAuthorized
+        | DisconnectReasonCode::SessionTakenOver
+        | DisconnectReasonCode::ServerMoved
+        | DisconnectReasonCode::TopicFilterInvalid
+        | DisconnectReasonCode::SharedSubscriptionNotSupported
+        | DisconnectReasonCode::WildcardSubscriptionsNotSupported => ErrorKind::Fatal,
+        _ => ErrorKind::Transient,
+    }
+}
+
+// Failures to reach the proxy or to open the tunnel through it, as opposed
+// to trouble with the broker behind it
+pub fn is_proxy_error(error: &anyhow::Error) -> bool {
+    matches!(error.downcast_ref::<rumqttc::ConnectionError>(), Some(rumqttc::ConnectionError::Proxy(_)))
+        || matches!(error.downcast_ref::<v5::ConnectionError>(), Some(v5::ConnectionError::Proxy(_)))
+}
+
+// The reason code (and explanation, if any) of a broker's DISCONNECT
+pub fn disconnect_reason(error: &anyhow::Error) -> Option<String> {
+    match error.downcast_ref::<v5::ConnectionError>()? {
+        v5::ConnectionError::MqttState(v5::StateError::ServerDisconnect {
+            reason_code,
+            reason_string,
+        }) => Some(match reason_string {
+            Some(explanation) => format!("{:?}: {}", reason_code, explanation),
+            None => format!("{:?}", reason_code),
+        }),
+        _ => None,
+    }
+}
+
+// Plain I/O errors (connection reset, refused, ...) are worth retrying. A
+// handshake failure also arrives as I/O, but wraps the rustls error that
+// caused it, e.g. an untrusted or expired certificate.
+fn classify_tls_error(error: &rumqttc::TlsError) -> ErrorKind {
+    match error {
+        rumqttc::TlsError::Io(error) => {
+            let handshake_failed = error
+                .get_ref()
+                .is_some_and(|inner| inner.is::<rumqttc::tokio_rustls::rustls::Error>());
+            if handshake_failed {
+                ErrorKind::Fatal
+            } else {
+                ErrorKind::Transient
+            }
+        }
+        _ => ErrorKind::Fatal,
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use bytes::BytesMut;
+    use rumqttc::mqttbytes::v4::{self, ConnAck, Packet, PingResp, PubAck, SubAck, SubscribeReasonCode};
+    use std::sync::Mutex;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    // How a mock broker treats one connection
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub(crate) enum Session {
+        // CONNACK, then SUBACK, PUBACK and PINGRESP as asked
+        Accept,
+        // Like `Accept` until the first SUBSCRIBE is answered, then closes
+        // the connection
+        Close,
+        // Closes the connection before CONNACK
+        Refuse,
+        // Reads everything but never answers, CONNECT included
+        Silent,
+        // CONNACK, then reads everything but never answers
+        Mute,
+    }
+
+    // --- MOCK BROKER ---
+    // Just enough of an MQTT 3.1.1 broker on localhost to drive a real
+    // client. Connection N follows `sessions[N]` (`Accept` past the end), and
+    // every packet received is kept with the index of its connection.
+    pub(crate) struct MockBroker {
+        pub port: u16,
+        received: Arc<Mutex<Vec<(usize, Packet)>>>,
+        listener: tokio::task::JoinHandle<()>,
+    }
+
+    impl MockBroker {
+        pub(crate) async fn start(sessions: Vec<Session>) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let received = Arc::new(Mutex::new(Vec::new()));
+            let listener = tokio::spawn({
+                let received = received.clone();
+                async move {
+                    for index in 0.. {
+                        let Ok((stream, _)) = listener.accept().await else {
+                            return;
+                        };
+                        let session = sessions.get(index).copied().unwrap_or(Session::Accept);
+                        tokio::spawn(serve(stream, index, session, received.clone()));
+                    }
+                }
+            });
+            MockBroker {
+                port,
+                received,
+                listener,
+            }
+        }
+
+        // A client config pointing at this broker
+        pub(crate) fn config(&self) -> Config {
+            Config {
+                broker_host: "127.0.0.1".to_string(),
+                broker_port: Some(self.port),
+                ..Config::default()
+            }
+        }
+
+        pub(crate) fn received(&self) -> Vec<(usize, Packet)> {
+            self.received.lock().expect("mock broker lock poisoned").clone()
+        }
+
+        // Waits, for at most five seconds, until what was received so far
+        // satisfies `done`
+        pub(crate) async fn until(&self, done: impl Fn(&[(usize, Packet)]) -> bool) {
+            let waited = tokio::time::timeout(Duration::from_secs(5), async {
+                while !done(&self.received()) {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await;
+            assert!(waited.is_ok(), "the mock broker only received {:?}", self.received());
+        }
+    }
+
+    impl std::ops::Drop for MockBroker {
+        fn drop(&mut self) {
+            self.listener.abort();
+        }
+    }
+
+    async fn serve(
+        mut stream: TcpStream,
+        index: usize,
+        session: Session,
+        received: Arc<Mutex<Vec<(usize, Packet)>>>,
+    ) {
+        let mut buffer = BytesMut::new();
+        loop {
+            let packet = match v4::read(&mut buffer, 1 << 20) {
+                Ok(packet) => packet,
+                Err(rumqttc::mqttbytes::Error::InsufficientBytes(_)) => match stream.read_buf(&mut buffer).await {
+                    Ok(0) | Err(_) => return,
+                    Ok(_) => continue,
+                },
+                Err(_) => return,
+            };
+            received.lock().expect("mock broker lock poisoned").push((index, packet.clone()));
+            let mut out = BytesMut::new();
+            match (session, packet) {
+                (Session::Silent, _) => continue,
+                (Session::Refuse, Packet::Connect(_)) => return,
+                (_, Packet::Connect(_)) => ConnAck::new(v4::ConnectReturnCode::Success, false).write(&mut out),
+                (Session::Mute, _) => continue,
+                (_, Packet::Subscribe(subscribe)) => {
+                    let granted = subscribe.filters.iter().map(|filter| SubscribeReasonCode::Success(filter.qos)).collect();
+                    SubAck::new(subscribe.pkid, granted).write(&mut out)
+                }
+                (_, Packet::Publish(publish)) if publish.qos != QoS::AtMostOnce => PubAck::new(publish.pkid).write(&mut out),
+                (_, Packet::PingReq) => PingResp.write(&mut out),
+                (_, Packet::Disconnect) => return,
+                _ => continue,
+            }
+            .unwrap();
+            if stream.write_all(&out).await.is_err() {
+                return;
+            }
+            let subscribed = received
+                .lock()
+                .expect("mock broker lock poisoned")
+                .iter()
+                .any(|(connection, packet)| *connection == index && matches!(packet, Packet::Subscribe(_)));
+            if session == Session::Close && subscribed {
+                return;
+            }
+        }
+    }
+
+    #[test]
+    fn a_password_needs_a_username() {
+        let both = Config {
+            username: Some("bridge".to_string()),
+            password: Some("secret".to_string()),
+            ..Config::default()
+        };
+        assert_eq!(credentials(&both), Some(("bridge".to_string(), "secret".to_string())));
+        let username_only = Config {
+            username: Some("bridge".to_string()),
+            ..Config::default()
+        };
+        assert_eq!(credentials(&username_only), Some(("bridge".to_string(), String::new())));
+        let password_only = Config {
+            password: Some("secret".to_string()),
+            ..Config::default()
+        };
+        assert_eq!(credentials(&password_only), None);
+    }
+
+    #[test]
+    fn websocket_urls_carry_the_port_and_path() {
+        let ws = Config {
+            broker_host: "broker.local".to_string(),
+            transport: TransportKind::Ws,
+            ..Config::default()
+        };
+        assert_eq!(broker_addr(&ws), "ws://broker.local:80/mqtt");
+        let wss = Config {
+            broker_port: Some(8884),
+            websocket_path: "/ws".to_string(),
+            transport: TransportKind::Wss,
+            ..ws.clone()
+        };
+        assert_eq!(broker_addr(&wss), "wss://broker.local:8884/ws");
+        let tcp = Config {
+            transport: TransportKind::Tcp,
+            ..ws
+        };
+        assert_eq!(broker_addr(&tcp), "broker.local");
+    }
+
+    #[tokio::test]
+    async fn several_connections_are_connected_only_together() {
+        let (first, first_state) = watch::channel(ConnectionState::Connected);
+        let (second, second_state) = watch::channel(ConnectionState::Connecting);
+        let mut combined = combine_states(vec![first_state, second_state]);
+        assert_eq!(*combined.borrow_and_update(), ConnectionState::Connecting);
+
+        second.send(ConnectionState::Connected).unwrap();
+        combined.changed().await.unwrap();
+        assert_eq!(*combined.borrow_and_update(), ConnectionState::Connected);
+        first.send(ConnectionState::Reconnecting).unwrap();
+        combined.changed().await.unwrap();
+        assert_eq!(*combined.borrow_and_update(), ConnectionState::Reconnecting);
+    }
+
+    #[test]
+    fn proxy_credentials_become_basic_auth() {
+        let config = Config {
+            proxy: Some(config::ProxyConfig {
+                kind: ProxyKind::Http,
+                host: "proxy.local".to_string(),
+                port: 3128,
+                username: Some("squid".to_string()),
+                password: Some("secret".to_string()),
+            }),
+            ..Config::default()
+        };
+        let tunnel = proxy(&config).unwrap();
+        assert_eq!((tunnel.addr.as_str(), tunnel.port), ("proxy.local", 3128));
+        assert!(matches!(tunnel.ty, rumqttc::ProxyType::Http));
+        assert!(matches!(tunnel.auth, rumqttc::ProxyAuth::Basic { ref username, ref password } if username == "squid" && password == "secret"));
+        assert!(proxy(&Config::default()).is_none());
+    }
+
+    // The options the event loop will connect with
+    fn v3_options(config: &Config) -> rumqttc::MqttOptions {
+        match create_client(config).unwrap().1 {
+            MqttEventLoop::V3(eventloop) => eventloop.mqtt_options,
+            MqttEventLoop::V5(_) => unreachable!("v3 is the default"),
+        }
+    }
+
+    #[test]
+    fn a_persistent_session_keeps_the_configured_client_id() {
+        let persistent = Config {
+            client_id: Some("bridge-1".to_string()),
+            clean_session: false,
+            ..Config::default()
+        };
+        let options = v3_options(&persistent);
+        assert_eq!(options.client_id(), "bridge-1");
+        assert!(!options.clean_session());
+        // Without one every run gets a fresh ID
+        let options = v3_options(&Config::default());
+        assert!(options.client_id().starts_with("drasi-poc-"));
+        assert!(options.clean_session());
+    }
+
+    #[test]
+    fn the_last_will_is_set_from_the_lwt_settings() {
+        let config = Config {
+            lwt_topic: Some("drasi/status".to_string()),
+            lwt_payload: "offline".to_string(),
+            lwt_qos: QoS::AtLeastOnce,
+            lwt_retain: true,
+            ..Config::default()
+        };
+        let will = v3_options(&config).last_will().unwrap();
+        assert_eq!(will.topic, "drasi/status");
+        assert_eq!(&will.message[..], b"offline");
+        assert_eq!(will.qos, QoS::AtLeastOnce);
+        assert!(will.retain);
+        assert!(v3_options(&Config::default()).last_will().is_none());
+    }
+
+    #[test]
+    fn refused_credentials_are_fatal_and_io_errors_transient() {
+        let refused = anyhow::Error::new(rumqttc::ConnectionError::ConnectionRefused(
+            rumqttc::ConnectReturnCode::BadUserNamePassword,
+        ));
+        assert_eq!(classify(&refused), ErrorKind::Fatal);
+        let reset = anyhow::Error::new(rumqttc::ConnectionError::Io(std::io::Error::from(
+            std::io::ErrorKind::ConnectionReset,
+        )));
+        assert_eq!(classify(&reset), ErrorKind::Transient);
+        assert_eq!(classify(&anyhow::anyhow!("something else")), ErrorKind::Transient);
+    }
+
+    #[test]
+    fn the_inflight_window_is_passed_on() {
+        let config = Config {
+            inflight: 10,
+            ..Config::default()
+        };
+        assert_eq!(v3_options(&config).inflight(), 10);
+    }
+
+    #[test]
+    fn the_birth_message_goes_to_its_own_topic_or_the_lwt_one() {
+        let (client, _) = create_client(&Config::default()).unwrap();
+        assert!(Status::new(&client, &Config::default()).is_none());
+
+        let lwt_only = Config {
+            lwt_topic: Some("drasi/status".to_string()),
+            lwt_retain: false,
+            birth_payload: "online".to_string(),
+            ..Config::default()
+        };
+        let birth = Status::new(&client, &lwt_only).unwrap().birth.unwrap();
+        assert_eq!((birth.topic.as_str(), birth.payload.as_str(), birth.retain), ("drasi/status", "online", false));
+
+        let own_topic = Config {
+            birth_topic: Some("drasi/birth".to_string()),
+            ..lwt_only
+        };
+        let birth = Status::new(&client, &own_topic).unwrap().birth.unwrap();
+        // Retained so late subscribers still see it
+        assert_eq!((birth.topic.as_str(), birth.retain), ("drasi/birth", true));
+    }
+
+    #[test]
+    fn the_keep_alive_is_passed_on() {
+        let config = Config {
+            keep_alive_secs: 15,
+            ..Config::default()
+        };
+        assert_eq!(v3_options(&config).keep_alive(), Duration::from_secs(15));
+    }
+
+    #[test]
+    fn a_broker_disconnect_is_classified_by_its_reason() {
+        use v5::mqttbytes::v5::DisconnectReasonCode;
+        let disconnect = |reason_code, reason_string: Option<&str>| {
+            anyhow::Error::new(v5::ConnectionError::MqttState(v5::StateError::ServerDisconnect {
+                reason_code,
+                reason_string: reason_string.map(str::to_string),
+            }))
+        };
+        let taken_over = disconnect(DisconnectReasonCode::SessionTakenOver, None);
+        assert_eq!(classify(&taken_over), ErrorKind::Fatal);
+        assert_eq!(disconnect_reason(&taken_over).as_deref(), Some("SessionTakenOver"));
+        let busy = disconnect(DisconnectReasonCode::ServerBusy, Some("try later"));
+        assert_eq!(classify(&busy), ErrorKind::Transient);
+        assert_eq!(disconnect_reason(&busy).as_deref(), Some("ServerBusy: try later"));
+        assert_eq!(disconnect_reason(&anyhow::anyhow!("reset")), None);
+    }
+}
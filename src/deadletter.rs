@@ -0,0 +1,261 @@
+use anyhow::{anyhow, bail, Context, Result};
+use base64::Engine;
+use rumqttc::QoS;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{oneshot, Mutex};
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use crate::backoff::Backoff;
+use crate::config::{Config, DeadLetterConfig};
+use crate::connection::{self, MqttClient, MqttEventLoop, SourceEvent};
+use crate::error::{MappingError, ParsePosition};
+
+// How long a dead letter sent over MQTT may wait for the broker's PUBACK
+const ACK_TIMEOUT: Duration = Duration::from_secs(10);
+// How long closing waits for the DISCONNECT to go out
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+// --- DEAD LETTERS ---
+// A message we failed to turn into a graph change, kept with enough context
+// to investigate it or feed it back in later.
+#[derive(Debug, Clone, Serialize)]
+pub struct DeadLetter {
+    pub topic: String,
+    // The body as UTF-8 text when possible, otherwise base64 (see `encoding`)
+    pub payload: String,
+    pub encoding: &'static str,
+    // `oversized`, `too_deep`, `parse`, `validation`, `too_many_properties`,
+    // `missing_id`, `id`, `emit` or `shutdown`
+    pub category: &'static str,
+    pub error: String,
+    // Where a JSON payload stopped parsing
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parse_error: Option<ParsePosition>,
+    pub failed_at: String,
+}
+
+impl DeadLetter {
+    pub fn new(topic: &str, payload: &[u8], error: &MappingError) -> Self {
+        let (payload, encoding) = match std::str::from_utf8(payload) {
+            Ok(text) => (text.to_string(), "utf8"),
+            Err(_) => (base64::engine::general_purpose::STANDARD.encode(payload), "base64"),
+        };
+        DeadLetter {
+            topic: topic.to_string(),
+            payload,
+            encoding,
+            category: error.category(),
+            error: error.to_string(),
+            parse_error: error.parse_position(),
+            failed_at: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        }
+    }
+}
+
+// --- DEAD-LETTER SINKS ---
+// Either appends one JSON object per line to a local file, or republishes
+// the record to `<topic_prefix>/<original topic>` on the same broker.
+pub enum DeadLetterSink {
+    File(Mutex<File>),
+    Mqtt(MqttDeadLetters),
+}
+
+// Dead letters go out on a connection of their own, so they are still
+// delivered while the source leaves the broker at shutdown, and a send only
+// succeeds once the broker has acknowledged the record.
+pub struct MqttDeadLetters {
+    client: MqttClient,
+    topic_prefix: String,
+    acks: Acks,
+    // Keeps each publish and its place in `acks` together
+    sending: Mutex<()>,
+    connection: std::sync::Mutex<Option<JoinHandle<()>>>,
+}
+
+// One per publish awaiting its PUBACK, in the order they were sent; QoS 1
+// acknowledgements come back in that order
+type Acks = Arc<std::sync::Mutex<VecDeque<oneshot::Sender<Result<(), String>>>>>;
+
+impl DeadLetterSink {
+    // Opens the file up front so a bad path fails at startup rather than on
+    // the first failed message. `broker` is None when there is no broker
+    // connection (replay mode).
+    pub async fn build(config: &DeadLetterConfig, broker: Option<&Config>) -> Result<Self> {
+        match config {
+            DeadLetterConfig::File { path } => Ok(DeadLetterSink::File(Mutex::new(open_append(path).await?))),
+            DeadLetterConfig::Mqtt { topic_prefix } => Ok(DeadLetterSink::Mqtt(MqttDeadLetters::connect(
+                broker.context("the mqtt dead-letter sink needs a broker connection")?,
+                topic_prefix,
+            )?)),
+        }
+    }
+
+    pub async fn send(&self, letter: &DeadLetter) -> Result<()> {
+        let mut record = serde_json::to_vec(letter)?;
+        match self {
+            DeadLetterSink::File(file) => {
+                record.push(b'\n');
+                let mut file = file.lock().await;
+                file.write_all(&record).await.context("Failed to write dead letter")?;
+                file.flush().await.context("Failed to write dead letter")?;
+            }
+            DeadLetterSink::Mqtt(mqtt) => mqtt.send(&letter.topic, record).await?,
+        }
+        Ok(())
+    }
+
+    // Called last on shutdown; the file is already flushed after every record
+    pub async fn close(&self) {
+        if let DeadLetterSink::Mqtt(mqtt) = self {
+            mqtt.close().await;
+        }
+    }
+}
+
+impl MqttDeadLetters {
+    // The source's connection settings under a client ID of its own, without
+    // its will, subscriptions or persistent session
+    fn connect(broker: &Config, topic_prefix: &str) -> Result<Self> {
+        let mut config = broker.clone();
+        match &broker.client_id {
+            Some(client_id) => config.client_id = Some(format!("{}-dead-letters", client_id)),
+            None => config.client_id_prefix = format!("{}-dead-letters", broker.client_id_prefix),
+        }
+        config.clean_session = true;
+        config.manual_ack = false;
+        config.lwt_topic = None;
+        config.subscriptions = Vec::new();
+        let (client, eventloop) = connection::create_client(&config)?;
+        let acks = Arc::default();
+        let target = format!("{}:{}", config.broker_host, config.port());
+        let connection = tokio::spawn(run(eventloop, Arc::clone(&acks), target));
+        Ok(MqttDeadLetters {
+            client,
+            topic_prefix: topic_prefix.trim_end_matches('/').to_string(),
+            acks,
+            sending: Mutex::new(()),
+            connection: std::sync::Mutex::new(Some(connection)),
+        })
+    }
+
+    async fn send(&self, original_topic: &str, record: Vec<u8>) -> Result<()> {
+        let topic = format!("{}/{}", self.topic_prefix, original_topic);
+        let (ack, acked) = oneshot::channel();
+        {
+            let _sending = self.sending.lock().await;
+            self.acks.lock().expect("dead-letter ack lock poisoned").push_back(ack);
+            if let Err(e) = self.client.publish(topic, QoS::AtLeastOnce, false, record).await {
+                self.acks.lock().expect("dead-letter ack lock poisoned").pop_back();
+                return Err(e.context("Failed to publish dead letter"));
+            }
+        }
+        match tokio::time::timeout(ACK_TIMEOUT, acked).await {
+            Ok(Ok(Ok(()))) => Ok(()),
+            Ok(Ok(Err(reason))) => bail!("the broker refused the dead letter: {}", reason),
+            Ok(Err(_)) => bail!("the dead-letter connection closed before the broker acknowledged the record"),
+            Err(_) => Err(anyhow!("the broker did not acknowledge the dead letter within {:?}", ACK_TIMEOUT)),
+        }
+    }
+
+    async fn close(&self) {
+        let Some(connection) = self.connection.lock().expect("dead-letter connection lock poisoned").take() else {
+            return;
+        };
+        if let Err(e) = self.client.disconnect().await {
+            warn!("Failed to disconnect the dead-letter connection: {:#}", e);
+        }
+        if tokio::time::timeout(CLOSE_TIMEOUT, connection).await.is_err() {
+            warn!("The dead-letter connection did not close within {:?}", CLOSE_TIMEOUT);
+        }
+    }
+}
+
+// Polls the dead-letter connection until `close`, reconnecting with backoff
+// and matching each PUBACK to the oldest publish still waiting for one
+async fn run(mut eventloop: MqttEventLoop, acks: Acks, target: String) {
+    let mut backoff = Backoff::default();
+    loop {
+        match eventloop.poll().await {
+            Ok(SourceEvent::Connected { .. }) => {
+                info!("Connected to {} for dead letters", target);
+                backoff.reset();
+            }
+            Ok(SourceEvent::PubAck(result)) => {
+                if let Some(ack) = acks.lock().expect("dead-letter ack lock poisoned").pop_front() {
+                    let _ = ack.send(result.map(|_| ()));
+                }
+            }
+            Ok(SourceEvent::DisconnectSent) => break,
+            Ok(_) => {}
+            Err(e) => {
+                let delay = backoff.next_delay();
+                warn!("Lost the dead-letter connection to {}: {:#}. Retrying in {:?}...", target, e, delay);
+                tokio::time::sleep(delay).await;
+            }
+        }
+    }
+}
+
+async fn open_append(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+        .with_context(|| format!("Failed to open dead-letter file {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    #[tokio::test]
+    async fn dead_letters_are_appended_to_the_file() {
+        let path = std::env::temp_dir().join(format!("drasi-mqtt-deadletter-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let sink = DeadLetterSink::build(&DeadLetterConfig::File { path: path.clone() }, None).await.unwrap();
+        let error = MappingError::MissingId { pointer: "/deviceId".to_string() };
+        sink.send(&DeadLetter::new("sensors/a", b"{}", &error)).await.unwrap();
+        sink.send(&DeadLetter::new("sensors/b", &[0xff, 0x00], &error)).await.unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let letters: Vec<Value> = contents.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(letters.len(), 2);
+        assert_eq!(letters[0]["topic"], "sensors/a");
+        assert_eq!((&letters[0]["payload"], &letters[0]["encoding"]), (&Value::from("{}"), &Value::from("utf8")));
+        assert_eq!(letters[0]["category"], "missing_id");
+        assert_eq!(letters[0]["error"], "payload has no ID at /deviceId");
+        assert_eq!((&letters[1]["payload"], &letters[1]["encoding"]), (&Value::from("/wA="), &Value::from("base64")));
+    }
+
+    #[tokio::test]
+    async fn the_mqtt_sink_needs_a_broker() {
+        let config = DeadLetterConfig::Mqtt { topic_prefix: "dlq".to_string() };
+        assert!(DeadLetterSink::build(&config, None).await.is_err());
+    }
+
+    #[test]
+    fn a_json_parse_error_records_where_it_stopped() {
+        let decoder = crate::decode::Decoder {
+            format: std::borrow::Cow::Owned(crate::config::PayloadFormat::Json),
+            max_depth: 16,
+            preview_bytes: 0,
+        };
+        let error = decoder.decode(b"{\n  \"t\": 21.5,\n  oops\n}").unwrap_err();
+        let letter = DeadLetter::new("sensors/a", b"", &MappingError::Parse(error));
+        let position = letter.parse_error.unwrap();
+        assert_eq!((position.line, position.column, position.kind), (3, 3, "syntax"));
+        // Other formats don't say
+        let letter = DeadLetter::new("sensors/a", b"", &MappingError::Parse(anyhow!("payload is not valid UTF-8")));
+        assert!(letter.parse_error.is_none());
+    }
+}
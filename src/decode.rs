@@ -0,0 +1,496 @@
+use anyhow::{anyhow, bail, Result};
+use base64::Engine;
+use flate2::read::{GzDecoder, ZlibDecoder};
+use serde_json::{json, Value};
+use std::borrow::Cow;
+use std::io::Read;
+use tracing::warn;
+
+use crate::config::{BinaryField, BinaryType, Compression, Endian, PayloadFormat};
+use crate::error::JsonError;
+use crate::message::Message;
+
+// The user property naming a message's compression
+const CONTENT_ENCODING: &str = "content-encoding";
+// CBOR tag 55799 (RFC 8949, section 3.4.6), which may open any CBOR payload
+const CBOR_SELF_DESCRIBE: &[u8] = &[0xd9, 0xd9, 0xf7];
+
+// --- PAYLOAD DECODING ---
+// Turns the raw MQTT body into a JSON value according to `format`. Shared
+// brokers carry plenty of traffic we can't make sense of, so the error
+// includes the first `preview_bytes` of what actually arrived (none at 0,
+// for sensitive data).
+pub struct Decoder<'a> {
+    pub format: Cow<'a, PayloadFormat>,
+    // Only JSON is checked; the CBOR and MessagePack decoders have limits
+    // of their own
+    pub max_depth: usize,
+    pub preview_bytes: usize,
+}
+
+impl Decoder<'_> {
+    // Counts brackets outside strings without parsing anything, stopping as
+    // soon as the limit is passed. Malformed JSON is left for `decode`.
+    pub fn is_too_deep(&self, payload: &[u8]) -> bool {
+        if *self.format != PayloadFormat::Json {
+            return false;
+        }
+        let mut depth = 0usize;
+        let mut in_string = false;
+        let mut escaped = false;
+        for byte in payload {
+            match byte {
+                _ if escaped => escaped = false,
+                b'\\' if in_string => escaped = true,
+                b'"' => in_string = !in_string,
+                _ if in_string => {}
+                b'{' | b'[' => {
+                    depth += 1;
+                    if depth > self.max_depth {
+                        return true;
+                    }
+                }
+                b'}' | b']' => depth = depth.saturating_sub(1),
+                _ => {}
+            }
+        }
+        false
+    }
+
+    pub fn decode(&self, payload: &[u8]) -> Result<Value> {
+        let quote = |payload: &[u8]| preview(payload, self.preview_bytes);
+        match self.format.as_ref() {
+            PayloadFormat::Json => serde_json::from_slice(payload).map_err(|error| {
+                anyhow::Error::new(JsonError {
+                    error,
+                    preview: quote(payload),
+                })
+            }),
+            PayloadFormat::RawString => std::str::from_utf8(payload)
+                .map(|text| Value::String(text.to_string()))
+                .map_err(|e| anyhow!("payload is not valid UTF-8: {}{}", e, quote(payload))),
+            PayloadFormat::Bytes => Ok(json!({ "raw": base64::engine::general_purpose::STANDARD.encode(payload) })),
+            // The self-describe tag only marks the payload as CBOR, and
+            // ciborium can't put a tag into JSON
+            PayloadFormat::Cbor => ciborium::from_reader(payload.strip_prefix(CBOR_SELF_DESCRIBE).unwrap_or(payload))
+                .map_err(|e| anyhow!("payload is not valid CBOR: {}{}", e, quote(payload))),
+            PayloadFormat::MsgPack => rmp_serde::from_slice(payload)
+                .map_err(|e| anyhow!("payload is not valid MessagePack: {}{}", e, quote(payload))),
+            PayloadFormat::Auto => Decoder {
+                format: Cow::Owned(detect_format(payload)),
+                ..*self
+            }
+            .decode(payload),
+            PayloadFormat::Csv {
+                headers,
+                delimiter,
+                infer_types,
+            } => decode_csv(headers, *delimiter, *infer_types, payload, self.preview_bytes),
+            PayloadFormat::Template(template) => {
+                let text = std::str::from_utf8(payload)
+                    .map_err(|e| anyhow!("payload is not valid UTF-8: {}{}", e, quote(payload)))?;
+                Ok(render_template(template, text.trim()))
+            }
+            PayloadFormat::Binary { fields, endian } => Ok(decode_binary(fields, *endian, payload)),
+        }
+    }
+}
+
+// Inflates a compressed payload for `Decoder::decode`, or returns None when
+// it isn't compressed. An empty payload stays empty, since that's a delete
+// rather than a compressed body. The output is capped at `max_bytes` (the same limit
+// as uncompressed payloads) so a small message can't inflate without bound.
+pub fn decompress(compression: Compression, payload: &[u8], max_bytes: usize, preview_bytes: usize) -> Result<Option<Vec<u8>>> {
+    let (name, reader): (&str, Box<dyn Read + '_>) = match compression {
+        _ if payload.is_empty() => return Ok(None),
+        Compression::None => return Ok(None),
+        Compression::Gzip => ("gzip", Box::new(GzDecoder::new(payload))),
+        Compression::Deflate => ("deflate", Box::new(ZlibDecoder::new(payload))),
+    };
+    let mut inflated = Vec::new();
+    reader
+        .take(max_bytes as u64 + 1)
+        .read_to_end(&mut inflated)
+        .map_err(|e| anyhow!("payload is not valid {} data: {}{}", name, e, preview(payload, preview_bytes)))?;
+    if inflated.len() > max_bytes {
+        bail!("payload inflates to more than max_payload_bytes ({})", max_bytes);
+    }
+    Ok(Some(inflated))
+}
+
+// Cuts a length-prefixed payload into its records. A prefix or record that
+// runs past the end means the framing is off, so nothing is taken from it.
+pub fn split_frames(payload: &[u8], prefix_bytes: u8, endian: Endian) -> Result<Vec<Vec<u8>>> {
+    let prefix_bytes = usize::from(prefix_bytes);
+    let mut records = Vec::new();
+    let mut rest = payload;
+    while !rest.is_empty() {
+        let offset = payload.len() - rest.len();
+        if rest.len() < prefix_bytes {
+            bail!("malformed framing: {} stray byte(s) at offset {}, too few for a length prefix", rest.len(), offset);
+        }
+        let (prefix, body) = rest.split_at(prefix_bytes);
+        let mut length = [0u8; 8];
+        match endian {
+            Endian::Big => length[8 - prefix_bytes..].copy_from_slice(prefix),
+            Endian::Little => length[..prefix_bytes].copy_from_slice(prefix),
+        }
+        let length = match endian {
+            Endian::Big => u64::from_be_bytes(length),
+            Endian::Little => u64::from_le_bytes(length),
+        } as usize;
+        if body.len() < length {
+            bail!(
+                "malformed framing: the record at offset {} claims {} byte(s) but only {} remain",
+                offset,
+                length,
+                body.len()
+            );
+        }
+        let (record, next) = body.split_at(length);
+        records.push(record.to_vec());
+        rest = next;
+    }
+    Ok(records)
+}
+
+// Surrounding whitespace (including the line ending) is trimmed from the
+// row and from each field. A row with more or fewer fields than headers is
+// rejected rather than guessed at.
+fn decode_csv(headers: &[String], delimiter: char, infer_types: bool, payload: &[u8], preview_bytes: usize) -> Result<Value> {
+    let row = std::str::from_utf8(payload)
+        .map_err(|e| anyhow!("CSV payload is not valid UTF-8: {}{}", e, preview(payload, preview_bytes)))?;
+    let fields: Vec<&str> = row.trim().split(delimiter).map(str::trim).collect();
+    if fields.len() != headers.len() {
+        bail!(
+            "CSV row has {} field(s), expected {} ({}){}",
+            fields.len(),
+            headers.len(),
+            headers.join(", "),
+            preview(payload, preview_bytes)
+        );
+    }
+    let properties = headers
+        .iter()
+        .zip(fields)
+        .map(|(header, field)| {
+            let value = if infer_types {
+                infer_type(field)
+            } else {
+                Value::String(field.to_string())
+            };
+            (header.clone(), value)
+        })
+        .collect();
+    Ok(Value::Object(properties))
+}
+
+const TEMPLATE_PLACEHOLDER: &str = "{{value}}";
+
+fn render_template(template: &Value, value: &str) -> Value {
+    match template {
+        Value::String(text) if text == TEMPLATE_PLACEHOLDER => infer_type(value),
+        Value::String(text) => Value::String(text.replace(TEMPLATE_PLACEHOLDER, value)),
+        Value::Array(items) => Value::Array(items.iter().map(|item| render_template(item, value)).collect()),
+        Value::Object(fields) => Value::Object(
+            fields
+                .iter()
+                .map(|(key, field)| (key.clone(), render_template(field, value)))
+                .collect(),
+        ),
+        other => other.clone(),
+    }
+}
+
+// Never fails: a short payload just yields fewer properties. Floats that
+// aren't finite (NaN, infinity) have no JSON number and become null.
+fn decode_binary(fields: &[BinaryField], endian: Endian, payload: &[u8]) -> Value {
+    let mut properties = serde_json::Map::new();
+    for field in fields {
+        let end = field.offset.saturating_add(field.kind.size());
+        let Some(bytes) = payload.get(field.offset..end) else {
+            warn!(
+                "Skipping binary field {}: {} byte(s) at offset {} run past the {}-byte payload",
+                field.name,
+                field.kind.size(),
+                field.offset,
+                payload.len()
+            );
+            continue;
+        };
+        let value = read_binary(bytes, field.kind, field.endian.unwrap_or(endian));
+        properties.insert(field.name.clone(), value);
+    }
+    Value::Object(properties)
+}
+
+// `bytes` is exactly `kind.size()` long
+fn read_binary(bytes: &[u8], kind: BinaryType, endian: Endian) -> Value {
+    macro_rules! read {
+        ($type:ty) => {{
+            let bytes = bytes.try_into().expect("slice has the type's size");
+            match endian {
+                Endian::Big => <$type>::from_be_bytes(bytes),
+                Endian::Little => <$type>::from_le_bytes(bytes),
+            }
+        }};
+    }
+    match kind {
+        BinaryType::U8 => Value::from(bytes[0]),
+        BinaryType::I8 => Value::from(bytes[0] as i8),
+        BinaryType::Bool => Value::Bool(bytes[0] != 0),
+        BinaryType::U16 => Value::from(read!(u16)),
+        BinaryType::I16 => Value::from(read!(i16)),
+        BinaryType::U32 => Value::from(read!(u32)),
+        BinaryType::I32 => Value::from(read!(i32)),
+        BinaryType::U64 => Value::from(read!(u64)),
+        BinaryType::I64 => Value::from(read!(i64)),
+        // Through its shortest decimal form, so 24.3 stays 24.3 instead of
+        // widening to 24.299999237060547
+        BinaryType::F32 => float(read!(f32).to_string().parse().unwrap_or(f64::NAN)),
+        BinaryType::F64 => float(read!(f64)),
+    }
+}
+
+fn float(value: f64) -> Value {
+    serde_json::Number::from_f64(value).map_or(Value::Null, Value::Number)
+}
+
+// Integers stay integers; anything else that parses as a finite float is a
+// float, and the rest (including empty fields) strings
+fn infer_type(field: &str) -> Value {
+    if let Ok(integer) = field.parse::<i64>() {
+        return Value::from(integer);
+    }
+    match field.parse::<f64>().ok().and_then(serde_json::Number::from_f64) {
+        Some(number) => Value::Number(number),
+        None => Value::String(field.to_string()),
+    }
+}
+
+// MQTT v5 publishers can say what they sent. Parameters such as
+// `; charset=utf-8` are ignored, and `+json` types (e.g.
+// `application/ld+json`) count as JSON. Unknown types return None so the
+// configured format applies.
+pub fn format_for_content_type(content_type: &str) -> Option<PayloadFormat> {
+    let mime = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+    match mime.as_str() {
+        "application/json" => Some(PayloadFormat::Json),
+        mime if mime.ends_with("+json") => Some(PayloadFormat::Json),
+        mime if mime.starts_with("text/") => Some(PayloadFormat::RawString),
+        "application/octet-stream" => Some(PayloadFormat::Bytes),
+        "application/cbor" => Some(PayloadFormat::Cbor),
+        "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => Some(PayloadFormat::MsgPack),
+        _ => None,
+    }
+}
+
+// MQTT v5 has no content encoding field, so producers that mix compressed
+// and plain payloads on one topic say which is which the HTTP way: a
+// `content-encoding` user property (gzip, deflate or identity). It wins over
+// the configured compression; an encoding we don't know leaves the payload
+// as it is, with a warning.
+pub fn compression_for_message(message: &Message) -> Option<Compression> {
+    let (_, encoding) = message
+        .user_properties
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(CONTENT_ENCODING))?;
+    match encoding.trim().to_ascii_lowercase().as_str() {
+        "gzip" | "x-gzip" => Some(Compression::Gzip),
+        "deflate" => Some(Compression::Deflate),
+        "identity" => Some(Compression::None),
+        _ => {
+            warn!(
+                event = "unknown_encoding",
+                topic = %message.topic,
+                "Unknown content-encoding {:?} on a message from {}; using the payload as it is",
+                encoding,
+                message.topic
+            );
+            Some(Compression::None)
+        }
+    }
+}
+
+// For `auto`. Valid UTF-8 is text: JSON when it starts with `{` or `[`
+// (a broken object fails as JSON then, rather than turning into a string),
+// a raw string otherwise. Binary is recognised by its first byte: CBOR's
+// self-describe tag or a CBOR map, else a MessagePack map. Those bytes can't
+// start UTF-8 text, so neither is mistaken for the other; anything else is
+// kept as bytes.
+pub fn detect_format(payload: &[u8]) -> PayloadFormat {
+    if let Ok(text) = std::str::from_utf8(payload) {
+        return match text.trim_start().as_bytes().first() {
+            Some(b'{' | b'[') => PayloadFormat::Json,
+            _ => PayloadFormat::RawString,
+        };
+    }
+    match payload {
+        _ if payload.starts_with(CBOR_SELF_DESCRIBE) => PayloadFormat::Cbor,
+        [0xa0..=0xbf, ..] => PayloadFormat::Cbor,
+        [0x80..=0x8f | 0xde | 0xdf, ..] => PayloadFormat::MsgPack,
+        _ => PayloadFormat::Bytes,
+    }
+}
+
+// ` (payload: ...)` for an error message. Lossy so binary garbage still
+// renders, truncated so megabyte payloads don't end up in the logs.
+fn preview(payload: &[u8], limit: usize) -> String {
+    if limit == 0 {
+        return String::new();
+    }
+    if payload.len() <= limit {
+        return format!(" (payload: {})", String::from_utf8_lossy(payload));
+    }
+    format!(
+        " (payload: {}...(truncated, {} bytes total))",
+        String::from_utf8_lossy(&payload[..limit]),
+        payload.len()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn decoder(format: PayloadFormat) -> Decoder<'static> {
+        Decoder {
+            format: Cow::Owned(format),
+            max_depth: 4,
+            preview_bytes: 16,
+        }
+    }
+
+    fn reading() -> Value {
+        json!({ "temperature": 21.5, "ok": true })
+    }
+
+    #[test]
+    fn cbor_and_msgpack_decode_to_json() {
+        let mut cbor = Vec::new();
+        ciborium::into_writer(&reading(), &mut cbor).unwrap();
+        assert_eq!(decoder(PayloadFormat::Cbor).decode(&cbor).unwrap(), reading());
+        let tagged = [CBOR_SELF_DESCRIBE, &cbor].concat();
+        assert_eq!(decoder(PayloadFormat::Cbor).decode(&tagged).unwrap(), reading());
+
+        let msgpack = rmp_serde::to_vec_named(&reading()).unwrap();
+        assert_eq!(decoder(PayloadFormat::MsgPack).decode(&msgpack).unwrap(), reading());
+    }
+
+    #[test]
+    fn raw_strings_and_bytes_are_kept_as_they_are() {
+        assert_eq!(decoder(PayloadFormat::RawString).decode(b"on").unwrap(), json!("on"));
+        assert_eq!(decoder(PayloadFormat::Bytes).decode(&[0xff, 0x00]).unwrap(), json!({ "raw": "/wA=" }));
+        let error = decoder(PayloadFormat::RawString).decode(&[0xff]).unwrap_err();
+        assert!(error.to_string().contains("not valid UTF-8"));
+    }
+
+    #[test]
+    fn auto_detects_the_format() {
+        let mut cbor = Vec::new();
+        ciborium::into_writer(&reading(), &mut cbor).unwrap();
+        let msgpack = rmp_serde::to_vec_named(&reading()).unwrap();
+        assert_eq!(detect_format(b" {\"a\": 1}"), PayloadFormat::Json);
+        assert_eq!(detect_format(b"on"), PayloadFormat::RawString);
+        assert_eq!(detect_format(&cbor), PayloadFormat::Cbor);
+        assert_eq!(detect_format(&msgpack), PayloadFormat::MsgPack);
+        assert_eq!(detect_format(&[0xff, 0x00]), PayloadFormat::Bytes);
+        assert_eq!(decoder(PayloadFormat::Auto).decode(&msgpack).unwrap(), reading());
+    }
+
+    #[test]
+    fn content_types_pick_a_format() {
+        assert_eq!(format_for_content_type("application/ld+json; charset=utf-8"), Some(PayloadFormat::Json));
+        assert_eq!(format_for_content_type("text/plain"), Some(PayloadFormat::RawString));
+        assert_eq!(format_for_content_type("application/vnd.msgpack"), Some(PayloadFormat::MsgPack));
+        assert_eq!(format_for_content_type("image/png"), None);
+    }
+
+    #[test]
+    fn json_errors_quote_a_truncated_preview() {
+        let error = decoder(PayloadFormat::Json).decode(b"{\"temperature\": oops, \"ok\": true}").unwrap_err();
+        let message = error.to_string();
+        assert!(message.contains("{\"temperature\": "), "{}", message);
+        assert!(message.contains("truncated, 33 bytes total"), "{}", message);
+    }
+
+    #[test]
+    fn nesting_past_the_limit_is_too_deep() {
+        let decoder = decoder(PayloadFormat::Json);
+        assert!(!decoder.is_too_deep(br#"{"a": [[{"b": "[[[[[["}]]]}"#));
+        assert!(decoder.is_too_deep(br#"{"a": [[[{"b": 1}]]]}"#));
+    }
+
+    #[test]
+    fn csv_rows_map_to_the_headers() {
+        let format = PayloadFormat::Csv {
+            headers: vec!["device".to_string(), "temperature".to_string()],
+            delimiter: ',',
+            infer_types: true,
+        };
+        assert_eq!(decoder(format.clone()).decode(b"temp-01, 21.5\n").unwrap(), json!({ "device": "temp-01", "temperature": 21.5 }));
+        let error = decoder(format).decode(b"temp-01").unwrap_err();
+        assert!(error.to_string().contains("CSV row has 1 field(s), expected 2"));
+    }
+
+    #[test]
+    fn binary_fields_are_read_at_their_offsets() {
+        let fields = vec![
+            BinaryField {
+                name: "deviceId".to_string(),
+                offset: 0,
+                kind: BinaryType::U16,
+                endian: None,
+            },
+            BinaryField {
+                name: "temperature".to_string(),
+                offset: 2,
+                kind: BinaryType::F32,
+                endian: Some(Endian::Little),
+            },
+            BinaryField {
+                name: "alarm".to_string(),
+                offset: 6,
+                kind: BinaryType::Bool,
+                endian: None,
+            },
+        ];
+        let payload = [&[0x00, 0x07][..], &24.3f32.to_le_bytes()].concat();
+        let format = PayloadFormat::Binary { fields, endian: Endian::Big };
+        // The payload stops before `alarm`
+        assert_eq!(decoder(format).decode(&payload).unwrap(), json!({ "deviceId": 7, "temperature": 24.3 }));
+    }
+
+    #[test]
+    fn gzip_is_inflated_within_the_limit() {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&[b'a'; 100]).unwrap();
+        let gzip = encoder.finish().unwrap();
+        assert_eq!(decompress(Compression::Gzip, &gzip, 100, 0).unwrap(), Some(vec![b'a'; 100]));
+        let error = decompress(Compression::Gzip, &gzip, 99, 0).unwrap_err();
+        assert!(error.to_string().contains("inflates to more than max_payload_bytes"));
+        assert_eq!(decompress(Compression::Gzip, b"", 100, 0).unwrap(), None);
+        assert!(decompress(Compression::Deflate, b"plain", 100, 0).is_err());
+    }
+
+    #[test]
+    fn length_prefixed_records_are_split() {
+        let payload = [0, 2, b'a', b'b', 0, 1, b'c'];
+        assert_eq!(split_frames(&payload, 2, Endian::Big).unwrap(), [b"ab".to_vec(), b"c".to_vec()]);
+        let error = split_frames(&payload[..6], 2, Endian::Big).unwrap_err();
+        assert!(error.to_string().contains("claims 1 byte(s) but only 0 remain"));
+        assert!(split_frames(&[1, 0, b'x', 0], 2, Endian::Little).is_err());
+    }
+
+    #[test]
+    fn plain_text_fills_in_the_template() {
+        let template = decoder(PayloadFormat::Template(json!({ "state": "{{value}}", "note": "was {{value}}", "source": "plc" })));
+        assert_eq!(
+            template.decode(b" 21.5\n").unwrap(),
+            json!({ "state": 21.5, "note": "was 21.5", "source": "plc" })
+        );
+        assert_eq!(template.decode(b"open").unwrap()["state"], "open");
+        assert!(template.decode(&[0xff, 0xfe]).unwrap_err().to_string().contains("not valid UTF-8"));
+    }
+}
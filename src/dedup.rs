@@ -0,0 +1,169 @@
+use serde_json::Value;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::DedupConfig;
+use crate::model::DrasiElement;
+
+// --- DEDUPLICATION ---
+// Drops an element if the same key was emitted less than `ttl_ms` ago. The
+// key is the element ID plus either the raw payload or, with `key_pointer`,
+// one field of the mapped properties (e.g. a sequence number). The window
+// starts at the first sighting and is not extended by repeats, so a reading
+// that keeps being re-published still gets through once per window.
+pub struct Deduplicator {
+    ttl: Duration,
+    max_entries: usize,
+    key_pointer: Option<String>,
+    seen: Mutex<Seen>,
+}
+
+#[derive(Default)]
+struct Seen {
+    // key hash -> when it was last let through
+    at: HashMap<u64, Instant>,
+    // The same, oldest first, so eviction never has to search; a key let
+    // through again leaves its older entry behind, which is skipped
+    order: VecDeque<(u64, Instant)>,
+}
+
+impl Deduplicator {
+    pub fn new(config: &DedupConfig) -> Self {
+        Deduplicator {
+            ttl: Duration::from_millis(config.ttl_ms),
+            max_entries: config.max_entries,
+            key_pointer: config.key_pointer.clone(),
+            seen: Mutex::new(Seen::default()),
+        }
+    }
+
+    // True if the element should be suppressed; otherwise records it
+    pub fn is_duplicate(&self, element: &DrasiElement, payload: &[u8]) -> bool {
+        let key = self.key(element, payload);
+        let now = Instant::now();
+        let mut seen = self.seen.lock().expect("dedup map lock poisoned");
+
+        if let Some(first_seen) = seen.at.get(&key) {
+            if now.duration_since(*first_seen) < self.ttl {
+                return true;
+            }
+        }
+        self.evict(&mut seen, now);
+        seen.at.insert(key, now);
+        seen.order.push_back((key, now));
+        false
+    }
+
+    fn key(&self, element: &DrasiElement, payload: &[u8]) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        element.id.hash(&mut hasher);
+        match &self.key_pointer {
+            // A missing field hashes as null, so such elements dedup by ID alone
+            Some(pointer) => element.properties.pointer(pointer).unwrap_or(&Value::Null).to_string().hash(&mut hasher),
+            None => payload.hash(&mut hasher),
+        }
+        hasher.finish()
+    }
+
+    // Expired entries go first; if that leaves no room the oldest ones follow
+    fn evict(&self, seen: &mut Seen, now: Instant) {
+        while let Some(&(key, at)) = seen.order.front() {
+            if now.duration_since(at) < self.ttl && seen.at.len() < self.max_entries {
+                break;
+            }
+            seen.order.pop_front();
+            if seen.at.get(&key) == Some(&at) {
+                seen.at.remove(&key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emit::tests::element;
+    use serde_json::json;
+
+    fn deduplicator(ttl_ms: u64, key_pointer: Option<&str>) -> Deduplicator {
+        Deduplicator::new(&DedupConfig {
+            ttl_ms,
+            key_pointer: key_pointer.map(str::to_string),
+            ..DedupConfig::default()
+        })
+    }
+
+    #[test]
+    fn a_repeat_within_the_window_is_dropped() {
+        let dedup = deduplicator(60_000, None);
+        let reading = element("temp-01");
+        assert!(!dedup.is_duplicate(&reading, b"{\"t\":21.5}"));
+        assert!(dedup.is_duplicate(&reading, b"{\"t\":21.5}"));
+        // Another payload or another ID is news
+        assert!(!dedup.is_duplicate(&reading, b"{\"t\":22.0}"));
+        assert!(!dedup.is_duplicate(&element("temp-02"), b"{\"t\":21.5}"));
+    }
+
+    #[test]
+    fn a_repeat_gets_through_again_once_the_window_has_passed() {
+        let dedup = deduplicator(30, None);
+        let reading = element("temp-01");
+        assert!(!dedup.is_duplicate(&reading, b"same"));
+        assert!(dedup.is_duplicate(&reading, b"same"));
+        std::thread::sleep(Duration::from_millis(40));
+        assert!(!dedup.is_duplicate(&reading, b"same"));
+        assert!(dedup.is_duplicate(&reading, b"same"));
+    }
+
+    #[test]
+    fn a_full_cache_forgets_the_oldest_keys_first() {
+        let dedup = Deduplicator::new(&DedupConfig {
+            ttl_ms: 60_000,
+            key_pointer: None,
+            max_entries: 2,
+        });
+        let reading = element("temp-01");
+        for payload in [b"a", b"b", b"c"] {
+            assert!(!dedup.is_duplicate(&reading, payload));
+        }
+        assert_eq!(dedup.seen.lock().unwrap().at.len(), 2);
+        // "a" made room for "c"; "b" and "c" are still remembered
+        assert!(dedup.is_duplicate(&reading, b"c"));
+        assert!(dedup.is_duplicate(&reading, b"b"));
+        assert!(!dedup.is_duplicate(&reading, b"a"));
+    }
+
+    #[test]
+    fn expired_entries_leave_the_eviction_order_too() {
+        let dedup = Deduplicator::new(&DedupConfig {
+            ttl_ms: 10,
+            key_pointer: None,
+            max_entries: 100,
+        });
+        let reading = element("temp-01");
+        for _ in 0..5 {
+            assert!(!dedup.is_duplicate(&reading, b"same"));
+            std::thread::sleep(Duration::from_millis(15));
+        }
+        let seen = dedup.seen.lock().unwrap();
+        assert_eq!((seen.at.len(), seen.order.len()), (1, 1));
+    }
+
+    #[test]
+    fn key_pointer_compares_one_field_instead_of_the_payload() {
+        let dedup = deduplicator(60_000, Some("/seq"));
+        let mut first = element("temp-01");
+        first.properties = json!({ "seq": 7, "temperature": 21.5 });
+        let mut resent = first.clone();
+        resent.properties["temperature"] = json!(21.6);
+        let mut next = first.clone();
+        next.properties["seq"] = json!(8);
+
+        assert!(!dedup.is_duplicate(&first, b"a"));
+        assert!(dedup.is_duplicate(&resent, b"b"));
+        assert!(!dedup.is_duplicate(&next, b"c"));
+    }
+}
@@ -0,0 +1,262 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use tracing::error;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+
+use super::Emitter;
+use crate::config::BatchConfig;
+use crate::metrics::Metrics;
+use crate::model::{DrasiDelete, DrasiElement, DrasiRelation};
+
+enum Command {
+    Emit(DrasiElement),
+    Delete(DrasiDelete),
+    Relation(DrasiRelation),
+    Flush(oneshot::Sender<Result<()>>),
+}
+
+// --- BATCHING EMITTER ---
+// Buffers elements on a background task and hands them to the inner emitter
+// via `emit_batch` once `max_batch_size` accumulate or `flush_interval_ms`
+// passes, whichever comes first. Deletes and relations flush the buffer
+// before going out, so a node is never removed ahead of its own pending
+// update and an edge never arrives before its nodes. The messages behind a
+// buffered change were settled when it was buffered, so a change the inner
+// emitter fails is only logged and counted as failed and undelivered.
+pub struct BatchingEmitter {
+    commands: mpsc::Sender<Command>,
+    // Changes sent to the task that the inner emitter hasn't finished with
+    pending: Arc<AtomicUsize>,
+}
+
+impl BatchingEmitter {
+    pub fn new(inner: Arc<dyn Emitter>, config: &BatchConfig, metrics: Arc<Metrics>) -> Self {
+        let max_batch_size = config.max_batch_size.max(1);
+        // Room for a few batches before `emit` starts waiting on the task
+        let (commands, receiver) = mpsc::channel(max_batch_size * 4);
+        let pending = Arc::new(AtomicUsize::new(0));
+        let batcher = Batcher {
+            inner,
+            pending: pending.clone(),
+            metrics,
+        };
+        tokio::spawn(batcher.run(receiver, max_batch_size, Duration::from_millis(config.flush_interval_ms)));
+        BatchingEmitter { commands, pending }
+    }
+
+    async fn send(&self, command: Command) -> Result<()> {
+        let change = !matches!(command, Command::Flush(_));
+        if change {
+            self.pending.fetch_add(1, Ordering::Relaxed);
+        }
+        let sent = self.commands.send(command).await;
+        if sent.is_err() && change {
+            self.pending.fetch_sub(1, Ordering::Relaxed);
+        }
+        sent.map_err(|_| anyhow!("batching task has stopped"))
+    }
+}
+
+#[async_trait]
+impl Emitter for BatchingEmitter {
+    // Returns once the element is buffered; delivery errors surface in the
+    // log when the batch is flushed
+    async fn emit(&self, element: DrasiElement) -> Result<()> {
+        self.send(Command::Emit(element)).await
+    }
+
+    async fn delete(&self, delete: DrasiDelete) -> Result<()> {
+        self.send(Command::Delete(delete)).await
+    }
+
+    async fn emit_relation(&self, relation: DrasiRelation) -> Result<()> {
+        self.send(Command::Relation(relation)).await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        let (ack, done) = oneshot::channel();
+        self.send(Command::Flush(ack)).await?;
+        done.await.map_err(|_| anyhow!("batching task stopped before flushing"))?
+    }
+
+    fn pending(&self) -> usize {
+        self.pending.load(Ordering::Relaxed)
+    }
+}
+
+// The background task's side
+struct Batcher {
+    inner: Arc<dyn Emitter>,
+    pending: Arc<AtomicUsize>,
+    metrics: Arc<Metrics>,
+}
+
+impl Batcher {
+    async fn run(self, mut commands: mpsc::Receiver<Command>, max_batch_size: usize, flush_interval: Duration) {
+        let mut buffer = Vec::with_capacity(max_batch_size);
+        // Starting a full interval out; a first tick right away would send
+        // whatever arrived with it as a batch of its own
+        let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + flush_interval, flush_interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                command = commands.recv() => match command {
+                    Some(Command::Emit(element)) => {
+                        buffer.push(element);
+                        if buffer.len() >= max_batch_size {
+                            log_failure(self.flush_buffer(&mut buffer).await);
+                        }
+                    }
+                    Some(Command::Delete(delete)) => {
+                        log_failure(self.flush_buffer(&mut buffer).await);
+                        let result = self.inner.delete(delete).await;
+                        self.handed(1, result.is_ok());
+                        log_failure(result);
+                    }
+                    Some(Command::Relation(relation)) => {
+                        log_failure(self.flush_buffer(&mut buffer).await);
+                        let result = self.inner.emit_relation(relation).await;
+                        self.handed(1, result.is_ok());
+                        log_failure(result);
+                    }
+                    Some(Command::Flush(ack)) => {
+                        let mut result = self.flush_buffer(&mut buffer).await;
+                        if result.is_ok() {
+                            result = self.inner.flush().await;
+                        }
+                        let _ = ack.send(result);
+                    }
+                    // Every handle is gone; don't lose what's left
+                    None => {
+                        log_failure(self.flush_buffer(&mut buffer).await);
+                        break;
+                    }
+                },
+                _ = ticker.tick() => {
+                    if !buffer.is_empty() {
+                        log_failure(self.flush_buffer(&mut buffer).await);
+                    }
+                }
+            }
+        }
+    }
+
+    async fn flush_buffer(&self, buffer: &mut Vec<DrasiElement>) -> Result<()> {
+        if buffer.is_empty() {
+            return Ok(());
+        }
+        let batch = std::mem::take(buffer);
+        let size = batch.len();
+        let result = self.inner.emit_batch(batch).await;
+        self.handed(size, result.is_ok());
+        result.map_err(|e| e.context(format!("failed to emit batch of {} element(s)", size)))
+    }
+
+    // Done with, delivered or not; failures are logged where they happen
+    fn handed(&self, count: usize, delivered: bool) {
+        self.pending.fetch_sub(count, Ordering::Relaxed);
+        if !delivered {
+            Metrics::add(&self.metrics.failed, count as u64);
+            Metrics::add(&self.metrics.undelivered, count as u64);
+        }
+    }
+}
+
+fn log_failure(result: Result<()>) {
+    if let Err(e) = result {
+        error!("{:#}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emit::tests::{element, Recording};
+
+    fn batching(max_batch_size: usize) -> (BatchingEmitter, Arc<Recording>) {
+        let recording = Arc::new(Recording::default());
+        let config = BatchConfig {
+            max_batch_size,
+            flush_interval_ms: 1000,
+        };
+        (BatchingEmitter::new(recording.clone(), &config, Arc::new(Metrics::default())), recording)
+    }
+
+    // Lets the background task catch up without reaching the next interval
+    async fn settle() {
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_full_buffer_goes_out_at_once() {
+        let (emitter, recording) = batching(2);
+        for id in ["a", "b", "c"] {
+            emitter.emit(element(id)).await.unwrap();
+        }
+        assert_eq!(emitter.pending(), 3);
+        settle().await;
+        assert_eq!(recording.calls(), ["batch a,b"]);
+        assert_eq!(emitter.pending(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_interval_flushes_a_partial_batch() {
+        let (emitter, recording) = batching(10);
+        emitter.emit(element("a")).await.unwrap();
+        settle().await;
+        assert!(recording.calls().is_empty());
+        tokio::time::sleep(Duration::from_millis(1000)).await;
+        assert_eq!(recording.calls(), ["batch a"]);
+        assert_eq!(emitter.pending(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_delete_goes_out_after_the_buffer() {
+        let (emitter, recording) = batching(10);
+        emitter.emit(element("a")).await.unwrap();
+        emitter.emit(element("b")).await.unwrap();
+        emitter.delete(DrasiDelete { id: "a".to_string() }).await.unwrap();
+        settle().await;
+        assert_eq!(recording.calls(), ["batch a,b", "delete a"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn flush_hands_over_what_is_buffered() {
+        let (emitter, recording) = batching(10);
+        emitter.emit(element("a")).await.unwrap();
+        emitter.flush().await.unwrap();
+        assert_eq!(recording.calls(), ["batch a"]);
+        assert_eq!(emitter.pending(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_failed_batch_is_no_longer_pending() {
+        let (emitter, recording) = batching(10);
+        recording.failing.store(true, std::sync::atomic::Ordering::Relaxed);
+        emitter.emit(element("a")).await.unwrap();
+        assert!(emitter.flush().await.is_err());
+        assert_eq!(emitter.pending(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn changes_the_output_fails_are_counted_as_undelivered() {
+        let recording = Arc::new(Recording::default());
+        recording.failing.store(true, std::sync::atomic::Ordering::Relaxed);
+        let metrics = Arc::new(Metrics::default());
+        let config = BatchConfig {
+            max_batch_size: 2,
+            flush_interval_ms: 1000,
+        };
+        let emitter = BatchingEmitter::new(recording.clone(), &config, metrics.clone());
+        emitter.emit(element("a")).await.unwrap();
+        emitter.emit(element("b")).await.unwrap();
+        emitter.delete(DrasiDelete { id: "c".to_string() }).await.unwrap();
+        settle().await;
+        assert_eq!(metrics.failed.load(std::sync::atomic::Ordering::Relaxed), 3);
+        assert_eq!(metrics.undelivered.load(std::sync::atomic::Ordering::Relaxed), 3);
+    }
+}
@@ -0,0 +1,214 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use tokio::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+use super::{CloudEvent, Emitter};
+use crate::config::CircuitBreakerConfig;
+use crate::metrics::Metrics;
+use crate::model::{DrasiDelete, DrasiElement, DrasiRelation};
+
+// --- CIRCUIT BREAKER ---
+// Stops calling an output that keeps failing. After `failure_threshold`
+// failed calls in a row the circuit opens: for `cooldown_ms` every change
+// fails with `CircuitOpen` without touching the output (and is counted),
+// instead of each one waiting out its own retries. The pipeline treats that
+// like any other emit failure, so the message is parked for retry or
+// dead-lettered rather than lost. The first change after the cooldown is let
+// through as a probe (half-open): success closes the circuit again, failure
+// reopens it for another cooldown. Changes arriving while the probe is in
+// flight are refused too.
+pub struct CircuitBreakerEmitter {
+    inner: Box<dyn Emitter>,
+    threshold: u32,
+    cooldown: Duration,
+    state: Mutex<BreakerState>,
+    metrics: Arc<Metrics>,
+}
+
+// What a change refused by an open circuit fails with
+#[derive(Debug, thiserror::Error)]
+#[error("circuit open: not sending {changes} change(s) to the failing output until the cooldown is over")]
+pub struct CircuitOpen {
+    pub changes: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum BreakerState {
+    // Failures in a row so far
+    Closed(u32),
+    Open { until: Instant },
+    HalfOpen,
+}
+
+impl CircuitBreakerEmitter {
+    pub fn new(inner: Box<dyn Emitter>, config: &CircuitBreakerConfig, metrics: Arc<Metrics>) -> Self {
+        CircuitBreakerEmitter {
+            inner,
+            threshold: config.failure_threshold,
+            cooldown: Duration::from_millis(config.cooldown_ms),
+            state: Mutex::new(BreakerState::Closed(0)),
+            metrics,
+        }
+    }
+
+    // Whether a call may go through now; moves an expired open circuit to
+    // half-open, letting this call be the probe
+    fn admit(&self) -> bool {
+        let mut state = self.state.lock().expect("circuit breaker lock poisoned");
+        match *state {
+            BreakerState::Closed(_) => true,
+            BreakerState::Open { until } if Instant::now() >= until => {
+                *state = BreakerState::HalfOpen;
+                true
+            }
+            BreakerState::Open { .. } | BreakerState::HalfOpen => false,
+        }
+    }
+
+    fn record(&self, succeeded: bool) {
+        let mut state = self.state.lock().expect("circuit breaker lock poisoned");
+        *state = match (*state, succeeded) {
+            (BreakerState::HalfOpen, true) => {
+                info!(event = "circuit_closed", "Output recovered; closing the circuit");
+                BreakerState::Closed(0)
+            }
+            (_, true) => BreakerState::Closed(0),
+            (BreakerState::HalfOpen, false) => {
+                warn!(event = "circuit_open", "Probe failed; keeping the circuit open for another {:?}", self.cooldown);
+                BreakerState::Open { until: Instant::now() + self.cooldown }
+            }
+            (BreakerState::Closed(failures), false) if failures + 1 >= self.threshold => {
+                warn!(
+                    event = "circuit_open",
+                    "Output failed {} time(s) in a row; refusing changes for {:?}",
+                    failures + 1,
+                    self.cooldown
+                );
+                BreakerState::Open { until: Instant::now() + self.cooldown }
+            }
+            (BreakerState::Closed(failures), false) => BreakerState::Closed(failures + 1),
+            // A call admitted just before the circuit opened
+            (open @ BreakerState::Open { .. }, false) => open,
+        };
+    }
+
+    // `call` is only polled (i.e. the output only contacted) if admitted
+    async fn guard(&self, changes: u64, call: impl Future<Output = Result<()>> + Send) -> Result<()> {
+        if !self.admit() {
+            debug!(event = "short_circuited", "Circuit open; refusing {} change(s)", changes);
+            Metrics::add(&self.metrics.short_circuited, changes);
+            return Err(CircuitOpen { changes }.into());
+        }
+        let result = call.await;
+        self.record(result.is_ok());
+        result
+    }
+}
+
+#[async_trait]
+impl Emitter for CircuitBreakerEmitter {
+    async fn emit(&self, element: DrasiElement) -> Result<()> {
+        self.guard(1, self.inner.emit(element)).await
+    }
+
+    async fn emit_batch(&self, elements: Vec<DrasiElement>) -> Result<()> {
+        self.guard(elements.len() as u64, self.inner.emit_batch(elements)).await
+    }
+
+    async fn delete(&self, delete: DrasiDelete) -> Result<()> {
+        self.guard(1, self.inner.delete(delete)).await
+    }
+
+    async fn emit_relation(&self, relation: DrasiRelation) -> Result<()> {
+        self.guard(1, self.inner.emit_relation(relation)).await
+    }
+
+    async fn emit_events(&self, events: Vec<CloudEvent>) -> Result<()> {
+        self.guard(events.len() as u64, self.inner.emit_events(events)).await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.inner.flush().await
+    }
+
+    fn pending(&self) -> usize {
+        self.inner.pending()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emit::tests::{element, Recording};
+    use std::sync::atomic::Ordering;
+
+    fn breaker() -> (CircuitBreakerEmitter, Arc<Recording>, Arc<Metrics>) {
+        let recording = Arc::new(Recording::default());
+        let metrics = Arc::new(Metrics::default());
+        let config = CircuitBreakerConfig {
+            failure_threshold: 2,
+            cooldown_ms: 1000,
+        };
+        let emitter = CircuitBreakerEmitter::new(Box::new(recording.clone()), &config, metrics.clone());
+        (emitter, recording, metrics)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn opens_after_the_threshold() {
+        let (emitter, recording, metrics) = breaker();
+        recording.failing.store(true, Ordering::Relaxed);
+        assert!(emitter.emit(element("a")).await.is_err());
+        assert!(emitter.emit(element("b")).await.is_err());
+        recording.failing.store(false, Ordering::Relaxed);
+        // Refused without reaching the output
+        let error = emitter.emit(element("c")).await.unwrap_err();
+        assert_eq!(error.downcast_ref::<CircuitOpen>().unwrap().changes, 1);
+        assert!(recording.calls().is_empty());
+        assert_eq!(metrics.short_circuited.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_success_resets_the_count() {
+        let (emitter, recording, metrics) = breaker();
+        recording.failing.store(true, Ordering::Relaxed);
+        assert!(emitter.emit(element("a")).await.is_err());
+        recording.failing.store(false, Ordering::Relaxed);
+        emitter.emit(element("b")).await.unwrap();
+        recording.failing.store(true, Ordering::Relaxed);
+        assert!(emitter.emit(element("c")).await.is_err());
+        recording.failing.store(false, Ordering::Relaxed);
+        emitter.emit(element("d")).await.unwrap();
+        assert_eq!(recording.calls(), ["emit b", "emit d"]);
+        assert_eq!(metrics.short_circuited.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_probe_after_the_cooldown_closes_it() {
+        let (emitter, recording, _) = breaker();
+        recording.failing.store(true, Ordering::Relaxed);
+        let _ = emitter.emit(element("a")).await;
+        let _ = emitter.emit(element("b")).await;
+        recording.failing.store(false, Ordering::Relaxed);
+        tokio::time::advance(Duration::from_millis(1000)).await;
+        emitter.emit(element("c")).await.unwrap();
+        emitter.emit(element("d")).await.unwrap();
+        assert_eq!(recording.calls(), ["emit c", "emit d"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_failed_probe_reopens_it() {
+        let (emitter, recording, metrics) = breaker();
+        recording.failing.store(true, Ordering::Relaxed);
+        let _ = emitter.emit(element("a")).await;
+        let _ = emitter.emit(element("b")).await;
+        tokio::time::advance(Duration::from_millis(1000)).await;
+        assert!(emitter.emit(element("c")).await.is_err());
+        recording.failing.store(false, Ordering::Relaxed);
+        assert!(emitter.emit(element("d")).await.unwrap_err().is::<CircuitOpen>());
+        assert!(recording.calls().is_empty());
+        assert_eq!(metrics.short_circuited.load(Ordering::Relaxed), 1);
+    }
+}
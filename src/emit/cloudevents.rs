@@ -0,0 +1,151 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::Serialize;
+use serde_json::Value;
+
+use super::Emitter;
+use crate::model::{DrasiDelete, DrasiElement, DrasiRelation};
+
+const SPEC_VERSION: &str = "1.0";
+const ELEMENT_INGESTED: &str = "io.drasi.element.ingested";
+const ELEMENT_DELETED: &str = "io.drasi.element.deleted";
+const RELATION_INGESTED: &str = "io.drasi.relation.ingested";
+
+// A CloudEvents 1.0 event in structured JSON mode
+#[derive(Debug, Clone, Serialize)]
+pub struct CloudEvent {
+    pub specversion: &'static str,
+    #[serde(rename = "type")]
+    pub event_type: &'static str,
+    pub source: String,
+    pub id: String,
+    pub time: String,
+    pub datacontenttype: &'static str,
+    // The element or relation ID, so consumers can route without parsing data
+    pub subject: String,
+    pub data: Value,
+}
+
+// --- CLOUDEVENTS EMITTER ---
+// Wraps every change in a CloudEvents envelope and hands the events to the
+// inner emitter. `source` identifies the broker the data came from; each
+// event gets a fresh ID.
+pub struct CloudEventEmitter {
+    inner: Box<dyn Emitter>,
+    source: String,
+}
+
+impl CloudEventEmitter {
+    pub fn new(inner: Box<dyn Emitter>, source: String) -> Self {
+        CloudEventEmitter { inner, source }
+    }
+
+    fn event(&self, event_type: &'static str, subject: &str, data: impl Serialize) -> Result<CloudEvent> {
+        Ok(CloudEvent {
+            specversion: SPEC_VERSION,
+            event_type,
+            source: self.source.clone(),
+            id: uuid::Uuid::new_v4().to_string(),
+            time: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            datacontenttype: "application/json",
+            subject: subject.to_string(),
+            data: serde_json::to_value(data)?,
+        })
+    }
+}
+
+#[async_trait]
+impl Emitter for CloudEventEmitter {
+    async fn emit(&self, element: DrasiElement) -> Result<()> {
+        let event = self.event(ELEMENT_INGESTED, &element.id, &element)?;
+        self.inner.emit_events(vec![event]).await
+    }
+
+    async fn emit_batch(&self, elements: Vec<DrasiElement>) -> Result<()> {
+        let events = elements
+            .iter()
+            .map(|element| self.event(ELEMENT_INGESTED, &element.id, element))
+            .collect::<Result<Vec<_>>>()?;
+        self.inner.emit_events(events).await
+    }
+
+    async fn delete(&self, delete: DrasiDelete) -> Result<()> {
+        let event = self.event(ELEMENT_DELETED, &delete.id, &delete)?;
+        self.inner.emit_events(vec![event]).await
+    }
+
+    async fn emit_relation(&self, relation: DrasiRelation) -> Result<()> {
+        let event = self.event(RELATION_INGESTED, &relation.id, &relation)?;
+        self.inner.emit_events(vec![event]).await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.inner.flush().await
+    }
+
+    fn pending(&self) -> usize {
+        self.inner.pending()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emit::tests::element;
+    use std::sync::{Arc, Mutex};
+
+    // Keeps the events, shared with the test
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<CloudEvent>>>);
+
+    #[async_trait]
+    impl Emitter for Captured {
+        async fn emit(&self, _element: DrasiElement) -> Result<()> {
+            anyhow::bail!("expected an event")
+        }
+
+        async fn delete(&self, _delete: DrasiDelete) -> Result<()> {
+            anyhow::bail!("expected an event")
+        }
+
+        async fn emit_relation(&self, _relation: DrasiRelation) -> Result<()> {
+            anyhow::bail!("expected an event")
+        }
+
+        async fn emit_events(&self, events: Vec<CloudEvent>) -> Result<()> {
+            self.0.lock().unwrap().extend(events);
+            Ok(())
+        }
+    }
+
+    fn wrapping() -> (CloudEventEmitter, Captured) {
+        let captured = Captured::default();
+        (CloudEventEmitter::new(Box::new(captured.clone()), "mqtt://broker:1883".to_string()), captured)
+    }
+
+    #[tokio::test]
+    async fn an_element_is_wrapped_in_an_envelope() {
+        let (emitter, captured) = wrapping();
+        emitter.emit(element("temp-01")).await.unwrap();
+        let events = captured.0.lock().unwrap();
+        let event = serde_json::to_value(&events[0]).unwrap();
+        assert_eq!(event["specversion"], "1.0");
+        assert_eq!(event["type"], "io.drasi.element.ingested");
+        assert_eq!(event["source"], "mqtt://broker:1883");
+        assert_eq!(event["subject"], "temp-01");
+        assert_eq!(event["datacontenttype"], "application/json");
+        assert_eq!(event["data"]["properties"]["temperature"], 21.5);
+        assert!(uuid::Uuid::parse_str(event["id"].as_str().unwrap()).is_ok());
+    }
+
+    #[tokio::test]
+    async fn deletes_and_batches_have_their_own_events() {
+        let (emitter, captured) = wrapping();
+        emitter.emit_batch(vec![element("a"), element("b")]).await.unwrap();
+        emitter.delete(DrasiDelete { id: "a".to_string() }).await.unwrap();
+        let events = captured.0.lock().unwrap();
+        let kinds: Vec<_> = events.iter().map(|event| (event.event_type, event.subject.as_str())).collect();
+        assert_eq!(kinds, [(ELEMENT_INGESTED, "a"), (ELEMENT_INGESTED, "b"), (ELEMENT_DELETED, "a")]);
+        assert_ne!(events[0].id, events[1].id);
+    }
+}
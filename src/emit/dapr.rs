@@ -0,0 +1,103 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use reqwest::Url;
+use serde_json::json;
+
+use super::http::CLOUDEVENT_CONTENT_TYPE;
+use super::{CloudEvent, Emitter, HttpEmitter};
+use crate::config::{DaprConfig, HttpConfig};
+use crate::model::{DrasiDelete, DrasiElement, DrasiRelation};
+
+// The sidecar's default HTTP port, used when DAPR_HTTP_PORT is unset
+const DEFAULT_DAPR_HTTP_PORT: u16 = 3500;
+
+// --- DAPR EMITTER ---
+// Publishes each change to a Dapr pub/sub component through the local
+// sidecar (`POST /v1.0/publish/<pubsub>/<topic>`), which wraps it in its own
+// CloudEvent unless it already is one. The publish API has no delete, so a
+// delete goes out as `{"id": ..., "op": "delete"}` on the same topic.
+// Requests, retries and errors are the HTTP emitter's.
+pub struct DaprEmitter {
+    http: HttpEmitter,
+}
+
+impl DaprEmitter {
+    pub fn new(config: &DaprConfig) -> Result<Self> {
+        // Dapr sets DAPR_HTTP_PORT in the app's environment
+        let port = match std::env::var("DAPR_HTTP_PORT") {
+            Ok(port) => port
+                .trim()
+                .parse::<u16>()
+                .map_err(|e| anyhow!("DAPR_HTTP_PORT must be a valid port number, got {:?}: {}", port, e))?,
+            Err(_) => DEFAULT_DAPR_HTTP_PORT,
+        };
+        let mut url = Url::parse(&format!("http://localhost:{}/v1.0/publish", port))?;
+        url.path_segments_mut()
+            .expect("an http URL has path segments")
+            .push(&config.pubsub)
+            .push(&config.topic);
+        let http = HttpEmitter::new(&HttpConfig {
+            url: url.to_string(),
+            max_attempts: config.max_attempts,
+        })?;
+        Ok(DaprEmitter { http })
+    }
+}
+
+#[async_trait]
+impl Emitter for DaprEmitter {
+    async fn emit(&self, element: DrasiElement) -> Result<()> {
+        self.http.emit(element).await
+    }
+
+    async fn delete(&self, delete: DrasiDelete) -> Result<()> {
+        let body = json!({ "id": delete.id, "op": "delete" });
+        self.http.post(&delete.id, &body, None).await
+    }
+
+    async fn emit_relation(&self, relation: DrasiRelation) -> Result<()> {
+        self.http.emit_relation(relation).await
+    }
+
+    // One publish per event, passed through as-is by Dapr because of the
+    // content type
+    async fn emit_events(&self, events: Vec<CloudEvent>) -> Result<()> {
+        for event in &events {
+            self.http.post(&event.subject, event, Some(CLOUDEVENT_CONTENT_TYPE)).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emit::http::tests::mock_endpoint;
+    use crate::emit::tests::element;
+    use serde_json::Value;
+
+    #[tokio::test]
+    async fn changes_are_published_to_the_topic_through_the_sidecar() {
+        let (url, requests) = mock_endpoint(vec![204, 204]).await;
+        let port = Url::parse(&url).unwrap().port().unwrap();
+        // No other test reads DAPR_HTTP_PORT
+        std::env::set_var("DAPR_HTTP_PORT", port.to_string());
+        let emitter = DaprEmitter::new(&DaprConfig {
+            pubsub: "pubsub".to_string(),
+            topic: "sensor-updates".to_string(),
+            max_attempts: 1,
+        })
+        .unwrap();
+        emitter.emit(element("temp-01")).await.unwrap();
+        emitter.delete(DrasiDelete { id: "temp-01".to_string() }).await.unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        for request in requests.iter() {
+            assert_eq!(request.method, "POST");
+            assert_eq!(request.path, "/v1.0/publish/pubsub/sensor-updates");
+        }
+        let delete: Value = serde_json::from_slice(&requests[1].body).unwrap();
+        assert_eq!(delete, json!({ "id": "temp-01", "op": "delete" }));
+    }
+}
@@ -0,0 +1,194 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use serde::Serialize;
+use serde_json::json;
+use std::fs::OpenOptions;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::fs::File;
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::Mutex;
+use tracing::{error, info};
+
+use super::{CloudEvent, Emitter};
+use crate::config::FileOutputConfig;
+use crate::model::{DrasiDelete, DrasiElement, DrasiRelation};
+
+// --- FILE EMITTER ---
+// Appends every change to a JSON Lines file, in the same shape as the
+// stdout output: nodes and relations as they are, a delete as
+// `{"id": ..., "deleted": true}`. Unlike `--record` it keeps what the
+// mapping produced, not the raw messages. With `rotate_daily` the UTC date
+// goes into the file name (`graph.jsonl` -> `graph-2024-05-01.jsonl`) and a
+// new file is started at midnight. Lines are buffered, pushed to disk and
+// fsynced every `fsync_interval_secs` and on shutdown.
+pub struct FileEmitter {
+    path: PathBuf,
+    rotate_daily: bool,
+    current: Arc<Mutex<Current>>,
+}
+
+struct Current {
+    // The date in the file name, with rotation
+    date: Option<String>,
+    path: PathBuf,
+    writer: BufWriter<File>,
+}
+
+impl FileEmitter {
+    // Opened right away, so a path that can't be written fails at startup
+    pub fn open(config: &FileOutputConfig) -> Result<Self> {
+        let date = config.rotate_daily.then(today);
+        let path = dated(&config.path, date.as_deref());
+        let writer = BufWriter::new(File::from_std(append(&path)?));
+        let current = Arc::new(Mutex::new(Current { date, path, writer }));
+
+        // Holds only a weak handle so the task ends with the emitter
+        let weak = Arc::downgrade(&current);
+        let interval = Duration::from_secs(config.fsync_interval_secs);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                let Some(current) = weak.upgrade() else {
+                    break;
+                };
+                let mut current = current.lock().await;
+                if let Err(e) = sync(&mut current).await {
+                    error!("{:#}", e);
+                }
+            }
+        });
+        Ok(FileEmitter {
+            path: config.path.clone(),
+            rotate_daily: config.rotate_daily,
+            current,
+        })
+    }
+
+    async fn write_lines<T: Serialize>(&self, values: &[T]) -> Result<()> {
+        let mut lines = Vec::new();
+        for value in values {
+            serde_json::to_writer(&mut lines, value)?;
+            lines.push(b'\n');
+        }
+        // Held across the write so concurrent workers can't interleave lines
+        let mut current = self.current.lock().await;
+        if self.rotate_daily {
+            let date = today();
+            if current.date.as_deref() != Some(date.as_str()) {
+                sync(&mut current).await?;
+                let path = dated(&self.path, Some(&date));
+                info!("Rotating element file to {}", path.display());
+                *current = Current {
+                    writer: BufWriter::new(File::from_std(append(&path)?)),
+                    date: Some(date),
+                    path,
+                };
+            }
+        }
+        current
+            .writer
+            .write_all(&lines)
+            .await
+            .with_context(|| format!("Failed to write to element file {}", current.path.display()))
+    }
+
+}
+
+#[async_trait]
+impl Emitter for FileEmitter {
+    async fn emit(&self, element: DrasiElement) -> Result<()> {
+        self.write_lines(&[element]).await
+    }
+
+    async fn delete(&self, delete: DrasiDelete) -> Result<()> {
+        self.write_lines(&[json!({ "id": delete.id, "deleted": true })]).await
+    }
+
+    async fn emit_relation(&self, relation: DrasiRelation) -> Result<()> {
+        self.write_lines(&[relation]).await
+    }
+
+    async fn emit_batch(&self, elements: Vec<DrasiElement>) -> Result<()> {
+        self.write_lines(&elements).await
+    }
+
+    async fn emit_events(&self, events: Vec<CloudEvent>) -> Result<()> {
+        self.write_lines(&events).await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        sync(&mut *self.current.lock().await).await
+    }
+}
+
+// Empties the buffer into the file and waits for the disk to have it
+async fn sync(current: &mut Current) -> Result<()> {
+    let context = || format!("Failed to flush element file {}", current.path.display());
+    current.writer.flush().await.with_context(context)?;
+    current.writer.get_ref().sync_data().await.with_context(context)
+}
+
+// Opened while a worker holds the lock, which is brief (once a day)
+fn append(path: &Path) -> Result<std::fs::File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open element file {}", path.display()))
+}
+
+fn today() -> String {
+    chrono::Utc::now().format("%Y-%m-%d").to_string()
+}
+
+// `graph.jsonl` with a date becomes `graph-<date>.jsonl`
+fn dated(path: &Path, date: Option<&str>) -> PathBuf {
+    let Some(date) = date else {
+        return path.to_path_buf();
+    };
+    let stem = path.file_stem().map(|stem| stem.to_string_lossy()).unwrap_or_default();
+    let name = match path.extension() {
+        Some(extension) => format!("{}-{}.{}", stem, date, extension.to_string_lossy()),
+        None => format!("{}-{}", stem, date),
+    };
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emit::tests::element;
+    use serde_json::Value;
+
+    #[tokio::test]
+    async fn changes_are_appended_as_json_lines() {
+        let path = std::env::temp_dir().join(format!("drasi-mqtt-elements-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let config = FileOutputConfig {
+            path: path.clone(),
+            rotate_daily: false,
+            fsync_interval_secs: 3600,
+        };
+        let emitter = FileEmitter::open(&config).unwrap();
+        emitter.emit_batch(vec![element("a"), element("b")]).await.unwrap();
+        emitter.delete(DrasiDelete { id: "a".to_string() }).await.unwrap();
+        emitter.flush().await.unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<Value> = contents.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!((&lines[0]["id"], &lines[0]["properties"]["temperature"]), (&json!("a"), &json!(21.5)));
+        assert_eq!(lines[2], json!({ "id": "a", "deleted": true }));
+    }
+
+    #[test]
+    fn a_rotated_file_has_the_date_before_its_extension() {
+        assert_eq!(dated(Path::new("/data/graph.jsonl"), Some("2024-05-01")), Path::new("/data/graph-2024-05-01.jsonl"));
+        assert_eq!(dated(Path::new("graph"), Some("2024-05-01")), Path::new("graph-2024-05-01"));
+        assert_eq!(dated(Path::new("graph.jsonl"), None), Path::new("graph.jsonl"));
+    }
+}
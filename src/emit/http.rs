@@ -0,0 +1,253 @@
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use tracing::warn;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{RequestBuilder, Url};
+use serde::Serialize;
+use std::time::Duration;
+
+use super::{CloudEvent, Emitter};
+use crate::config::HttpConfig;
+use crate::model::{DrasiDelete, DrasiElement, DrasiRelation};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const RETRY_DELAY: Duration = Duration::from_millis(200);
+pub(super) const CLOUDEVENT_CONTENT_TYPE: &str = "application/cloudevents+json";
+
+// --- HTTP EMITTER ---
+// POSTs each element (or, when batching, a JSON array of elements) to a
+// change-stream endpoint, and removes nodes with `DELETE <url>/<id>`. As
+// CloudEvents, everything (deletes included) is POSTed in structured or batch
+// mode. Server errors (5xx) and transport failures are retried a few times;
+// client errors (4xx) mean the request itself is wrong, so retrying would not
+// help.
+pub struct HttpEmitter {
+    client: reqwest::Client,
+    url: Url,
+    max_attempts: u32,
+}
+
+impl HttpEmitter {
+    pub fn new(config: &HttpConfig) -> Result<Self> {
+        let client = reqwest::Client::builder().timeout(REQUEST_TIMEOUT).build()?;
+        let url = Url::parse(&config.url).with_context(|| format!("Invalid http.url {:?}", config.url))?;
+        Ok(HttpEmitter {
+            client,
+            url,
+            max_attempts: config.max_attempts.max(1),
+        })
+    }
+
+    fn element_url(&self, id: &str) -> Result<Url> {
+        let mut url = self.url.clone();
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("{} cannot be used as a base URL", self.url))?
+            .pop_if_empty()
+            .push(id);
+        Ok(url)
+    }
+
+    // Runs the request built by `request` until it succeeds, is rejected, or
+    // runs out of attempts. `id` is only used for error messages.
+    async fn send_with_retry(&self, id: &str, request: impl Fn() -> RequestBuilder) -> Result<()> {
+        let mut attempt = 1;
+        loop {
+            let error = match request().send().await {
+                Ok(response) if response.status().is_success() => return Ok(()),
+                Ok(response) if response.status().is_server_error() => {
+                    anyhow!("{} responded with {}", self.url, response.status())
+                }
+                Ok(response) => bail!("{} rejected element {}: {}", self.url, id, response.status()),
+                Err(e) => anyhow!("Request to {} failed: {}", self.url, e),
+            };
+
+            if attempt >= self.max_attempts {
+                return Err(error.context(format!("giving up on element {} after {} attempts", id, attempt)));
+            }
+            warn!("Emit attempt {}/{} failed: {}. Retrying...", attempt, self.max_attempts, error);
+            tokio::time::sleep(RETRY_DELAY * attempt).await;
+            attempt += 1;
+        }
+    }
+
+    // POSTs `body` as JSON, with `content_type` in place of application/json
+    // when given (a header set before `.json()` is kept)
+    pub(super) async fn post(&self, id: &str, body: &(impl Serialize + Sync), content_type: Option<&str>) -> Result<()> {
+        self.send_with_retry(id, || {
+            let request = self.client.post(self.url.clone());
+            match content_type {
+                Some(content_type) => request.header(CONTENT_TYPE, content_type),
+                None => request,
+            }
+            .json(body)
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl Emitter for HttpEmitter {
+    async fn emit(&self, element: DrasiElement) -> Result<()> {
+        self.post(&element.id, &element, None).await
+    }
+
+    // The whole batch goes out as one JSON array
+    async fn emit_batch(&self, elements: Vec<DrasiElement>) -> Result<()> {
+        self.post(&format!("batch of {}", elements.len()), &elements, None).await
+    }
+
+    async fn delete(&self, delete: DrasiDelete) -> Result<()> {
+        let url = self.element_url(&delete.id)?;
+        self.send_with_retry(&delete.id, || self.client.delete(url.clone())).await
+    }
+
+    // Same endpoint as nodes; receivers tell them apart by `start_id`/`end_id`
+    async fn emit_relation(&self, relation: DrasiRelation) -> Result<()> {
+        self.post(&relation.id, &relation, None).await
+    }
+
+    // Content types from the CloudEvents HTTP binding
+    async fn emit_events(&self, events: Vec<CloudEvent>) -> Result<()> {
+        match events.as_slice() {
+            [event] => self.post(&event.subject, event, Some(CLOUDEVENT_CONTENT_TYPE)).await,
+            events => {
+                let label = format!("batch of {}", events.len());
+                self.post(&label, &events, Some("application/cloudevents-batch+json")).await
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    // What the mock endpoint received
+    #[derive(Debug)]
+    pub(crate) struct Request {
+        pub method: String,
+        pub path: String,
+        pub content_type: Option<String>,
+        pub body: Vec<u8>,
+    }
+
+    // Answers one request per connection with the next of `statuses`
+    pub(crate) async fn mock_endpoint(statuses: Vec<u16>) -> (String, Arc<Mutex<Vec<Request>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/changes", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let received = requests.clone();
+        tokio::spawn(async move {
+            for status in statuses {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut data = Vec::new();
+                let mut chunk = [0; 4096];
+                let header_end = loop {
+                    let read = socket.read(&mut chunk).await.unwrap();
+                    data.extend_from_slice(&chunk[..read]);
+                    if let Some(end) = data.windows(4).position(|window| window == b"\r\n\r\n") {
+                        break end + 4;
+                    }
+                };
+                let head = String::from_utf8_lossy(&data[..header_end]).to_string();
+                let header = |name: &str| {
+                    head.lines()
+                        .find_map(|line| line.split_once(':').filter(|(key, _)| key.eq_ignore_ascii_case(name)))
+                        .map(|(_, value)| value.trim().to_string())
+                };
+                let length: usize = header("content-length").map_or(0, |length| length.parse().unwrap());
+                while data.len() < header_end + length {
+                    let read = socket.read(&mut chunk).await.unwrap();
+                    data.extend_from_slice(&chunk[..read]);
+                }
+                let mut request_line = head.split_whitespace();
+                received.lock().unwrap().push(Request {
+                    method: request_line.next().unwrap().to_string(),
+                    path: request_line.next().unwrap().to_string(),
+                    content_type: header("content-type"),
+                    body: data[header_end..header_end + length].to_vec(),
+                });
+                let response = format!("HTTP/1.1 {} Mock\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status);
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        (url, requests)
+    }
+
+    fn emitter(url: &str, max_attempts: u32) -> HttpEmitter {
+        HttpEmitter::new(&HttpConfig {
+            url: url.to_string(),
+            max_attempts,
+        })
+        .unwrap()
+    }
+
+    fn element() -> DrasiElement {
+        crate::emit::tests::element("temp-01")
+    }
+
+    #[tokio::test]
+    async fn posts_each_element_as_json() {
+        let (url, requests) = mock_endpoint(vec![200]).await;
+        emitter(&url, 1).emit(element()).await.unwrap();
+
+        let requests = requests.lock().unwrap();
+        let [request] = requests.as_slice() else {
+            panic!("expected one request, got {:?}", requests);
+        };
+        assert_eq!((request.method.as_str(), request.path.as_str()), ("POST", "/changes"));
+        assert_eq!(request.content_type.as_deref(), Some("application/json"));
+        let body: Value = serde_json::from_slice(&request.body).unwrap();
+        assert_eq!(body, json!({ "id": "temp-01", "labels": ["Sensor"], "properties": { "temperature": 21.5 } }));
+    }
+
+    #[tokio::test]
+    async fn batches_go_out_as_one_array() {
+        let (url, requests) = mock_endpoint(vec![200]).await;
+        emitter(&url, 1).emit_batch(vec![element(), element()]).await.unwrap();
+
+        let body: Value = serde_json::from_slice(&requests.lock().unwrap()[0].body).unwrap();
+        assert_eq!(body.as_array().map(Vec::len), Some(2));
+    }
+
+    #[tokio::test]
+    async fn server_errors_are_retried() {
+        let (url, requests) = mock_endpoint(vec![503, 200]).await;
+        emitter(&url, 3).emit(element()).await.unwrap();
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_attempts() {
+        let (url, requests) = mock_endpoint(vec![500, 500]).await;
+        let error = emitter(&url, 2).emit(element()).await.unwrap_err();
+        assert!(format!("{:#}", error).contains("after 2 attempts"), "{:#}", error);
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn client_errors_are_not_retried() {
+        let (url, requests) = mock_endpoint(vec![400, 200]).await;
+        let error = emitter(&url, 3).emit(element()).await.unwrap_err();
+        assert!(error.to_string().contains("rejected element temp-01"), "{:#}", error);
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn deletes_go_to_the_element_url() {
+        let (url, requests) = mock_endpoint(vec![204]).await;
+        emitter(&url, 1)
+            .delete(DrasiDelete {
+                id: "temp-01".to_string(),
+            })
+            .await
+            .unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert_eq!((requests[0].method.as_str(), requests[0].path.as_str()), ("DELETE", "/changes/temp-01"));
+    }
+}
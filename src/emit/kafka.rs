@@ -0,0 +1,224 @@
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use rdkafka::config::ClientConfig;
+use rdkafka::error::{KafkaError, RDKafkaErrorCode};
+use rdkafka::producer::{FutureProducer, FutureRecord, Producer};
+use serde::Serialize;
+use tracing::warn;
+use std::time::Duration;
+
+use super::{CloudEvent, Emitter};
+use crate::config::KafkaConfig;
+use crate::model::{DrasiDelete, DrasiElement, DrasiRelation};
+
+// How long librdkafka keeps trying to deliver one message
+const MESSAGE_TIMEOUT: Duration = Duration::from_secs(10);
+// How long a send may wait for room in librdkafka's local queue
+const QUEUE_TIMEOUT: Duration = Duration::from_secs(5);
+const RETRY_DELAY: Duration = Duration::from_millis(200);
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+// What the emitter needs from a producer: one awaited send per message and a
+// flush. `FutureProducer` is the real one; tests swap in a recording one.
+#[async_trait]
+pub trait Produce: Send + Sync {
+    async fn send(&self, topic: &str, key: &str, payload: Option<&[u8]>) -> Result<(), KafkaError>;
+    async fn flush(&self) -> Result<()>;
+}
+
+#[async_trait]
+impl Produce for FutureProducer {
+    async fn send(&self, topic: &str, key: &str, payload: Option<&[u8]>) -> Result<(), KafkaError> {
+        let mut record = FutureRecord::<str, [u8]>::to(topic).key(key);
+        if let Some(payload) = payload {
+            record = record.payload(payload);
+        }
+        match FutureProducer::send(self, record, QUEUE_TIMEOUT).await {
+            Ok(_) => Ok(()),
+            Err((error, _)) => Err(error),
+        }
+    }
+
+    async fn flush(&self) -> Result<()> {
+        let producer = self.clone();
+        tokio::task::spawn_blocking(move || Producer::flush(&producer, FLUSH_TIMEOUT)).await??;
+        Ok(())
+    }
+}
+
+// --- KAFKA EMITTER ---
+// Produces each change as a JSON message keyed by its ID. A delete is a
+// tombstone (the node's key with no value), which is also what lets a
+// compacted topic forget the node. A failed delivery is retried a few times;
+// errors that can't succeed on retry (message too large, topic not
+// authorised) are returned straight away.
+pub struct KafkaEmitter<P = FutureProducer> {
+    producer: P,
+    topic: String,
+    max_attempts: u32,
+}
+
+impl KafkaEmitter {
+    pub fn new(config: &KafkaConfig) -> Result<Self> {
+        let producer = ClientConfig::new()
+            .set("bootstrap.servers", &config.bootstrap_servers)
+            .set("message.timeout.ms", MESSAGE_TIMEOUT.as_millis().to_string())
+            .create()
+            .with_context(|| format!("Invalid kafka settings for {}", config.bootstrap_servers))?;
+        Ok(KafkaEmitter::with_producer(producer, config))
+    }
+}
+
+impl<P: Produce> KafkaEmitter<P> {
+    pub fn with_producer(producer: P, config: &KafkaConfig) -> Self {
+        KafkaEmitter {
+            producer,
+            topic: config.topic.clone(),
+            max_attempts: config.max_attempts.max(1),
+        }
+    }
+
+    async fn produce(&self, key: &str, value: Option<&impl Serialize>) -> Result<()> {
+        let payload = value.map(serde_json::to_vec).transpose()?;
+        let mut attempt = 1;
+        loop {
+            let error = match self.producer.send(&self.topic, key, payload.as_deref()).await {
+                Ok(()) => return Ok(()),
+                Err(error) => error,
+            };
+            if matches!(
+                error.rdkafka_error_code(),
+                Some(RDKafkaErrorCode::MessageSizeTooLarge | RDKafkaErrorCode::TopicAuthorizationFailed)
+            ) {
+                bail!("{} rejected {}: {}", self.topic, key, error);
+            }
+
+            let error = anyhow!("Producing {} to {} failed: {}", key, self.topic, error);
+            if attempt >= self.max_attempts {
+                return Err(error.context(format!("giving up on {} after {} attempts", key, attempt)));
+            }
+            warn!("Emit attempt {}/{} failed: {}. Retrying...", attempt, self.max_attempts, error);
+            tokio::time::sleep(RETRY_DELAY * attempt).await;
+            attempt += 1;
+        }
+    }
+}
+
+#[async_trait]
+impl<P: Produce> Emitter for KafkaEmitter<P> {
+    async fn emit(&self, element: DrasiElement) -> Result<()> {
+        self.produce(&element.id, Some(&element)).await
+    }
+
+    async fn delete(&self, delete: DrasiDelete) -> Result<()> {
+        self.produce(&delete.id, None::<&DrasiDelete>).await
+    }
+
+    async fn emit_relation(&self, relation: DrasiRelation) -> Result<()> {
+        self.produce(&relation.id, Some(&relation)).await
+    }
+
+    // One message per event, deletes included, keyed by its subject
+    async fn emit_events(&self, events: Vec<CloudEvent>) -> Result<()> {
+        for event in &events {
+            self.produce(&event.subject, Some(event)).await?;
+        }
+        Ok(())
+    }
+
+    // Every send above already waited for its delivery report; this only
+    // matters if one was abandoned mid-flight
+    async fn flush(&self) -> Result<()> {
+        self.producer.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emit::tests::element;
+    use std::sync::{Arc, Mutex};
+
+    // (topic, key, payload)
+    type Sent = (String, String, Option<Vec<u8>>);
+
+    // Keeps every (topic, key, payload) it is handed; fails the first
+    // `failures` sends with `error`
+    struct Recording {
+        sent: Mutex<Vec<Sent>>,
+        failures: Mutex<u32>,
+        error: RDKafkaErrorCode,
+    }
+
+    impl Recording {
+        fn failing(failures: u32, error: RDKafkaErrorCode) -> Self {
+            Recording { sent: Mutex::default(), failures: Mutex::new(failures), error }
+        }
+    }
+
+    #[async_trait]
+    impl Produce for Arc<Recording> {
+        async fn send(&self, topic: &str, key: &str, payload: Option<&[u8]>) -> Result<(), KafkaError> {
+            let mut failures = self.failures.lock().expect("failures lock poisoned");
+            if *failures > 0 {
+                *failures -= 1;
+                return Err(KafkaError::MessageProduction(self.error));
+            }
+            let sent = (topic.to_string(), key.to_string(), payload.map(<[u8]>::to_vec));
+            self.sent.lock().expect("sent lock poisoned").push(sent);
+            Ok(())
+        }
+
+        async fn flush(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn emitter(producer: &Arc<Recording>) -> KafkaEmitter<Arc<Recording>> {
+        let config = KafkaConfig {
+            bootstrap_servers: "localhost:9092".to_string(),
+            topic: "drasi-changes".to_string(),
+            max_attempts: 3,
+        };
+        KafkaEmitter::with_producer(producer.clone(), &config)
+    }
+
+    #[tokio::test]
+    async fn an_element_is_keyed_by_its_id_with_the_json_as_value() {
+        let producer = Arc::new(Recording::failing(0, RDKafkaErrorCode::NoError));
+        emitter(&producer).emit(element("temp-01")).await.unwrap();
+
+        let sent = producer.sent.lock().unwrap();
+        let (topic, key, payload) = &sent[0];
+        assert_eq!(topic, "drasi-changes");
+        assert_eq!(key, "temp-01");
+        let value: serde_json::Value = serde_json::from_slice(payload.as_deref().unwrap()).unwrap();
+        assert_eq!(value, serde_json::to_value(element("temp-01")).unwrap());
+    }
+
+    #[tokio::test]
+    async fn a_delete_is_a_tombstone() {
+        let producer = Arc::new(Recording::failing(0, RDKafkaErrorCode::NoError));
+        emitter(&producer).delete(DrasiDelete { id: "temp-01".to_string() }).await.unwrap();
+
+        let sent = producer.sent.lock().unwrap();
+        assert_eq!(sent[0].1, "temp-01");
+        assert_eq!(sent[0].2, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_failed_send_is_retried() {
+        let producer = Arc::new(Recording::failing(2, RDKafkaErrorCode::BrokerTransportFailure));
+        emitter(&producer).emit(element("temp-01")).await.unwrap();
+        assert_eq!(producer.sent.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn a_message_too_large_is_not_retried() {
+        let producer = Arc::new(Recording::failing(1, RDKafkaErrorCode::MessageSizeTooLarge));
+        let error = emitter(&producer).emit(element("temp-01")).await.unwrap_err();
+        assert!(error.to_string().contains("rejected temp-01"), "{}", error);
+        assert_eq!(*producer.failures.lock().unwrap(), 0);
+        assert!(producer.sent.lock().unwrap().is_empty());
+    }
+}
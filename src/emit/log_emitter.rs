@@ -0,0 +1,94 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::Serialize;
+use tracing::info;
+
+use super::{CloudEvent, Emitter};
+use crate::config::LogOutput;
+use crate::model::{DrasiDelete, DrasiElement, DrasiRelation};
+use crate::redact;
+
+// The PoC's original behaviour: print the element and move on. Values at
+// the `redact` pointers are printed as "***".
+pub struct LogEmitter {
+    format: LogOutput,
+    redact: Vec<String>,
+}
+
+impl LogEmitter {
+    pub fn new(format: LogOutput, redact: Vec<String>) -> Self {
+        LogEmitter { format, redact }
+    }
+
+    fn render(&self, value: &impl Serialize) -> Result<String> {
+        Ok(match self.format {
+            LogOutput::Compact => serde_json::to_string(value)?,
+            LogOutput::Pretty => serde_json::to_string_pretty(value)?,
+        })
+    }
+}
+
+#[async_trait]
+impl Emitter for LogEmitter {
+    async fn emit(&self, mut element: DrasiElement) -> Result<()> {
+        redact::redact(&mut element.properties, &self.redact);
+        info!(event = "ingested", device_id = %element.id, "-> Ingested Graph Node: {}", self.render(&element)?);
+        Ok(())
+    }
+
+    async fn delete(&self, delete: DrasiDelete) -> Result<()> {
+        info!(event = "deleted", device_id = %delete.id, "-> Deleted Graph Node: {}", delete.id);
+        Ok(())
+    }
+
+    async fn emit_relation(&self, relation: DrasiRelation) -> Result<()> {
+        info!(
+            event = "relation",
+            device_id = %relation.start_id,
+            "-> Ingested Graph Relation: {} -[:{}]-> {}",
+            relation.start_id, relation.label, relation.end_id
+        );
+        Ok(())
+    }
+
+    async fn emit_events(&self, events: Vec<CloudEvent>) -> Result<()> {
+        for mut event in events {
+            // `data` is the element or relation
+            if let Some(properties) = event.data.get_mut("properties") {
+                redact::redact(properties, &self.redact);
+            }
+            info!(
+                event = "cloudevent",
+                device_id = %event.subject,
+                "-> CloudEvent: {}",
+                self.render(&event)?
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emit::tests::element;
+
+    #[tokio::test]
+    async fn logs_a_sample_element_without_error() {
+        LogEmitter::new(LogOutput::Compact, Vec::new()).emit(element("temp-01")).await.unwrap();
+        LogEmitter::new(LogOutput::Pretty, Vec::new()).emit(element("temp-01")).await.unwrap();
+        let delete = DrasiDelete {
+            id: "temp-01".to_string(),
+        };
+        LogEmitter::new(LogOutput::Compact, Vec::new()).delete(delete).await.unwrap();
+    }
+
+    #[test]
+    fn compact_is_one_line_and_pretty_is_indented() {
+        let value = element("temp-01");
+        let compact = LogEmitter::new(LogOutput::Compact, Vec::new()).render(&value).unwrap();
+        let pretty = LogEmitter::new(LogOutput::Pretty, Vec::new()).render(&value).unwrap();
+        assert!(!compact.contains('\n'));
+        assert!(pretty.contains("\n  \"id\": \"temp-01\""), "{}", pretty);
+    }
+}
@@ -0,0 +1,358 @@
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use tracing::info;
+use std::sync::Arc;
+
+use crate::config::{Config, OutputKind, TransportKind};
+use crate::metrics::Metrics;
+use crate::model::{DrasiDelete, DrasiElement, DrasiRelation, GraphChange};
+
+mod batch;
+mod breaker;
+mod cloudevents;
+mod dapr;
+mod file;
+mod http;
+#[cfg(feature = "kafka")]
+mod kafka;
+mod log_emitter;
+mod mqtt;
+mod sample;
+mod source;
+mod stdout;
+mod throttle;
+
+pub use batch::BatchingEmitter;
+pub use breaker::CircuitBreakerEmitter;
+pub use cloudevents::{CloudEvent, CloudEventEmitter};
+pub use dapr::DaprEmitter;
+pub use file::FileEmitter;
+pub use http::HttpEmitter;
+#[cfg(feature = "kafka")]
+pub use kafka::KafkaEmitter;
+pub use log_emitter::LogEmitter;
+pub use mqtt::MqttEmitter;
+pub use sample::SamplingEmitter;
+pub use source::SourceEmitter;
+pub use stdout::StdoutEmitter;
+pub use throttle::ThrottlingEmitter;
+
+// --- EMITTERS ---
+// An Emitter is the last hop of the pipeline: it hands a mapped element to
+// whatever sits downstream (the Drasi change stream, a log, ...).
+#[async_trait]
+pub trait Emitter: Send + Sync {
+    async fn emit(&self, element: DrasiElement) -> Result<()>;
+    async fn delete(&self, delete: DrasiDelete) -> Result<()>;
+    async fn emit_relation(&self, relation: DrasiRelation) -> Result<()>;
+
+    // Sinks with a bulk API should override this; by default the batch is
+    // sent one element at a time
+    async fn emit_batch(&self, elements: Vec<DrasiElement>) -> Result<()> {
+        for element in elements {
+            self.emit(element).await?;
+        }
+        Ok(())
+    }
+
+    // Already-enveloped changes from CloudEventEmitter. Only the outputs
+    // themselves (log, http, kafka, dapr, mqtt, null) take these.
+    async fn emit_events(&self, _events: Vec<CloudEvent>) -> Result<()> {
+        bail!("this emitter does not accept CloudEvents")
+    }
+
+    // Called on shutdown so buffering emitters can push out what they hold
+    async fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    // Changes accepted but not yet handed on, for reporting what a shutdown
+    // that ran out of time before `flush` finished leaves undelivered
+    fn pending(&self) -> usize {
+        0
+    }
+}
+
+// Hands one change to the emitter method for its kind
+pub async fn send(emitter: &dyn Emitter, change: GraphChange) -> Result<()> {
+    match change {
+        GraphChange::Upsert(element) => emitter.emit(element).await,
+        GraphChange::Delete(delete) => emitter.delete(delete).await,
+        GraphChange::Relation(relation) => emitter.emit_relation(relation).await,
+    }
+}
+
+// Accepts and discards everything (`output: null`)
+pub struct NullEmitter;
+
+#[async_trait]
+impl Emitter for NullEmitter {
+    async fn emit(&self, _element: DrasiElement) -> Result<()> {
+        Ok(())
+    }
+
+    async fn delete(&self, _delete: DrasiDelete) -> Result<()> {
+        Ok(())
+    }
+
+    async fn emit_relation(&self, _relation: DrasiRelation) -> Result<()> {
+        Ok(())
+    }
+
+    async fn emit_events(&self, _events: Vec<CloudEvent>) -> Result<()> {
+        Ok(())
+    }
+}
+
+// Builds the emitter selected by `output`, wrapped (innermost first) in a
+// circuit breaker, CloudEvents envelopes, batching, throttling, sampling and
+// source stamping when configured, so the rate limit applies to elements
+// rather than batches and each batch is one CloudEvents batch. Called once at
+// startup; the result is shared by every processing task.
+pub fn build(config: &Config, metrics: &Arc<Metrics>) -> Result<Arc<dyn Emitter>> {
+    let output: Box<dyn Emitter> = match config.output {
+        OutputKind::Log => Box::new(LogEmitter::new(config.log_output, config.redact.clone())),
+        OutputKind::Stdout => Box::new(StdoutEmitter::new()),
+        OutputKind::File => {
+            let file = config.file.as_ref().context("output is file but no file section is configured")?;
+            if file.rotate_daily {
+                info!("Appending elements to {}, one file per day", file.path.display());
+            } else {
+                info!("Appending elements to {}", file.path.display());
+            }
+            Box::new(FileEmitter::open(file)?)
+        }
+        OutputKind::Null => Box::new(NullEmitter),
+        OutputKind::Http => {
+            let http = config.http.as_ref().context("output is http but no http section is configured")?;
+            info!("Emitting elements to {}", http.url);
+            Box::new(HttpEmitter::new(http)?)
+        }
+        #[cfg(feature = "kafka")]
+        OutputKind::Kafka => {
+            let kafka = config.kafka.as_ref().context("output is kafka but no kafka section is configured")?;
+            info!("Producing elements to Kafka topic {} at {}", kafka.topic, kafka.bootstrap_servers);
+            Box::new(KafkaEmitter::new(kafka)?)
+        }
+        OutputKind::Dapr => {
+            let dapr = config.dapr.as_ref().context("output is dapr but no dapr section is configured")?;
+            info!("Publishing elements to Dapr pub/sub {} topic {}", dapr.pubsub, dapr.topic);
+            Box::new(DaprEmitter::new(dapr)?)
+        }
+        OutputKind::Mqtt => {
+            let mqtt = config.mqtt_output.as_ref().context("output is mqtt but no mqtt_output section is configured")?;
+            info!("Republishing elements to {} on {}:{}", mqtt.topic, mqtt.host, mqtt.port);
+            Box::new(MqttEmitter::new(mqtt, &format!("{}-bridge", config.source_name()))?)
+        }
+    };
+    let output: Box<dyn Emitter> = match &config.circuit_breaker {
+        Some(breaker) => {
+            info!(
+                "Opening the output's circuit after {} failure(s) in a row, for {}ms",
+                breaker.failure_threshold, breaker.cooldown_ms
+            );
+            Box::new(CircuitBreakerEmitter::new(output, breaker, metrics.clone()))
+        }
+        None => output,
+    };
+    let mut emitter: Arc<dyn Emitter> = if config.cloudevents {
+        let source = cloudevents_source(config);
+        info!("Wrapping output in CloudEvents from {}", source);
+        Arc::new(CloudEventEmitter::new(output, source))
+    } else {
+        Arc::from(output)
+    };
+
+    if let Some(batch) = &config.batch {
+        info!(
+            "Batching up to {} element(s) or {}ms per flush",
+            batch.max_batch_size, batch.flush_interval_ms
+        );
+        emitter = Arc::new(BatchingEmitter::new(emitter, batch, metrics.clone()));
+    }
+    if let Some(throttle) = &config.throttle {
+        info!(
+            "Limiting output to {} element(s) per second ({:?} when exceeded)",
+            throttle.max_per_second, throttle.overflow
+        );
+        emitter = Arc::new(ThrottlingEmitter::new(emitter, throttle, metrics.clone()));
+    }
+    // Outside the throttle, so skipped updates don't use up its tokens
+    if let Some(sample) = &config.sample {
+        info!("Sampling one in {} update(s) per element ID ({:?})", sample.one_in, sample.mode);
+        emitter = Arc::new(SamplingEmitter::new(emitter, sample, metrics.clone()));
+    }
+    if config.stamp_source || config.source_id_prefix {
+        let name = config.source_name().to_string();
+        info!("Stamping changes with source {}", name);
+        emitter = Arc::new(SourceEmitter::new(emitter, name, config.stamp_source, config.source_id_prefix));
+    }
+    Ok(emitter)
+}
+
+// The URL of the one broker (see `Config::validate`) the changes came from,
+// as the client dials it
+fn cloudevents_source(config: &Config) -> String {
+    let scheme = match config.transport {
+        TransportKind::Tcp if config.tls.enabled => "mqtts",
+        TransportKind::Tcp => "mqtt",
+        TransportKind::Ws => "ws",
+        TransportKind::Wss => "wss",
+    };
+    let path = match config.transport {
+        TransportKind::Tcp => "",
+        TransportKind::Ws | TransportKind::Wss => config.websocket_path.as_str(),
+    };
+    format!("{}://{}:{}{}", scheme, config.broker_host, config.port(), path)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Mutex;
+
+    pub(crate) use super::http::tests::mock_endpoint;
+
+    pub(crate) fn element(id: &str) -> DrasiElement {
+        DrasiElement {
+            id: id.to_string(),
+            element_type: None,
+            labels: vec!["Sensor".to_string()],
+            properties: json!({ "temperature": 21.5 }),
+            op: None,
+        }
+    }
+
+    // Writes down what reaches it, e.g. "emit a" or "batch a,b", and fails
+    // everything while `failing` is set
+    #[derive(Default)]
+    pub(crate) struct Recording {
+        pub calls: Mutex<Vec<String>>,
+        pub failing: AtomicBool,
+    }
+
+    impl Recording {
+        pub fn calls(&self) -> Vec<String> {
+            self.calls.lock().unwrap().clone()
+        }
+
+        fn record(&self, call: String) -> Result<()> {
+            if self.failing.load(Ordering::Relaxed) {
+                bail!("{} failed", call);
+            }
+            self.calls.lock().unwrap().push(call);
+            Ok(())
+        }
+    }
+
+    #[async_trait]
+    impl Emitter for Recording {
+        async fn emit(&self, element: DrasiElement) -> Result<()> {
+            self.record(format!("emit {}", element.id))
+        }
+
+        async fn emit_batch(&self, elements: Vec<DrasiElement>) -> Result<()> {
+            let ids: Vec<_> = elements.iter().map(|element| element.id.as_str()).collect();
+            self.record(format!("batch {}", ids.join(",")))
+        }
+
+        async fn delete(&self, delete: DrasiDelete) -> Result<()> {
+            self.record(format!("delete {}", delete.id))
+        }
+
+        async fn emit_relation(&self, relation: DrasiRelation) -> Result<()> {
+            self.record(format!("relation {}", relation.id))
+        }
+    }
+
+    // For wrappers that own their inner emitter, so the test can still look
+    #[async_trait]
+    impl Emitter for Arc<Recording> {
+        async fn emit(&self, element: DrasiElement) -> Result<()> {
+            self.as_ref().emit(element).await
+        }
+
+        async fn emit_batch(&self, elements: Vec<DrasiElement>) -> Result<()> {
+            self.as_ref().emit_batch(elements).await
+        }
+
+        async fn delete(&self, delete: DrasiDelete) -> Result<()> {
+            self.as_ref().delete(delete).await
+        }
+
+        async fn emit_relation(&self, relation: DrasiRelation) -> Result<()> {
+            self.as_ref().emit_relation(relation).await
+        }
+    }
+
+    fn relation() -> DrasiRelation {
+        DrasiRelation {
+            id: "temp-01-IN_ROOM-r2".to_string(),
+            start_id: "temp-01".to_string(),
+            end_id: "r2".to_string(),
+            label: "IN_ROOM".to_string(),
+            properties: Default::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn send_hands_each_change_to_its_method() {
+        let recording = Recording::default();
+        send(&recording, GraphChange::Upsert(element("a"))).await.unwrap();
+        send(&recording, GraphChange::Delete(DrasiDelete { id: "a".to_string() })).await.unwrap();
+        send(&recording, GraphChange::Relation(relation())).await.unwrap();
+        assert_eq!(recording.calls(), ["emit a", "delete a", "relation temp-01-IN_ROOM-r2"]);
+    }
+
+    #[tokio::test]
+    async fn the_default_batch_is_sent_one_element_at_a_time() {
+        struct OneByOne(Recording);
+
+        #[async_trait]
+        impl Emitter for OneByOne {
+            async fn emit(&self, element: DrasiElement) -> Result<()> {
+                self.0.emit(element).await
+            }
+            async fn delete(&self, delete: DrasiDelete) -> Result<()> {
+                self.0.delete(delete).await
+            }
+            async fn emit_relation(&self, relation: DrasiRelation) -> Result<()> {
+                self.0.emit_relation(relation).await
+            }
+        }
+
+        let emitter = OneByOne(Recording::default());
+        emitter.emit_batch(vec![element("a"), element("b")]).await.unwrap();
+        assert_eq!(emitter.0.calls(), ["emit a", "emit b"]);
+    }
+
+    #[tokio::test]
+    async fn the_null_emitter_accepts_every_change() {
+        send(&NullEmitter, GraphChange::Upsert(element("a"))).await.unwrap();
+        send(&NullEmitter, GraphChange::Delete(DrasiDelete { id: "a".to_string() })).await.unwrap();
+        send(&NullEmitter, GraphChange::Relation(relation())).await.unwrap();
+        NullEmitter.flush().await.unwrap();
+    }
+
+    #[test]
+    fn the_cloudevents_source_follows_the_transport() {
+        let tcp = Config {
+            broker_host: "broker.local".to_string(),
+            ..Config::default()
+        };
+        assert_eq!(cloudevents_source(&tcp), "mqtt://broker.local:1883");
+        let wss = Config {
+            transport: TransportKind::Wss,
+            broker_port: Some(8884),
+            ..tcp.clone()
+        };
+        assert_eq!(cloudevents_source(&wss), "wss://broker.local:8884/mqtt");
+        let ws = Config {
+            transport: TransportKind::Ws,
+            ..tcp
+        };
+        assert_eq!(cloudevents_source(&ws), "ws://broker.local:80/mqtt");
+    }
+}
@@ -0,0 +1,319 @@
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use rumqttc::{AsyncClient, Event, MqttOptions, Outgoing, Packet, QoS, Transport};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use super::{CloudEvent, Emitter};
+use crate::backoff::Backoff;
+use crate::config::MqttOutputConfig;
+use crate::model::{DrasiDelete, DrasiElement, DrasiRelation};
+use crate::tls;
+
+// Publishes queued for the connection task before a send has to wait
+const REQUEST_CAPACITY: usize = 100;
+// How long shutdown waits for queued publishes to be written out
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+// Past this many nodes the remembered topics start over; a delete for a
+// forgotten one goes to its topic with an empty `{label}`
+const MAX_TOPICS: usize = 10_000;
+
+// --- MQTT EMITTER ---
+// Republishes each change as JSON on another broker, turning the source into
+// a normalizing bridge. The topic is a template: `{id}` is the element ID and
+// `{label}` its type, else its first label; a relation fills in its own ID
+// and label. A delete goes to the node's topic: with `retain`, where the
+// target keeps every node's latest state, as an empty retained message that
+// clears it, otherwise as `{"id": ..., "op": "delete"}`. The connection runs on
+// its own task and reconnects with backoff; QoS 1 and 2 changes published
+// while it is down go out once it is back.
+pub struct MqttEmitter {
+    client: AsyncClient,
+    target: String,
+    topic: Vec<Part>,
+    qos: QoS,
+    retain: bool,
+    // element ID -> topic of its latest update, for its delete
+    topics: Mutex<HashMap<String, String>>,
+    // Ends once the DISCONNECT sent by `flush` is out
+    connection: Mutex<Option<JoinHandle<()>>>,
+}
+
+enum Part {
+    Literal(String),
+    Id,
+    Label,
+}
+
+impl MqttEmitter {
+    // `client_id` is used unless the config names its own
+    pub fn new(config: &MqttOutputConfig, client_id: &str) -> Result<Self> {
+        let topic = parse_topic(&config.topic)?;
+        let client_id = config.client_id.clone().unwrap_or_else(|| client_id.to_string());
+        let mut options = MqttOptions::new(client_id, &config.host, config.port);
+        options.set_keep_alive(Duration::from_secs(u64::from(config.keep_alive_secs)));
+        if let Some(username) = &config.username {
+            options.set_credentials(username, config.password.as_deref().unwrap_or_default());
+        }
+        if config.tls.enabled {
+            options.set_transport(Transport::tls_with_config(tls::load_tls_configuration(&config.tls)?));
+        }
+        let (client, mut eventloop) = AsyncClient::new(options, REQUEST_CAPACITY);
+
+        let target = format!("{}:{}", config.host, config.port);
+        let connection = tokio::spawn({
+            let target = target.clone();
+            async move {
+                let mut backoff = Backoff::default();
+                loop {
+                    match eventloop.poll().await {
+                        Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                            info!("Connected to the output broker {}", target);
+                            backoff.reset();
+                        }
+                        Ok(Event::Outgoing(Outgoing::Disconnect)) => break,
+                        Ok(_) => {}
+                        Err(e) => {
+                            let delay = backoff.next_delay();
+                            warn!(
+                                event = "output_disconnected",
+                                "Lost the output broker {}: {}. Retrying in {:?}...",
+                                target,
+                                e,
+                                delay
+                            );
+                            tokio::time::sleep(delay).await;
+                        }
+                    }
+                }
+            }
+        });
+        Ok(MqttEmitter {
+            client,
+            target,
+            topic,
+            qos: config.qos,
+            retain: config.retain,
+            topics: Mutex::new(HashMap::new()),
+            connection: Mutex::new(Some(connection)),
+        })
+    }
+
+    fn topic(&self, id: &str, label: &str) -> Result<String> {
+        let mut topic = String::new();
+        for part in &self.topic {
+            match part {
+                Part::Literal(text) => topic.push_str(text),
+                Part::Id => topic.push_str(id),
+                Part::Label => topic.push_str(label),
+            }
+        }
+        // Would be taken for wildcards; rumqttc refuses them without saying why
+        if topic.contains(['+', '#']) {
+            bail!("cannot publish {} to {:?}: MQTT topics can't contain '+' or '#'", id, topic);
+        }
+        Ok(topic)
+    }
+
+    // Remembered for the node's delete
+    fn node_topic(&self, id: &str, label: &str) -> Result<String> {
+        let topic = self.topic(id, label)?;
+        let mut topics = self.topics.lock().expect("mqtt output lock poisoned");
+        if !topics.contains_key(id) && topics.len() >= MAX_TOPICS {
+            topics.clear();
+        }
+        topics.insert(id.to_string(), topic.clone());
+        Ok(topic)
+    }
+
+    // A delete carries no labels, so it goes where the node's updates went
+    fn delete_topic(&self, id: &str) -> Result<String> {
+        match self.topics.lock().expect("mqtt output lock poisoned").remove(id) {
+            Some(topic) => Ok(topic),
+            None => self.topic(id, ""),
+        }
+    }
+
+    async fn publish(&self, topic: String, payload: Vec<u8>) -> Result<()> {
+        self.client
+            .publish(&topic, self.qos, self.retain, payload)
+            .await
+            .map_err(|e| anyhow!("Publishing to {} on {} failed: {}", topic, self.target, e))
+    }
+
+    async fn publish_json(&self, topic: String, value: &impl Serialize) -> Result<()> {
+        self.publish(topic, serde_json::to_vec(value)?).await
+    }
+}
+
+// `{id}` and `{label}`; any other `{...}` is a mistake worth reporting now
+// rather than a literal in every topic
+fn parse_topic(template: &str) -> Result<Vec<Part>> {
+    let mut parts = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| anyhow!("mqtt_output.topic {:?} has an unclosed '{{'", template))?;
+        if start > 0 {
+            parts.push(Part::Literal(rest[..start].to_string()));
+        }
+        parts.push(match &rest[start + 1..start + end] {
+            "id" => Part::Id,
+            "label" => Part::Label,
+            other => bail!("mqtt_output.topic {:?}: unknown placeholder {{{}}}; use {{id}} or {{label}}", template, other),
+        });
+        rest = &rest[start + end + 1..];
+    }
+    if !rest.is_empty() {
+        parts.push(Part::Literal(rest.to_string()));
+    }
+    Ok(parts)
+}
+
+fn label(element: &DrasiElement) -> &str {
+    element
+        .element_type
+        .as_deref()
+        .or(element.labels.first().map(String::as_str))
+        .unwrap_or_default()
+}
+
+#[async_trait]
+impl Emitter for MqttEmitter {
+    async fn emit(&self, element: DrasiElement) -> Result<()> {
+        let topic = self.node_topic(&element.id, label(&element))?;
+        self.publish_json(topic, &element).await
+    }
+
+    async fn delete(&self, delete: DrasiDelete) -> Result<()> {
+        let topic = self.delete_topic(&delete.id)?;
+        if self.retain {
+            self.publish(topic, Vec::new()).await
+        } else {
+            self.publish_json(topic, &json!({ "id": delete.id, "op": "delete" })).await
+        }
+    }
+
+    async fn emit_relation(&self, relation: DrasiRelation) -> Result<()> {
+        let topic = self.topic(&relation.id, &relation.label)?;
+        self.publish_json(topic, &relation).await
+    }
+
+    // One message per event, on the topic of the change it wraps; a delete's
+    // data has no labels
+    async fn emit_events(&self, events: Vec<CloudEvent>) -> Result<()> {
+        for event in &events {
+            let data = &event.data;
+            let label = data
+                .get("type")
+                .or_else(|| data.pointer("/labels/0"))
+                .or_else(|| data.get("label"))
+                .and_then(Value::as_str);
+            let topic = match label {
+                Some(label) => self.node_topic(&event.subject, label)?,
+                None => self.delete_topic(&event.subject)?,
+            };
+            self.publish_json(topic, event).await?;
+        }
+        Ok(())
+    }
+
+    // Called on shutdown: leaves the target once everything queued before
+    // is written out
+    async fn flush(&self) -> Result<()> {
+        let Some(connection) = self.connection.lock().expect("mqtt output lock poisoned").take() else {
+            return Ok(());
+        };
+        self.client.disconnect().await?;
+        if tokio::time::timeout(FLUSH_TIMEOUT, connection).await.is_err() {
+            bail!("the output broker {} was unreachable; the last changes may not have reached it", self.target);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::tests::{MockBroker, Session};
+    use crate::emit::tests::element;
+    use rumqttc::mqttbytes::v4::Packet as Received;
+
+    // Nothing listens on port 1; the connection task just keeps retrying
+    fn emitter(topic: &str) -> MqttEmitter {
+        emitter_on(1, &format!("topic: {:?}", topic))
+    }
+
+    fn emitter_on(port: u16, settings: &str) -> MqttEmitter {
+        let config: MqttOutputConfig = serde_yaml::from_str(&format!("host: 127.0.0.1\nport: {}\n{}", port, settings)).unwrap();
+        MqttEmitter::new(&config, "test-bridge").unwrap()
+    }
+
+    #[test]
+    fn unknown_or_unclosed_placeholders_are_refused() {
+        let error = parse_topic("drasi/{room}/{id}").err().unwrap();
+        assert!(error.to_string().contains("unknown placeholder {room}"));
+        assert!(parse_topic("drasi/{id").is_err());
+        assert!(parse_topic("drasi/{label}/{id}/state").is_ok());
+    }
+
+    #[tokio::test]
+    async fn a_delete_goes_to_the_topic_of_the_nodes_updates() {
+        let emitter = emitter("drasi/{label}/{id}");
+        assert_eq!(emitter.node_topic("temp-01", "Sensor").unwrap(), "drasi/Sensor/temp-01");
+        assert_eq!(emitter.delete_topic("temp-01").unwrap(), "drasi/Sensor/temp-01");
+        // Forgotten once deleted
+        assert_eq!(emitter.delete_topic("temp-01").unwrap(), "drasi//temp-01");
+    }
+
+    #[tokio::test]
+    async fn ids_with_wildcards_can_not_be_published() {
+        let emitter = emitter("drasi/{id}");
+        let error = emitter.topic("rack+1", "Sensor").unwrap_err();
+        assert!(error.to_string().contains("can't contain '+' or '#'"));
+    }
+
+    #[test]
+    fn the_type_is_the_label_before_the_first_label() {
+        let mut sensor = element("temp-01");
+        assert_eq!(label(&sensor), "Sensor");
+        sensor.element_type = Some("Thermometer".to_string());
+        assert_eq!(label(&sensor), "Thermometer");
+        sensor.element_type = None;
+        sensor.labels.clear();
+        assert_eq!(label(&sensor), "");
+    }
+
+    #[tokio::test]
+    async fn changes_are_published_as_json_on_their_topics() {
+        let broker = MockBroker::start(vec![Session::Accept]).await;
+        let emitter = emitter_on(broker.port, "topic: \"drasi/{label}/{id}\"\nqos: 1\nretain: true");
+        emitter.emit(element("temp-01")).await.unwrap();
+        emitter.delete(DrasiDelete { id: "temp-01".to_string() }).await.unwrap();
+        emitter.flush().await.unwrap();
+        broker.until(|received| received.iter().filter(|(_, packet)| matches!(packet, Received::Publish(_))).count() == 2).await;
+
+        let published: Vec<_> = broker
+            .received()
+            .into_iter()
+            .filter_map(|(_, packet)| match packet {
+                Received::Publish(publish) => Some(publish),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(published.len(), 2);
+        assert_eq!(published[0].topic, "drasi/Sensor/temp-01");
+        assert_eq!((published[0].qos, published[0].retain), (QoS::AtLeastOnce, true));
+        let payload: Value = serde_json::from_slice(&published[0].payload).unwrap();
+        assert_eq!(payload, serde_json::to_value(element("temp-01")).unwrap());
+        // Clears the retained state
+        assert_eq!(published[1].topic, "drasi/Sensor/temp-01");
+        assert!(published[1].payload.is_empty());
+    }
+}
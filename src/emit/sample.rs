@@ -0,0 +1,138 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tracing::info;
+
+use super::Emitter;
+use crate::config::{SampleConfig, SampleMode};
+use crate::metrics::Metrics;
+use crate::model::{DrasiDelete, DrasiElement, DrasiRelation};
+
+// Past this many IDs the counts start over, rather than growing with every
+// device ever seen
+const MAX_SAMPLED_IDS: usize = 10_000;
+
+// --- SAMPLING EMITTER ---
+// Counts node updates per element ID and samples the first of every
+// `one_in`: `emit` passes only the sampled ones on, `log` passes everything
+// and logs the sampled ones. Like throttling, deletes and relations always
+// pass, so the graph stays right while its updates are thinned out.
+pub struct SamplingEmitter {
+    inner: Arc<dyn Emitter>,
+    one_in: u64,
+    mode: SampleMode,
+    // element ID -> updates seen
+    seen: Mutex<HashMap<String, u64>>,
+    metrics: Arc<Metrics>,
+}
+
+impl SamplingEmitter {
+    pub fn new(inner: Arc<dyn Emitter>, config: &SampleConfig, metrics: Arc<Metrics>) -> Self {
+        SamplingEmitter {
+            inner,
+            one_in: config.one_in,
+            mode: config.mode,
+            seen: Mutex::new(HashMap::new()),
+            metrics,
+        }
+    }
+
+    fn sampled(&self, id: &str) -> bool {
+        let mut seen = self.seen.lock().expect("sample lock poisoned");
+        if !seen.contains_key(id) && seen.len() >= MAX_SAMPLED_IDS {
+            seen.clear();
+        }
+        let count = seen.entry(id.to_string()).or_insert(0);
+        let sampled = count.is_multiple_of(self.one_in);
+        *count += 1;
+        sampled
+    }
+}
+
+#[async_trait]
+impl Emitter for SamplingEmitter {
+    async fn emit(&self, element: DrasiElement) -> Result<()> {
+        let sampled = self.sampled(&element.id);
+        match self.mode {
+            SampleMode::Emit if !sampled => {
+                Metrics::inc(&self.metrics.sampled_out);
+                Ok(())
+            }
+            SampleMode::Emit => self.inner.emit(element).await,
+            SampleMode::Log => {
+                if sampled {
+                    info!(
+                        event = "sampled",
+                        device_id = %element.id,
+                        "Sampled element: {}",
+                        serde_json::to_string(&element).unwrap_or_default()
+                    );
+                }
+                self.inner.emit(element).await
+            }
+        }
+    }
+
+    // A deleted ID starts over, so it shows up as soon as it is back
+    async fn delete(&self, delete: DrasiDelete) -> Result<()> {
+        self.seen.lock().expect("sample lock poisoned").remove(&delete.id);
+        self.inner.delete(delete).await
+    }
+
+    async fn emit_relation(&self, relation: DrasiRelation) -> Result<()> {
+        self.inner.emit_relation(relation).await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.inner.flush().await
+    }
+
+    fn pending(&self) -> usize {
+        self.inner.pending()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emit::tests::{element, Recording};
+    use std::sync::atomic::Ordering;
+
+    fn sampling(mode: SampleMode) -> (SamplingEmitter, Arc<Recording>, Arc<Metrics>) {
+        let recording = Arc::new(Recording::default());
+        let metrics = Arc::new(Metrics::default());
+        let config = SampleConfig { one_in: 3, mode };
+        (SamplingEmitter::new(recording.clone(), &config, metrics.clone()), recording, metrics)
+    }
+
+    #[tokio::test]
+    async fn emit_passes_the_first_of_every_n_per_id() {
+        let (emitter, recording, metrics) = sampling(SampleMode::Emit);
+        for id in ["a", "a", "b", "a", "a"] {
+            emitter.emit(element(id)).await.unwrap();
+        }
+        assert_eq!(recording.calls(), ["emit a", "emit b", "emit a"]);
+        assert_eq!(metrics.sampled_out.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn a_delete_starts_the_count_over() {
+        let (emitter, recording, _) = sampling(SampleMode::Emit);
+        emitter.emit(element("a")).await.unwrap();
+        emitter.emit(element("a")).await.unwrap();
+        emitter.delete(DrasiDelete { id: "a".to_string() }).await.unwrap();
+        emitter.emit(element("a")).await.unwrap();
+        assert_eq!(recording.calls(), ["emit a", "delete a", "emit a"]);
+    }
+
+    #[tokio::test]
+    async fn log_passes_everything() {
+        let (emitter, recording, metrics) = sampling(SampleMode::Log);
+        for _ in 0..3 {
+            emitter.emit(element("a")).await.unwrap();
+        }
+        assert_eq!(recording.calls().len(), 3);
+        assert_eq!(metrics.sampled_out.load(Ordering::Relaxed), 0);
+    }
+}
@@ -0,0 +1,140 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+use super::Emitter;
+use crate::model::{DrasiDelete, DrasiElement, DrasiRelation};
+
+// --- SOURCE STAMPING EMITTER ---
+// Marks every change with the source that produced it, for graphs fed by
+// several sources: a `_source` property on nodes and relations, and/or a
+// `<source>:` prefix on every ID (deletes and relation endpoints included,
+// so they still line up with the nodes). Outermost, so heartbeats and
+// aggregates are stamped like mapped elements.
+pub struct SourceEmitter {
+    inner: Arc<dyn Emitter>,
+    name: String,
+    stamp: bool,
+    prefix_ids: bool,
+}
+
+impl SourceEmitter {
+    pub fn new(inner: Arc<dyn Emitter>, name: String, stamp: bool, prefix_ids: bool) -> Self {
+        SourceEmitter {
+            inner,
+            name,
+            stamp,
+            prefix_ids,
+        }
+    }
+
+    fn id(&self, id: String) -> String {
+        if self.prefix_ids {
+            format!("{}:{}", self.name, id)
+        } else {
+            id
+        }
+    }
+}
+
+#[async_trait]
+impl Emitter for SourceEmitter {
+    async fn emit(&self, mut element: DrasiElement) -> Result<()> {
+        element.id = self.id(element.id);
+        // Scalar properties (e.g. raw strings) have nowhere to put it
+        if let (true, Value::Object(map)) = (self.stamp, &mut element.properties) {
+            map.insert("_source".to_string(), json!(self.name));
+        }
+        self.inner.emit(element).await
+    }
+
+    async fn delete(&self, mut delete: DrasiDelete) -> Result<()> {
+        delete.id = self.id(delete.id);
+        self.inner.delete(delete).await
+    }
+
+    async fn emit_relation(&self, mut relation: DrasiRelation) -> Result<()> {
+        relation.id = self.id(relation.id);
+        relation.start_id = self.id(relation.start_id);
+        relation.end_id = self.id(relation.end_id);
+        if self.stamp {
+            relation.properties.insert("_source".to_string(), json!(self.name));
+        }
+        self.inner.emit_relation(relation).await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.inner.flush().await
+    }
+
+    fn pending(&self) -> usize {
+        self.inner.pending()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emit::tests::{element, Recording};
+    use std::sync::Mutex;
+
+    // Keeps what reached the output, properties included
+    #[derive(Default)]
+    struct Captured {
+        elements: Mutex<Vec<DrasiElement>>,
+        relations: Mutex<Vec<DrasiRelation>>,
+    }
+
+    #[async_trait]
+    impl Emitter for Captured {
+        async fn emit(&self, element: DrasiElement) -> Result<()> {
+            self.elements.lock().unwrap().push(element);
+            Ok(())
+        }
+
+        async fn delete(&self, _delete: DrasiDelete) -> Result<()> {
+            Ok(())
+        }
+
+        async fn emit_relation(&self, relation: DrasiRelation) -> Result<()> {
+            self.relations.lock().unwrap().push(relation);
+            Ok(())
+        }
+    }
+
+    fn relation() -> DrasiRelation {
+        DrasiRelation {
+            id: "r2-CONTAINS-temp-01".to_string(),
+            start_id: "r2".to_string(),
+            end_id: "temp-01".to_string(),
+            label: "CONTAINS".to_string(),
+            properties: serde_json::Map::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn nodes_and_relations_are_stamped_with_the_source() {
+        let captured = Arc::new(Captured::default());
+        let emitter = SourceEmitter::new(captured.clone(), "plant-a".to_string(), true, false);
+        emitter.emit(element("temp-01")).await.unwrap();
+        emitter.emit_relation(relation()).await.unwrap();
+        let elements = captured.elements.lock().unwrap();
+        assert_eq!(elements[0].id, "temp-01");
+        assert_eq!(elements[0].properties["_source"], "plant-a");
+        assert_eq!(captured.relations.lock().unwrap()[0].properties["_source"], "plant-a");
+    }
+
+    #[tokio::test]
+    async fn every_id_can_be_prefixed_with_the_source() {
+        let recording = Arc::new(Recording::default());
+        let emitter = SourceEmitter::new(recording.clone(), "plant-a".to_string(), false, true);
+        emitter.emit(element("temp-01")).await.unwrap();
+        emitter.delete(DrasiDelete { id: "temp-01".to_string() }).await.unwrap();
+        emitter.emit_relation(relation()).await.unwrap();
+        assert_eq!(
+            recording.calls(),
+            ["emit plant-a:temp-01", "delete plant-a:temp-01", "relation plant-a:r2-CONTAINS-temp-01"]
+        );
+    }
+}
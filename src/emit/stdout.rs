@@ -0,0 +1,90 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use serde::Serialize;
+use serde_json::json;
+use tokio::io::{AsyncWrite, AsyncWriteExt, Stdout};
+use tokio::sync::Mutex;
+
+use super::{CloudEvent, Emitter};
+use crate::model::{DrasiDelete, DrasiElement, DrasiRelation};
+
+// --- STDOUT EMITTER ---
+// One JSON object per line on stdout, for shell pipelines
+// (`drasi-mqtt-poc | jq ...`); the logs stay on stderr. Nodes and relations
+// are written as they are, a delete as `{"id": ..., "deleted": true}`, and
+// with `cloudevents` each envelope gets its own line. Every line is flushed
+// as soon as it is written. Tests hand it a buffer in place of stdout.
+pub struct StdoutEmitter<W = Stdout> {
+    stdout: Mutex<W>,
+}
+
+impl StdoutEmitter {
+    pub fn new() -> Self {
+        StdoutEmitter::with_writer(tokio::io::stdout())
+    }
+}
+
+impl<W: AsyncWrite + Unpin + Send> StdoutEmitter<W> {
+    pub fn with_writer(writer: W) -> Self {
+        StdoutEmitter {
+            stdout: Mutex::new(writer),
+        }
+    }
+
+    async fn write_lines<T: Serialize>(&self, values: &[T]) -> Result<()> {
+        let mut lines = Vec::new();
+        for value in values {
+            serde_json::to_writer(&mut lines, value)?;
+            lines.push(b'\n');
+        }
+        // Held across the write so concurrent workers can't interleave lines
+        let mut stdout = self.stdout.lock().await;
+        stdout.write_all(&lines).await.context("Failed to write to stdout")?;
+        stdout.flush().await.context("Failed to write to stdout")
+    }
+}
+
+#[async_trait]
+impl<W: AsyncWrite + Unpin + Send> Emitter for StdoutEmitter<W> {
+    async fn emit(&self, element: DrasiElement) -> Result<()> {
+        self.write_lines(&[element]).await
+    }
+
+    async fn delete(&self, delete: DrasiDelete) -> Result<()> {
+        self.write_lines(&[json!({ "id": delete.id, "deleted": true })]).await
+    }
+
+    async fn emit_relation(&self, relation: DrasiRelation) -> Result<()> {
+        self.write_lines(&[relation]).await
+    }
+
+    async fn emit_batch(&self, elements: Vec<DrasiElement>) -> Result<()> {
+        self.write_lines(&elements).await
+    }
+
+    async fn emit_events(&self, events: Vec<CloudEvent>) -> Result<()> {
+        self.write_lines(&events).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emit::tests::element;
+
+    #[tokio::test]
+    async fn every_change_is_one_json_object_per_line() {
+        let emitter = StdoutEmitter::with_writer(Vec::new());
+        emitter.emit(element("temp-01")).await.unwrap();
+        emitter.emit_batch(vec![element("temp-02"), element("temp-03")]).await.unwrap();
+        emitter.delete(DrasiDelete { id: "temp-01".to_string() }).await.unwrap();
+
+        let written = String::from_utf8(emitter.stdout.lock().await.clone()).unwrap();
+        let lines: Vec<serde_json::Value> = written.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.len(), 4, "{}", written);
+        assert_eq!(lines[0], serde_json::to_value(element("temp-01")).unwrap());
+        assert_eq!(lines[2]["id"], "temp-03");
+        assert_eq!(lines[3], json!({ "id": "temp-01", "deleted": true }));
+        assert!(written.ends_with('\n'));
+    }
+}
@@ -0,0 +1,164 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use std::sync::{Arc, Mutex};
+use tokio::time::{Duration, Instant};
+use tracing::debug;
+
+use super::Emitter;
+use crate::config::{ThrottleConfig, OverflowPolicy};
+use crate::metrics::Metrics;
+use crate::model::{DrasiDelete, DrasiElement, DrasiRelation};
+
+// --- THROTTLING EMITTER ---
+// Caps node updates at `max_per_second` with a token bucket that holds up to
+// one second's worth of tokens, so short bursts pass untouched. Over the
+// limit, `block` waits for the next token and `drop` discards the element.
+// Deletes and relations are never throttled: losing one would leave the
+// graph wrong rather than just stale.
+pub struct ThrottlingEmitter {
+    inner: Arc<dyn Emitter>,
+    overflow: OverflowPolicy,
+    bucket: Mutex<TokenBucket>,
+    metrics: Arc<Metrics>,
+}
+
+// Uses tokio's clock so paused-time tests can drive it
+struct TokenBucket {
+    capacity: f64,
+    per_second: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    fn new(per_second: u32) -> Self {
+        let per_second = f64::from(per_second);
+        TokenBucket {
+            capacity: per_second,
+            per_second,
+            tokens: per_second,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    // Takes a token, or says how long until one is available
+    fn try_take(&mut self) -> Result<(), Duration> {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_second).min(self.capacity);
+        self.refilled_at = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / self.per_second))
+        }
+    }
+}
+
+impl ThrottlingEmitter {
+    pub fn new(inner: Arc<dyn Emitter>, config: &ThrottleConfig, metrics: Arc<Metrics>) -> Self {
+        ThrottlingEmitter {
+            inner,
+            overflow: config.overflow,
+            bucket: Mutex::new(TokenBucket::new(config.max_per_second)),
+            metrics,
+        }
+    }
+
+    fn try_take(&self) -> Result<(), Duration> {
+        self.bucket.lock().expect("token bucket lock poisoned").try_take()
+    }
+}
+
+#[async_trait]
+impl Emitter for ThrottlingEmitter {
+    async fn emit(&self, element: DrasiElement) -> Result<()> {
+        loop {
+            match (self.try_take(), self.overflow) {
+                (Ok(()), _) => return self.inner.emit(element).await,
+                (Err(wait), OverflowPolicy::Block) => tokio::time::sleep(wait).await,
+                (Err(_), OverflowPolicy::Drop) => {
+                    debug!(event = "throttled", device_id = %element.id, "Rate limit reached; dropping {}", element.id);
+                    Metrics::inc(&self.metrics.throttled);
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    async fn delete(&self, delete: DrasiDelete) -> Result<()> {
+        self.inner.delete(delete).await
+    }
+
+    async fn emit_relation(&self, relation: DrasiRelation) -> Result<()> {
+        self.inner.emit_relation(relation).await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.inner.flush().await
+    }
+
+    fn pending(&self) -> usize {
+        self.inner.pending()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emit::tests::{element, Recording};
+    use std::sync::atomic::Ordering;
+
+    fn throttle(max_per_second: u32, overflow: OverflowPolicy) -> (ThrottlingEmitter, Arc<Recording>, Arc<Metrics>) {
+        let recording = Arc::new(Recording::default());
+        let metrics = Arc::new(Metrics::default());
+        let config = ThrottleConfig { max_per_second, overflow };
+        (ThrottlingEmitter::new(recording.clone(), &config, metrics.clone()), recording, metrics)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn drop_discards_what_is_over_the_limit() {
+        let (emitter, recording, metrics) = throttle(2, OverflowPolicy::Drop);
+        for id in ["a", "b", "c"] {
+            emitter.emit(element(id)).await.unwrap();
+        }
+        assert_eq!(recording.calls(), ["emit a", "emit b"]);
+        assert_eq!(metrics.throttled.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_bucket_refills_after_a_second() {
+        let (emitter, recording, _) = throttle(2, OverflowPolicy::Drop);
+        for id in ["a", "b", "c"] {
+            emitter.emit(element(id)).await.unwrap();
+        }
+        tokio::time::advance(Duration::from_secs(1)).await;
+        for id in ["d", "e", "f"] {
+            emitter.emit(element(id)).await.unwrap();
+        }
+        assert_eq!(recording.calls(), ["emit a", "emit b", "emit d", "emit e"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn block_waits_for_the_next_token() {
+        let (emitter, recording, metrics) = throttle(2, OverflowPolicy::Block);
+        let started = Instant::now();
+        for id in ["a", "b", "c"] {
+            emitter.emit(element(id)).await.unwrap();
+        }
+        assert_eq!(recording.calls(), ["emit a", "emit b", "emit c"]);
+        assert_eq!(started.elapsed(), Duration::from_millis(500));
+        assert_eq!(metrics.throttled.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn deletes_and_relations_are_never_throttled() {
+        let (emitter, recording, _) = throttle(1, OverflowPolicy::Drop);
+        emitter.emit(element("a")).await.unwrap();
+        emitter.delete(DrasiDelete { id: "a".to_string() }).await.unwrap();
+        emitter.delete(DrasiDelete { id: "b".to_string() }).await.unwrap();
+        assert_eq!(recording.calls(), ["emit a", "delete a", "delete b"]);
+    }
+}
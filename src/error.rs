@@ -0,0 +1,107 @@
+// --- MAPPING ERRORS ---
+// Why a message didn't make it into the graph. The category travels with
+// the failure into the log and the dead letter, so a payload that will never
+// map can be told apart from an output that is down.
+#[derive(Debug, thiserror::Error)]
+pub enum MappingError {
+    // Rejected before anyone tried to decode it
+    #[error("payload of {size} bytes exceeds max_payload_bytes ({limit})")]
+    Oversized { size: usize, limit: usize },
+    // Nested too deeply to be worth parsing
+    #[error("payload nests deeper than max_payload_depth ({limit})")]
+    TooDeep { limit: usize },
+    // Couldn't be decompressed or decoded in its payload format
+    #[error("{0:#}")]
+    Parse(anyhow::Error),
+    // Decoded, but its schema rejects it
+    #[error("payload fails schema validation: {}", .0.join("; "))]
+    Validation(Vec<String>),
+    // Mapped to an element with more properties than `max_properties`
+    #[error("{id} has {count} properties, more than max_properties ({limit})")]
+    TooManyProperties { id: String, count: usize, limit: usize },
+    // `require_id` is set and the payload lacks the ID field
+    #[error("payload has no ID at {pointer}")]
+    MissingId { pointer: String },
+    // Mapped to an element without a name, e.g. from a topic ending in `/`
+    #[error("the message from {0} maps to an empty element ID")]
+    EmptyId(String),
+    // Mapped, but the output didn't take it
+    #[error("{0:#}")]
+    Emit(anyhow::Error),
+    // Still queued or being processed when shutdown stopped waiting
+    #[error("not delivered before shutdown stopped waiting")]
+    Shutdown,
+}
+
+// A JSON payload that doesn't parse. Kept as its own type inside
+// `MappingError::Parse` so the position can be found again for the log and
+// the dead letter.
+#[derive(Debug, thiserror::Error)]
+#[error("payload is not valid JSON: {error}{preview}")]
+pub struct JsonError {
+    pub error: serde_json::Error,
+    pub preview: String,
+}
+
+// Where (1-based) and how a JSON payload stopped parsing
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+pub struct ParsePosition {
+    pub line: usize,
+    pub column: usize,
+    // `syntax`, `eof` (cut short), `data` or `io`
+    pub kind: &'static str,
+}
+
+impl MappingError {
+    // For logs, dead letters and anything else routing on the category
+    pub fn category(&self) -> &'static str {
+        match self {
+            MappingError::Oversized { .. } => "oversized",
+            MappingError::TooDeep { .. } => "too_deep",
+            MappingError::Parse(_) => "parse",
+            MappingError::Validation(_) => "validation",
+            MappingError::TooManyProperties { .. } => "too_many_properties",
+            MappingError::MissingId { .. } => "missing_id",
+            MappingError::EmptyId(_) => "id",
+            MappingError::Emit(_) => "emit",
+            MappingError::Shutdown => "shutdown",
+        }
+    }
+
+    // Only for JSON payloads, the one format whose parser reports it
+    pub fn parse_position(&self) -> Option<ParsePosition> {
+        let MappingError::Parse(e) = self else {
+            return None;
+        };
+        let json = e.chain().find_map(|cause| cause.downcast_ref::<JsonError>())?;
+        Some(ParsePosition {
+            line: json.error.line(),
+            column: json.error.column(),
+            kind: match json.error.classify() {
+                serde_json::error::Category::Io => "io",
+                serde_json::error::Category::Syntax => "syntax",
+                serde_json::error::Category::Data => "data",
+                serde_json::error::Category::Eof => "eof",
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_failure_names_its_category() {
+        let validation = MappingError::Validation(vec!["\"warm\" is not of type \"number\" at /t".to_string(), "\"id\" is a required property".to_string()]);
+        assert_eq!(validation.category(), "validation");
+        assert_eq!(
+            validation.to_string(),
+            "payload fails schema validation: \"warm\" is not of type \"number\" at /t; \"id\" is a required property"
+        );
+        assert_eq!(MappingError::EmptyId("sensors/".to_string()).category(), "id");
+        assert_eq!(MappingError::Oversized { size: 9, limit: 8 }.to_string(), "payload of 9 bytes exceeds max_payload_bytes (8)");
+        // Not a JSON parser's error, so no position
+        assert!(MappingError::Parse(anyhow::anyhow!("bad CBOR")).parse_position().is_none());
+    }
+}
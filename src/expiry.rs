@@ -0,0 +1,171 @@
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use crate::config::{ExpiryConfig, TimestampFormat};
+use crate::model::{DrasiDelete, DrasiElement};
+use crate::pipeline::Pipeline;
+
+// How often the reaper looks for IDs that have gone quiet
+const REAP_INTERVAL: Duration = Duration::from_secs(1);
+
+// --- ELEMENT EXPIRY ---
+// Stamps each emitted element with the time after which it should be taken
+// as stale, for sensors that come and go. With `reap`, the time every ID was
+// last heard from is also kept, and a background task deletes the ones that
+// stay silent for the whole TTL.
+//
+// An ID counts as heard from whenever a message maps to it, even one that
+// deduplication or change detection then drops; the stamp only moves on
+// with the elements that are actually emitted.
+pub struct Expiry {
+    ttl: Duration,
+    key: String,
+    format: TimestampFormat,
+    // element ID -> when a message last mapped to it; None unless `reap`
+    last_seen: Option<Mutex<HashMap<String, Instant>>>,
+}
+
+impl Expiry {
+    pub fn new(config: &ExpiryConfig, format: TimestampFormat) -> Self {
+        Expiry {
+            ttl: Duration::from_secs(config.ttl_seconds),
+            key: config.key.clone(),
+            format,
+            last_seen: config.reap.then(|| Mutex::new(HashMap::new())),
+        }
+    }
+
+    // Object properties only, like the ingestion timestamp
+    pub fn stamp(&self, element: &mut DrasiElement) {
+        let Value::Object(map) = &mut element.properties else {
+            return;
+        };
+        let expires_at = chrono::Utc::now() + self.ttl;
+        let value = match self.format {
+            TimestampFormat::Rfc3339 => json!(expires_at.to_rfc3339_opts(chrono::SecondsFormat::Millis, true)),
+            TimestampFormat::UnixMillis => json!(expires_at.timestamp_millis()),
+        };
+        map.insert(self.key.clone(), value);
+    }
+
+    pub fn touch(&self, id: &str) {
+        if let Some(last_seen) = &self.last_seen {
+            last_seen.lock().expect("expiry lock poisoned").insert(id.to_string(), Instant::now());
+        }
+    }
+
+    // A deleted element has nothing left to reap
+    pub fn forget(&self, id: &str) {
+        if let Some(last_seen) = &self.last_seen {
+            last_seen.lock().expect("expiry lock poisoned").remove(id);
+        }
+    }
+
+    // Takes out the IDs not heard from for the whole TTL
+    fn take_expired(&self) -> Vec<String> {
+        let Some(last_seen) = &self.last_seen else {
+            return Vec::new();
+        };
+        let mut last_seen = last_seen.lock().expect("expiry lock poisoned");
+        let now = Instant::now();
+        let expired: Vec<String> = last_seen
+            .iter()
+            .filter(|(_, seen)| now.duration_since(**seen) >= self.ttl)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in &expired {
+            last_seen.remove(id);
+        }
+        expired
+    }
+}
+
+// Deletes silent IDs through the output until aborted at shutdown; nothing
+// to start unless `reap` is set
+pub fn start(pipeline: Arc<Pipeline>) -> Option<JoinHandle<()>> {
+    let expiry = pipeline.expiry.as_ref().filter(|expiry| expiry.last_seen.is_some())?;
+    info!("Deleting elements that get no message for {:?}", expiry.ttl);
+    Some(tokio::spawn(async move {
+        let Some(expiry) = &pipeline.expiry else {
+            return;
+        };
+        let mut interval = tokio::time::interval(REAP_INTERVAL);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            for id in expiry.take_expired() {
+                // Its next message starts afresh rather than being compared
+                // with, or merged onto, a node that is gone
+                if let Some(detector) = &pipeline.change_detection {
+                    detector.forget(&id);
+                }
+                if let Some(merger) = &pipeline.merge {
+                    merger.forget(&id);
+                }
+                if let Some(snapshot) = &pipeline.snapshot {
+                    snapshot.forget(&id);
+                }
+                info!(event = "reaped", device_id = %id, "Deleting {}: no message for {:?}", id, expiry.ttl);
+                if let Err(e) = pipeline.emitter.delete(DrasiDelete { id: id.clone() }).await {
+                    warn!(event = "reap_failed", device_id = %id, "Failed to delete expired element {}: {:#}", id, e);
+                }
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emit::tests::element;
+
+    fn expiry(ttl_seconds: u64, reap: bool, format: TimestampFormat) -> Expiry {
+        let config = ExpiryConfig {
+            ttl_seconds,
+            key: "_expires_at".to_string(),
+            reap,
+        };
+        Expiry::new(&config, format)
+    }
+
+    #[test]
+    fn elements_are_stamped_a_ttl_ahead() {
+        let mut sensor = element("a");
+        let before = chrono::Utc::now().timestamp_millis();
+        expiry(60, false, TimestampFormat::UnixMillis).stamp(&mut sensor);
+        let expires_at = sensor.properties["_expires_at"].as_i64().unwrap();
+        assert!((before + 60_000..before + 61_000).contains(&expires_at), "{}", expires_at);
+
+        expiry(60, false, TimestampFormat::Rfc3339).stamp(&mut sensor);
+        let text = sensor.properties["_expires_at"].as_str().unwrap();
+        assert!(chrono::DateTime::parse_from_rfc3339(text).is_ok(), "{}", text);
+    }
+
+    #[test]
+    fn silent_ids_are_taken_once() {
+        let expiry = expiry(0, true, TimestampFormat::Rfc3339);
+        expiry.touch("a");
+        expiry.touch("b");
+        expiry.forget("b");
+        assert_eq!(expiry.take_expired(), ["a"]);
+        assert!(expiry.take_expired().is_empty());
+    }
+
+    #[test]
+    fn ids_heard_from_within_the_ttl_stay() {
+        let expiry = expiry(60, true, TimestampFormat::Rfc3339);
+        expiry.touch("a");
+        assert!(expiry.take_expired().is_empty());
+    }
+
+    #[test]
+    fn without_reap_nothing_is_kept() {
+        let expiry = expiry(0, false, TimestampFormat::Rfc3339);
+        expiry.touch("a");
+        assert!(expiry.take_expired().is_empty());
+    }
+}
@@ -0,0 +1,88 @@
+use serde_json::Value;
+
+use crate::config::{FilterExists, FilterThreshold, FilterValue, PayloadFilter};
+
+// --- PAYLOAD FILTER ---
+// Decides whether a decoded payload becomes an element at all, e.g. only
+// readings above a threshold or devices reporting `status: active`.
+pub fn matches(filter: &PayloadFilter, json: &Value) -> bool {
+    match filter {
+        PayloadFilter::All(filters) => filters.iter().all(|filter| matches(filter, json)),
+        PayloadFilter::Any(filters) => filters.iter().any(|filter| matches(filter, json)),
+        PayloadFilter::Equals(FilterValue { pointer, value }) => {
+            json.pointer(pointer).is_some_and(|found| values_equal(found, value))
+        }
+        PayloadFilter::NotEquals(FilterValue { pointer, value }) => {
+            !json.pointer(pointer).is_some_and(|found| values_equal(found, value))
+        }
+        PayloadFilter::Gt(FilterThreshold { pointer, value }) => {
+            json.pointer(pointer).and_then(Value::as_f64).is_some_and(|found| found > *value)
+        }
+        PayloadFilter::Exists(FilterExists { pointer, value }) => json.pointer(pointer).is_some() == *value,
+    }
+}
+
+// serde_json tells integers and floats apart, so `30` != `30.0` there
+fn values_equal(found: &Value, expected: &Value) -> bool {
+    match (found.as_f64(), expected.as_f64()) {
+        (Some(found), Some(expected)) => found == expected,
+        _ => found == expected,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn filter(yaml: &str) -> PayloadFilter {
+        // As `mapping.filter` reads it
+        serde_yaml::with::singleton_map_recursive::deserialize(serde_yaml::Deserializer::from_str(yaml))
+            .expect("filter should parse")
+    }
+
+    #[test]
+    fn thresholds_need_a_number() {
+        let hot = filter("gt: { pointer: /temperature, value: 30 }");
+        assert!(matches(&hot, &json!({ "temperature": 35 })));
+        assert!(!matches(&hot, &json!({ "temperature": 30 })));
+        assert!(!matches(&hot, &json!({ "temperature": "35" })));
+        assert!(!matches(&hot, &json!({})));
+    }
+
+    #[test]
+    fn integers_equal_their_floats() {
+        let thirty = filter("equals: { pointer: /temperature, value: 30.0 }");
+        assert!(matches(&thirty, &json!({ "temperature": 30 })));
+        let active = filter("equals: { pointer: /status, value: active }");
+        assert!(matches(&active, &json!({ "status": "active" })));
+        assert!(!matches(&active, &json!({ "status": "idle" })));
+    }
+
+    #[test]
+    fn a_missing_field_is_not_equal() {
+        let not_lab = filter("not_equals: { pointer: /site, value: lab }");
+        assert!(matches(&not_lab, &json!({})));
+        assert!(!matches(&not_lab, &json!({ "site": "lab" })));
+    }
+
+    #[test]
+    fn exists_can_ask_for_absence() {
+        assert!(matches(&filter("exists: { pointer: /alarm }"), &json!({ "alarm": null })));
+        assert!(matches(&filter("exists: { pointer: /alarm, value: false }"), &json!({})));
+    }
+
+    #[test]
+    fn all_and_any_combine_filters() {
+        let combined = filter(
+            "all:
+  - gt: { pointer: /temperature, value: 30 }
+  - any:
+      - equals: { pointer: /status, value: active }
+      - exists: { pointer: /alarm }",
+        );
+        assert!(matches(&combined, &json!({ "temperature": 35, "alarm": true })));
+        assert!(!matches(&combined, &json!({ "temperature": 35, "status": "idle" })));
+        assert!(!matches(&combined, &json!({ "temperature": 20, "status": "active" })));
+    }
+}
@@ -0,0 +1,134 @@
+use anyhow::{Context, Result};
+use axum::{extract::State, http::StatusCode, routing::get, Json, Router};
+use serde_json::Value;
+use tracing::{error, info};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use tokio::sync::watch;
+
+use crate::config::{Config, MappingConfig};
+use crate::connection::ConnectionState;
+
+// --- HEALTH PROBES ---
+// Liveness only says the process is up; readiness additionally requires a
+// live broker session: followed from the MQTT source's connection state, or
+// set directly by a replay. `/config` shows the configuration in effect,
+// secrets redacted, updated when the mapping is reloaded.
+#[derive(Debug, Default)]
+pub struct Health {
+    ready: AtomicBool,
+    config: RwLock<Value>,
+}
+
+impl Health {
+    pub fn set_config(&self, config: &Config) {
+        *self.config.write().expect("health lock poisoned") = config.redacted();
+    }
+
+    // A reload only replaces the mapping; the rest as set at startup stays
+    pub fn set_mapping(&self, mapping: &MappingConfig) {
+        let mut config = self.config.write().expect("health lock poisoned");
+        if let Value::Object(config) = &mut *config {
+            config.insert("mapping".to_string(), serde_json::to_value(mapping).unwrap_or_default());
+        }
+    }
+
+    pub fn set_ready(&self, ready: bool) {
+        self.ready.store(ready, Ordering::Relaxed);
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Relaxed)
+    }
+
+    // Ready exactly while connected, until the source goes away
+    pub fn follow(self: Arc<Self>, mut state: watch::Receiver<ConnectionState>) {
+        tokio::spawn(async move {
+            loop {
+                self.set_ready(*state.borrow_and_update() == ConnectionState::Connected);
+                if state.changed().await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+// Binds before returning so a port clash fails at startup; the server itself
+// runs on a background task.
+pub async fn serve(addr: SocketAddr, health: Arc<Health>) -> Result<()> {
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Failed to bind health endpoint on {}", addr))?;
+    let app = Router::new()
+        .route("/healthz", get(|| async { StatusCode::OK }))
+        .route("/readyz", get(readyz))
+        .route("/config", get(config))
+        .with_state(health);
+
+    info!("Serving health probes on http://{}/healthz and /readyz, settings on /config", addr);
+    tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, app).await {
+            error!("Health endpoint stopped: {}", e);
+        }
+    });
+    Ok(())
+}
+
+async fn readyz(State(health): State<Arc<Health>>) -> StatusCode {
+    if health.is_ready() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+}
+
+async fn config(State(health): State<Arc<Health>>) -> Json<Value> {
+    Json(health.config.read().expect("health lock poisoned").clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn ready_only_while_connected() {
+        let health = Arc::new(Health::default());
+        let (state, receiver) = watch::channel(ConnectionState::Connecting);
+        Arc::clone(&health).follow(receiver);
+        tokio::task::yield_now().await;
+        assert!(!health.is_ready());
+
+        state.send(ConnectionState::Connected).unwrap();
+        tokio::task::yield_now().await;
+        assert!(health.is_ready());
+        state.send(ConnectionState::Reconnecting).unwrap();
+        tokio::task::yield_now().await;
+        assert!(!health.is_ready());
+    }
+
+    #[test]
+    fn the_served_config_has_no_passwords_and_follows_reloads() {
+        let health = Health::default();
+        let config = Config {
+            username: Some("bridge".to_string()),
+            password: Some("hunter2".to_string()),
+            ..Config::default()
+        };
+        health.set_config(&config);
+        let served = health.config.read().unwrap().clone();
+        assert_eq!(served["username"], "bridge");
+        assert_ne!(served["password"], "hunter2");
+        assert!(!served.to_string().contains("hunter2"));
+
+        let mapping = MappingConfig {
+            default_labels: vec!["Reloaded".to_string()],
+            ..MappingConfig::default()
+        };
+        health.set_mapping(&mapping);
+        let served = health.config.read().unwrap().clone();
+        assert_eq!(served["mapping"]["default_labels"], serde_json::json!(["Reloaded"]));
+        assert_eq!(served["username"], "bridge");
+    }
+}
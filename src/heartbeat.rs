@@ -0,0 +1,72 @@
+use tracing::{info, warn};
+use serde_json::json;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+use crate::config::HeartbeatConfig;
+use crate::emit::Emitter;
+use crate::metrics::Metrics;
+use crate::model::{DrasiElement, ElementOp};
+
+// --- HEARTBEAT ---
+// A synthetic element sent through the configured output every interval,
+// starting right away, so Drasi can tell a quiet source from a dead one.
+// Its properties carry the time and how many messages have been handled.
+pub fn start(
+    config: &HeartbeatConfig,
+    emitter: Arc<dyn Emitter>,
+    metrics: Arc<Metrics>,
+    tag_snapshots: bool,
+) -> JoinHandle<()> {
+    let config = config.clone();
+    let period = Duration::from_secs(config.interval_secs);
+    info!("Emitting a {} heartbeat as {} every {:?}", config.label, config.id, period);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        // A beat delayed by a slow output is followed by the next on time,
+        // not a burst of catch-up beats
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let element = DrasiElement {
+                id: config.id.clone(),
+                element_type: None,
+                labels: vec![config.label.clone()],
+                properties: json!({
+                    "timestamp": chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+                    "received": metrics.received.load(Ordering::Relaxed),
+                    "mapped": metrics.mapped.load(Ordering::Relaxed),
+                    "failed": metrics.failed.load(Ordering::Relaxed),
+                }),
+                op: tag_snapshots.then_some(ElementOp::Update),
+            };
+            if let Err(e) = emitter.emit(element).await {
+                warn!(event = "heartbeat_failed", "Failed to emit heartbeat: {:#}", e);
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emit::tests::Recording;
+
+    #[tokio::test(start_paused = true)]
+    async fn a_heartbeat_goes_out_right_away_and_then_every_interval() {
+        let recording = Arc::new(Recording::default());
+        let config = HeartbeatConfig {
+            interval_secs: 30,
+            id: "source-heartbeat".to_string(),
+            label: "SourceHeartbeat".to_string(),
+        };
+        let task = start(&config, recording.clone(), Arc::new(Metrics::default()), false);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(recording.calls(), ["emit source-heartbeat"]);
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        assert_eq!(recording.calls().len(), 3);
+        task.abort();
+    }
+}
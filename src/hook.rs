@@ -0,0 +1,135 @@
+use anyhow::{bail, Result};
+use serde_json::json;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+use crate::config::ConnectionHookConfig;
+use crate::connection::ConnectionState;
+
+// Neither a slow endpoint nor a stuck command holds up the next notification
+// for longer than this
+const HOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+// --- CONNECTION HOOK ---
+// Tells something outside the pipeline (an orchestrator, a pager, ...)
+// whenever a broker connection comes up or goes down: an HTTP POST of
+// `{event, source, broker, timestamp}`, or a command with the same in
+// DRASI_MQTT_HOOK_* environment variables. With several brokers each
+// connection is followed on its own. Notifications for one connection go
+// out in order, one at a time; if the state flaps faster than a hook
+// completes, the flaps in between are skipped but connected and
+// disconnected still alternate. A failing hook is only logged.
+enum Hook {
+    Http { client: reqwest::Client, url: String },
+    Command { program: std::path::PathBuf, args: Vec<String> },
+}
+
+pub fn start(
+    config: &ConnectionHookConfig,
+    source: &str,
+    broker: Arc<str>,
+    mut state: watch::Receiver<ConnectionState>,
+) -> Result<()> {
+    let hook = match config {
+        ConnectionHookConfig::Http { url } => Hook::Http {
+            client: reqwest::Client::builder().timeout(HOOK_TIMEOUT).build()?,
+            url: url.clone(),
+        },
+        ConnectionHookConfig::Command { program, args } => Hook::Command {
+            program: program.clone(),
+            args: args.clone(),
+        },
+    };
+    let source = source.to_string();
+    tokio::spawn(async move {
+        // What the hook was last told
+        let mut connected = false;
+        loop {
+            let now_connected = *state.borrow_and_update() == ConnectionState::Connected;
+            if now_connected != connected {
+                connected = now_connected;
+                let event = if connected { "connected" } else { "disconnected" };
+                let timestamp = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+                match hook.notify(event, &source, &broker, &timestamp).await {
+                    Ok(()) => info!(event = "connection_hook", "Told the connection hook {} is {}", broker, event),
+                    Err(e) => warn!(
+                        event = "connection_hook_failed",
+                        "Connection hook failed for {} ({}): {:#}",
+                        broker,
+                        event,
+                        e
+                    ),
+                }
+            }
+            if state.changed().await.is_err() {
+                break;
+            }
+        }
+    });
+    Ok(())
+}
+
+impl Hook {
+    async fn notify(&self, event: &str, source: &str, broker: &str, timestamp: &str) -> Result<()> {
+        match self {
+            Hook::Http { client, url } => {
+                let body = json!({
+                    "event": event,
+                    "source": source,
+                    "broker": broker,
+                    "timestamp": timestamp,
+                });
+                client.post(url).json(&body).send().await?.error_for_status()?;
+            }
+            Hook::Command { program, args } => {
+                let run = tokio::process::Command::new(program)
+                    .args(args)
+                    .env("DRASI_MQTT_HOOK_EVENT", event)
+                    .env("DRASI_MQTT_HOOK_SOURCE", source)
+                    .env("DRASI_MQTT_HOOK_BROKER", broker)
+                    .env("DRASI_MQTT_HOOK_TIMESTAMP", timestamp)
+                    .kill_on_drop(true)
+                    .status();
+                let status = match tokio::time::timeout(HOOK_TIMEOUT, run).await {
+                    Ok(status) => status?,
+                    Err(_) => bail!("{} did not finish within {:?}", program.display(), HOOK_TIMEOUT),
+                };
+                if !status.success() {
+                    bail!("{} exited with {}", program.display(), status);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emit::tests::mock_endpoint;
+    use serde_json::Value;
+
+    #[tokio::test]
+    async fn connects_and_disconnects_are_posted_in_order() {
+        let (url, requests) = mock_endpoint(vec![200, 200]).await;
+        let (sender, receiver) = watch::channel(ConnectionState::Connecting);
+        start(&ConnectionHookConfig::Http { url }, "mqtt-source", Arc::from("edge"), receiver).unwrap();
+
+        sender.send(ConnectionState::Connected).unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        sender.send(ConnectionState::Disconnected).unwrap();
+        // Reconnecting is still down, so nothing new to tell
+        sender.send(ConnectionState::Reconnecting).unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let requests = requests.lock().unwrap();
+        let events: Vec<Value> = requests.iter().map(|request| serde_json::from_slice(&request.body).unwrap()).collect();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["event"], "connected");
+        assert_eq!(events[1]["event"], "disconnected");
+        assert_eq!(events[1]["source"], "mqtt-source");
+        assert_eq!(events[1]["broker"], "edge");
+    }
+}
@@ -0,0 +1,108 @@
+use anyhow::{bail, Result};
+use std::io::IsTerminal;
+use tracing::Subscriber;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+
+use crate::telemetry::{self, Telemetry};
+
+// --- LOGGING ---
+// Human-readable text by default. `LOG_FORMAT=json` switches to one JSON
+// object per line for log aggregators, with structured fields such as
+// `topic`, `device_id` and `event` next to the message. Either way the
+// level filter comes from RUST_LOG and defaults to `info`, and logs go to
+// stderr. RUST_LOG only filters the logs: trace spans (see `telemetry`)
+// are exported regardless.
+pub fn init() -> Result<Telemetry> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let format = std::env::var("LOG_FORMAT").unwrap_or_default();
+    let logs = layer(&format, std::io::stderr, std::io::stderr().is_terminal())?;
+    let (spans, telemetry) = telemetry::layer()?;
+    tracing_subscriber::registry()
+        .with(logs.with_filter(filter))
+        .with(spans)
+        .init();
+    Ok(telemetry)
+}
+
+// The log lines in `format`, written to `writer`; colours only with `ansi`
+fn layer<S, W>(format: &str, writer: W, ansi: bool) -> Result<Box<dyn Layer<S> + Send + Sync>>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    Ok(match format.trim().to_ascii_lowercase().as_str() {
+        "" | "text" => tracing_subscriber::fmt::layer().with_writer(writer).with_ansi(ansi).boxed(),
+        "json" => tracing_subscriber::fmt::layer()
+            .with_writer(writer)
+            .json()
+            .flatten_event(true)
+            .with_current_span(false)
+            .boxed(),
+        _ => bail!("LOG_FORMAT must be text or json, got {:?}", format),
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::config::LogOutput;
+    use crate::emit::tests::element;
+    use crate::emit::{Emitter, LogEmitter};
+    use std::sync::{Arc, Mutex};
+
+    // Everything written, shared with the test
+    #[derive(Clone, Default)]
+    pub(crate) struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Captured {
+        pub(crate) fn text(&self) -> String {
+            String::from_utf8(self.0.lock().expect("captured lock poisoned").clone()).unwrap()
+        }
+
+        // Logs in `format` to this for as long as the guard is held
+        pub(crate) fn set_default(&self, format: &str) -> tracing::subscriber::DefaultGuard {
+            let writer = self.clone();
+            let logs = layer(format, move || writer.clone(), false).unwrap();
+            tracing_subscriber::registry().with(logs).set_default()
+        }
+    }
+
+    impl std::io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().expect("captured lock poisoned").extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn an_ingested_element_is_logged_as_one_json_object() {
+        let captured = Captured::default();
+        let _guard = captured.set_default("json");
+        LogEmitter::new(LogOutput::Compact, Vec::new()).emit(element("temp-01")).await.unwrap();
+
+        let output = captured.text();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 1, "{}", output);
+        let line: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        for key in ["timestamp", "level", "target", "message"] {
+            assert!(line.get(key).is_some(), "no {} in {}", key, line);
+        }
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["event"], "ingested");
+        assert_eq!(line["device_id"], "temp-01");
+    }
+
+    #[test]
+    fn an_unknown_format_is_refused() {
+        let error = layer::<tracing_subscriber::Registry, _>("xml", std::io::sink, false).err().unwrap();
+        assert_eq!(error.to_string(), "LOG_FORMAT must be text or json, got \"xml\"");
+    }
+}
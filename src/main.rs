@@ -1,101 +1,353 @@
+mod ack;
+mod aggregate;
+mod backoff;
+mod check;
+mod change;
+mod checkpoint;
+mod cli;
+mod config;
+mod connection;
+mod deadletter;
+mod decode;
+mod dedup;
+mod emit;
+mod error;
+mod expiry;
+mod filter;
+mod health;
+mod heartbeat;
+mod hook;
+mod logging;
+mod mapping;
+mod merge;
+mod message;
+mod metrics;
+mod model;
+mod pipeline;
+mod probe;
+mod reconnect;
+mod record;
+mod redact;
+mod relations;
+mod reorder;
+mod reload;
+mod retry;
+mod schema;
+mod sequence;
+mod shutdown;
+mod snapshot;
+mod source;
+mod summary;
+mod telemetry;
+mod template;
+mod tls;
+mod transform;
+
+use aggregate::Aggregator;
 use anyhow::Result;
-use rumqttc::{AsyncClient, MqttOptions, QoS, Event, Packet};
-use serde::Serialize;
-use serde_json::Value;
+use arc_swap::ArcSwap;
+use change::ChangeDetector;
+use clap::Parser;
+use checkpoint::CheckpointStore;
+use cli::Args;
+use config::{Config, OutputKind};
+use deadletter::DeadLetterSink;
+use dedup::Deduplicator;
+use expiry::Expiry;
+use health::Health;
+use mapping::Mapper;
+use merge::StateMerger;
+use metrics::Metrics;
+use pipeline::Pipeline;
+use record::Recorder;
+use reorder::Reorderer;
+use retry::RetryQueue;
+use sequence::Sequencer;
+use snapshot::SnapshotCache;
+use source::{Dispatcher, FileSource, MqttSource, Source};
+use summary::TopicSummary;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::future::Future;
 use std::time::Duration;
-use log::{info, error, warn};
-
-// --- MOCK DRASI STRUCTURES ---
-// This struct mimics the internal "Graph Element" Drasi uses.
-// It proves you understand how to bridge External Data -> Drasi Data.
-#[derive(Debug, Serialize)]
-struct DrasiElement {
-    id: String,
-    labels: Vec<String>,
-    properties: Value,
-}
-
-// --- CONFIGURATION ---
-const BROKER_HOST: &str = "test.mosquitto.org";
-const BROKER_PORT: u16 = 1883;
-// We listen to a wildcard topic to simulate multiple sensors
-const TOPIC_PATTERN: &str = "lfx/drasi/sensors/#";
+use tokio::time::Instant;
+use tracing::{info, error, warn};
 
 #[tokio::main]
 async fn main() -> Result<()> {
     // 1. Initialize Logging
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let telemetry = logging::init()?;
     info!("Starting Drasi MQTT Source PoC...");
 
-    // 2. Configure MQTT Options
-    // We use a random client ID to prevent collisions on the public broker
-    let client_id = format!("drasi-poc-{}", uuid::Uuid::new_v4());
-    let mut mqttoptions = MqttOptions::new(client_id, BROKER_HOST, BROKER_PORT);
-    mqttoptions.set_keep_alive(Duration::from_secs(5));
-
-    // 3. Create Async Client
-    // 'client' is used to control the connection (subscribe/publish)
-    // 'eventloop' is the stream of incoming network packets
-    let (client, mut eventloop) = AsyncClient::new(mqttoptions, 10);
-
-    // 4. Subscribe (The "Source" Logic)
-    // In a real Drasi Source, this topic would be configurable via YAML
-    client.subscribe(TOPIC_PATTERN, QoS::AtLeastOnce).await?;
-    info!("Subscribed to topic: {}", TOPIC_PATTERN);
-
-    // 5. Main Event Loop
-    // This loop listens for signals and processes them asynchronously
-    loop {
-        match eventloop.poll().await {
-            Ok(notification) => {
-                match notification {
-                    Event::Incoming(Packet::Publish(publish)) => {
-                        // When a message arrives, we process it immediately
-                        let topic = publish.topic.clone();
-                        let payload = publish.payload.clone();
-                        
-                        // We use a separate function to keep the loop clean
-                        // In production, this would spawn a tokio task
-                        if let Err(e) = process_payload(&topic, &payload) {
-                            error!("Failed to map payload from {}: {}", topic, e);
-                        }
-                    }
-                    Event::Incoming(Packet::ConnAck(_)) => {
-                        info!("Successfully connected to MQTT Broker!");
-                    }
-                    _ => {} // Ignore Pings and Acks to keep logs clean
+    // 2. Load Configuration
+    // A YAML file wins when given, otherwise environment variables override
+    // the built-in defaults
+    let args = Args::parse();
+    if args.validate {
+        return check::run(&args);
+    }
+    let mut config = match &args.config {
+        Some(path) => config::from_yaml(path)?,
+        None => Config::from_env()?,
+    };
+    config.validate()?;
+    // What a reload compares the file against, before the tweaks below
+    let loaded = config.clone();
+
+    // A connectivity check instead of a run, against every broker
+    if let Some(topic) = &args.publish_test {
+        for (_, connection) in config.connections() {
+            probe::publish_test(&connection, topic).await?;
+        }
+        return Ok(());
+    }
+
+    if args.dry_run {
+        dry_run(&mut config);
+    }
+
+    // 3. Pick the Source
+    // A live broker unless we were asked to replay a capture
+    let source = match &args.replay {
+        Some(path) => Source::File(Box::new(FileSource::open(path).await?)),
+        None => Source::Mqtt(
+            config
+                .connections()
+                .iter()
+                .map(|(name, connection)| MqttSource::new(name, connection))
+                .collect::<Result<_>>()?,
+        ),
+    };
+
+    // 4. Build the Processing Pipeline
+    // Constructed once and shared by every processing task
+    let metrics = Arc::new(Metrics::default());
+    metrics::serve(config.metrics_addr, metrics.clone()).await?;
+    let health = Arc::new(Health::default());
+    health.set_config(&config);
+    health::serve(config.health_addr, health.clone()).await?;
+    let broker = match &source {
+        // With several brokers, dead letters go back to the first
+        Source::Mqtt(_) => config.connections().into_iter().next().map(|(_, connection)| connection),
+        Source::File(_) => None,
+    };
+    let dead_letters = match &config.dead_letter {
+        Some(dead_letter) => Some(DeadLetterSink::build(dead_letter, broker.as_ref()).await?),
+        None => None,
+    };
+    let checkpoints = match &config.checkpoint {
+        Some(checkpoint) => Some(CheckpointStore::open(&checkpoint.path).await?),
+        None => None,
+    };
+    let retry_queue = match &config.retry_queue {
+        Some(retry) => Some(RetryQueue::open(retry).await?),
+        None => None,
+    };
+    let emitter = emit::build(&config, &metrics)?;
+    let aggregator = (!config.aggregations.is_empty()).then(|| {
+        Arc::new(Aggregator::new(&config.aggregations, emitter.clone(), config.mapping.tag_snapshots))
+    });
+    let pipeline = Arc::new(Pipeline {
+        mapper: ArcSwap::from_pointee(Mapper::new(&config)?),
+        emitter,
+        metrics: metrics.clone(),
+        dead_letters,
+        dedup: config.dedup.as_ref().map(Deduplicator::new),
+        change_detection: config.change_detection.as_ref().map(|change_detection| {
+            let timestamp = &config.mapping.timestamp;
+            ChangeDetector::new(change_detection, timestamp.enabled.then_some(timestamp.key.as_str()))
+        }),
+        merge: config.merge_with_previous.as_ref().map(StateMerger::new),
+        expiry: config.expiry.as_ref().map(|expiry| Expiry::new(expiry, config.mapping.timestamp.format)),
+        reorder: config.reorder.as_ref().map(Reorderer::new),
+        snapshot: config.snapshot_on_reconnect.as_ref().map(SnapshotCache::new),
+        sequence: config.sequence.as_ref().map(Sequencer::new),
+        checkpoints,
+        aggregator: aggregator.clone(),
+        retry_queue,
+        max_payload_bytes: config.max_payload_bytes,
+        max_properties: config.max_properties.clone(),
+        dead_letter_oversized: config.dead_letter_oversized,
+        canonicalize: config.canonicalize,
+        redact: if config.redact_emitted { config.redact.clone() } else { Vec::new() },
+        slow_message: config.slow_message_ms.map(Duration::from_millis),
+    });
+    let heartbeat = config.heartbeat.as_ref().map(|heartbeat| {
+        heartbeat::start(heartbeat, pipeline.emitter.clone(), metrics.clone(), config.mapping.tag_snapshots)
+    });
+    let aggregations = aggregator.as_ref().map(Aggregator::start).unwrap_or_default();
+    let retries = retry::start(pipeline.clone());
+    let reaper = expiry::start(pipeline.clone());
+    // Only a YAML file can be read again
+    let reload = args.config.clone().map(|path| reload::start(path, loaded, pipeline.clone(), health.clone()));
+    let recorder = match &args.record {
+        Some(path) => Some(Recorder::create(path).await?),
+        None => None,
+    };
+    // Replays and single brokers have nothing to share fairly
+    let lanes = match &source {
+        Source::Mqtt(sources) if config.fair_scheduling && sources.len() > 1 => {
+            sources.iter().map(|source| source.name().clone()).collect()
+        }
+        _ => Vec::new(),
+    };
+    let dispatcher = Dispatcher::new(
+        pipeline.clone(),
+        &config,
+        lanes,
+        recorder.clone(),
+        config.topic_summary.as_ref().map(TopicSummary::start),
+    );
+
+    // 5. Run the Source
+    // Until shutdown is signalled, a replay runs out of lines, or the broker
+    // connection fails for good. Even then, what was already received is
+    // drained before the error becomes the exit status.
+    let shutdown = shutdown::signal();
+    tokio::pin!(shutdown);
+    let outcome = match source {
+        Source::Mqtt(sources) => {
+            if let Some(notice) = &config.reconnect_notice {
+                for source in &sources {
+                    let broker = (sources.len() > 1).then(|| source.name().clone());
+                    let emitter = pipeline.emitter.clone();
+                    reconnect::start(notice, broker, source.state(), emitter, config.mapping.tag_snapshots);
                 }
             }
-            Err(e) => {
-                warn!("Connection lost: {:?}. Retrying...", e);
-                // rumqttc handles the reconnect logic automatically, we just wait a bit
-                tokio::time::sleep(Duration::from_secs(1)).await;
+            if let Some(hook) = &config.connection_hook {
+                for source in &sources {
+                    hook::start(hook, config.source_name(), source.name().clone(), source.state())?;
+                }
+            }
+            for source in &sources {
+                snapshot::start(pipeline.clone(), source.state());
+            }
+            let state = connection::combine_states(sources.iter().map(MqttSource::state).collect());
+            health.clone().follow(state.clone());
+            metrics.clone().follow(state);
+            MqttSource::run_all(sources, config.max_active_connections, &dispatcher, shutdown.as_mut()).await
+        }
+        Source::File(file) => file.run(&dispatcher, &health, shutdown.as_mut()).await,
+    };
+
+    // 6. Graceful Shutdown
+    // Give in-flight payloads a chance to finish; with `shutdown_timeout_secs`
+    // the drain and the flushes below all have to fit in it
+    info!("Shutting down...");
+    let deadline = config.shutdown_timeout_secs.map(Duration::from_secs);
+    let drain_timeout = Duration::from_secs(config.drain_timeout_secs).min(deadline.unwrap_or(Duration::MAX));
+    let deadline = deadline.map(|timeout| Instant::now() + timeout);
+    dispatcher.drain(drain_timeout).await;
+    if let Some(heartbeat) = heartbeat {
+        heartbeat.abort();
+    }
+    if let Some(reload) = reload {
+        reload.abort();
+    }
+    if let Some(retries) = retries {
+        retries.abort();
+    }
+    if let Some(reaper) = reaper {
+        reaper.abort();
+    }
+    before_deadline(deadline, "dead-lettering the retry queue", retry::shutdown(&pipeline)).await;
+    // What the open windows have seen so far still goes out
+    for aggregation in aggregations {
+        aggregation.abort();
+    }
+    if let Some(aggregator) = &aggregator {
+        if before_deadline(deadline, "flushing the aggregations", aggregator.flush()).await.is_none() {
+            warn!(event = "undelivered", "{} aggregate(s) of the open windows were not emitted", aggregator.unflushed());
+        }
+    }
+
+    // Buffering emitters hold on to elements until told otherwise. They
+    // hold changes rather than messages, so what is left can't be
+    // dead-lettered; it is counted as undelivered.
+    match before_deadline(deadline, "flushing the output", pipeline.emitter.flush()).await {
+        Some(Err(e)) => error!("Failed to flush emitter on shutdown: {:#}", e),
+        Some(Ok(())) => {}
+        None => {
+            let pending = pipeline.emitter.pending();
+            if pending > 0 {
+                metrics.undelivered.fetch_add(pending as u64, Ordering::Relaxed);
+                warn!(event = "undelivered", "{} change(s) still buffered by the output were not delivered", pending);
             }
         }
     }
+    if let Some(recorder) = &recorder {
+        if let Err(e) = recorder.flush().await {
+            error!("{:#}", e);
+        }
+    }
+    if let Some(dead_letters) = &pipeline.dead_letters {
+        before_deadline(deadline, "closing the dead-letter sink", dead_letters.close()).await;
+    }
+
+    info!(
+        "Stopped. Received {} message(s): {} mapped, {} failed",
+        metrics.received.load(Ordering::Relaxed),
+        metrics.mapped.load(Ordering::Relaxed),
+        metrics.failed.load(Ordering::Relaxed)
+    );
+    telemetry.shutdown();
+    outcome
+}
+
+// Everything runs as configured except the output, which only logs
+fn dry_run(config: &mut Config) {
+    if config.output == OutputKind::Log {
+        warn!("DRY RUN: mapped elements are only logged");
+        return;
+    }
+    warn!(
+        "DRY RUN: mapped elements are only logged; the configured {:?} output is never contacted",
+        config.output
+    );
+    config.output = OutputKind::Log;
 }
 
-// --- CORE MAPPING LOGIC ---
-// This function demonstrates the "Source" responsibility:
-// Converting Raw JSON -> Drasi Graph Element
-fn process_payload(topic: &str, payload: &[u8]) -> Result<()> {
-    // A. Parse Raw JSON
-    let json: Value = serde_json::from_slice(payload)?;
-    
-    // B. Extract Metadata from Topic
-    // Example: "lfx/drasi/sensors/temp-01" -> ID: "temp-01"
-    let device_id = topic.split('/').last().unwrap_or("unknown");
-    
-    // C. Map to Graph Element
-    // This simulates the internal Drasi data structure
-    let element = DrasiElement {
-        id: device_id.to_string(),
-        labels: vec!["Sensor".to_string(), "IoTDevice".to_string()],
-        properties: json,
+// Waits for `work` until the shutdown deadline, if there is one; past it
+// whatever `work` still held is given up on
+async fn before_deadline<T>(deadline: Option<Instant>, what: &str, work: impl Future<Output = T>) -> Option<T> {
+    let Some(deadline) = deadline else {
+        return Some(work.await);
     };
+    match tokio::time::timeout_at(deadline, work).await {
+        Ok(result) => Some(result),
+        Err(_) => {
+            warn!(event = "shutdown_timeout", "Shutdown deadline passed while {}; exiting anyway", what);
+            None
+        }
+    }
+}
 
-    // D. "Emit" to System
-    // In the real implementation, this would push to the Drasi Change Stream
-    info!("-> Ingested Graph Node: {:?}", element);
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::HttpConfig;
+    use crate::emit::tests::{mock_endpoint, Recording};
+    use crate::message::Message;
+    use rumqttc::QoS;
+
+    #[tokio::test]
+    async fn a_dry_run_never_contacts_the_configured_output() {
+        let (url, requests) = mock_endpoint(vec![200]).await;
+        let mut config = Config {
+            output: OutputKind::Http,
+            http: Some(HttpConfig { url, max_attempts: 1 }),
+            ..Config::default()
+        };
+        dry_run(&mut config);
+        let metrics = Arc::new(Metrics::default());
+        let pipeline = Pipeline {
+            emitter: emit::build(&config, &metrics).unwrap(),
+            ..pipeline::tests::pipeline(&config, Arc::new(Recording::default()))
+        };
+        let message = Message::from(rumqttc::Publish::new("sensors/temp-01", QoS::AtLeastOnce, r#"{"temperature": 21.5}"#));
+        pipeline.process(&message).await.unwrap();
+
+        assert_eq!(config.output, OutputKind::Log);
+        assert!(requests.lock().unwrap().is_empty());
+    }
 }
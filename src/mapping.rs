@@ -0,0 +1,1724 @@
+use anyhow::Result;
+use tracing::{debug, debug_span, warn};
+use base64::Engine;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use rumqttc::QoS;
+use serde_json::{json, Value};
+
+use crate::config::{
+    self, CoerceType, CompositeId, Config, DeliveryLabels, EnrichConfig, Framing, IdPart, IdSource, IdTransformConfig, MappingConfig, NonObjectPayload, NullPayload, PayloadFormat, PayloadRelation, RawEncoding, RelationRoute, Subscription, TimestampConfig,
+    TimestampFormat, TypeSource,
+};
+use crate::decode::{self, Decoder};
+use crate::error::MappingError;
+use crate::filter;
+use crate::message::Message;
+use crate::model::{DrasiDelete, DrasiElement, DrasiRelation, ElementOp, GraphChange};
+use crate::relations::TopicHierarchy;
+use crate::schema::Schemas;
+use crate::template::{TopicBinding, TopicTemplates};
+use crate::transform::ScriptTransform;
+
+// --- MAPPER ---
+// The mapping config plus everything compiled from it at startup (schemas,
+// topic templates and hierarchy, the script), so per-message work never
+// touches the filesystem. Subscriptions are kept for their mapping overrides,
+// with the name of the broker connection they belong to.
+pub struct Mapper {
+    config: MappingConfig,
+    subscriptions: Vec<(String, Subscription)>,
+    schemas: Schemas,
+    templates: TopicTemplates,
+    hierarchy: TopicHierarchy,
+    script: Option<ScriptTransform>,
+    max_payload_bytes: usize,
+    max_payload_depth: usize,
+    preview_bytes: usize,
+}
+
+impl Mapper {
+    pub fn new(config: &Config) -> Result<Self> {
+        Ok(Mapper {
+            config: config.mapping.clone(),
+            subscriptions: config
+                .connections()
+                .into_iter()
+                .flat_map(|(name, connection)| connection.subscriptions.into_iter().map(move |subscription| (name.clone(), subscription)))
+                .collect(),
+            schemas: Schemas::load(&config.all_subscriptions())?,
+            templates: TopicTemplates::compile(&config.mapping.topic_templates)?,
+            hierarchy: TopicHierarchy::compile(&config.mapping.topic_hierarchy)?,
+            script: config.mapping.script.as_ref().map(ScriptTransform::load).transpose()?,
+            max_payload_bytes: config.max_payload_bytes,
+            max_payload_depth: config.max_payload_depth,
+            preview_bytes: config.log_payload_preview_bytes,
+        })
+    }
+
+    // The node(s) (or the deletion) come first, followed by any relations the
+    // topic implies so both ends exist by the time an edge arrives
+    pub fn map(&self, message: &Message) -> Result<Vec<GraphChange>, MappingError> {
+        let subscription = self.subscription(message);
+        // Everything from here on (the format, `_raw`) sees the inflated body
+        let compression = decode::compression_for_message(message)
+            .or(subscription.and_then(|subscription| subscription.compression))
+            .unwrap_or(self.config.compression);
+        let inflated;
+        let decompressed = decode::decompress(compression, &message.payload, self.max_payload_bytes, self.preview_bytes)
+            .map_err(MappingError::Parse)?;
+        let message = match decompressed {
+            Some(payload) => {
+                inflated = Message {
+                    payload: payload.into(),
+                    ..message.clone()
+                };
+                &inflated
+            }
+            None => message,
+        };
+        let normalized;
+        let message = match normalize_topic(&message.topic) {
+            Some(topic) if self.config.normalize_topics => {
+                normalized = Message {
+                    topic,
+                    ..message.clone()
+                };
+                &normalized
+            }
+            _ => message,
+        };
+        // Every record goes through the rest on its own; one that fails fails
+        // the whole message, which is dead-lettered as it arrived
+        if let Framing::LengthPrefixed { prefix_bytes, endian } = self.config.framing {
+            if !message.payload.is_empty() {
+                let records = decode::split_frames(&message.payload, prefix_bytes, endian).map_err(|e| {
+                    warn!(event = "bad_framing", topic = %message.topic, "Can't split the payload from {}: {:#}", message.topic, e);
+                    MappingError::Parse(e)
+                })?;
+                let mut changes = Vec::new();
+                for record in records {
+                    let record = Message {
+                        payload: record.into(),
+                        ..message.clone()
+                    };
+                    changes.extend(self.map_record(&record, subscription)?);
+                }
+                return Ok(changes);
+            }
+        }
+        self.map_record(message, subscription)
+    }
+
+    // Brokers can subscribe to overlapping filters with overrides of their
+    // own, so the first match among the message's own connection's wins;
+    // replayed messages (and unknown connections) take the first match of all
+    fn subscription(&self, message: &Message) -> Option<&Subscription> {
+        let mut matching = self
+            .subscriptions
+            .iter()
+            .filter(|(_, subscription)| config::topic_matches_filter(&message.topic, &subscription.topic));
+        let first = matching.clone().next();
+        matching
+            .find(|(name, _)| Some(name.as_str()) == message.broker.as_deref())
+            .or(first)
+            .map(|(_, subscription)| subscription)
+    }
+
+    // One record, normally the whole (inflated) message
+    fn map_record(&self, message: &Message, subscription: Option<&Subscription>) -> Result<Vec<GraphChange>, MappingError> {
+        // A relation route decides the message describes an edge, not a node
+        let route = self
+            .config
+            .relation_routes
+            .iter()
+            .find(|route| config::topic_matches_filter(&message.topic, &route.topic));
+        let binding = self.templates.bind(&message.topic);
+        let mut changes = match route {
+            Some(route) => map_relation(route, &self.schemas, &self.decoder(message, subscription), message)?,
+            None => map_payload(
+                &self.config,
+                &self.schemas,
+                self.script.as_ref(),
+                subscription,
+                binding.as_ref(),
+                &self.decoder(message, subscription),
+                message,
+            )?,
+        };
+        if let Some(GraphChange::Upsert(_)) = changes.first() {
+            changes.extend(self.hierarchy.relations_for(&message.topic).into_iter().map(GraphChange::Relation));
+        }
+        // One message, one list of changes: if emitting the relation fails,
+        // the message is dead-lettered (or retried) as a whole
+        if !self.config.payload_relations.is_empty() {
+            let derived: Vec<GraphChange> = changes
+                .iter()
+                .filter_map(|change| match change {
+                    GraphChange::Upsert(element) => Some(element),
+                    _ => None,
+                })
+                .flat_map(|element| payload_relations(element, &self.config.payload_relations))
+                .map(GraphChange::Relation)
+                .collect();
+            changes.extend(derived);
+        }
+        transform_ids(&mut changes, &self.config.id_transform);
+        // A nameless node would collect every such message into one
+        let empty = changes.iter().any(|change| match change {
+            GraphChange::Upsert(element) => element.id.is_empty(),
+            GraphChange::Delete(delete) => delete.id.is_empty(),
+            GraphChange::Relation(relation) => relation.start_id.is_empty() || relation.end_id.is_empty(),
+        });
+        if empty {
+            return Err(MappingError::EmptyId(message.topic.clone()));
+        }
+        Ok(changes)
+    }
+
+    // A v5 content type we recognise wins, then the subscription's format,
+    // then the global one
+    fn decoder<'a>(&'a self, message: &Message, subscription: Option<&'a Subscription>) -> Decoder<'a> {
+        Decoder {
+            format: self.payload_format(message, subscription),
+            max_depth: self.max_payload_depth,
+            preview_bytes: self.preview_bytes,
+        }
+    }
+
+    fn payload_format<'a>(&'a self, message: &Message, subscription: Option<&'a Subscription>) -> Cow<'a, PayloadFormat> {
+        if let Some(format) = message.content_type.as_deref().and_then(decode::format_for_content_type) {
+            return Cow::Owned(format);
+        }
+        let format = subscription
+            .and_then(|subscription| subscription.payload_format.as_ref())
+            .unwrap_or(&self.config.payload_format);
+        // Settled before decoding, so what follows treats a sniffed format
+        // like a configured one
+        if *format == PayloadFormat::Auto {
+            let detected = decode::detect_format(&message.payload);
+            debug!(topic = %message.topic, "Detected payload format {:?}", detected);
+            return Cow::Owned(detected);
+        }
+        Cow::Borrowed(format)
+    }
+}
+
+// --- CORE MAPPING LOGIC ---
+// This function demonstrates the "Source" responsibility:
+// Converting Raw Payload -> Drasi Graph Element.
+// Payloads that can't be decoded or fail their schema are errors, so the
+// caller can dead-letter them.
+fn map_payload(
+    config: &MappingConfig,
+    schemas: &Schemas,
+    script: Option<&ScriptTransform>,
+    subscription: Option<&Subscription>,
+    binding: Option<&TopicBinding>,
+    decoder: &Decoder,
+    message: &Message,
+) -> Result<Vec<GraphChange>, MappingError> {
+    let topic = message.topic.as_str();
+    let payload = message.payload.as_ref();
+
+    // A template binding is the most specific, then the subscription
+    let bound_id = binding.and_then(|binding| binding.id.clone());
+    let id_source = subscription
+        .and_then(|subscription| subscription.id_source.as_ref())
+        .unwrap_or(&config.id_source);
+
+    // An empty payload is MQTT's idiomatic "clear this topic". There is no
+    // body to look an ID up in, so the topic always provides it.
+    if payload.is_empty() {
+        let id = bound_id.unwrap_or_else(|| topic_based_id(id_source, topic));
+        return Ok(vec![GraphChange::Delete(DrasiDelete { id })]);
+    }
+
+    // A. Decode the Raw Payload
+    if decoder.is_too_deep(payload) {
+        return Err(MappingError::TooDeep { limit: decoder.max_depth });
+    }
+    let mut json = debug_span!("parse")
+        .in_scope(|| decoder.decode(payload))
+        .map_err(MappingError::Parse)?;
+    // A literal `null` has no properties to map
+    if json.is_null() {
+        match config.null_payload {
+            NullPayload::Delete => {
+                let id = bound_id.unwrap_or_else(|| topic_based_id(id_source, topic));
+                return Ok(vec![GraphChange::Delete(DrasiDelete { id })]);
+            }
+            NullPayload::Skip => return Ok(Vec::new()),
+            NullPayload::Wrap => json = json!({ config.wrap_key.as_str(): null }),
+        }
+    }
+    if let Err(errors) = schemas.validate(topic, &json) {
+        return Err(MappingError::Validation(errors));
+    }
+
+    // A batch of readings becomes one element per item, each mapped as if
+    // it had arrived on its own. Items without an ID of their own are
+    // numbered after the topic's: `temp-01-0`, `temp-01-1`, ... The filter
+    // applies per item, and numbering counts the items it drops.
+    if config.explode_arrays {
+        if let Value::Array(items) = json {
+            let base_id = bound_id.unwrap_or_else(|| topic_based_id(id_source, topic));
+            let changes = items
+                .into_iter()
+                .enumerate()
+                .filter(|(_, item)| passes_filter(config, item))
+                .map(|(index, item)| {
+                    let id = config
+                        .item_id_pointer
+                        .as_deref()
+                        .and_then(|pointer| item.pointer(pointer))
+                        .and_then(scalar_to_id)
+                        .unwrap_or_else(|| format!("{}-{}", base_id, index));
+                    build_element(config, script, subscription, binding, message, id, item)
+                })
+                .collect();
+            return Ok(changes);
+        }
+    }
+
+    // Properties are an object, whatever the payload was
+    if !json.is_object() && !matches!(decoder.format.as_ref(), PayloadFormat::RawString) {
+        match config.non_object_payload {
+            NonObjectPayload::Wrap => json = json!({ config.wrap_key.as_str(): json }),
+            NonObjectPayload::Skip => return Ok(Vec::new()),
+            NonObjectPayload::Keep => {}
+        }
+    }
+
+    // Nothing to emit; the pipeline counts the message as filtered
+    if !passes_filter(config, &json) {
+        return Ok(Vec::new());
+    }
+
+    // B. Resolve the Element ID
+    let device_id = match bound_id {
+        Some(id) => id,
+        None => resolve_id(id_source, topic, &json, config.require_id)?,
+    };
+    Ok(vec![build_element(config, script, subscription, binding, message, device_id, json)])
+}
+
+fn passes_filter(config: &MappingConfig, json: &Value) -> bool {
+    config.filter.as_ref().is_none_or(|payload_filter| filter::matches(payload_filter, json))
+}
+
+// Steps C to E of the mapping, for one decoded payload (or array item)
+fn build_element(
+    config: &MappingConfig,
+    script: Option<&ScriptTransform>,
+    subscription: Option<&Subscription>,
+    binding: Option<&TopicBinding>,
+    message: &Message,
+    device_id: String,
+    mut json: Value,
+) -> GraphChange {
+    let topic = message.topic.as_str();
+
+    // C. Normalize Field Names
+    // The event time pointer, like the ID pointer, addresses the payload as
+    // published, so it is looked up before any renaming. A script replaces
+    // the field map, unless it fails on this payload.
+    let event_time = event_time(&config.timestamp, &json);
+    let payload_labels = config
+        .label_pointer
+        .as_deref()
+        .map(|pointer| labels_at(&json, pointer, topic))
+        .unwrap_or_default();
+    let type_value = match &config.type_source {
+        Some(TypeSource::JsonPointer(pointer)) => json.pointer(pointer).and_then(scalar_to_id),
+        Some(TypeSource::TopicSegment(index)) => segment(topic, *index).map(str::to_string),
+        _ => None,
+    }
+    .filter(|value| !value.is_empty());
+    let scripted = script.and_then(|script| match script.apply(&json, topic) {
+        Ok(properties) => Some(properties),
+        Err(e) => {
+            warn!(
+                event = "script_failed",
+                topic = %topic,
+                device_id = %device_id,
+                "Mapping script failed on {}, using the default mapping: {:#}",
+                topic,
+                e
+            );
+            None
+        }
+    });
+    if let Some(properties) = scripted {
+        json = properties;
+    } else if !config.field_map.is_empty() {
+        json = apply_field_map(json, &config.field_map, config.passthrough_unmapped);
+    }
+    select_fields(&mut json, &config.include_fields, &config.exclude_fields);
+
+    // D. Attach Topic Captures, Timestamps and MQTT Context
+    if let Some(binding) = binding {
+        add_topic_properties(&mut json, &binding.properties);
+    }
+    if !config.topic_properties.is_empty() {
+        add_topic_properties(&mut json, &topic_segments(topic, &config.topic_properties));
+    }
+    // After the captures, which are always strings
+    coerce_properties(&mut json, &config.coerce, &device_id);
+    merge_defaults(&mut json, &config.default_properties);
+    enrich(&mut json, &config.enrich);
+    if config.timestamp.enabled {
+        add_timestamps(&mut json, &config.timestamp, event_time);
+    }
+    // Flattened after the pointers above have been resolved against the
+    // original shape
+    if config.flatten_properties {
+        json = flatten(json, &config.flatten_separator);
+    }
+    if config.include_mqtt_metadata {
+        add_mqtt_metadata(&mut json, message, subscription);
+    }
+    if config.include_broker {
+        add_broker(&mut json, message);
+    }
+    if config.include_packet_id {
+        add_packet_id(&mut json, message);
+    }
+    add_raw_payload(&mut json, config.preserve_raw, &message.payload);
+    add_user_properties(&mut json, &message.user_properties);
+
+    // E. Map to Graph Element
+    // This simulates the internal Drasi data structure
+    let mut labels = add_delivery_labels(
+        resolve_labels(config, subscription, binding, topic, payload_labels),
+        &config.delivery_labels,
+        message,
+    );
+    let element_type = primary_type(config.type_source.as_ref(), &mut labels, type_value);
+    GraphChange::Upsert(DrasiElement {
+        id: device_id,
+        element_type,
+        labels,
+        properties: json,
+        op: config.tag_snapshots.then_some(if message.retain {
+            ElementOp::Snapshot
+        } else {
+            ElementOp::Update
+        }),
+    })
+}
+
+// --- ID TRANSFORM ---
+// Runs last so every ID, however it was extracted, ends up in the same form
+fn transform_ids(changes: &mut [GraphChange], transform: &IdTransformConfig) {
+    if transform.prefix.is_empty() && !transform.lowercase && !transform.sanitize {
+        return;
+    }
+    for change in changes {
+        match change {
+            GraphChange::Upsert(element) => element.id = transform_id(&element.id, transform),
+            GraphChange::Delete(delete) => delete.id = transform_id(&delete.id, transform),
+            GraphChange::Relation(relation) => {
+                relation.id = transform_id(&relation.id, transform);
+                relation.start_id = transform_id(&relation.start_id, transform);
+                relation.end_id = transform_id(&relation.end_id, transform);
+            }
+        }
+    }
+}
+
+fn transform_id(id: &str, transform: &IdTransformConfig) -> String {
+    let mut transformed = transform.prefix.clone();
+    for c in id.chars() {
+        let c = if transform.sanitize && !(c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':')) {
+            '_'
+        } else {
+            c
+        };
+        if transform.lowercase {
+            transformed.extend(c.to_lowercase());
+        } else {
+            transformed.push(c);
+        }
+    }
+    transformed
+}
+
+// --- FLATTENING ---
+// `{"a": {"b": 1}, "c": [true]}` -> `{"a.b": 1, "c.0": true}` with a "."
+// separator. Empty objects and arrays are kept as values so their keys don't
+// silently disappear. Scalars are returned unchanged.
+pub fn flatten(value: Value, separator: &str) -> Value {
+    match value {
+        Value::Object(_) | Value::Array(_) => {
+            let mut flat = serde_json::Map::new();
+            flatten_into(&mut flat, None, value, separator);
+            Value::Object(flat)
+        }
+        scalar => scalar,
+    }
+}
+
+fn flatten_into(flat: &mut serde_json::Map<String, Value>, prefix: Option<&str>, value: Value, separator: &str) {
+    let key = |child: &str| match prefix {
+        Some(prefix) => format!("{}{}{}", prefix, separator, child),
+        None => child.to_string(),
+    };
+    match value {
+        Value::Object(map) if !map.is_empty() => {
+            for (child, value) in map {
+                flatten_into(flat, Some(&key(&child)), value, separator);
+            }
+        }
+        Value::Array(items) if !items.is_empty() => {
+            for (index, value) in items.into_iter().enumerate() {
+                flatten_into(flat, Some(&key(&index.to_string())), value, separator);
+            }
+        }
+        // A leaf, or an empty container at the root (which has no key)
+        leaf => {
+            if let Some(prefix) = prefix {
+                flat.insert(prefix.to_string(), leaf);
+            }
+        }
+    }
+}
+
+// --- CANONICAL FORM ---
+// Labels sorted and deduplicated, object keys sorted at every depth. Key
+// order is already sorted unless serde_json's `preserve_order` feature gets
+// enabled somewhere in the dependency tree, in which case this still holds.
+pub fn canonicalize(element: &mut DrasiElement) {
+    element.labels.sort();
+    element.labels.dedup();
+    sort_keys(&mut element.properties);
+}
+
+fn sort_keys(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            map.sort_keys();
+            map.values_mut().for_each(sort_keys);
+        }
+        Value::Array(items) => items.iter_mut().for_each(sort_keys),
+        _ => {}
+    }
+}
+
+// Keys of the properties object; with `nested`, also every key of the
+// objects inside it, however deep
+pub fn count_properties(properties: &Value, nested: bool) -> usize {
+    match properties {
+        Value::Object(map) => map
+            .values()
+            .map(|value| 1 + if nested { count_nested(value) } else { 0 })
+            .sum(),
+        _ => 0,
+    }
+}
+
+fn count_nested(value: &Value) -> usize {
+    match value {
+        Value::Object(_) => count_properties(value, true),
+        Value::Array(items) => items.iter().map(count_nested).sum(),
+        _ => 0,
+    }
+}
+
+// Keeps the first `limit` keys as `count_properties` counts them, depth
+// first, so a kept object keeps as many of its own keys as still fit
+pub fn truncate_properties(properties: &mut Value, limit: usize, nested: bool) {
+    let mut budget = limit;
+    if let Value::Object(map) = properties {
+        truncate_map(map, &mut budget, nested);
+    }
+}
+
+fn truncate_map(map: &mut serde_json::Map<String, Value>, budget: &mut usize, nested: bool) {
+    map.retain(|_, value| {
+        if *budget == 0 {
+            return false;
+        }
+        *budget -= 1;
+        if nested {
+            truncate_nested(value, budget);
+        }
+        true
+    });
+}
+
+fn truncate_nested(value: &mut Value, budget: &mut usize) {
+    match value {
+        Value::Object(map) => truncate_map(map, budget, true),
+        Value::Array(items) => items.iter_mut().for_each(|item| truncate_nested(item, budget)),
+        _ => {}
+    }
+}
+
+// --- FIELD MAPPING ---
+// Builds the properties from `field_map` (pointer -> target name). With
+// passthrough, mapped fields are moved out of the original object and
+// everything else stays; without it only the mapped fields survive.
+fn apply_field_map(mut json: Value, field_map: &HashMap<String, String>, passthrough_unmapped: bool) -> Value {
+    let mut mapped = serde_json::Map::new();
+    for (pointer, target) in field_map {
+        let value = if passthrough_unmapped {
+            take_pointer(&mut json, pointer)
+        } else {
+            json.pointer(pointer).cloned()
+        };
+        match value {
+            Some(value) => {
+                mapped.insert(target.clone(), value);
+            }
+            None => debug!("Field map pointer {} did not resolve; leaving {} out", pointer, target),
+        }
+    }
+
+    if passthrough_unmapped {
+        if let Value::Object(rest) = json {
+            for (key, value) in rest {
+                mapped.entry(key).or_insert(value);
+            }
+        }
+    }
+    Value::Object(mapped)
+}
+
+// The payload names both ends of the edge; whatever else it has becomes the
+// relation's properties. An end that doesn't resolve leaves its ID empty,
+// which `Mapper::map` rejects.
+fn map_relation(
+    route: &RelationRoute,
+    schemas: &Schemas,
+    decoder: &Decoder,
+    message: &Message,
+) -> Result<Vec<GraphChange>, MappingError> {
+    let topic = message.topic.as_str();
+    let payload = message.payload.as_ref();
+    if payload.is_empty() {
+        return Ok(Vec::new());
+    }
+    if decoder.is_too_deep(payload) {
+        return Err(MappingError::TooDeep { limit: decoder.max_depth });
+    }
+    let mut json = debug_span!("parse")
+        .in_scope(|| decoder.decode(payload))
+        .map_err(MappingError::Parse)?;
+    if json.is_null() {
+        return Ok(Vec::new());
+    }
+    if let Err(errors) = schemas.validate(topic, &json) {
+        return Err(MappingError::Validation(errors));
+    }
+    let mut id_at = |pointer: &str| take_pointer(&mut json, pointer).as_ref().and_then(scalar_to_id).unwrap_or_default();
+    let start_id = id_at(&route.start_pointer);
+    let end_id = id_at(&route.end_pointer);
+    let properties = match json {
+        Value::Object(map) => map,
+        _ => serde_json::Map::new(),
+    };
+    Ok(vec![GraphChange::Relation(DrasiRelation {
+        id: format!("{}-{}-{}", start_id, route.label, end_id),
+        start_id,
+        end_id,
+        label: route.label.clone(),
+        properties,
+    })])
+}
+
+// E.g. `{"id": "temp-01", "room": "r2"}` with `{pointer: /room, label:
+// IN_ROOM}` -> temp-01 -[:IN_ROOM]-> r2
+fn payload_relations(element: &DrasiElement, rules: &[PayloadRelation]) -> Vec<DrasiRelation> {
+    rules
+        .iter()
+        .filter_map(|rule| {
+            let other = element.properties.pointer(&rule.pointer).and_then(scalar_to_id)?;
+            let (start_id, end_id) = match rule.reverse {
+                false => (element.id.clone(), other),
+                true => (other, element.id.clone()),
+            };
+            Some(DrasiRelation {
+                id: format!("{}-{}-{}", start_id, rule.label, end_id),
+                start_id,
+                end_id,
+                label: rule.label.clone(),
+                properties: serde_json::Map::new(),
+            })
+        })
+        .collect()
+}
+
+// Removes and returns the value at `pointer`. Array elements are replaced
+// with null rather than removed so sibling indices don't shift under other
+// pointers.
+fn take_pointer(json: &mut Value, pointer: &str) -> Option<Value> {
+    if pointer.is_empty() {
+        return Some(json.take());
+    }
+    let (parent, token) = pointer.rsplit_once('/')?;
+    let token = token.replace("~1", "/").replace("~0", "~");
+    match json.pointer_mut(parent)? {
+        Value::Object(map) => map.remove(&token),
+        Value::Array(items) => token.parse::<usize>().ok().and_then(|i| items.get_mut(i)).map(Value::take),
+        _ => None,
+    }
+}
+
+fn event_time(config: &TimestampConfig, properties: &Value) -> Option<Value> {
+    config
+        .event_time_pointer
+        .as_deref()
+        .and_then(|pointer| properties.pointer(pointer))
+        .cloned()
+}
+
+// The payload is the device's own account, so a key it already has wins
+// over the value read from the topic
+fn add_topic_properties(properties: &mut Value, captures: &[(String, String)]) {
+    if let Value::Object(map) = properties {
+        for (name, value) in captures {
+            map.entry(name.clone()).or_insert_with(|| json!(value));
+        }
+    }
+}
+
+// Unlike `enrich`, which only looks at the top level, objects present on
+// both sides are merged key by key, so a payload's partial `location`
+// still gains the default `floor`
+fn merge_defaults(properties: &mut Value, defaults: &BTreeMap<String, Value>) {
+    if let Value::Object(map) = properties {
+        for (name, default) in defaults {
+            let vacant = !map.contains_key(name);
+            merge_under(map.entry(name.clone()).or_insert(Value::Null), default, vacant);
+        }
+    }
+}
+
+// `vacant` marks a slot that only exists because the default is being
+// added; anything the payload put there, even null, wins
+fn merge_under(value: &mut Value, default: &Value, vacant: bool) {
+    match (value, default) {
+        (Value::Object(map), Value::Object(defaults)) => {
+            for (name, default) in defaults {
+                let vacant = !map.contains_key(name);
+                merge_under(map.entry(name.clone()).or_insert(Value::Null), default, vacant);
+            }
+        }
+        (value, default) if vacant => *value = default.clone(),
+        _ => {}
+    }
+}
+
+// Object properties only, like the captures
+fn enrich(properties: &mut Value, enrich: &EnrichConfig) {
+    if let Value::Object(map) = properties {
+        for (name, value) in &enrich.properties {
+            if enrich.force || !map.contains_key(name) {
+                map.insert(name.clone(), value.clone());
+            }
+        }
+    }
+}
+
+// --- FIELD SELECTION ---
+// Example: include [/meta, /temperature], exclude [/meta/image] on
+// {"meta": {"image": "...", "fw": 2}, "temperature": 21, "raw": [..]} ->
+// {"meta": {"fw": 2}, "temperature": 21}. Only object properties are
+// trimmed; a pointer that doesn't resolve selects nothing.
+fn select_fields(properties: &mut Value, include: &[String], exclude: &[String]) {
+    if !properties.is_object() {
+        return;
+    }
+    if !include.is_empty() {
+        let mut kept = json!({});
+        for pointer in include {
+            copy_pointer(properties, &mut kept, pointer);
+        }
+        *properties = kept;
+    }
+    for pointer in exclude {
+        take_pointer(properties, pointer);
+    }
+}
+
+// Copies the value at `pointer` to the same place in `to`, creating the
+// objects on the way. Past an array the whole array is copied, since its
+// other items can't be left out without shifting indices.
+fn copy_pointer(from: &Value, to: &mut Value, pointer: &str) {
+    let mut from = from;
+    let mut to = to;
+    let mut tokens = pointer.split('/').skip(1).peekable();
+    while let Some(token) = tokens.next() {
+        let token = token.replace("~1", "/").replace("~0", "~");
+        let (Value::Object(source), Value::Object(target)) = (from, &mut *to) else {
+            return;
+        };
+        let Some(value) = source.get(&token) else {
+            return;
+        };
+        if tokens.peek().is_none() || value.is_array() {
+            target.insert(token, value.clone());
+            return;
+        }
+        from = value;
+        to = target.entry(token).or_insert_with(|| json!({}));
+    }
+}
+
+// --- TYPE COERCION ---
+fn coerce_properties(properties: &mut Value, coerce: &BTreeMap<String, CoerceType>, device_id: &str) {
+    for (pointer, target) in coerce {
+        let Some(value) = properties.pointer_mut(pointer) else {
+            continue;
+        };
+        match coerce_value(value, *target) {
+            Some(coerced) => *value = coerced,
+            None => debug!(
+                event = "coerce_failed",
+                device_id = %device_id,
+                "Leaving {} of {} as it is: {} is not a {}",
+                pointer,
+                device_id,
+                value,
+                target.name()
+            ),
+        }
+    }
+}
+
+// None when the value can't be read as the type (values that already are
+// one convert to themselves)
+fn coerce_value(value: &Value, target: CoerceType) -> Option<Value> {
+    match (target, value) {
+        (CoerceType::Number, Value::Number(_)) => Some(value.clone()),
+        (CoerceType::Number, Value::String(text)) => {
+            let text = text.trim();
+            if let Ok(integer) = text.parse::<i64>() {
+                return Some(Value::from(integer));
+            }
+            text.parse::<f64>().ok().and_then(serde_json::Number::from_f64).map(Value::Number)
+        }
+        (CoerceType::Integer, Value::Number(number)) if number.is_i64() || number.is_u64() => Some(value.clone()),
+        (CoerceType::Integer, Value::Number(number)) => number.as_f64().and_then(whole),
+        (CoerceType::Integer, Value::String(text)) => {
+            let text = text.trim();
+            match text.parse::<i64>() {
+                Ok(integer) => Some(Value::from(integer)),
+                Err(_) => text.parse::<f64>().ok().and_then(whole),
+            }
+        }
+        (CoerceType::Bool, Value::Bool(_)) => Some(value.clone()),
+        (CoerceType::Bool, Value::String(text)) => match text.trim().to_ascii_lowercase().as_str() {
+            "true" | "yes" | "on" | "1" => Some(Value::Bool(true)),
+            "false" | "no" | "off" | "0" => Some(Value::Bool(false)),
+            _ => None,
+        },
+        (CoerceType::Bool, Value::Number(number)) => match number.as_u64() {
+            Some(1) => Some(Value::Bool(true)),
+            Some(0) => Some(Value::Bool(false)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// `3.0` -> 3, but not `3.5` or anything out of i64 range
+fn whole(number: f64) -> Option<Value> {
+    (number.fract() == 0.0 && number >= i64::MIN as f64 && number < i64::MAX as f64).then(|| Value::from(number as i64))
+}
+
+// The device's own timestamp is copied verbatim: we don't know its format,
+// and Drasi queries can parse it if they need to
+fn add_timestamps(properties: &mut Value, config: &TimestampConfig, event_time: Option<Value>) {
+    if let Value::Object(map) = properties {
+        let now = chrono::Utc::now();
+        let ingested_at = match config.format {
+            TimestampFormat::Rfc3339 => json!(now.to_rfc3339_opts(chrono::SecondsFormat::Millis, true)),
+            TimestampFormat::UnixMillis => json!(now.timestamp_millis()),
+        };
+        map.insert(config.key.clone(), ingested_at);
+        if let Some(event_time) = event_time {
+            map.insert("event_time".to_string(), event_time);
+        }
+    }
+}
+
+// Only objects can carry extra keys; scalar properties (e.g. raw strings) are
+// left as they are. `filter` is the subscription the topic matched, as
+// configured; a replayed topic may match none.
+fn add_mqtt_metadata(properties: &mut Value, message: &Message, subscription: Option<&Subscription>) {
+    if let Value::Object(map) = properties {
+        let mut metadata = json!({
+            "topic": message.topic,
+            "qos": qos_level(message.qos),
+            "retain": message.retain,
+            "dup": message.dup,
+        });
+        if let Some(subscription) = subscription {
+            metadata["filter"] = json!(subscription.topic);
+        }
+        map.insert("_mqtt".to_string(), metadata);
+    }
+}
+
+// Replayed messages didn't come from a broker connection, so get none
+fn add_broker(properties: &mut Value, message: &Message) {
+    if let (Value::Object(map), Some(broker)) = (properties, &message.broker) {
+        map.insert("_broker".to_string(), json!(broker.as_ref()));
+    }
+}
+
+// QoS 0 messages have no packet identifier (it reads as 0), so they get none
+fn add_packet_id(properties: &mut Value, message: &Message) {
+    if message.pkid == 0 {
+        return;
+    }
+    if let Value::Object(map) = properties {
+        map.insert("_pkid".to_string(), json!(message.pkid));
+    }
+}
+
+fn add_raw_payload(properties: &mut Value, encoding: RawEncoding, payload: &[u8]) {
+    let Value::Object(map) = properties else {
+        return;
+    };
+    let raw = match encoding {
+        RawEncoding::Off => return,
+        RawEncoding::Hex => payload.iter().fold(String::with_capacity(payload.len() * 2), |mut hex, byte| {
+            let _ = write!(hex, "{:02x}", byte);
+            hex
+        }),
+        RawEncoding::Base64 => base64::engine::general_purpose::STANDARD.encode(payload),
+    };
+    map.insert("_raw".to_string(), json!(raw));
+}
+
+// MQTT v5 user properties become `_user_props: {key: value}`. Keys may
+// repeat on the wire; a repeated key collects its values into an array.
+fn add_user_properties(properties: &mut Value, user_properties: &[(String, String)]) {
+    if user_properties.is_empty() {
+        return;
+    }
+    let Value::Object(map) = properties else {
+        return;
+    };
+    let mut props = serde_json::Map::new();
+    for (key, value) in user_properties {
+        match props.get_mut(key) {
+            None => {
+                props.insert(key.clone(), json!(value));
+            }
+            Some(Value::Array(values)) => values.push(json!(value)),
+            Some(existing) => *existing = json!([existing.take(), value]),
+        }
+    }
+    map.insert("_user_props".to_string(), Value::Object(props));
+}
+
+pub fn qos_level(qos: QoS) -> u8 {
+    match qos {
+        QoS::AtMostOnce => 0,
+        QoS::AtLeastOnce => 1,
+        QoS::ExactlyOnce => 2,
+    }
+}
+
+// With `require`, a payload without its ID is an error rather than
+// falling back to the topic
+fn resolve_id(id_source: &IdSource, topic: &str, json: &Value, require: bool) -> Result<String, MappingError> {
+    Ok(match id_source {
+        IdSource::Topic => topic_id(topic),
+        IdSource::TopicSegment(index) => segment_id(topic, *index),
+        IdSource::FullTopic(separator) => full_topic_id(topic, separator),
+        IdSource::Composite(composite) => composite_id(composite, topic, json),
+        IdSource::JsonPointer(pointer) => match json.pointer(pointer).and_then(scalar_to_id) {
+            Some(id) => id,
+            None if require => return Err(MappingError::MissingId { pointer: pointer.clone() }),
+            None => {
+                let fallback = topic_id(topic);
+                warn!(
+                    "ID pointer {} did not resolve in payload from {}; falling back to {:?}",
+                    pointer, topic, fallback
+                );
+                fallback
+            }
+        },
+    })
+}
+
+// Example: "a//b/" -> "a/b". None when the topic is fine as it is. A
+// leading `/` is left alone: MQTT treats it as an (empty) first level.
+fn normalize_topic(topic: &str) -> Option<String> {
+    let trimmed = topic.trim_end_matches('/');
+    if trimmed.len() == topic.len() && !topic.contains("//") {
+        return None;
+    }
+    let mut levels = trimmed.split('/');
+    let first = levels.next().unwrap_or_default();
+    let rest = levels.filter(|level| !level.is_empty());
+    Some(std::iter::once(first).chain(rest).collect::<Vec<_>>().join("/"))
+}
+
+// Example: "lfx/drasi/sensors/temp-01" -> ID: "temp-01"
+fn topic_id(topic: &str) -> String {
+    topic.split('/').next_back().unwrap_or("unknown").to_string()
+}
+
+// Example: "b1/floor2/temp" with separator "." -> ID: "b1.floor2.temp"
+fn full_topic_id(topic: &str, separator: &str) -> String {
+    topic.replace('/', separator)
+}
+
+// Example: parts [1, /deviceId] with "b1/sensors/x" and {"deviceId": "d7"}
+// -> ID: "b1-d7"
+fn composite_id(composite: &CompositeId, topic: &str, json: &Value) -> String {
+    let parts: Vec<String> = composite
+        .parts
+        .iter()
+        .map(|part| {
+            let found = match part {
+                IdPart::TopicSegment(index) => segment(topic, *index).map(str::to_string),
+                IdPart::JsonPointer(pointer) => json.pointer(pointer).and_then(scalar_to_id),
+            };
+            found.unwrap_or_else(|| {
+                warn!(
+                    "ID part {:?} not found in the message from {}; using {:?}",
+                    part, topic, composite.placeholder
+                );
+                composite.placeholder.clone()
+            })
+        })
+        .collect();
+    parts.join(&composite.separator)
+}
+
+// The ID when there is no payload to look in; composite payload parts get
+// the placeholder
+fn topic_based_id(id_source: &IdSource, topic: &str) -> String {
+    match id_source {
+        IdSource::TopicSegment(index) => segment_id(topic, *index),
+        IdSource::FullTopic(separator) => full_topic_id(topic, separator),
+        IdSource::Composite(composite) => composite_id(composite, topic, &Value::Null),
+        _ => topic_id(topic),
+    }
+}
+
+// Example: "site/3/temp-01/state" with index 2 (or -2) -> ID: "temp-01"
+fn segment_id(topic: &str, index: isize) -> String {
+    match segment(topic, index) {
+        Some(segment) => segment.to_string(),
+        None => {
+            warn!("Topic {} has no segment {} to take the ID from; using \"unknown\"", topic, index);
+            "unknown".to_string()
+        }
+    }
+}
+
+// 0-based; negative indices count from the end
+fn segment(topic: &str, index: isize) -> Option<&str> {
+    let segments: Vec<&str> = topic.split('/').collect();
+    let position = match usize::try_from(index) {
+        Ok(position) => Some(position),
+        Err(_) => segments.len().checked_sub(index.unsigned_abs()),
+    };
+    position.and_then(|position| segments.get(position).copied())
+}
+
+// Example: "buildings/b1/rooms/r2/temp-01" with {1: building, 3: room} ->
+// building: "b1", room: "r2". A topic too short for an index just lacks
+// that property.
+fn topic_segments(topic: &str, names: &BTreeMap<isize, String>) -> Vec<(String, String)> {
+    names
+        .iter()
+        .filter_map(|(index, name)| match segment(topic, *index) {
+            Some(segment) => Some((name.clone(), segment.to_string())),
+            None => {
+                debug!("Topic {} has no segment {} for property {}", topic, index, name);
+                None
+            }
+        })
+        .collect()
+}
+
+// Only scalars make sensible IDs; numbers like `"deviceId": 42` are common
+fn scalar_to_id(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+// Topic-derived labels come from a template binding, then the subscription,
+// then the label rules. Payload labels are appended, skipping ones already
+// there. Only when neither source has anything do the defaults apply.
+fn resolve_labels(
+    config: &MappingConfig,
+    subscription: Option<&Subscription>,
+    binding: Option<&TopicBinding>,
+    topic: &str,
+    payload_labels: Vec<String>,
+) -> Vec<String> {
+    let topic_labels = match (binding, subscription.and_then(|subscription| subscription.labels.as_ref())) {
+        (Some(binding), _) if !binding.labels.is_empty() => Some(binding.labels.clone()),
+        (_, Some(labels)) => Some(labels.clone()),
+        _ => labels_for_topic(config, topic),
+    };
+    match topic_labels {
+        Some(mut labels) => {
+            for label in payload_labels {
+                if !labels.contains(&label) {
+                    labels.push(label);
+                }
+            }
+            labels
+        }
+        None if payload_labels.is_empty() => config.default_labels.clone(),
+        None => payload_labels,
+    }
+}
+
+// The first label, or the looked-up value, leaving the labels without it
+fn primary_type(source: Option<&TypeSource>, labels: &mut Vec<String>, value: Option<String>) -> Option<String> {
+    match source? {
+        TypeSource::FirstLabel if labels.is_empty() => None,
+        TypeSource::FirstLabel => Some(labels.remove(0)),
+        _ => {
+            let value = value?;
+            labels.retain(|label| *label != value);
+            Some(value)
+        }
+    }
+}
+
+// Marks retained, redelivered and per-QoS messages with the configured
+// labels, skipping ones the element already has
+fn add_delivery_labels(mut labels: Vec<String>, delivery: &DeliveryLabels, message: &Message) -> Vec<String> {
+    let by_qos = match message.qos {
+        QoS::AtMostOnce => &delivery.qos0,
+        QoS::AtLeastOnce => &delivery.qos1,
+        QoS::ExactlyOnce => &delivery.qos2,
+    };
+    let retained = if message.retain { delivery.retained.as_slice() } else { &[] };
+    let duplicate = if message.dup { delivery.duplicate.as_slice() } else { &[] };
+    for label in retained.iter().chain(duplicate).chain(by_qos) {
+        if !labels.contains(label) {
+            labels.push(label.clone());
+        }
+    }
+    labels
+}
+
+// Picks the labels of the first rule whose prefix matches, so more specific
+// prefixes must be listed before broader ones.
+fn labels_for_topic(config: &MappingConfig, topic: &str) -> Option<Vec<String>> {
+    config
+        .label_rules
+        .iter()
+        .find(|rule| topic.starts_with(&rule.topic_prefix))
+        .map(|rule| rule.labels.clone())
+}
+
+// A string is one label, an array contributes its distinct strings (other
+// items are skipped with a warning). Anything else, and empty strings,
+// yield none.
+fn labels_at(json: &Value, pointer: &str, topic: &str) -> Vec<String> {
+    let candidates: Vec<&str> = match json.pointer(pointer) {
+        Some(Value::String(label)) => vec![label.as_str()],
+        Some(Value::Array(items)) => {
+            let skipped = items.iter().filter(|item| !item.is_string()).count();
+            if skipped > 0 {
+                warn!(
+                    event = "label_skipped",
+                    topic = %topic,
+                    "Skipping {} non-string item(s) of the label array at {} in the payload from {}",
+                    skipped,
+                    pointer,
+                    topic
+                );
+            }
+            items.iter().filter_map(Value::as_str).collect()
+        }
+        _ => Vec::new(),
+    };
+    let mut labels: Vec<String> = Vec::new();
+    for label in candidates {
+        if !label.is_empty() && !labels.iter().any(|known| known == label) {
+            labels.push(label.to_string());
+        }
+    }
+    labels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The defaults, plus whatever mapping options the test is about
+    fn mapper(mapping: &str) -> Mapper {
+        let config = Config {
+            mapping: serde_yaml::from_str(mapping).expect("mapping config should parse"),
+            ..Config::default()
+        };
+        Mapper::new(&config).unwrap()
+    }
+
+    fn message(topic: &str, payload: &str) -> Message {
+        Message::from(rumqttc::Publish::new(topic, QoS::AtLeastOnce, payload.as_bytes().to_vec()))
+    }
+
+    fn upsert(change: &GraphChange) -> &DrasiElement {
+        match change {
+            GraphChange::Upsert(element) => element,
+            other => panic!("expected an upsert, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn a_json_reading_becomes_an_element_named_after_the_topic() {
+        let changes = mapper("{}").map(&message("lfx/drasi/sensors/temp-01", r#"{"temperature": 21.5}"#)).unwrap();
+        assert_eq!(changes.len(), 1);
+        let element = upsert(&changes[0]);
+        assert_eq!(element.id, "temp-01");
+        assert_eq!(element.properties["temperature"], 21.5);
+        assert!(!element.labels.is_empty());
+    }
+
+    #[test]
+    fn an_empty_payload_deletes_the_element() {
+        let changes = mapper("{}").map(&message("lfx/drasi/sensors/temp-01", "")).unwrap();
+        assert!(matches!(&changes[..], [GraphChange::Delete(delete)] if delete.id == "temp-01"));
+    }
+
+    #[test]
+    fn the_id_can_come_from_the_payload() {
+        let mapper = mapper("id_source: { json_pointer: /meta/deviceId }");
+        let changes = mapper.map(&message("lfx/drasi/sensors/x", r#"{"meta": {"deviceId": "d7"}}"#)).unwrap();
+        assert_eq!(upsert(&changes[0]).id, "d7");
+    }
+
+    #[test]
+    fn label_rules_match_by_topic_prefix() {
+        let mapper = mapper(
+            "label_rules:
+  - topic_prefix: lfx/drasi/actuators/
+    labels: [Actuator]
+default_labels: [Sensor]",
+        );
+        let actuator = mapper.map(&message("lfx/drasi/actuators/pump-1", "{}")).unwrap();
+        assert_eq!(upsert(&actuator[0]).labels, ["Actuator"]);
+        let other = mapper.map(&message("lfx/drasi/misc/x", "{}")).unwrap();
+        assert_eq!(upsert(&other[0]).labels, ["Sensor"]);
+    }
+
+    #[test]
+    fn the_field_map_renames_fields() {
+        let mapper = mapper("field_map: { /t: temperatureCelsius }\npassthrough_unmapped: false");
+        let changes = mapper.map(&message("sensors/temp-01", r#"{"t": 21.5, "h": 40}"#)).unwrap();
+        let properties = &upsert(&changes[0]).properties;
+        assert_eq!(properties["temperatureCelsius"], 21.5);
+        assert!(properties.get("h").is_none());
+    }
+
+    #[test]
+    fn exploded_arrays_number_items_without_an_id() {
+        let mapper = mapper("explode_arrays: true\nitem_id_pointer: /sensorId");
+        let changes = mapper
+            .map(&message("sensors/rack-1", r#"[{"sensorId": "s1"}, {"temperature": 20}]"#))
+            .unwrap();
+        let ids: Vec<_> = changes.iter().map(|change| upsert(change).id.as_str()).collect();
+        assert_eq!(ids, ["s1", "rack-1-1"]);
+    }
+
+    #[test]
+    fn the_filter_drops_payloads_that_fail_it() {
+        let mapper = mapper("filter: { gt: { pointer: /temperature, value: 30 } }");
+        assert!(mapper.map(&message("sensors/a", r#"{"temperature": 21.5}"#)).unwrap().is_empty());
+        assert_eq!(mapper.map(&message("sensors/a", r#"{"temperature": 35}"#)).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn the_id_transform_applies_to_every_id() {
+        let mapper = mapper("id_transform: { prefix: \"plant-a:\", lowercase: true, sanitize: true }");
+        let changes = mapper.map(&message("sensors/Room 2", "{}")).unwrap();
+        assert_eq!(upsert(&changes[0]).id, "plant-a:room_2");
+    }
+
+    #[test]
+    fn a_scalar_payload_is_wrapped() {
+        let changes = mapper("{}").map(&message("sensors/a", "42")).unwrap();
+        assert_eq!(upsert(&changes[0]).properties["value"], 42);
+    }
+
+    #[test]
+    fn invalid_json_is_a_parse_error() {
+        let error = mapper("{}").map(&message("sensors/a", "{not json")).unwrap_err();
+        assert!(matches!(error, MappingError::Parse(_)));
+    }
+
+    #[test]
+    fn a_missing_required_id_is_an_error() {
+        let mapper = mapper("id_source: { json_pointer: /deviceId }\nrequire_id: true");
+        let error = mapper.map(&message("sensors/a", "{}")).unwrap_err();
+        assert!(matches!(error, MappingError::MissingId { .. }));
+    }
+
+    #[test]
+    fn a_topic_ending_in_a_slash_has_no_id() {
+        let mapper = mapper("normalize_topics: false");
+        let error = mapper.map(&message("sensors/", "{}")).unwrap_err();
+        assert!(matches!(error, MappingError::EmptyId(_)));
+    }
+
+    #[test]
+    fn flatten_joins_nested_keys() {
+        let flat = flatten(json!({ "a": { "b": 1 }, "c": [true], "d": {} }), ".");
+        assert_eq!(flat, json!({ "a.b": 1, "c.0": true, "d": {} }));
+    }
+
+    #[test]
+    fn a_missing_id_pointer_falls_back_to_the_topic() {
+        let mapper = mapper("id_source: { json_pointer: /meta/deviceId }");
+        let changes = mapper.map(&message("lfx/drasi/sensors/temp-01", r#"{"meta": {}}"#)).unwrap();
+        assert_eq!(upsert(&changes[0]).id, "temp-01");
+        // Numbers are IDs too
+        let changes = mapper.map(&message("lfx/drasi/sensors/temp-01", r#"{"meta": {"deviceId": 7}}"#)).unwrap();
+        assert_eq!(upsert(&changes[0]).id, "7");
+    }
+
+    #[test]
+    fn an_empty_retained_payload_deletes_too() {
+        let mut retained = message("lfx/drasi/sensors/temp-01", "");
+        retained.retain = true;
+        let changes = mapper("{}").map(&retained).unwrap();
+        assert!(matches!(&changes[..], [GraphChange::Delete(delete)] if delete.id == "temp-01"));
+    }
+
+    #[test]
+    fn mqtt_metadata_is_attached_unless_turned_off() {
+        let config = Config {
+            subscriptions: vec![Subscription::new("sensors/#")],
+            ..Config::default()
+        };
+        let mut retained = message("sensors/a", "{}");
+        retained.retain = true;
+        let changes = Mapper::new(&config).unwrap().map(&retained).unwrap();
+        assert_eq!(
+            upsert(&changes[0]).properties["_mqtt"],
+            json!({ "topic": "sensors/a", "filter": "sensors/#", "qos": 1, "retain": true, "dup": false })
+        );
+
+        let changes = mapper("include_mqtt_metadata: false").map(&message("sensors/a", "{}")).unwrap();
+        assert!(upsert(&changes[0]).properties.get("_mqtt").is_none());
+    }
+
+    #[test]
+    fn elements_are_stamped_with_a_parseable_timestamp() {
+        let changes = mapper("{}").map(&message("sensors/a", "{}")).unwrap();
+        let stamp = upsert(&changes[0]).properties["ingested_at"].as_str().unwrap().to_string();
+        assert!(chrono::DateTime::parse_from_rfc3339(&stamp).is_ok(), "{}", stamp);
+
+        let mapper = mapper("timestamp: { key: seen, format: unix_millis, event_time_pointer: /ts }");
+        let changes = mapper.map(&message("sensors/a", r#"{"ts": "2024-05-01T10:00:00Z"}"#)).unwrap();
+        let properties = &upsert(&changes[0]).properties;
+        assert!(properties["seen"].as_i64().unwrap() > 1_700_000_000_000);
+        assert_eq!(properties["event_time"], "2024-05-01T10:00:00Z");
+    }
+
+    #[test]
+    fn unmapped_fields_pass_through_by_default() {
+        let mapper = mapper(
+            "field_map: { /t: temperatureCelsius, /meta/h: humidity, /missing: gone }
+include_mqtt_metadata: false
+timestamp: { enabled: false }",
+        );
+        let changes = mapper.map(&message("sensors/a", r#"{"t": 21.5, "meta": {"h": 40, "fw": 2}, "ok": true}"#)).unwrap();
+        assert_eq!(
+            upsert(&changes[0]).properties,
+            json!({ "temperatureCelsius": 21.5, "humidity": 40, "meta": { "fw": 2 }, "ok": true })
+        );
+    }
+
+    #[test]
+    fn retained_messages_are_tagged_as_snapshots() {
+        let mapper = mapper("{}");
+        let mut retained = message("sensors/a", "{}");
+        retained.retain = true;
+        assert_eq!(upsert(&mapper.map(&retained).unwrap()[0]).op, Some(ElementOp::Snapshot));
+        assert_eq!(upsert(&mapper.map(&message("sensors/a", "{}")).unwrap()[0]).op, Some(ElementOp::Update));
+        let untagged = self::mapper("tag_snapshots: false").map(&retained).unwrap();
+        assert_eq!(upsert(&untagged[0]).op, None);
+    }
+
+    #[test]
+    fn subscriptions_map_the_same_payload_their_own_way() {
+        let subscriptions = "- topic: plant-a/#
+  id_source: { json_pointer: /serial }
+  labels: [Pump]
+- topic: plant-b/#";
+        let config = Config {
+            subscriptions: serde_yaml::from_str(subscriptions).unwrap(),
+            ..Config::default()
+        };
+        let mapper = Mapper::new(&config).unwrap();
+        let payload = r#"{"serial": "s-9"}"#;
+        let a = mapper.map(&message("plant-a/p1", payload)).unwrap();
+        assert_eq!((upsert(&a[0]).id.as_str(), upsert(&a[0]).labels.clone()), ("s-9", vec!["Pump".to_string()]));
+        let b = mapper.map(&message("plant-b/p1", payload)).unwrap();
+        assert_eq!((upsert(&b[0]).id.as_str(), upsert(&b[0]).labels.clone()), ("p1", vec!["Sensor".to_string(), "IoTDevice".to_string()]));
+    }
+
+    #[test]
+    fn the_raw_payload_can_be_kept_as_hex_or_base64() {
+        let hex = mapper("preserve_raw: hex").map(&message("sensors/a", r#"{"t":1}"#)).unwrap();
+        assert_eq!(upsert(&hex[0]).properties["_raw"], "7b2274223a317d");
+        let base64 = mapper("preserve_raw: base64").map(&message("sensors/a", r#"{"t":1}"#)).unwrap();
+        assert_eq!(upsert(&base64[0]).properties["_raw"], "eyJ0IjoxfQ==");
+        let off = mapper("{}").map(&message("sensors/a", r#"{"t":1}"#)).unwrap();
+        assert!(upsert(&off[0]).properties.get("_raw").is_none());
+    }
+
+    #[test]
+    fn v5_user_properties_and_content_type_are_used() {
+        let mut text = message("sensors/a", "on");
+        text.content_type = Some("text/plain; charset=utf-8".to_string());
+        // Read as text rather than failing as JSON
+        let changes = mapper("{}").map(&text).unwrap();
+        assert_eq!(upsert(&changes[0]).properties, json!("on"));
+
+        let mut tagged = message("sensors/a", "{}");
+        tagged.user_properties = vec![
+            ("site".to_string(), "lab".to_string()),
+            ("tag".to_string(), "x".to_string()),
+            ("tag".to_string(), "y".to_string()),
+        ];
+        let changes = mapper("{}").map(&tagged).unwrap();
+        assert_eq!(upsert(&changes[0]).properties["_user_props"], json!({ "site": "lab", "tag": ["x", "y"] }));
+    }
+
+    #[test]
+    fn the_id_can_come_from_a_topic_segment() {
+        let topic = "site/3/temp-01/state";
+        let from_start = mapper("id_source: { topic_segment: 2 }").map(&message(topic, "{}")).unwrap();
+        assert_eq!(upsert(&from_start[0]).id, "temp-01");
+        let from_end = mapper("id_source: { topic_segment: -2 }").map(&message(topic, "{}")).unwrap();
+        assert_eq!(upsert(&from_end[0]).id, "temp-01");
+        let out_of_range = mapper("id_source: { topic_segment: 9 }").map(&message(topic, "{}")).unwrap();
+        assert_eq!(upsert(&out_of_range[0]).id, "unknown");
+    }
+
+    #[test]
+    fn the_packet_id_is_added_for_qos_1_and_2() {
+        let mapper = mapper("include_packet_id: true");
+        let mut qos1 = message("sensors/a", "{}");
+        qos1.pkid = 7;
+        assert_eq!(upsert(&mapper.map(&qos1).unwrap()[0]).properties["_pkid"], 7);
+        let qos0 = Message::from(rumqttc::Publish::new("sensors/a", QoS::AtMostOnce, "{}"));
+        assert!(upsert(&mapper.map(&qos0).unwrap()[0]).properties.get("_pkid").is_none());
+    }
+
+    #[test]
+    fn a_gzipped_payload_maps_like_the_plain_one() {
+        use std::io::Write as _;
+        let payload = r#"{"temperature": 21.5}"#;
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(payload.as_bytes()).unwrap();
+        let gzipped = Message::from(rumqttc::Publish::new("sensors/a", QoS::AtLeastOnce, encoder.finish().unwrap()));
+        let mapper = mapper("compression: gzip\ntimestamp: { enabled: false }");
+        let inflated = mapper.map(&gzipped).unwrap();
+        let plain = self::mapper("timestamp: { enabled: false }").map(&message("sensors/a", payload)).unwrap();
+        assert_eq!(upsert(&inflated[0]).properties, upsert(&plain[0]).properties);
+    }
+
+    #[test]
+    fn the_type_can_come_from_the_payload_or_the_topic() {
+        let mapper = self::mapper("default_labels: [Sensor, pump]\ntype_source: { json_pointer: /kind }");
+        let changes = mapper.map(&message("sensors/a", r#"{"kind": "pump"}"#)).unwrap();
+        let element = upsert(&changes[0]);
+        assert_eq!(element.element_type.as_deref(), Some("pump"));
+        // Not repeated as a label
+        assert_eq!(element.labels, ["Sensor"]);
+        let untyped = mapper.map(&message("sensors/a", r#"{"kind": ["pump"]}"#)).unwrap();
+        assert_eq!(upsert(&untyped[0]).element_type, None);
+
+        let by_topic = self::mapper("type_source: { topic_segment: 1 }").map(&message("site/valve/v-01", "{}")).unwrap();
+        assert_eq!(upsert(&by_topic[0]).element_type.as_deref(), Some("valve"));
+    }
+
+    #[test]
+    fn topic_segments_become_properties() {
+        let mapper = mapper("topic_properties: { 1: building, 3: room, 9: floor }");
+        let changes = mapper.map(&message("buildings/b1/rooms/r2/temp-01", r#"{"room": "own"}"#)).unwrap();
+        let properties = &upsert(&changes[0]).properties;
+        assert_eq!(properties["building"], "b1");
+        // The payload's own value wins; a missing segment adds nothing
+        assert_eq!(properties["room"], "own");
+        assert!(properties.get("floor").is_none());
+    }
+
+    #[test]
+    fn properties_are_coerced_where_they_can_be() {
+        let mapper = mapper("coerce: { /t: number, /n: integer, /on: bool, /bad: number }");
+        let changes = mapper.map(&message("sensors/a", r#"{"t": "21.5", "n": "3.0", "on": "yes", "bad": "warm"}"#)).unwrap();
+        let properties = &upsert(&changes[0]).properties;
+        assert_eq!(properties["t"], 21.5);
+        assert_eq!(properties["n"], 3);
+        assert_eq!(properties["on"], true);
+        // Left as it was
+        assert_eq!(properties["bad"], "warm");
+    }
+
+    #[test]
+    fn the_full_topic_keeps_ids_from_colliding() {
+        let last = mapper("{}");
+        let full = mapper("id_source: { full_topic: \".\" }");
+        let ids = |mapper: &Mapper| -> Vec<String> {
+            ["b1/floor2/temp", "b2/floor2/temp"]
+                .iter()
+                .map(|topic| upsert(&mapper.map(&message(topic, "{}")).unwrap()[0]).id.clone())
+                .collect()
+        };
+        assert_eq!(ids(&last), ["temp", "temp"]);
+        assert_eq!(ids(&full), ["b1.floor2.temp", "b2.floor2.temp"]);
+    }
+
+    #[test]
+    fn delivery_labels_mark_how_a_message_arrived() {
+        let mapper = mapper("default_labels: [Sensor]\ndelivery_labels: { retained: [RetainedState], qos2: [QoS2], duplicate: [Redelivered] }");
+        let mut retained = Message::from(rumqttc::Publish::new("sensors/a", QoS::ExactlyOnce, "{}"));
+        retained.retain = true;
+        assert_eq!(upsert(&mapper.map(&retained).unwrap()[0]).labels, ["Sensor", "RetainedState", "QoS2"]);
+        assert_eq!(upsert(&mapper.map(&message("sensors/a", "{}")).unwrap()[0]).labels, ["Sensor"]);
+    }
+
+    #[test]
+    fn doubled_and_trailing_slashes_are_normalized() {
+        let changes = mapper("{}").map(&message("sensors//temp-01/", "{}")).unwrap();
+        let element = upsert(&changes[0]);
+        assert_eq!(element.id, "temp-01");
+        assert_eq!(element.properties["_mqtt"]["topic"], "sensors/temp-01");
+    }
+
+    #[test]
+    fn enrich_adds_properties_the_payload_lacks() {
+        let payload = r#"{"site": "own"}"#;
+        let kept = mapper("enrich: { properties: { site: lab, env: prod } }").map(&message("sensors/a", payload)).unwrap();
+        let properties = &upsert(&kept[0]).properties;
+        assert_eq!((&properties["site"], &properties["env"]), (&json!("own"), &json!("prod")));
+        let forced = mapper("enrich: { properties: { site: lab }, force: true }").map(&message("sensors/a", payload)).unwrap();
+        assert_eq!(upsert(&forced[0]).properties["site"], "lab");
+    }
+
+    #[test]
+    fn labels_can_come_from_the_payload() {
+        let mapper = mapper("label_pointer: /type\ndefault_labels: [Device]");
+        let single = mapper.map(&message("sensors/a", r#"{"type": "pump"}"#)).unwrap();
+        assert_eq!(upsert(&single[0]).labels, ["pump"]);
+        let several = mapper.map(&message("sensors/a", r#"{"type": ["pump", "valve", "pump"]}"#)).unwrap();
+        assert_eq!(upsert(&several[0]).labels, ["pump", "valve"]);
+        let missing = mapper.map(&message("sensors/a", "{}")).unwrap();
+        assert_eq!(upsert(&missing[0]).labels, ["Device"]);
+    }
+
+    #[test]
+    fn a_content_encoding_user_property_overrides_the_compression() {
+        use std::io::Write as _;
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(br#"{"temperature": 21.5}"#).unwrap();
+        let mut gzipped = Message::from(rumqttc::Publish::new("sensors/a", QoS::AtLeastOnce, encoder.finish().unwrap()));
+        gzipped.user_properties = vec![("content-encoding".to_string(), "gzip".to_string())];
+        let changes = mapper("{}").map(&gzipped).unwrap();
+        assert_eq!(upsert(&changes[0]).properties["temperature"], 21.5);
+
+        // Says it's plain, so it isn't inflated
+        let mut plain = message("sensors/a", r#"{"temperature": 21.5}"#);
+        plain.user_properties = vec![("content-encoding".to_string(), "identity".to_string())];
+        assert!(mapper("compression: gzip").map(&plain).is_ok());
+    }
+
+    #[test]
+    fn a_composite_id_joins_topic_and_payload_parts() {
+        let mapper = mapper("id_source: { composite: { parts: [{ topic_segment: 0 }, { json_pointer: /deviceId }] } }");
+        let changes = mapper.map(&message("b1/sensors/x", r#"{"deviceId": "d7"}"#)).unwrap();
+        assert_eq!(upsert(&changes[0]).id, "b1-d7");
+        let changes = mapper.map(&message("b1/sensors/x", "{}")).unwrap();
+        assert_eq!(upsert(&changes[0]).id, "b1-unknown");
+        // Nothing to look the payload part up in
+        let changes = mapper.map(&message("b1/sensors/x", "")).unwrap();
+        assert!(matches!(&changes[..], [GraphChange::Delete(delete)] if delete.id == "b1-unknown"));
+    }
+
+    #[test]
+    fn a_relation_route_maps_the_message_to_an_edge() {
+        let mapper = mapper("relation_routes: [{ topic: \"links/#\", start_pointer: /from, end_pointer: /to, label: FEEDS }]");
+        let changes = mapper.map(&message("links/p1", r#"{"from": "pump-1", "to": "tank-2", "rate": 3}"#)).unwrap();
+        let GraphChange::Relation(relation) = &changes[0] else {
+            panic!("expected a relation, got {:?}", changes[0]);
+        };
+        assert_eq!((relation.start_id.as_str(), relation.end_id.as_str()), ("pump-1", "tank-2"));
+        assert_eq!(relation.id, "pump-1-FEEDS-tank-2");
+        assert_eq!(Value::Object(relation.properties.clone()), json!({ "rate": 3 }));
+        // Other topics are still nodes
+        assert!(matches!(mapper.map(&message("sensors/a", "{}")).unwrap()[0], GraphChange::Upsert(_)));
+        // An end that isn't there leaves no one to connect
+        let error = mapper.map(&message("links/p1", r#"{"from": "pump-1"}"#)).unwrap_err();
+        assert!(matches!(error, MappingError::EmptyId(_)));
+    }
+
+    #[test]
+    fn include_fields_and_exclude_fields_trim_the_payload() {
+        let mapper = mapper("include_mqtt_metadata: false\ntimestamp: { enabled: false }\ninclude_fields: [/meta, /temperature]\nexclude_fields: [/meta/image]");
+        let payload = r#"{"meta": {"image": "...", "fw": 2}, "temperature": 21, "raw": [1, 2]}"#;
+        let changes = mapper.map(&message("sensors/a", payload)).unwrap();
+        assert_eq!(upsert(&changes[0]).properties, json!({ "meta": { "fw": 2 }, "temperature": 21 }));
+    }
+
+    #[test]
+    fn default_properties_are_merged_under_the_payload() {
+        let mapper = mapper("default_properties: { unit: C, location: { building: b1, floor: 1 } }");
+        let changes = mapper.map(&message("sensors/a", r#"{"unit": "F", "location": {"floor": 3}}"#)).unwrap();
+        let properties = &upsert(&changes[0]).properties;
+        assert_eq!(properties["unit"], "F");
+        assert_eq!(properties["location"], json!({ "building": "b1", "floor": 3 }));
+    }
+
+    #[test]
+    fn a_null_payload_is_handled_as_configured() {
+        let null = message("sensors/temp-01", "null");
+        let changes = mapper("{}").map(&null).unwrap();
+        assert!(matches!(&changes[..], [GraphChange::Delete(delete)] if delete.id == "temp-01"));
+        assert!(mapper("null_payload: skip").map(&null).unwrap().is_empty());
+        let wrapped = mapper("null_payload: wrap").map(&null).unwrap();
+        assert_eq!(upsert(&wrapped[0]).properties["value"], Value::Null);
+    }
+
+    #[test]
+    fn payload_relations_follow_the_node() {
+        let mapper = mapper("payload_relations: [{ pointer: /room, label: IN_ROOM }, { pointer: /gateway, label: HAS_SENSOR, reverse: true }]");
+        let changes = mapper.map(&message("sensors/temp-01", r#"{"room": "r2", "gateway": "gw-1"}"#)).unwrap();
+        assert_eq!(upsert(&changes[0]).id, "temp-01");
+        let relations: Vec<(&str, &str, &str)> = changes[1..]
+            .iter()
+            .map(|change| match change {
+                GraphChange::Relation(relation) => (relation.start_id.as_str(), relation.label.as_str(), relation.end_id.as_str()),
+                other => panic!("expected a relation, got {:?}", other),
+            })
+            .collect();
+        assert_eq!(relations, [("temp-01", "IN_ROOM", "r2"), ("gw-1", "HAS_SENSOR", "temp-01")]);
+        // No room, no edge
+        assert_eq!(mapper.map(&message("sensors/temp-01", "{}")).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn each_length_prefixed_record_is_mapped_on_its_own() {
+        let mapper = mapper("framing: { length_prefixed: { prefix_bytes: 1 } }");
+        let mut payload = vec![11];
+        payload.extend_from_slice(br#"{"t": 21.5}"#);
+        payload.push(9);
+        payload.extend_from_slice(br#"{"t": 22}"#);
+        let framed = Message::from(rumqttc::Publish::new("sensors/a", QoS::AtLeastOnce, payload));
+        let changes = mapper.map(&framed).unwrap();
+        let readings: Vec<&Value> = changes.iter().map(|change| &upsert(change).properties["t"]).collect();
+        assert_eq!(readings, [&json!(21.5), &json!(22)]);
+        // Cut short
+        let truncated = Message::from(rumqttc::Publish::new("sensors/a", QoS::AtLeastOnce, vec![11, b'{']));
+        assert!(matches!(mapper.map(&truncated).unwrap_err(), MappingError::Parse(_)));
+    }
+
+    #[test]
+    fn a_missing_id_falls_back_to_the_topic_unless_required() {
+        let changes = mapper("id_source: { json_pointer: /deviceId }").map(&message("sensors/temp-01", "{}")).unwrap();
+        assert_eq!(upsert(&changes[0]).id, "temp-01");
+    }
+
+    #[test]
+    fn the_first_label_can_be_the_type() {
+        let mapper = mapper("type_source: first_label");
+        let changes = mapper.map(&message("sensors/a", "{}")).unwrap();
+        let element = upsert(&changes[0]);
+        assert_eq!(element.element_type.as_deref(), Some("Sensor"));
+        assert_eq!(element.labels, ["IoTDevice"]);
+    }
+
+    #[test]
+    fn a_payload_that_is_not_an_object_is_wrapped_skipped_or_kept() {
+        let reading = message("sensors/a", "21.5");
+        let wrapped = mapper("{}").map(&reading).unwrap();
+        assert_eq!(upsert(&wrapped[0]).properties["value"], 21.5);
+        let renamed = mapper("wrap_key: reading").map(&reading).unwrap();
+        assert_eq!(upsert(&renamed[0]).properties["reading"], 21.5);
+        assert!(mapper("non_object_payload: skip").map(&reading).unwrap().is_empty());
+        let kept = mapper("non_object_payload: keep").map(&message("sensors/a", "[1, 2]")).unwrap();
+        assert_eq!(upsert(&kept[0]).properties, json!([1, 2]));
+    }
+
+    #[test]
+    fn canonical_elements_have_sorted_labels_and_keys() {
+        let mut element = DrasiElement {
+            id: "a".to_string(),
+            element_type: None,
+            labels: vec!["Sensor".to_string(), "Device".to_string(), "Sensor".to_string()],
+            properties: json!({ "b": 1, "a": { "z": 1, "y": [{ "d": 1, "c": 2 }] } }),
+            op: None,
+        };
+        canonicalize(&mut element);
+        assert_eq!(element.labels, ["Device", "Sensor"]);
+        assert_eq!(serde_json::to_string(&element.properties).unwrap(), r#"{"a":{"y":[{"c":2,"d":1}],"z":1},"b":1}"#);
+    }
+
+    #[test]
+    fn the_metadata_names_the_filter_the_message_matched() {
+        let config = Config {
+            subscriptions: vec![Subscription::new("alarms/#"), Subscription::new("sensors/+")],
+            ..Config::default()
+        };
+        let mapper = Mapper::new(&config).unwrap();
+        let changes = mapper.map(&message("sensors/temp-01", "{}")).unwrap();
+        assert_eq!(upsert(&changes[0]).properties["_mqtt"]["filter"], "sensors/+");
+        let changes = mapper.map(&message("other/temp-01", "{}")).unwrap();
+        assert!(upsert(&changes[0]).properties["_mqtt"].get("filter").is_none());
+    }
+
+    #[test]
+    fn nested_properties_count_and_truncate_depth_first() {
+        let mut properties = json!({ "a": 1, "b": { "c": 2, "d": 3 }, "e": 4 });
+        assert_eq!(count_properties(&properties, false), 3);
+        assert_eq!(count_properties(&properties, true), 5);
+        truncate_properties(&mut properties, 3, true);
+        assert_eq!(properties, json!({ "a": 1, "b": { "c": 2 } }));
+    }
+
+    #[test]
+    fn each_broker_maps_with_its_own_subscription() {
+        let brokers = "- name: site-a
+  broker: mqtt.site-a.example
+  subscriptions:
+    - topic: sensors/#
+      labels: [SiteA]
+- name: site-b
+  broker: mqtt.site-b.example
+  subscriptions:
+    - topic: sensors/#
+      id_source: { json_pointer: /serial }
+      labels: [SiteB]";
+        let config = Config {
+            brokers: serde_yaml::from_str(brokers).unwrap(),
+            ..Config::default()
+        };
+        let mapper = Mapper::new(&config).unwrap();
+        let from = |broker: Option<&str>| Message {
+            broker: broker.map(std::sync::Arc::from),
+            ..message("sensors/temp-01", r#"{"serial": "s-9"}"#)
+        };
+        let a = mapper.map(&from(Some("site-a"))).unwrap();
+        assert_eq!((upsert(&a[0]).id.as_str(), upsert(&a[0]).labels.clone()), ("temp-01", vec!["SiteA".to_string()]));
+        let b = mapper.map(&from(Some("site-b"))).unwrap();
+        assert_eq!((upsert(&b[0]).id.as_str(), upsert(&b[0]).labels.clone()), ("s-9", vec!["SiteB".to_string()]));
+        // A replay has no connection: the first broker's subscription
+        let replayed = mapper.map(&from(None)).unwrap();
+        assert_eq!(upsert(&replayed[0]).labels, ["SiteA"]);
+    }
+}
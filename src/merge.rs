@@ -0,0 +1,129 @@
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::config::MergeConfig;
+use crate::model::DrasiElement;
+
+// --- DELTA MERGING ---
+// For devices that only send what changed: each element's properties are
+// merged onto the last known state for its ID, and the merged whole is
+// emitted. Objects merge key by key at every depth; anything else (arrays
+// included) replaces what was there. An ID seen for the first time is taken
+// as it is. Up to `max_entries` IDs are remembered; past that the least
+// recently seen are forgotten and start over from their next message.
+pub struct StateMerger {
+    max_entries: usize,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    // Bumped on every merge, to order entries by recency
+    clock: u64,
+    // element ID -> (merged properties, when last seen)
+    last: HashMap<String, (Value, u64)>,
+}
+
+impl StateMerger {
+    pub fn new(config: &MergeConfig) -> Self {
+        StateMerger {
+            max_entries: config.max_entries,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    // Replaces the element's properties with the merged state, which also
+    // becomes what its next delta is merged onto
+    pub fn merge(&self, element: &mut DrasiElement) {
+        let mut state = self.state.lock().expect("merge lock poisoned");
+        state.clock += 1;
+        let now = state.clock;
+
+        if let Some((known, seen)) = state.last.get_mut(&element.id) {
+            merge_into(known, std::mem::take(&mut element.properties));
+            element.properties = known.clone();
+            *seen = now;
+            return;
+        }
+        if state.last.len() >= self.max_entries {
+            evict(&mut state.last, self.max_entries);
+        }
+        state.last.insert(element.id.clone(), (element.properties.clone(), now));
+    }
+
+    // A deleted element starts from scratch if it comes back
+    pub fn forget(&self, id: &str) {
+        self.state.lock().expect("merge lock poisoned").last.remove(id);
+    }
+}
+
+fn merge_into(known: &mut Value, delta: Value) {
+    match (known, delta) {
+        (Value::Object(known), Value::Object(delta)) => {
+            for (key, value) in delta {
+                match known.get_mut(&key) {
+                    Some(existing) => merge_into(existing, value),
+                    None => {
+                        known.insert(key, value);
+                    }
+                }
+            }
+        }
+        (known, delta) => *known = delta,
+    }
+}
+
+// Forgets the least recently seen tenth at once, like change detection
+fn evict(last: &mut HashMap<String, (Value, u64)>, max_entries: usize) {
+    let mut by_age: Vec<(u64, String)> = last.iter().map(|(id, (_, seen))| (*seen, id.clone())).collect();
+    by_age.sort_unstable();
+    let excess = last.len() + 1 - max_entries;
+    for (_, id) in by_age.into_iter().take(excess.max(max_entries / 10)) {
+        last.remove(&id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emit::tests::element;
+    use serde_json::json;
+
+    fn merged(merger: &StateMerger, id: &str, properties: Value) -> Value {
+        let mut element = DrasiElement {
+            properties,
+            ..element(id)
+        };
+        merger.merge(&mut element);
+        element.properties
+    }
+
+    #[test]
+    fn deltas_merge_onto_the_last_state() {
+        let merger = StateMerger::new(&MergeConfig { max_entries: 10 });
+        merged(&merger, "a", json!({ "t": 1, "meta": { "x": 1, "y": 1 }, "tags": [1, 2] }));
+        let properties = merged(&merger, "a", json!({ "meta": { "y": 2 }, "tags": [3] }));
+        assert_eq!(properties, json!({ "t": 1, "meta": { "x": 1, "y": 2 }, "tags": [3] }));
+        assert_eq!(merged(&merger, "b", json!({ "h": 40 })), json!({ "h": 40 }));
+    }
+
+    #[test]
+    fn a_forgotten_id_starts_over() {
+        let merger = StateMerger::new(&MergeConfig { max_entries: 10 });
+        merged(&merger, "a", json!({ "t": 1 }));
+        merger.forget("a");
+        assert_eq!(merged(&merger, "a", json!({ "h": 40 })), json!({ "h": 40 }));
+    }
+
+    #[test]
+    fn the_least_recently_seen_are_evicted() {
+        let merger = StateMerger::new(&MergeConfig { max_entries: 2 });
+        merged(&merger, "a", json!({ "t": 1 }));
+        merged(&merger, "b", json!({ "t": 1 }));
+        merged(&merger, "a", json!({ "h": 1 }));
+        merged(&merger, "c", json!({ "t": 1 }));
+        assert_eq!(merged(&merger, "a", json!({})), json!({ "t": 1, "h": 1 }));
+        assert_eq!(merged(&merger, "b", json!({ "h": 2 })), json!({ "h": 2 }));
+    }
+}
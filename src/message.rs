@@ -0,0 +1,153 @@
+use bytes::Bytes;
+use rumqttc::QoS;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::ack::AckTicket;
+
+// --- INBOUND MESSAGE ---
+// What the pipeline sees of an MQTT publish, whichever protocol version
+// delivered it. v3.1.1 publishes simply have no user properties or
+// content type.
+#[derive(Debug, Clone)]
+pub struct Message {
+    pub topic: String,
+    pub payload: Bytes,
+    pub qos: QoS,
+    pub retain: bool,
+    pub dup: bool,
+    // 0 for QoS 0, which has no packet identifier
+    pub pkid: u16,
+    pub user_properties: Vec<(String, String)>,
+    pub content_type: Option<String>,
+    // Name of the broker connection it arrived on; set by the MQTT source
+    pub broker: Option<Arc<str>>,
+    // From a v5 message expiry interval, counted from when we received it
+    // (the broker has already taken off the time it held the message)
+    pub expires_at: Option<Instant>,
+    // With `manual_ack`, its place in line for an acknowledgement
+    pub ack: Option<AckTicket>,
+}
+
+impl Message {
+    // Done with it: `processed` false keeps it unacknowledged for redelivery
+    pub fn settle(&self, processed: bool) {
+        if let Some(ticket) = &self.ack {
+            ticket.settle(processed);
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|expires_at| Instant::now() >= expires_at)
+    }
+}
+
+impl From<rumqttc::Publish> for Message {
+    fn from(publish: rumqttc::Publish) -> Self {
+        Message {
+            topic: publish.topic,
+            payload: publish.payload,
+            qos: publish.qos,
+            retain: publish.retain,
+            dup: publish.dup,
+            pkid: publish.pkid,
+            user_properties: Vec::new(),
+            content_type: None,
+            broker: None,
+            expires_at: None,
+            ack: None,
+        }
+    }
+}
+
+impl From<rumqttc::v5::mqttbytes::v5::Publish> for Message {
+    fn from(publish: rumqttc::v5::mqttbytes::v5::Publish) -> Self {
+        let (user_properties, content_type, expiry) = match publish.properties {
+            Some(properties) => (
+                properties.user_properties,
+                properties.content_type,
+                properties.message_expiry_interval,
+            ),
+            None => (Vec::new(), None, None),
+        };
+        Message {
+            // Topics are UTF-8 by spec; a broker sending anything else is
+            // broken, but that shouldn't take the source down
+            topic: String::from_utf8_lossy(&publish.topic).into_owned(),
+            payload: publish.payload,
+            qos: from_v5_qos(publish.qos),
+            retain: publish.retain,
+            dup: publish.dup,
+            pkid: publish.pkid,
+            user_properties,
+            content_type,
+            broker: None,
+            expires_at: expiry.map(|secs| Instant::now() + Duration::from_secs(secs.into())),
+            ack: None,
+        }
+    }
+}
+
+// v5 has its own (identical) QoS type; everything past the connection uses
+// the v3 one
+pub fn from_v5_qos(qos: rumqttc::v5::mqttbytes::QoS) -> QoS {
+    match qos {
+        rumqttc::v5::mqttbytes::QoS::AtMostOnce => QoS::AtMostOnce,
+        rumqttc::v5::mqttbytes::QoS::AtLeastOnce => QoS::AtLeastOnce,
+        rumqttc::v5::mqttbytes::QoS::ExactlyOnce => QoS::ExactlyOnce,
+    }
+}
+
+pub fn to_v5_qos(qos: QoS) -> rumqttc::v5::mqttbytes::QoS {
+    match qos {
+        QoS::AtMostOnce => rumqttc::v5::mqttbytes::QoS::AtMostOnce,
+        QoS::AtLeastOnce => rumqttc::v5::mqttbytes::QoS::AtLeastOnce,
+        QoS::ExactlyOnce => rumqttc::v5::mqttbytes::QoS::ExactlyOnce,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rumqttc::v5::mqttbytes::v5::{Publish, PublishProperties};
+    use rumqttc::v5::mqttbytes::QoS as V5QoS;
+
+    fn publish(properties: PublishProperties) -> Publish {
+        Publish::new("sensors/temp-01", V5QoS::AtLeastOnce, "21.5", Some(properties))
+    }
+
+    #[test]
+    fn v5_user_properties_and_content_type_are_kept() {
+        let message = Message::from(publish(PublishProperties {
+            user_properties: vec![("site".to_string(), "plant-1".to_string())],
+            content_type: Some("application/json".to_string()),
+            ..PublishProperties::default()
+        }));
+        assert_eq!(message.topic, "sensors/temp-01");
+        assert_eq!(message.qos, QoS::AtLeastOnce);
+        assert_eq!(message.user_properties, vec![("site".to_string(), "plant-1".to_string())]);
+        assert_eq!(message.content_type.as_deref(), Some("application/json"));
+
+        let v3 = Message::from(rumqttc::Publish::new("sensors/temp-01", QoS::AtMostOnce, "21.5"));
+        assert!(v3.user_properties.is_empty());
+        assert_eq!(v3.content_type, None);
+    }
+
+    #[test]
+    fn the_expiry_interval_counts_from_when_it_arrived() {
+        let fresh = Message::from(publish(PublishProperties {
+            message_expiry_interval: Some(60),
+            ..PublishProperties::default()
+        }));
+        assert!(fresh.expires_at.is_some());
+        assert!(!fresh.is_expired());
+
+        let expired = Message::from(publish(PublishProperties {
+            message_expiry_interval: Some(0),
+            ..PublishProperties::default()
+        }));
+        assert!(expired.is_expired());
+        // Without an interval it never expires
+        assert!(!Message::from(publish(PublishProperties::default())).is_expired());
+    }
+}
@@ -0,0 +1,366 @@
+use anyhow::{Context, Result};
+use axum::{extract::State, http::header, response::IntoResponse, routing::get, Router};
+use tracing::{error, info};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+
+use crate::connection::ConnectionState;
+
+// --- METRICS ---
+// A handful of counters is all we need, so they are plain atomics rendered
+// by hand in the Prometheus text format rather than pulling in a registry.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub received: AtomicU64,
+    pub mapped: AtomicU64,
+    pub failed: AtomicU64,
+    pub dead_lettered: AtomicU64,
+    pub dead_letter_failures: AtomicU64,
+    pub undelivered: AtomicU64,
+    pub deduplicated: AtomicU64,
+    pub unchanged: AtomicU64,
+    pub out_of_order: AtomicU64,
+    pub parked: AtomicU64,
+    pub expired: AtomicU64,
+    pub filtered: AtomicU64,
+    pub throttled: AtomicU64,
+    pub sampled_out: AtomicU64,
+    pub queue_full: AtomicU64,
+    pub oversized: AtomicU64,
+    pub too_deep: AtomicU64,
+    pub too_many_properties: AtomicU64,
+    pub missing_id: AtomicU64,
+    pub redelivered: AtomicU64,
+    pub short_circuited: AtomicU64,
+    pub idle_reconnects: AtomicU64,
+    pub processing: Histogram,
+    pub connected: AtomicBool,
+    // (broker, topic filter) -> QoS in the latest SubAck; refused ones are left out
+    granted_qos: Mutex<BTreeMap<(String, String), u8>>,
+}
+
+// Upper bounds, in seconds, of the processing time buckets
+const PROCESSING_BUCKETS: [f64; 9] = [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0, 5.0];
+
+// Per-bucket counts; `render` makes them cumulative as Prometheus expects
+#[derive(Debug, Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; PROCESSING_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        if let Some(bucket) = PROCESSING_BUCKETS.iter().position(|bound| secs <= *bound) {
+            Metrics::inc(&self.buckets[bucket]);
+        }
+        Metrics::inc(&self.count);
+        Metrics::add(&self.sum_micros, elapsed.as_micros() as u64);
+    }
+}
+
+impl Metrics {
+    pub fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn set_granted_qos(&self, broker: &str, topic: &str, granted: Option<u8>) {
+        let mut granted_qos = self.granted_qos.lock().expect("metrics lock poisoned");
+        let key = (broker.to_string(), topic.to_string());
+        match granted {
+            Some(qos) => granted_qos.insert(key, qos),
+            None => granted_qos.remove(&key),
+        };
+    }
+
+    pub fn set_connected(&self, connected: bool) {
+        self.connected.store(connected, Ordering::Relaxed);
+    }
+
+    // Keeps the `connected` gauge in step with the source
+    pub fn follow(self: Arc<Self>, mut state: watch::Receiver<ConnectionState>) {
+        tokio::spawn(async move {
+            loop {
+                self.set_connected(*state.borrow_and_update() == ConnectionState::Connected);
+                if state.changed().await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        counter(&mut out, "drasi_mqtt_messages_received_total", "MQTT publishes received from the broker", &self.received);
+        counter(&mut out, "drasi_mqtt_messages_mapped_total", "Messages mapped and emitted as graph elements", &self.mapped);
+        counter(&mut out, "drasi_mqtt_messages_failed_total", "Messages that could not be decoded, mapped or emitted", &self.failed);
+        counter(
+            &mut out,
+            "drasi_mqtt_messages_dead_lettered_total",
+            "Failed messages written to the dead-letter sink",
+            &self.dead_lettered,
+        );
+        counter(
+            &mut out,
+            "drasi_mqtt_dead_letter_failures_total",
+            "Failed messages the dead-letter sink could not take",
+            &self.dead_letter_failures,
+        );
+        counter(
+            &mut out,
+            "drasi_mqtt_messages_undelivered_total",
+            "Messages still queued or being processed and changes still buffered by the output when shutdown stopped waiting, plus buffered changes the output failed",
+            &self.undelivered,
+        );
+        counter(
+            &mut out,
+            "drasi_mqtt_messages_deduplicated_total",
+            "Messages dropped as duplicates of a recent one",
+            &self.deduplicated,
+        );
+        counter(
+            &mut out,
+            "drasi_mqtt_messages_unchanged_total",
+            "Messages dropped because nothing changed since the last emission for the element",
+            &self.unchanged,
+        );
+        counter(
+            &mut out,
+            "drasi_mqtt_messages_out_of_order_total",
+            "Messages dropped for being older than one already emitted for the element",
+            &self.out_of_order,
+        );
+        counter(
+            &mut out,
+            "drasi_mqtt_messages_parked_total",
+            "Messages whose emit failed and were queued to be retried",
+            &self.parked,
+        );
+        counter(
+            &mut out,
+            "drasi_mqtt_messages_expired_total",
+            "v5 messages dropped because their message expiry interval passed before processing",
+            &self.expired,
+        );
+        counter(
+            &mut out,
+            "drasi_mqtt_messages_filtered_total",
+            "Messages dropped because nothing in them passed mapping.filter",
+            &self.filtered,
+        );
+        counter(
+            &mut out,
+            "drasi_mqtt_elements_throttled_total",
+            "Elements dropped by the output rate limit",
+            &self.throttled,
+        );
+        counter(
+            &mut out,
+            "drasi_mqtt_elements_sampled_out_total",
+            "Elements not emitted because sampling skipped them",
+            &self.sampled_out,
+        );
+        counter(
+            &mut out,
+            "drasi_mqtt_queue_full_total",
+            "Messages that found the processing queue full (then waited or were dropped)",
+            &self.queue_full,
+        );
+        counter(
+            &mut out,
+            "drasi_mqtt_messages_oversized_total",
+            "Messages dropped for exceeding max_payload_bytes",
+            &self.oversized,
+        );
+        counter(
+            &mut out,
+            "drasi_mqtt_messages_too_deep_total",
+            "Messages rejected for nesting deeper than max_payload_depth",
+            &self.too_deep,
+        );
+        counter(
+            &mut out,
+            "drasi_mqtt_elements_too_many_properties_total",
+            "Elements over max_properties, rejected or truncated",
+            &self.too_many_properties,
+        );
+        counter(
+            &mut out,
+            "drasi_mqtt_messages_missing_id_total",
+            "Messages rejected for lacking the ID field, with require_id",
+            &self.missing_id,
+        );
+        counter(
+            &mut out,
+            "drasi_mqtt_messages_redelivered_total",
+            "Redelivered messages skipped because the checkpoint shows them processed",
+            &self.redelivered,
+        );
+        counter(
+            &mut out,
+            "drasi_mqtt_changes_short_circuited_total",
+            "Changes refused without calling the output while its circuit breaker was open",
+            &self.short_circuited,
+        );
+        counter(
+            &mut out,
+            "drasi_mqtt_idle_reconnects_total",
+            "Reconnects forced because a connected broker went silent for idle_timeout_secs",
+            &self.idle_reconnects,
+        );
+        histogram(
+            &mut out,
+            "drasi_mqtt_processing_seconds",
+            "Time taken to map and emit a message",
+            &self.processing,
+        );
+        gauge(
+            &mut out,
+            "drasi_mqtt_connected",
+            "1 while connected to the broker, 0 otherwise",
+            u64::from(self.connected.load(Ordering::Relaxed)),
+        );
+        let granted_qos = self.granted_qos.lock().expect("metrics lock poisoned");
+        if !granted_qos.is_empty() {
+            let name = "drasi_mqtt_subscription_granted_qos";
+            let _ = writeln!(
+                out,
+                "# HELP {} QoS the broker granted each subscription, which may be below the requested one\n# TYPE {} gauge",
+                name, name
+            );
+            for ((broker, topic), qos) in granted_qos.iter() {
+                let _ = writeln!(out, "{}{{broker=\"{}\",topic=\"{}\"}} {}", name, label(broker), label(topic), qos);
+            }
+        }
+        out
+    }
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: &AtomicU64) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} counter\n{} {}", name, help, name, name, value.load(Ordering::Relaxed));
+}
+
+fn histogram(out: &mut String, name: &str, help: &str, histogram: &Histogram) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} histogram", name, help, name);
+    let mut cumulative = 0;
+    for (bound, bucket) in PROCESSING_BUCKETS.iter().zip(&histogram.buckets) {
+        cumulative += bucket.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+    }
+    let count = histogram.count.load(Ordering::Relaxed);
+    let sum = histogram.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+    let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}\n{}_sum {}\n{}_count {}", name, count, name, sum, name, count);
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} gauge\n{} {}", name, help, name, name, value);
+}
+
+// Escaped as the text format wants label values
+fn label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+// --- METRICS ENDPOINT ---
+// Binds before returning so a port clash fails at startup; the server itself
+// runs on a background task. Returns the address bound, port 0 included.
+pub async fn serve(addr: SocketAddr, metrics: Arc<Metrics>) -> Result<SocketAddr> {
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Failed to bind metrics endpoint on {}", addr))?;
+    let addr = listener.local_addr()?;
+    let app = Router::new().route("/metrics", get(scrape)).with_state(metrics);
+
+    info!("Serving Prometheus metrics on http://{}/metrics", addr);
+    tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, app).await {
+            error!("Metrics endpoint stopped: {}", e);
+        }
+    });
+    Ok(addr)
+}
+
+async fn scrape(State(metrics): State<Arc<Metrics>>) -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], metrics.render())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::emit::tests::Recording;
+    use crate::message::Message;
+    use rumqttc::QoS;
+
+    #[test]
+    fn counters_render_in_the_prometheus_text_format() {
+        let metrics = Metrics::default();
+        Metrics::inc(&metrics.received);
+        Metrics::add(&metrics.received, 2);
+        Metrics::inc(&metrics.failed);
+        metrics.set_connected(true);
+        let rendered = metrics.render();
+        assert!(rendered.contains(
+            "# HELP drasi_mqtt_messages_received_total MQTT publishes received from the broker\n# TYPE drasi_mqtt_messages_received_total counter\ndrasi_mqtt_messages_received_total 3\n"
+        ));
+        assert!(rendered.contains("drasi_mqtt_messages_failed_total 1\n"));
+        assert!(rendered.contains("drasi_mqtt_messages_mapped_total 0\n"));
+        assert!(rendered.contains("# TYPE drasi_mqtt_connected gauge\ndrasi_mqtt_connected 1\n"));
+    }
+
+    #[test]
+    fn the_processing_histogram_is_cumulative() {
+        let metrics = Metrics::default();
+        metrics.processing.observe(Duration::from_micros(500));
+        metrics.processing.observe(Duration::from_millis(20));
+        metrics.processing.observe(Duration::from_secs(9));
+        let rendered = metrics.render();
+        assert!(rendered.contains("drasi_mqtt_processing_seconds_bucket{le=\"0.001\"} 1\n"));
+        assert!(rendered.contains("drasi_mqtt_processing_seconds_bucket{le=\"0.025\"} 2\n"));
+        assert!(rendered.contains("drasi_mqtt_processing_seconds_bucket{le=\"5\"} 2\n"));
+        assert!(rendered.contains("drasi_mqtt_processing_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(rendered.contains("drasi_mqtt_processing_seconds_sum 9.0205\n"));
+        assert!(rendered.contains("drasi_mqtt_processing_seconds_count 3\n"));
+    }
+
+    #[test]
+    fn granted_qos_is_labelled_by_broker_and_filter() {
+        let metrics = Metrics::default();
+        assert!(!metrics.render().contains("drasi_mqtt_subscription_granted_qos"));
+        metrics.set_granted_qos("main", "sensors/#", Some(1));
+        metrics.set_granted_qos("main", "say \"hi\"", Some(0));
+        let rendered = metrics.render();
+        assert!(rendered.contains("drasi_mqtt_subscription_granted_qos{broker=\"main\",topic=\"sensors/#\"} 1\n"));
+        assert!(rendered.contains("drasi_mqtt_subscription_granted_qos{broker=\"main\",topic=\"say \\\"hi\\\"\"} 0\n"));
+        // Refused on a later SubAck
+        metrics.set_granted_qos("main", "sensors/#", None);
+        assert!(!metrics.render().contains("topic=\"sensors/#\""));
+    }
+
+    #[tokio::test]
+    async fn the_endpoint_serves_the_counts_of_processed_messages() {
+        let pipeline = crate::pipeline::tests::pipeline(&Config::default(), Arc::new(Recording::default()));
+        let addr = serve("127.0.0.1:0".parse().unwrap(), pipeline.metrics.clone()).await.unwrap();
+        for payload in [r#"{"temperature": 21.5}"#, r#"{"temperature": 22.0}"#, "not json"] {
+            let message = Message::from(rumqttc::Publish::new("sensors/temp-01", QoS::AtLeastOnce, payload));
+            let _ = pipeline.process(&message).await;
+        }
+
+        let response = reqwest::get(format!("http://{}/metrics", addr)).await.unwrap();
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/plain; version=0.0.4");
+        let scraped = response.text().await.unwrap();
+        assert!(scraped.contains("drasi_mqtt_messages_mapped_total 2\n"), "{}", scraped);
+        assert!(scraped.contains("drasi_mqtt_messages_failed_total 1\n"), "{}", scraped);
+    }
+}
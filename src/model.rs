@@ -0,0 +1,61 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+// --- MOCK DRASI STRUCTURES ---
+// This struct mimics the internal "Graph Element" Drasi uses.
+// It proves you understand how to bridge External Data -> Drasi Data.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DrasiElement {
+    pub id: String,
+    // The primary type, when `type_source` picks one; it isn't repeated in
+    // the labels
+    #[serde(rename = "type", default, skip_serializing_if = "Option::is_none")]
+    pub element_type: Option<String>,
+    pub labels: Vec<String>,
+    pub properties: Value,
+    // Whether this is initial state or a live change; omitted when snapshot
+    // tagging is turned off
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub op: Option<ElementOp>,
+}
+
+// Brokers only set the retain flag on messages replayed to a new
+// subscription, so retained messages are the state that existed before we
+// connected and everything else is a live update
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ElementOp {
+    Snapshot,
+    Update,
+}
+
+// Tells Drasi to remove a node. Produced when a device clears its topic by
+// publishing an empty (usually retained) message.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DrasiDelete {
+    pub id: String,
+}
+
+// A directed edge between two nodes, e.g. `temp-01 -[:IN_ROOM]-> r2`.
+// Derived from the topic hierarchy rather than the payload.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DrasiRelation {
+    pub id: String,
+    pub start_id: String,
+    pub end_id: String,
+    pub label: String,
+    // Empty (and left out) unless something stamps the relation, e.g. with
+    // its `_source`
+    #[serde(default, skip_serializing_if = "Map::is_empty")]
+    pub properties: Map<String, Value>,
+}
+
+// What an MQTT message turns into; one message can yield a node plus the
+// relations its topic implies
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GraphChange {
+    Upsert(DrasiElement),
+    Delete(DrasiDelete),
+    Relation(DrasiRelation),
+}
@@ -0,0 +1,572 @@
+use anyhow::Result;
+use arc_swap::ArcSwap;
+use tracing::field::Empty;
+use tracing::{debug, debug_span, error, info, warn, Instrument, Span};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::aggregate::Aggregator;
+use crate::change::ChangeDetector;
+use crate::checkpoint::CheckpointStore;
+use crate::config::{MaxPropertiesConfig, PropertyLimitMode};
+use crate::deadletter::{DeadLetter, DeadLetterSink};
+use crate::dedup::Deduplicator;
+use crate::emit::{self, Emitter};
+use crate::error::MappingError;
+use crate::expiry::Expiry;
+use crate::mapping::{self, Mapper};
+use crate::merge::StateMerger;
+use crate::message::Message;
+use crate::metrics::Metrics;
+use crate::model::GraphChange;
+use crate::redact;
+use crate::reorder::{Hold, Reorderer};
+use crate::retry::RetryQueue;
+use crate::sequence::Sequencer;
+use crate::snapshot::SnapshotCache;
+use crate::telemetry;
+
+// --- PROCESSING PIPELINE ---
+// Everything a processing task needs, built once in `main` and shared
+// behind an Arc.
+pub struct Pipeline {
+    // Replaced as a whole when the configuration is reloaded; a message
+    // already being mapped finishes with the mapper it started with
+    pub mapper: ArcSwap<Mapper>,
+    pub emitter: Arc<dyn Emitter>,
+    pub metrics: Arc<Metrics>,
+    pub dead_letters: Option<DeadLetterSink>,
+    pub dedup: Option<Deduplicator>,
+    pub change_detection: Option<ChangeDetector>,
+    pub merge: Option<StateMerger>,
+    pub expiry: Option<Expiry>,
+    pub reorder: Option<Reorderer>,
+    pub snapshot: Option<SnapshotCache>,
+    pub sequence: Option<Sequencer>,
+    pub checkpoints: Option<CheckpointStore>,
+    pub aggregator: Option<Arc<Aggregator>>,
+    pub retry_queue: Option<RetryQueue>,
+    pub max_payload_bytes: usize,
+    pub max_properties: Option<MaxPropertiesConfig>,
+    pub dead_letter_oversized: bool,
+    pub canonicalize: bool,
+    // Empty unless `redact_emitted` is set
+    pub redact: Vec<String>,
+    pub slow_message: Option<Duration>,
+}
+
+// What became of a message that was processed without error
+enum Outcome {
+    Emitted,
+    // Duplicated a recent message and was dropped
+    Deduplicated,
+    // Matched the last emission for its element and was dropped
+    Unchanged,
+    // Older than what was already emitted for its element and was dropped
+    OutOfOrder,
+    // The filter (or an empty exploded array) left nothing to emit
+    Filtered,
+    // The output failed it; the retry queue has it now
+    Parked,
+}
+
+impl Pipeline {
+    // Map the raw message (see `Mapper`) and hand the element to the emitter.
+    // Any outcome other than a successful emit counts as a failure, and the
+    // original message goes to the dead-letter sink when one is configured.
+    pub async fn process(&self, message: &Message) -> Result<(), MappingError> {
+        let span = debug_span!("ingest", topic = %message.topic, device_id = Empty);
+        telemetry::continue_trace(&span, &message.user_properties);
+        let started = Instant::now();
+        let result = self.ingest(message).instrument(span).await;
+        let elapsed = started.elapsed();
+        self.metrics.processing.observe(elapsed);
+        if self.slow_message.is_some_and(|threshold| elapsed > threshold) {
+            warn!(
+                event = "slow_message",
+                topic = %message.topic,
+                payload_bytes = message.payload.len(),
+                "Processing a {}-byte message from {} took {:?}",
+                message.payload.len(),
+                message.topic,
+                elapsed
+            );
+        }
+        result
+    }
+
+    async fn ingest(&self, message: &Message) -> Result<(), MappingError> {
+        if let Some(checkpoints) = &self.checkpoints {
+            if checkpoints.is_processed(message).await {
+                info!(
+                    event = "redelivered",
+                    topic = %message.topic,
+                    "Skipping redelivered packet {} on {}: already processed before the restart",
+                    message.pkid,
+                    message.topic
+                );
+                Metrics::inc(&self.metrics.redelivered);
+                return Ok(());
+            }
+        }
+
+        // Only matters when the queue is backed up: the publisher said the
+        // reading is worthless by now
+        if message.is_expired() {
+            debug!(
+                event = "expired",
+                topic = %message.topic,
+                "Dropping message from {}: its v5 message expiry interval has passed",
+                message.topic
+            );
+            Metrics::inc(&self.metrics.expired);
+            return Ok(());
+        }
+
+        // Checked before anything tries to decode it
+        if message.payload.len() > self.max_payload_bytes {
+            warn!(
+                event = "oversized",
+                topic = %message.topic,
+                "Dropping {}-byte payload from {}: larger than max_payload_bytes ({})",
+                message.payload.len(),
+                message.topic,
+                self.max_payload_bytes
+            );
+            Metrics::inc(&self.metrics.oversized);
+            if self.dead_letter_oversized {
+                let error = MappingError::Oversized {
+                    size: message.payload.len(),
+                    limit: self.max_payload_bytes,
+                };
+                self.dead_letter(&message.topic, &message.payload, &error).await;
+            }
+            return Ok(());
+        }
+
+        let result = self.map_and_emit(message).await;
+        match &result {
+            Ok(Outcome::Emitted) => Metrics::inc(&self.metrics.mapped),
+            Ok(Outcome::Deduplicated) => Metrics::inc(&self.metrics.deduplicated),
+            Ok(Outcome::Unchanged) => Metrics::inc(&self.metrics.unchanged),
+            Ok(Outcome::OutOfOrder) => Metrics::inc(&self.metrics.out_of_order),
+            Ok(Outcome::Parked) => Metrics::inc(&self.metrics.parked),
+            Ok(Outcome::Filtered) => Metrics::inc(&self.metrics.filtered),
+            Err(e) => {
+                match e {
+                    MappingError::TooDeep { .. } => Metrics::inc(&self.metrics.too_deep),
+                    MappingError::MissingId { .. } => Metrics::inc(&self.metrics.missing_id),
+                    _ => {}
+                }
+                Metrics::inc(&self.metrics.failed);
+                self.dead_letter(&message.topic, &message.payload, e).await;
+            }
+        }
+        // Failed messages aren't checkpointed, so a redelivery gets another go
+        if let (Ok(_), Some(checkpoints)) = (&result, &self.checkpoints) {
+            if let Err(e) = checkpoints.record(message).await {
+                error!(topic = %message.topic, "{:#}", e);
+            }
+        }
+        result.map(|_| ())
+    }
+
+    async fn map_and_emit(&self, message: &Message) -> Result<Outcome, MappingError> {
+        let mut changes = debug_span!("map").in_scope(|| self.mapper.load().map(message))?;
+        if let Some(GraphChange::Upsert(element)) = changes.first() {
+            Span::current().record("device_id", element.id.as_str());
+        }
+        if changes.is_empty() {
+            debug!(
+                event = "filtered",
+                topic = %message.topic,
+                "Dropping message from {}: nothing in it passes the filter",
+                message.topic
+            );
+            return Ok(Outcome::Filtered);
+        }
+
+        // Before anything remembers the element, so what is kept is what is
+        // emitted
+        if let Some(max) = &self.max_properties {
+            for change in &mut changes {
+                let GraphChange::Upsert(element) = change else {
+                    continue;
+                };
+                let count = mapping::count_properties(&element.properties, max.nested);
+                if count <= max.limit {
+                    continue;
+                }
+                Metrics::inc(&self.metrics.too_many_properties);
+                match max.mode {
+                    PropertyLimitMode::Reject => {
+                        warn!(
+                            event = "too_many_properties",
+                            topic = %message.topic,
+                            device_id = %element.id,
+                            "Rejecting {} from {}: {} properties, more than max_properties ({})",
+                            element.id,
+                            message.topic,
+                            count,
+                            max.limit
+                        );
+                        return Err(MappingError::TooManyProperties {
+                            id: element.id.clone(),
+                            count,
+                            limit: max.limit,
+                        });
+                    }
+                    PropertyLimitMode::Truncate => {
+                        warn!(
+                            event = "too_many_properties",
+                            topic = %message.topic,
+                            device_id = %element.id,
+                            "Truncating {} from {}: {} properties, more than max_properties ({})",
+                            element.id,
+                            message.topic,
+                            count,
+                            max.limit
+                        );
+                        mapping::truncate_properties(&mut element.properties, max.limit, max.nested);
+                    }
+                }
+            }
+        }
+
+        // Whatever is dropped below, the device was heard from
+        if let Some(expiry) = &self.expiry {
+            for change in &changes {
+                match change {
+                    GraphChange::Upsert(element) => expiry.touch(&element.id),
+                    GraphChange::Delete(delete) => expiry.forget(&delete.id),
+                    GraphChange::Relation(_) => {}
+                }
+            }
+        }
+
+        // Everything after this sees the element's readings in timestamp
+        // order; the next one for the ID waits until this one is emitted
+        let _turn = match (&self.reorder, changes.first()) {
+            (Some(reorder), Some(GraphChange::Upsert(element))) => match reorder.hold(element).await {
+                Hold::Late => {
+                    debug!(
+                        event = "out_of_order",
+                        topic = %message.topic,
+                        device_id = %element.id,
+                        "Dropping reading for {} from {}: older than one already emitted",
+                        element.id,
+                        message.topic
+                    );
+                    return Ok(Outcome::OutOfOrder);
+                }
+                Hold::Released(turn) => Some(turn),
+                Hold::Passed => None,
+            },
+            _ => None,
+        };
+
+        // Only updates are deduplicated; a delete always goes through
+        if let (Some(dedup), Some(GraphChange::Upsert(element))) = (&self.dedup, changes.first()) {
+            if dedup.is_duplicate(element, &message.payload) {
+                debug!(
+                    event = "deduplicated",
+                    topic = %message.topic,
+                    device_id = %element.id,
+                    "Dropping duplicate of {} from {}",
+                    element.id,
+                    message.topic
+                );
+                return Ok(Outcome::Deduplicated);
+            }
+        }
+
+        // Before change detection, so a delta repeating known values counts
+        // as unchanged
+        if let Some(merger) = &self.merge {
+            for change in &mut changes {
+                match change {
+                    GraphChange::Upsert(element) => merger.merge(element),
+                    GraphChange::Delete(delete) => merger.forget(&delete.id),
+                    GraphChange::Relation(_) => {}
+                }
+            }
+        }
+
+        // Unchanged upserts are dropped one by one; when none is left, so
+        // are the relations derived alongside them
+        if let Some(detector) = &self.change_detection {
+            let upserts = changes.iter().filter(|change| matches!(change, GraphChange::Upsert(_))).count();
+            changes.retain(|change| match change {
+                GraphChange::Upsert(element) => detector.has_changed(element),
+                GraphChange::Delete(delete) => {
+                    detector.forget(&delete.id);
+                    true
+                }
+                GraphChange::Relation(_) => true,
+            });
+            if upserts > 0 && !changes.iter().any(|change| matches!(change, GraphChange::Upsert(_))) {
+                debug!(
+                    event = "unchanged",
+                    topic = %message.topic,
+                    "Dropping message from {}: nothing changed since the last emission",
+                    message.topic
+                );
+                return Ok(Outcome::Unchanged);
+            }
+        }
+
+        // Aggregates are taken over the real values
+        for change in &mut changes {
+            match change {
+                GraphChange::Upsert(element) => {
+                    if let Some(expiry) = &self.expiry {
+                        expiry.stamp(element);
+                    }
+                    if let Some(sequence) = &self.sequence {
+                        sequence.stamp(element);
+                    }
+                    if self.canonicalize {
+                        mapping::canonicalize(element);
+                    }
+                    if let Some(aggregator) = &self.aggregator {
+                        aggregator.record(element);
+                    }
+                    redact::redact(&mut element.properties, &self.redact);
+                    if let Some(snapshot) = &self.snapshot {
+                        snapshot.remember(element);
+                    }
+                }
+                GraphChange::Relation(relation) => redact::redact_relation(relation, &self.redact),
+                GraphChange::Delete(delete) => {
+                    if let Some(snapshot) = &self.snapshot {
+                        snapshot.forget(&delete.id);
+                    }
+                }
+            }
+        }
+
+        // Whichever Emitter is configured takes it from here. If it fails and
+        // there is a retry queue, the rest of the message waits there.
+        async {
+            let mut pending = changes.into_iter();
+            while let Some(change) = pending.next() {
+                let kept = self.retry_queue.is_some().then(|| change.clone());
+                let Err(e) = emit::send(self.emitter.as_ref(), change).await else {
+                    continue;
+                };
+                if let (Some(queue), Some(change)) = (&self.retry_queue, kept) {
+                    let remaining = std::iter::once(change).chain(pending).collect();
+                    if queue.park(message, remaining).await {
+                        warn!(
+                            event = "parked",
+                            topic = %message.topic,
+                            "Emit failed for the message from {}, retrying later: {:#}",
+                            message.topic,
+                            e
+                        );
+                        return Ok(Outcome::Parked);
+                    }
+                }
+                return Err(MappingError::Emit(e));
+            }
+            Ok(Outcome::Emitted)
+        }
+        .instrument(debug_span!("emit"))
+        .await
+    }
+
+    // Also used by the retry queue, which only keeps the topic and payload.
+    // True once the sink has the record.
+    pub async fn dead_letter(&self, topic: &str, payload: &[u8], error: &MappingError) -> bool {
+        let Some(sink) = &self.dead_letters else {
+            return false;
+        };
+        let letter = DeadLetter::new(topic, payload, error);
+        match sink.send(&letter).await {
+            Ok(()) => {
+                Metrics::inc(&self.metrics.dead_lettered);
+                true
+            }
+            Err(e) => {
+                Metrics::inc(&self.metrics.dead_letter_failures);
+                error!(topic = %topic, "Failed to dead-letter message from {}: {:#}", topic, e);
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::emit::tests::Recording;
+    use crate::logging::tests::Captured;
+    use crate::model::{DrasiDelete, DrasiElement, DrasiRelation};
+    use async_trait::async_trait;
+    use rumqttc::QoS;
+    use std::sync::atomic::Ordering;
+
+    pub(crate) fn pipeline(config: &Config, emitter: Arc<Recording>) -> Pipeline {
+        Pipeline {
+            mapper: ArcSwap::from_pointee(Mapper::new(config).unwrap()),
+            emitter,
+            metrics: Arc::new(Metrics::default()),
+            dead_letters: None,
+            dedup: None,
+            change_detection: None,
+            merge: None,
+            expiry: None,
+            reorder: None,
+            snapshot: None,
+            sequence: None,
+            checkpoints: None,
+            aggregator: None,
+            retry_queue: None,
+            max_payload_bytes: config.max_payload_bytes,
+            max_properties: None,
+            dead_letter_oversized: false,
+            canonicalize: false,
+            redact: Vec::new(),
+            slow_message: None,
+        }
+    }
+
+    fn message(payload: &str) -> Message {
+        Message::from(rumqttc::Publish::new("sensors/temp-01", QoS::AtLeastOnce, payload.to_string()))
+    }
+
+    #[tokio::test]
+    async fn an_oversized_payload_is_dropped_before_decoding() {
+        let recording = Arc::new(Recording::default());
+        let pipeline = Pipeline {
+            max_payload_bytes: 16,
+            ..pipeline(&Config::default(), recording.clone())
+        };
+        pipeline.process(&message(r#"{"temperature": 21.5}"#)).await.unwrap();
+        pipeline.process(&message(r#"{"t": 21.5}"#)).await.unwrap();
+        assert_eq!(pipeline.metrics.oversized.load(Ordering::Relaxed), 1);
+        assert_eq!(recording.calls(), ["emit temp-01"]);
+    }
+
+    #[tokio::test]
+    async fn an_expired_message_is_dropped() {
+        let recording = Arc::new(Recording::default());
+        let pipeline = pipeline(&Config::default(), recording.clone());
+        let mut expired = message("{}");
+        expired.expires_at = Some(std::time::Instant::now());
+        pipeline.process(&expired).await.unwrap();
+        assert_eq!(pipeline.metrics.expired.load(Ordering::Relaxed), 1);
+        assert!(recording.calls().is_empty());
+    }
+
+    #[tokio::test]
+    async fn too_many_properties_are_rejected_or_truncated() {
+        let config = Config {
+            mapping: serde_yaml::from_str("include_mqtt_metadata: false\ntag_snapshots: false\ntimestamp: { enabled: false }").unwrap(),
+            ..Config::default()
+        };
+        let limit = |mode| MaxPropertiesConfig { limit: 2, mode, nested: false };
+        let recording = Arc::new(Recording::default());
+        let rejecting = Pipeline {
+            max_properties: Some(limit(PropertyLimitMode::Reject)),
+            ..pipeline(&config, recording.clone())
+        };
+        let error = rejecting.process(&message(r#"{"a": 1, "b": 2, "c": 3}"#)).await.unwrap_err();
+        assert!(matches!(error, MappingError::TooManyProperties { count: 3, limit: 2, .. }));
+        assert_eq!(rejecting.metrics.failed.load(Ordering::Relaxed), 1);
+        assert!(recording.calls().is_empty());
+
+        let truncating = Pipeline {
+            max_properties: Some(limit(PropertyLimitMode::Truncate)),
+            ..pipeline(&config, recording.clone())
+        };
+        truncating.process(&message(r#"{"a": 1, "b": 2, "c": 3}"#)).await.unwrap();
+        assert_eq!(truncating.metrics.too_many_properties.load(Ordering::Relaxed), 1);
+        assert_eq!(recording.calls(), ["emit temp-01"]);
+    }
+
+    #[tokio::test]
+    async fn a_change_refused_by_an_open_circuit_is_dead_lettered() {
+        use crate::config::{CircuitBreakerConfig, DeadLetterConfig};
+        use crate::emit::CircuitBreakerEmitter;
+
+        let path = std::env::temp_dir().join(format!("drasi-mqtt-breaker-dlq-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let recording = Arc::new(Recording::default());
+        let breaker = CircuitBreakerEmitter::new(
+            Box::new(recording.clone()),
+            &CircuitBreakerConfig {
+                failure_threshold: 1,
+                cooldown_ms: 60_000,
+            },
+            Arc::new(Metrics::default()),
+        );
+        let pipeline = Pipeline {
+            emitter: Arc::new(breaker),
+            dead_letters: Some(DeadLetterSink::build(&DeadLetterConfig::File { path: path.clone() }, None).await.unwrap()),
+            ..pipeline(&Config::default(), recording.clone())
+        };
+        // Opens the circuit
+        recording.failing.store(true, Ordering::Relaxed);
+        assert!(pipeline.process(&message(r#"{"temperature": 21.5}"#)).await.is_err());
+        recording.failing.store(false, Ordering::Relaxed);
+
+        let error = pipeline.process(&message(r#"{"temperature": 22.0}"#)).await.unwrap_err();
+        assert!(error.to_string().starts_with("circuit open"));
+        assert!(recording.calls().is_empty());
+        assert_eq!(pipeline.metrics.failed.load(Ordering::Relaxed), 2);
+        let lines = std::fs::read_to_string(&path).unwrap();
+        let letters: Vec<serde_json::Value> = lines.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(letters.len(), 2);
+        assert_eq!(letters[1]["payload"], r#"{"temperature": 22.0}"#);
+        assert!(letters[1]["error"].as_str().unwrap().starts_with("circuit open"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    // Takes two seconds over every change
+    struct Slow;
+
+    #[async_trait]
+    impl Emitter for Slow {
+        async fn emit(&self, _element: DrasiElement) -> Result<()> {
+            tokio::time::sleep(Duration::from_secs(2)).await;
+            Ok(())
+        }
+
+        async fn delete(&self, _delete: DrasiDelete) -> Result<()> {
+            tokio::time::sleep(Duration::from_secs(2)).await;
+            Ok(())
+        }
+
+        async fn emit_relation(&self, _relation: DrasiRelation) -> Result<()> {
+            tokio::time::sleep(Duration::from_secs(2)).await;
+            Ok(())
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_message_slower_than_the_threshold_is_reported() {
+        let captured = Captured::default();
+        let _guard = captured.set_default("json");
+        let pipeline = Pipeline {
+            emitter: Arc::new(Slow),
+            slow_message: Some(Duration::from_secs(1)),
+            ..pipeline(&Config::default(), Arc::new(Recording::default()))
+        };
+        pipeline.process(&message(r#"{"temperature": 21.5}"#)).await.unwrap();
+
+        let slow: Vec<serde_json::Value> = captured
+            .text()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .filter(|line: &serde_json::Value| line["event"] == "slow_message")
+            .collect();
+        assert_eq!(slow.len(), 1, "{}", captured.text());
+        assert_eq!(slow[0]["level"], "WARN");
+        assert_eq!(slow[0]["topic"], "sensors/temp-01");
+        assert_eq!(slow[0]["payload_bytes"], 21);
+        assert!(pipeline.metrics.render().contains("drasi_mqtt_processing_seconds_count 1\n"));
+    }
+}
@@ -0,0 +1,102 @@
+use anyhow::{anyhow, bail, Result};
+use rumqttc::QoS;
+use serde_json::json;
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::connection::{self, SourceEvent};
+
+// Covers connecting, publishing and the broker's reply
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+// --- PUBLISH TEST ---
+// `--publish-test`: a single QoS 1 publish over the same transport, TLS,
+// credentials and protocol version the source would use, which proves the
+// deployment can reach and authenticate to the broker. Unlike the source it
+// gives up on the first connection error instead of retrying.
+pub async fn publish_test(config: &Config, topic: &str) -> Result<()> {
+    // A throwaway identity: a fixed client_id would kick a running source
+    // off the broker, and a will would announce it offline
+    let mut config = config.clone();
+    config.client_id = None;
+    config.clean_session = true;
+    config.lwt_topic = None;
+
+    let (client, mut eventloop) = connection::create_client(&config)?;
+    let payload = json!({
+        "id": "drasi-mqtt-publish-test",
+        "test": true,
+        "sent_at": chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+    });
+    // Queued until the event loop has connected
+    client
+        .publish(topic.to_string(), QoS::AtLeastOnce, false, payload.to_string().into_bytes())
+        .await?;
+
+    let acknowledged: Result<Result<u16, String>> = tokio::time::timeout(TIMEOUT, async {
+        loop {
+            match eventloop.poll().await? {
+                SourceEvent::Connected { .. } => println!("Connected to {}:{}", config.broker_host, config.port()),
+                SourceEvent::PubAck(result) => return Ok(result),
+                _ => {}
+            }
+        }
+    })
+    .await
+    .map_err(|_| anyhow!("broker did not acknowledge the test message within {:?}", TIMEOUT))?;
+
+    let pkid = match acknowledged {
+        Ok(Ok(pkid)) => pkid,
+        Ok(Err(reason)) => bail!("broker rejected the test message: {}", reason),
+        Err(e) => return Err(e.context("cannot connect to the MQTT broker")),
+    };
+    println!("Published test message to {}; broker acknowledged packet {}", topic, pkid);
+
+    // Best effort: the test already passed
+    if client.disconnect().await.is_ok() {
+        let _ = tokio::time::timeout(Duration::from_secs(1), async {
+            while let Ok(event) = eventloop.poll().await {
+                if let SourceEvent::DisconnectSent = event {
+                    break;
+                }
+            }
+        })
+        .await;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::tests::{MockBroker, Session};
+    use rumqttc::mqttbytes::v4::Packet;
+
+    #[tokio::test]
+    async fn the_test_message_is_published_and_acknowledged() {
+        let broker = MockBroker::start(vec![Session::Accept]).await;
+        publish_test(&broker.config(), "drasi/publish-test").await.unwrap();
+
+        let published = broker.received().into_iter().find_map(|(_, packet)| match packet {
+            Packet::Publish(publish) => Some(publish),
+            _ => None,
+        });
+        let published = published.unwrap();
+        assert_eq!((published.topic.as_str(), published.qos), ("drasi/publish-test", QoS::AtLeastOnce));
+        let payload: serde_json::Value = serde_json::from_slice(&published.payload).unwrap();
+        assert_eq!(payload["test"], true);
+    }
+
+    #[tokio::test]
+    async fn an_unreachable_broker_fails_the_test() {
+        // Free a port so nothing listens on it
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let config = Config {
+            broker_host: "127.0.0.1".to_string(),
+            broker_port: Some(port),
+            ..Config::default()
+        };
+        let error = publish_test(&config, "drasi/publish-test").await.unwrap_err();
+        assert!(error.to_string().contains("cannot connect to the MQTT broker"), "{:#}", error);
+    }
+}
@@ -0,0 +1,105 @@
+use serde_json::json;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+use crate::config::ReconnectNoticeConfig;
+use crate::connection::ConnectionState;
+use crate::emit::Emitter;
+use crate::model::{DrasiElement, ElementOp};
+
+// --- RECONNECT NOTICE ---
+// Downstream may have missed live updates while the broker connection was
+// down, so every reconnect (not the first connect) is announced with a
+// synthetic element: `id` labelled `label`, with when the connection was
+// lost, when it came back and the downtime in between. With several brokers
+// each follows its own connection, and its name is added to the ID and as
+// `broker`.
+pub fn start(
+    config: &ReconnectNoticeConfig,
+    broker: Option<Arc<str>>,
+    mut state: watch::Receiver<ConnectionState>,
+    emitter: Arc<dyn Emitter>,
+    tag_snapshots: bool,
+) {
+    let id = match &broker {
+        Some(broker) => format!("{}:{}", config.id, broker),
+        None => config.id.clone(),
+    };
+    let label = config.label.clone();
+    tokio::spawn(async move {
+        let mut connected_before = false;
+        // When the connection was lost, while it is
+        let mut lost: Option<(Instant, chrono::DateTime<chrono::Utc>)> = None;
+        loop {
+            let current = *state.borrow_and_update();
+            match current {
+                ConnectionState::Connected => {
+                    if let Some((since, at)) = lost.take() {
+                        let downtime = since.elapsed();
+                        info!("Reconnected after {:?}; emitting {} as {}", downtime, label, id);
+                        let mut properties = json!({
+                            "timestamp": timestamp(chrono::Utc::now()),
+                            "disconnected_at": timestamp(at),
+                            "downtime_ms": downtime.as_millis() as u64,
+                        });
+                        if let Some(broker) = &broker {
+                            properties["broker"] = json!(broker.as_ref());
+                        }
+                        let element = DrasiElement {
+                            id: id.clone(),
+                            element_type: None,
+                            labels: vec![label.clone()],
+                            properties,
+                            op: tag_snapshots.then_some(ElementOp::Update),
+                        };
+                        if let Err(e) = emitter.emit(element).await {
+                            warn!(event = "reconnect_notice_failed", "Failed to emit reconnect notice: {:#}", e);
+                        }
+                    }
+                    connected_before = true;
+                }
+                _ if connected_before && lost.is_none() => lost = Some((Instant::now(), chrono::Utc::now())),
+                _ => {}
+            }
+            if state.changed().await.is_err() {
+                break;
+            }
+        }
+    });
+}
+
+fn timestamp(at: chrono::DateTime<chrono::Utc>) -> String {
+    at.to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emit::tests::Recording;
+    use std::time::Duration;
+
+    #[tokio::test(start_paused = true)]
+    async fn only_a_reconnect_is_announced() {
+        let recording = Arc::new(Recording::default());
+        let (sender, receiver) = watch::channel(ConnectionState::Connecting);
+        let config = ReconnectNoticeConfig {
+            id: "mqtt-reconnect".to_string(),
+            label: "Reconnect".to_string(),
+        };
+        start(&config, Some(Arc::from("edge")), receiver, Arc::new(recording.clone()), false);
+
+        // Each state is given time to be seen before the next one
+        for state in [ConnectionState::Connected, ConnectionState::Disconnected, ConnectionState::Reconnecting] {
+            sender.send(state).unwrap();
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        // The first connect is not a reconnect
+        assert!(recording.calls().is_empty());
+
+        sender.send(ConnectionState::Connected).unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(recording.calls(), ["emit mqtt-reconnect:edge"]);
+    }
+}
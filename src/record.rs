@@ -0,0 +1,147 @@
+use anyhow::{Context, Result};
+use base64::Engine;
+use tracing::{error, info};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::Mutex;
+
+use crate::mapping::qos_level;
+use crate::message::Message;
+
+// How often buffered lines are pushed to disk while recording
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+// --- RECORDER ---
+// Tees every incoming message to a JSON Lines capture in the same format
+// `--replay` reads, so production traffic can be turned into fixtures.
+// Lines are buffered and flushed every second and on shutdown.
+pub struct Recorder {
+    path: PathBuf,
+    writer: Mutex<BufWriter<File>>,
+}
+
+// One line of the capture. UTF-8 bodies are kept as text in `payload`
+// (replay sends strings verbatim, so JSON round-trips byte for byte);
+// anything else goes in `payload_base64`. Empty bodies have neither.
+#[derive(Serialize)]
+struct CaptureRecord<'a> {
+    topic: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    payload: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    payload_base64: Option<String>,
+    qos: u8,
+    retain: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_type: Option<&'a str>,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    user_properties: &'a [(String, String)],
+    received_at: String,
+}
+
+impl Recorder {
+    // Appends to an existing capture rather than truncating it
+    pub async fn create(path: &Path) -> Result<Arc<Self>> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await
+            .with_context(|| format!("Failed to open capture file {}", path.display()))?;
+        info!("Recording incoming messages to {}", path.display());
+        let recorder = Arc::new(Recorder {
+            path: path.to_path_buf(),
+            writer: Mutex::new(BufWriter::new(file)),
+        });
+
+        // Holds only a weak handle so the task ends with the recorder
+        let weak = Arc::downgrade(&recorder);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(FLUSH_INTERVAL);
+            loop {
+                ticker.tick().await;
+                let Some(recorder) = weak.upgrade() else {
+                    break;
+                };
+                if let Err(e) = recorder.flush().await {
+                    error!("{:#}", e);
+                }
+            }
+        });
+        Ok(recorder)
+    }
+
+    pub async fn record(&self, message: &Message) -> Result<()> {
+        let text = std::str::from_utf8(&message.payload).ok();
+        let record = CaptureRecord {
+            topic: &message.topic,
+            payload: text.filter(|text| !text.is_empty()),
+            payload_base64: match text {
+                Some(_) => None,
+                None => Some(base64::engine::general_purpose::STANDARD.encode(&message.payload)),
+            },
+            qos: qos_level(message.qos),
+            retain: message.retain,
+            content_type: message.content_type.as_deref(),
+            user_properties: &message.user_properties,
+            received_at: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        };
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+        self.writer
+            .lock()
+            .await
+            .write_all(&line)
+            .await
+            .with_context(|| format!("Failed to write to capture file {}", self.path.display()))
+    }
+
+    pub async fn flush(&self) -> Result<()> {
+        self.writer
+            .lock()
+            .await
+            .flush()
+            .await
+            .with_context(|| format!("Failed to flush capture file {}", self.path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rumqttc::QoS;
+    use serde_json::{json, Value};
+
+    #[tokio::test]
+    async fn messages_are_captured_as_replayable_lines() {
+        let path = std::env::temp_dir().join(format!("drasi-mqtt-record-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let recorder = Recorder::create(&path).await.unwrap();
+        let mut text = Message::from(rumqttc::Publish::new("sensors/a", QoS::ExactlyOnce, r#"{"t":21.5}"#));
+        text.retain = true;
+        text.user_properties = vec![("site".to_string(), "lab".to_string())];
+        recorder.record(&text).await.unwrap();
+        recorder.record(&Message::from(rumqttc::Publish::new("sensors/b", QoS::AtMostOnce, vec![0xff]))).await.unwrap();
+        recorder.record(&Message::from(rumqttc::Publish::new("sensors/c", QoS::AtLeastOnce, ""))).await.unwrap();
+        recorder.flush().await.unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let mut lines: Vec<Value> = contents.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        for line in &mut lines {
+            assert!(line.as_object_mut().unwrap().remove("received_at").is_some());
+        }
+        assert_eq!(
+            lines,
+            [
+                json!({ "topic": "sensors/a", "payload": "{\"t\":21.5}", "qos": 2, "retain": true, "user_properties": [["site", "lab"]] }),
+                json!({ "topic": "sensors/b", "payload_base64": "/w==", "qos": 0, "retain": false }),
+                json!({ "topic": "sensors/c", "qos": 1, "retain": false }),
+            ]
+        );
+    }
+}
@@ -0,0 +1,63 @@
+use serde_json::Value;
+
+use crate::model::DrasiRelation;
+
+// What a redacted value is replaced with
+pub const REDACTED: &str = "***";
+
+// --- REDACTION ---
+// Replaces the values at the given JSON pointers into an element's
+// properties (e.g. `/owner/email`) with "***". Pointers that don't resolve
+// are skipped, so a field that is only sometimes present needs no special
+// care.
+pub fn redact(properties: &mut Value, pointers: &[String]) {
+    for pointer in pointers {
+        if let Some(value) = properties.pointer_mut(pointer) {
+            *value = Value::String(REDACTED.to_string());
+        }
+    }
+}
+
+// The same for a relation, whose properties are a map rather than a Value
+pub fn redact_relation(relation: &mut DrasiRelation, pointers: &[String]) {
+    for pointer in pointers {
+        let Some((first, rest)) = pointer.strip_prefix('/').map(|path| path.split_once('/').unwrap_or((path, ""))) else {
+            continue;
+        };
+        let key = first.replace("~1", "/").replace("~0", "~");
+        let value = match relation.properties.get_mut(&key) {
+            Some(value) if rest.is_empty() => Some(value),
+            Some(value) => value.pointer_mut(&format!("/{}", rest)),
+            None => None,
+        };
+        if let Some(value) = value {
+            *value = Value::String(REDACTED.to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn values_at_the_pointers_are_replaced() {
+        let mut properties = json!({ "owner": { "email": "a@example.com", "name": "A" }, "t": 21.5 });
+        redact(&mut properties, &["/owner/email".to_string(), "/missing".to_string()]);
+        assert_eq!(properties, json!({ "owner": { "email": "***", "name": "A" }, "t": 21.5 }));
+    }
+
+    #[test]
+    fn relation_properties_are_redacted_too() {
+        let mut relation = DrasiRelation {
+            id: "r".to_string(),
+            start_id: "a".to_string(),
+            end_id: "b".to_string(),
+            label: "OWNS".to_string(),
+            properties: json!({ "a/b": 1, "contact": { "phone": "123" }, "kept": true }).as_object().unwrap().clone(),
+        };
+        redact_relation(&mut relation, &["/a~1b".to_string(), "/contact/phone".to_string(), "/none".to_string()]);
+        assert_eq!(Value::Object(relation.properties), json!({ "a/b": "***", "contact": { "phone": "***" }, "kept": true }));
+    }
+}
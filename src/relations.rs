@@ -0,0 +1,112 @@
+use anyhow::{bail, Result};
+use serde_json::Map;
+
+use crate::config::{HierarchyRule, RelationRule};
+use crate::model::DrasiRelation;
+use crate::template::TopicTemplate;
+
+// --- TOPIC HIERARCHY ---
+// Topic trees often encode containment, e.g.
+// `lfx/drasi/buildings/b1/rooms/r2/sensors/temp-01`. A rule's pattern names
+// the entity segments (`buildings/{building}/rooms/{room}/sensors/{sensor}`)
+// and says which captured entities are connected by which edge label.
+pub struct TopicHierarchy {
+    rules: Vec<CompiledRule>,
+}
+
+struct CompiledRule {
+    template: TopicTemplate,
+    relations: Vec<RelationRule>,
+}
+
+impl TopicHierarchy {
+    // Patterns are checked at startup so a typo in a relation's `from`/`to`
+    // fails loudly instead of silently producing no edges
+    pub fn compile(rules: &[HierarchyRule]) -> Result<Self> {
+        let mut compiled = Vec::with_capacity(rules.len());
+        for rule in rules {
+            let template = TopicTemplate::parse(&rule.pattern)?;
+            for relation in &rule.relations {
+                for name in [&relation.from, &relation.to] {
+                    if !template.has_capture(name) {
+                        bail!("topic_hierarchy pattern {:?} has no {{{}}} segment", template.pattern(), name);
+                    }
+                }
+            }
+            compiled.push(CompiledRule {
+                template,
+                relations: rule.relations.clone(),
+            });
+        }
+        Ok(TopicHierarchy { rules: compiled })
+    }
+
+    // Relations from the first rule whose pattern matches, or none
+    pub fn relations_for(&self, topic: &str) -> Vec<DrasiRelation> {
+        for rule in &self.rules {
+            let Some(captures) = rule.template.bind(topic) else {
+                continue;
+            };
+            return rule
+                .relations
+                .iter()
+                .map(|relation| {
+                    let start_id = captures[relation.from.as_str()].to_string();
+                    let end_id = captures[relation.to.as_str()].to_string();
+                    DrasiRelation {
+                        id: format!("{}-{}-{}", start_id, relation.label, end_id),
+                        start_id,
+                        end_id,
+                        label: relation.label.clone(),
+                        properties: Map::new(),
+                    }
+                })
+                .collect();
+        }
+        Vec::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hierarchy(from: &str) -> Result<TopicHierarchy> {
+        TopicHierarchy::compile(&[HierarchyRule {
+            pattern: "lfx/drasi/buildings/{building}/rooms/{room}/sensors/{sensor}".to_string(),
+            relations: vec![
+                RelationRule {
+                    from: from.to_string(),
+                    to: "room".to_string(),
+                    label: "IN_ROOM".to_string(),
+                },
+                RelationRule {
+                    from: "room".to_string(),
+                    to: "building".to_string(),
+                    label: "IN_BUILDING".to_string(),
+                },
+            ],
+        }])
+    }
+
+    #[test]
+    fn a_matching_topic_implies_its_relations() {
+        let relations = hierarchy("sensor").unwrap().relations_for("lfx/drasi/buildings/b1/rooms/r2/sensors/temp-01");
+        let edges: Vec<_> = relations
+            .iter()
+            .map(|relation| (relation.id.as_str(), relation.start_id.as_str(), relation.end_id.as_str()))
+            .collect();
+        assert_eq!(edges, [("temp-01-IN_ROOM-r2", "temp-01", "r2"), ("r2-IN_BUILDING-b1", "r2", "b1")]);
+    }
+
+    #[test]
+    fn other_topics_imply_none() {
+        assert!(hierarchy("sensor").unwrap().relations_for("lfx/drasi/sensors/temp-01").is_empty());
+    }
+
+    #[test]
+    fn unknown_entities_are_refused() {
+        let error = hierarchy("device").err().unwrap();
+        assert!(error.to_string().contains("has no {device} segment"));
+    }
+}
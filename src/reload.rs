@@ -0,0 +1,159 @@
+use anyhow::{bail, Result};
+use tracing::{error, info, warn};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::task::JoinHandle;
+
+use crate::config::{self, Config, MappingConfig};
+use crate::health::Health;
+use crate::mapping::Mapper;
+use crate::pipeline::Pipeline;
+
+// --- CONFIG RELOAD ---
+// On SIGHUP (Unix only) the YAML file is read again and its `mapping`
+// section (label rules, filters, field maps, the script, ...) replaces the
+// running one, without touching the broker session. Everything else needs a
+// restart: changes there are logged and ignored. A file that no longer loads
+// or validates leaves the current mapping in place, and so does one changing
+// the mapping settings that parts of the pipeline were built with at startup
+// (the ingestion timestamp and `tag_snapshots`).
+pub fn start(path: PathBuf, config: Config, pipeline: Arc<Pipeline>, health: Arc<Health>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        #[cfg(unix)]
+        {
+            let mut hangup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+                Ok(hangup) => hangup,
+                Err(e) => {
+                    error!("Failed to listen for SIGHUP: {}", e);
+                    return;
+                }
+            };
+            let mut current = config;
+            while hangup.recv().await.is_some() {
+                info!("Received SIGHUP, reloading the mapping from {}", path.display());
+                match apply(&path, &current, &pipeline, &health) {
+                    Ok(next) => {
+                        current = next;
+                        info!("Reloaded the mapping; messages from now on use it");
+                    }
+                    Err(e) => error!("Keeping the current mapping: {:#}", e),
+                }
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = (path, config, pipeline, health);
+    })
+}
+
+// Swaps in the file's mapping; returns the configuration now in effect
+fn apply(path: &Path, current: &Config, pipeline: &Pipeline, health: &Health) -> Result<Config> {
+    let (next, mapper) = reload(path, current)?;
+    pipeline.mapper.store(Arc::new(mapper));
+    health.set_mapping(&next.mapping);
+    Ok(next)
+}
+
+// The running configuration with the file's mapping section, and its Mapper
+fn reload(path: &Path, current: &Config) -> Result<(Config, Mapper)> {
+    let reloaded = config::from_yaml(path)?;
+    reloaded.validate()?;
+    // Change detection, expiry, aggregations, heartbeats and reconnect
+    // notices took these at startup and would go on with the old ones
+    let (before, after) = (&current.mapping.timestamp, &reloaded.mapping.timestamp);
+    if (before.enabled, &before.key, before.format) != (after.enabled, &after.key, after.format) {
+        bail!("mapping.timestamp.enabled, key and format can only be changed with a restart");
+    }
+    if current.mapping.tag_snapshots != reloaded.mapping.tag_snapshots {
+        bail!("mapping.tag_snapshots can only be changed with a restart");
+    }
+    if outside_mapping(current) != outside_mapping(&reloaded) {
+        warn!(
+            "Settings outside `mapping` changed in {}; they are ignored until the source is restarted",
+            path.display()
+        );
+    }
+    let mut next = current.clone();
+    next.mapping = reloaded.mapping;
+    let mapper = Mapper::new(&next)?;
+    Ok((next, mapper))
+}
+
+// Config has no PartialEq, and its Debug output is deterministic once the
+// mapping (which holds the only HashMap) is out of the way
+fn outside_mapping(config: &Config) -> String {
+    let mut config = config.clone();
+    config.mapping = MappingConfig::default();
+    format!("{:?}", config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_mapping_is_taken_from_the_file() {
+        let path = std::env::temp_dir().join(format!("drasi-mqtt-reload-{}.yaml", std::process::id()));
+        std::fs::write(&path, "source:\n  broker: localhost\n  subscriptions:\n    - topic: sensors/#\n").unwrap();
+        let current = config::from_yaml(&path).unwrap();
+
+        std::fs::write(
+            &path,
+            "source:\n  broker: other.example\n  subscriptions:\n    - topic: sensors/#\n  mapping:\n    default_labels: [Gauge]\n",
+        )
+        .unwrap();
+        let (next, _) = reload(&path, &current).unwrap();
+        assert_eq!(next.mapping.default_labels, ["Gauge"]);
+        // Needs a restart
+        assert_eq!(next.broker_host, "localhost");
+
+        // A file that no longer loads changes nothing
+        std::fs::write(&path, "source: [").unwrap();
+        assert!(reload(&path, &next).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn messages_after_a_reload_use_the_new_mapping() {
+        use crate::emit::tests::Recording;
+        use crate::message::Message;
+        use crate::pipeline::tests::pipeline;
+
+        let path = std::env::temp_dir().join(format!("drasi-mqtt-reload-apply-{}.yaml", std::process::id()));
+        std::fs::write(&path, "source:\n  broker: localhost\n  subscriptions:\n    - topic: sensors/#\n").unwrap();
+        let current = config::from_yaml(&path).unwrap();
+        let recording = Arc::new(Recording::default());
+        let pipeline = pipeline(&current, recording.clone());
+        let health = Health::default();
+        let reading = || Message::from(rumqttc::Publish::new("sensors/temp-01", rumqttc::QoS::AtLeastOnce, "{}"));
+        pipeline.process(&reading()).await.unwrap();
+
+        std::fs::write(
+            &path,
+            "source:\n  broker: localhost\n  subscriptions:\n    - topic: sensors/#\n  mapping:\n    id_transform: { prefix: \"plant-a:\" }\n",
+        )
+        .unwrap();
+        apply(&path, &current, &pipeline, &health).unwrap();
+        pipeline.process(&reading()).await.unwrap();
+        assert_eq!(recording.calls(), ["emit temp-01", "emit plant-a:temp-01"]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn settings_built_in_at_startup_are_refused() {
+        let path = std::env::temp_dir().join(format!("drasi-mqtt-reload-refused-{}.yaml", std::process::id()));
+        let yaml = "source:\n  broker: localhost\n  subscriptions:\n    - topic: sensors/#\n";
+        std::fs::write(&path, yaml).unwrap();
+        let current = config::from_yaml(&path).unwrap();
+
+        std::fs::write(&path, format!("{}  mapping:\n    timestamp: {{ key: received_at }}\n", yaml)).unwrap();
+        let error = reload(&path, &current).err().unwrap();
+        assert!(error.to_string().contains("mapping.timestamp.enabled, key and format can only be changed with a restart"));
+        std::fs::write(&path, format!("{}  mapping:\n    tag_snapshots: {}\n", yaml, !current.mapping.tag_snapshots)).unwrap();
+        let error = reload(&path, &current).err().unwrap();
+        assert!(error.to_string().contains("mapping.tag_snapshots can only be changed with a restart"));
+        // Where the payload's own timestamp is found is the mapper's business
+        std::fs::write(&path, format!("{}  mapping:\n    timestamp: {{ event_time_pointer: /ts }}\n", yaml)).unwrap();
+        reload(&path, &current).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
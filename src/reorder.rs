@@ -0,0 +1,289 @@
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+use crate::config::ReorderConfig;
+use crate::model::DrasiElement;
+
+// Fewest tracked IDs before idle ones are swept out
+const MIN_SWEEP: usize = 1024;
+
+// --- REORDERING ---
+// Holds each element for up to `latency_ms` before it moves on, so a reading
+// that overtook an older one on its way here waits for it. Per element ID,
+// the held elements are let through in the order of their timestamp (a
+// number, or an RFC 3339 string, at `timestamp_pointer`), one at a time.
+//
+// The wait ends early when a later reading for the same ID has waited its
+// full latency, or when `capacity` readings are already held for the ID.
+// A reading older than one already let through is too late to be put in
+// its place and is dropped. Elements without a usable timestamp aren't held.
+// An ID with nothing held is forgotten once it has been idle for
+// `latency_ms`, so lateness is only judged against recent readings.
+pub struct Reorderer {
+    latency: Duration,
+    capacity: usize,
+    pointer: String,
+    state: Mutex<State>,
+    // Woken whenever a held element may have become free to go
+    changed: Notify,
+}
+
+#[derive(Default)]
+struct State {
+    next_ticket: u64,
+    ids: HashMap<String, Held>,
+    // Sweep out idle IDs once this many are tracked
+    sweep_at: usize,
+}
+
+impl State {
+    // Twice as many IDs as are left have to show up before the next sweep,
+    // so it costs O(1) per element
+    fn sweep(&mut self, latency: Duration) {
+        let now = Instant::now();
+        self.ids.retain(|_, held| {
+            let idle = held.waiting.is_empty() && held.releasing.is_none();
+            !idle || held.idle_since.is_none_or(|since| now.duration_since(since) < latency)
+        });
+        self.sweep_at = (self.ids.len() * 2).max(MIN_SWEEP);
+    }
+}
+
+#[derive(Default)]
+struct Held {
+    // (ticket, timestamp, when it may go regardless)
+    waiting: Vec<(u64, f64, Instant)>,
+    // The one currently on its way to the output, if any
+    releasing: Option<(u64, f64)>,
+    // Timestamp of the newest element let through
+    last: Option<f64>,
+    // When that one was done with
+    idle_since: Option<Instant>,
+}
+
+pub enum Hold<'a> {
+    // Older than what was already let through
+    Late,
+    // Free to go; the next one for the ID waits until this is dropped
+    Released(Release<'a>),
+    // No timestamp to order by
+    Passed,
+}
+
+pub struct Release<'a> {
+    reorderer: &'a Reorderer,
+    id: String,
+    ticket: u64,
+}
+
+impl Reorderer {
+    pub fn new(config: &ReorderConfig) -> Self {
+        Reorderer {
+            latency: Duration::from_millis(config.latency_ms),
+            capacity: config.capacity,
+            pointer: config.timestamp_pointer.clone(),
+            state: Mutex::new(State::default()),
+            changed: Notify::new(),
+        }
+    }
+
+    // Waits for the element's turn
+    pub async fn hold(&self, element: &DrasiElement) -> Hold<'_> {
+        let Some(timestamp) = element.properties.pointer(&self.pointer).and_then(timestamp) else {
+            return Hold::Passed;
+        };
+        let ticket = {
+            let mut state = self.state.lock().expect("reorder lock poisoned");
+            if state.ids.len() >= state.sweep_at {
+                state.sweep(self.latency);
+            }
+            let ticket = state.next_ticket;
+            state.next_ticket += 1;
+            let held = state.ids.entry(element.id.clone()).or_default();
+            let newest = held.releasing.map(|(_, released)| released).into_iter().chain(held.last);
+            if newest.into_iter().any(|released| timestamp < released) {
+                return Hold::Late;
+            }
+            // A full buffer lets its oldest readings through right away
+            let deadline = if held.waiting.len() >= self.capacity {
+                Instant::now()
+            } else {
+                Instant::now() + self.latency
+            };
+            held.waiting.push((ticket, timestamp, deadline));
+            ticket
+        };
+
+        // Said once, so waiters don't keep waking each other
+        let mut announced = false;
+        loop {
+            let changed = self.changed.notified();
+            tokio::pin!(changed);
+            changed.as_mut().enable();
+            let deadline = {
+                let mut state = self.state.lock().expect("reorder lock poisoned");
+                let held = state.ids.get_mut(&element.id).expect("held element vanished");
+                let now = Instant::now();
+                let (position, &(_, _, deadline)) = held
+                    .waiting
+                    .iter()
+                    .enumerate()
+                    .find(|(_, entry)| entry.0 == ticket)
+                    .expect("held element vanished");
+                let oldest = held.waiting.iter().all(|other| other.1 >= timestamp);
+                let due = held.waiting.iter().any(|other| other.1 >= timestamp && other.2 <= now);
+                if oldest && due && held.releasing.is_none() {
+                    held.waiting.remove(position);
+                    held.releasing = Some((ticket, timestamp));
+                    return Hold::Released(Release {
+                        reorderer: self,
+                        id: element.id.clone(),
+                        ticket,
+                    });
+                }
+                // Due but behind an older one: that one may go now
+                if deadline <= now && !announced {
+                    announced = true;
+                    self.changed.notify_waiters();
+                }
+                deadline
+            };
+            if deadline <= Instant::now() {
+                changed.await;
+            } else {
+                tokio::select! {
+                    _ = changed => {}
+                    _ = tokio::time::sleep_until(deadline) => {}
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Release<'_> {
+    fn drop(&mut self) {
+        let mut state = self.reorderer.state.lock().expect("reorder lock poisoned");
+        if let Some(held) = state.ids.get_mut(&self.id) {
+            if let Some((ticket, timestamp)) = held.releasing {
+                if ticket == self.ticket {
+                    held.releasing = None;
+                    held.last = Some(held.last.map_or(timestamp, |last| last.max(timestamp)));
+                    held.idle_since = Some(Instant::now());
+                }
+            }
+        }
+        self.reorderer.changed.notify_waiters();
+    }
+}
+
+// Epoch numbers in any unit compare fine among themselves; strings are
+// taken as RFC 3339 and compared in milliseconds
+fn timestamp(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(number) => number.as_f64(),
+        Value::String(text) => chrono::DateTime::parse_from_rfc3339(text)
+            .ok()
+            .map(|time| time.timestamp_millis() as f64),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::sync::Arc;
+
+    fn reorderer(capacity: usize) -> Arc<Reorderer> {
+        Arc::new(Reorderer::new(&ReorderConfig {
+            timestamp_pointer: "/ts".to_string(),
+            latency_ms: 1000,
+            capacity,
+        }))
+    }
+
+    fn reading(id: &str, ts: Value) -> DrasiElement {
+        DrasiElement {
+            id: id.to_string(),
+            element_type: None,
+            labels: vec!["Sensor".to_string()],
+            properties: json!({ "ts": ts }),
+            op: None,
+        }
+    }
+
+    // Holds the reading on its own task and writes down its timestamp once
+    // it is let through
+    async fn spawn_hold(reorderer: &Arc<Reorderer>, out: &Arc<Mutex<Vec<i64>>>, ts: i64) {
+        let (reorderer, out) = (reorderer.clone(), out.clone());
+        tokio::spawn(async move {
+            if let Hold::Released(_release) = reorderer.hold(&reading("a", json!(ts))).await {
+                out.lock().unwrap().push(ts);
+            }
+        });
+        // Lets it take its place before the next one arrives
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn readings_go_out_in_timestamp_order() {
+        let reorderer = reorderer(10);
+        let out = Arc::new(Mutex::new(Vec::new()));
+        for ts in [3, 1, 2] {
+            spawn_hold(&reorderer, &out, ts).await;
+        }
+        assert!(out.lock().unwrap().is_empty());
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        assert_eq!(*out.lock().unwrap(), [1, 2, 3]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_full_buffer_lets_the_oldest_through() {
+        let reorderer = reorderer(1);
+        let out = Arc::new(Mutex::new(Vec::new()));
+        spawn_hold(&reorderer, &out, 2).await;
+        spawn_hold(&reorderer, &out, 1).await;
+        assert_eq!(*out.lock().unwrap(), [1]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_reading_older_than_one_let_through_is_late() {
+        let reorderer = reorderer(10);
+        drop(reorderer.hold(&reading("a", json!(5))).await);
+        assert!(matches!(reorderer.hold(&reading("a", json!(3))).await, Hold::Late));
+        // Other IDs are ordered on their own
+        assert!(matches!(reorderer.hold(&reading("b", json!(3))).await, Hold::Released(_)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rfc3339_timestamps_are_ordered_too() {
+        let reorderer = reorderer(10);
+        drop(reorderer.hold(&reading("a", json!("2024-05-01T10:00:05Z"))).await);
+        let earlier = reorderer.hold(&reading("a", json!("2024-05-01T12:00:00+02:00"))).await;
+        assert!(matches!(earlier, Hold::Late));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn readings_without_a_timestamp_pass() {
+        let reorderer = reorderer(10);
+        assert!(matches!(reorderer.hold(&reading("a", json!("soon"))).await, Hold::Passed));
+        assert!(matches!(reorderer.hold(&reading("a", Value::Null)).await, Hold::Passed));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_ids_are_forgotten() {
+        let reorderer = reorderer(10);
+        drop(reorderer.hold(&reading("a", json!(5))).await);
+        reorderer.state.lock().unwrap().sweep(reorderer.latency);
+        assert_eq!(reorderer.state.lock().unwrap().ids.len(), 1);
+
+        tokio::time::advance(Duration::from_millis(1000)).await;
+        reorderer.state.lock().unwrap().sweep(reorderer.latency);
+        assert!(reorderer.state.lock().unwrap().ids.is_empty());
+        assert!(matches!(reorderer.hold(&reading("a", json!(3))).await, Hold::Released(_)));
+    }
+}
@@ -0,0 +1,482 @@
+use anyhow::{Context, Result};
+use base64::Engine;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Mutex, Notify};
+use tokio::time::Instant;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use crate::config::RetryQueueConfig;
+use crate::emit;
+use crate::error::MappingError;
+use crate::message::Message;
+use crate::metrics::Metrics;
+use crate::model::GraphChange;
+use crate::pipeline::Pipeline;
+
+// --- RETRY QUEUE ---
+// Where a message's changes wait when the output rejects them, instead of
+// failing the message straight away. A background task sends them again
+// with exponential backoff; once `max_attempts` are used up the message is
+// dead-lettered like any other emit failure. Holds at most `capacity`
+// messages; beyond that failures go straight to the dead-letter path. With
+// `path`, the queue is mirrored to a JSON file (rewritten on every change,
+// via a temporary file) and picked up again after a restart; without one,
+// what is still parked at shutdown is dead-lettered.
+//
+// Retries are not ordered with the live stream: a change that waits out its
+// backoff can land after a newer one for the same element.
+pub struct RetryQueue {
+    capacity: usize,
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    path: Option<PathBuf>,
+    parked: Mutex<Vec<Parked>>,
+    // Wakes the retry task when something is parked
+    arrived: Notify,
+    // When the queue was opened, on tokio's clock and as Unix milliseconds:
+    // due times count on from there, so a step of the wall clock neither
+    // stalls nor rushes the retries
+    opened: (Instant, i64),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Parked {
+    // The original message, for the dead letter if it never gets through
+    topic: String,
+    // base64, so binary payloads survive the JSON file
+    payload: String,
+    // What is still to be sent, in order
+    changes: Vec<GraphChange>,
+    // Including the one that parked it
+    attempts: u32,
+    // Unix milliseconds, see `RetryQueue::now`
+    due: i64,
+}
+
+impl RetryQueue {
+    // A missing file just means an empty queue
+    pub async fn open(config: &RetryQueueConfig) -> Result<Self> {
+        let parked = match &config.path {
+            Some(path) => load(path).await?,
+            None => Vec::new(),
+        };
+        if !parked.is_empty() {
+            info!("Resuming {} message(s) waiting to be retried", parked.len());
+        }
+        Ok(RetryQueue {
+            capacity: config.capacity,
+            max_attempts: config.max_attempts,
+            initial_backoff: Duration::from_millis(config.initial_backoff_ms),
+            max_backoff: Duration::from_millis(config.max_backoff_ms),
+            path: config.path.clone(),
+            parked: Mutex::new(parked),
+            arrived: Notify::new(),
+            opened: (Instant::now(), Utc::now().timestamp_millis()),
+        })
+    }
+
+    // False (and nothing kept) when retrying is pointless or the queue is full
+    pub async fn park(&self, message: &Message, changes: Vec<GraphChange>) -> bool {
+        if self.max_attempts <= 1 {
+            return false;
+        }
+        let mut parked = self.parked.lock().await;
+        if parked.len() >= self.capacity {
+            warn!(
+                event = "retry_queue_full",
+                topic = %message.topic,
+                "Retry queue is full ({} message(s)); not retrying the message from {}",
+                parked.len(),
+                message.topic
+            );
+            return false;
+        }
+        parked.push(Parked {
+            topic: message.topic.clone(),
+            payload: base64::engine::general_purpose::STANDARD.encode(&message.payload),
+            changes,
+            attempts: 1,
+            due: self.due_in(self.delay(1)),
+        });
+        self.save(&parked).await;
+        self.arrived.notify_one();
+        true
+    }
+
+    // Unix milliseconds, as of when the queue was opened plus the time since
+    fn now(&self) -> i64 {
+        self.opened.1 + self.opened.0.elapsed().as_millis() as i64
+    }
+
+    fn due_in(&self, delay: Duration) -> i64 {
+        self.now() + delay.as_millis() as i64
+    }
+
+    // The wait after the given number of failed attempts
+    fn delay(&self, attempts: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempts.saturating_sub(1));
+        self.initial_backoff.saturating_mul(factor).min(self.max_backoff)
+    }
+
+    // Takes the entry due soonest once its time has come
+    async fn next_due(&self) -> Parked {
+        loop {
+            let wait = {
+                let mut parked = self.parked.lock().await;
+                let soonest = parked.iter().map(|entry| entry.due).enumerate().min_by_key(|(_, due)| *due);
+                match soonest {
+                    Some((index, due)) if due <= self.now() => return parked.swap_remove(index),
+                    Some((_, due)) => Duration::from_millis((due - self.now()).max(0) as u64),
+                    None => Duration::MAX,
+                }
+            };
+            // A newly parked entry may be due before the current soonest
+            tokio::select! {
+                _ = tokio::time::sleep(wait.min(self.max_backoff)) => {}
+                _ = self.arrived.notified() => {}
+            }
+        }
+    }
+
+    async fn save(&self, parked: &[Parked]) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        if let Err(e) = write(path, parked).await {
+            warn!("{:#}", e);
+        }
+    }
+}
+
+// Sends parked changes as they fall due, until aborted at shutdown
+pub fn start(pipeline: Arc<Pipeline>) -> Option<JoinHandle<()>> {
+    let queue = pipeline.retry_queue.as_ref()?;
+    info!(
+        "Retrying failed emits up to {} time(s), holding at most {} message(s)",
+        queue.max_attempts, queue.capacity
+    );
+    Some(tokio::spawn(async move {
+        let Some(queue) = &pipeline.retry_queue else {
+            return;
+        };
+        loop {
+            let mut entry = queue.next_due().await;
+            let mut failure = None;
+            while !entry.changes.is_empty() {
+                let change = entry.changes.remove(0);
+                if let Err(e) = emit::send(pipeline.emitter.as_ref(), change.clone()).await {
+                    entry.changes.insert(0, change);
+                    failure = Some(e);
+                    break;
+                }
+            }
+
+            match failure {
+                None => {
+                    info!(
+                        event = "retried",
+                        topic = %entry.topic,
+                        "Emitted the message from {} on attempt {}",
+                        entry.topic,
+                        entry.attempts + 1
+                    );
+                    Metrics::inc(&pipeline.metrics.mapped);
+                }
+                Some(e) if entry.attempts + 1 >= queue.max_attempts => {
+                    let attempts = entry.attempts + 1;
+                    let error = MappingError::Emit(e.context(format!("giving up after {} attempt(s)", attempts)));
+                    warn!(event = "retry_exhausted", topic = %entry.topic, "Failed to emit the message from {}: {}", entry.topic, error);
+                    Metrics::inc(&pipeline.metrics.failed);
+                    let payload = base64::engine::general_purpose::STANDARD.decode(&entry.payload).unwrap_or_default();
+                    pipeline.dead_letter(&entry.topic, &payload, &error).await;
+                }
+                Some(e) => {
+                    entry.attempts += 1;
+                    let delay = queue.delay(entry.attempts);
+                    warn!(
+                        event = "retry_failed",
+                        topic = %entry.topic,
+                        "Attempt {}/{} for the message from {} failed: {:#}. Retrying in {:?}",
+                        entry.attempts,
+                        queue.max_attempts,
+                        entry.topic,
+                        e,
+                        delay
+                    );
+                    entry.due = queue.due_in(delay);
+                    queue.parked.lock().await.push(entry);
+                }
+            }
+            let parked = queue.parked.lock().await;
+            queue.save(&parked).await;
+        }
+    }))
+}
+
+// After the retry task has stopped: what is parked stays in the queue file,
+// or else counts as undelivered and is dead-lettered like the messages the
+// workers were cut off from
+pub async fn shutdown(pipeline: &Pipeline) {
+    let Some(queue) = &pipeline.retry_queue else {
+        return;
+    };
+    let parked = std::mem::take(&mut *queue.parked.lock().await);
+    if parked.is_empty() {
+        return;
+    }
+    if let Some(path) = &queue.path {
+        info!("{} message(s) waiting to be retried are kept in {}", parked.len(), path.display());
+        return;
+    }
+    let mut dead_lettered = 0;
+    for entry in &parked {
+        Metrics::inc(&pipeline.metrics.undelivered);
+        Metrics::inc(&pipeline.metrics.failed);
+        let payload = base64::engine::general_purpose::STANDARD.decode(&entry.payload).unwrap_or_default();
+        if pipeline.dead_letter(&entry.topic, &payload, &MappingError::Shutdown).await {
+            dead_lettered += 1;
+        }
+    }
+    if pipeline.dead_letters.is_none() {
+        warn!(event = "undelivered", "{} message(s) still waiting to be retried are lost", parked.len());
+    } else {
+        warn!(
+            event = "undelivered",
+            "{} message(s) still waiting to be retried were not delivered: {} dead-lettered, {} could not be dead-lettered",
+            parked.len(),
+            dead_lettered,
+            parked.len() - dead_lettered
+        );
+    }
+}
+
+async fn load(path: &Path) -> Result<Vec<Parked>> {
+    match tokio::fs::read(path).await {
+        Ok(contents) => serde_json::from_slice(&contents)
+            .with_context(|| format!("{} is not a valid retry queue file", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e).with_context(|| format!("Failed to read retry queue file {}", path.display())),
+    }
+}
+
+async fn write(path: &Path, parked: &[Parked]) -> Result<()> {
+    let contents = serde_json::to_vec(parked)?;
+    let temporary = path.with_extension("tmp");
+    tokio::fs::write(&temporary, contents)
+        .await
+        .with_context(|| format!("Failed to write retry queue file {}", temporary.display()))?;
+    tokio::fs::rename(&temporary, path)
+        .await
+        .with_context(|| format!("Failed to replace retry queue file {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::emit::tests::Recording;
+    use crate::emit::Emitter;
+    use crate::model::{DrasiDelete, DrasiElement, DrasiRelation};
+    use crate::pipeline::tests::pipeline;
+    use async_trait::async_trait;
+    use rumqttc::QoS;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn config(path: Option<PathBuf>) -> RetryQueueConfig {
+        RetryQueueConfig {
+            capacity: 2,
+            max_attempts: 5,
+            initial_backoff_ms: 100,
+            max_backoff_ms: 1000,
+            path,
+        }
+    }
+
+    fn message() -> Message {
+        Message::from(rumqttc::Publish::new("sensors/a", QoS::AtLeastOnce, vec![0xff, 0x00]))
+    }
+
+    fn changes() -> Vec<GraphChange> {
+        vec![GraphChange::Delete(DrasiDelete { id: "a".to_string() })]
+    }
+
+    fn reading() -> Message {
+        Message::from(rumqttc::Publish::new("sensors/a", QoS::AtLeastOnce, r#"{"temperature": 21.5}"#))
+    }
+
+    // Fails the first `failures` calls, then passes them on
+    struct Flaky {
+        failures: AtomicU32,
+        inner: Arc<Recording>,
+    }
+
+    impl Flaky {
+        fn fail(&self) -> anyhow::Result<()> {
+            if self.failures.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| left.checked_sub(1)).is_ok() {
+                anyhow::bail!("output unavailable");
+            }
+            Ok(())
+        }
+    }
+
+    #[async_trait]
+    impl Emitter for Flaky {
+        async fn emit(&self, element: DrasiElement) -> anyhow::Result<()> {
+            self.fail()?;
+            self.inner.emit(element).await
+        }
+
+        async fn delete(&self, delete: DrasiDelete) -> anyhow::Result<()> {
+            self.fail()?;
+            self.inner.delete(delete).await
+        }
+
+        async fn emit_relation(&self, relation: DrasiRelation) -> anyhow::Result<()> {
+            self.fail()?;
+            self.inner.emit_relation(relation).await
+        }
+    }
+
+    #[tokio::test]
+    async fn the_backoff_doubles_up_to_the_maximum() {
+        let queue = RetryQueue::open(&config(None)).await.unwrap();
+        let delays: Vec<_> = (1..=6).map(|attempts| queue.delay(attempts).as_millis()).collect();
+        assert_eq!(delays, [100, 200, 400, 800, 1000, 1000]);
+    }
+
+    #[tokio::test]
+    async fn a_full_queue_refuses_more() {
+        let queue = RetryQueue::open(&config(None)).await.unwrap();
+        assert!(queue.park(&message(), changes()).await);
+        assert!(queue.park(&message(), changes()).await);
+        assert!(!queue.park(&message(), changes()).await);
+        assert_eq!(queue.parked.lock().await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn one_attempt_leaves_nothing_to_retry() {
+        let queue = RetryQueue::open(&RetryQueueConfig {
+            max_attempts: 1,
+            ..config(None)
+        })
+        .await
+        .unwrap();
+        assert!(!queue.park(&message(), changes()).await);
+    }
+
+    #[tokio::test]
+    async fn a_queue_file_survives_a_restart() {
+        let path = std::env::temp_dir().join(format!("drasi-mqtt-retry-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let queue = RetryQueue::open(&config(Some(path.clone()))).await.unwrap();
+        assert!(queue.park(&message(), changes()).await);
+
+        let reopened = RetryQueue::open(&config(Some(path.clone()))).await.unwrap();
+        let parked = reopened.parked.lock().await;
+        assert_eq!(parked.len(), 1);
+        assert_eq!(parked[0].topic, "sensors/a");
+        let payload = base64::engine::general_purpose::STANDARD.decode(&parked[0].payload).unwrap();
+        assert_eq!(payload, [0xff, 0x00]);
+        assert_eq!(parked[0].attempts, 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_corrupt_queue_file_is_an_error() {
+        let path = std::env::temp_dir().join(format!("drasi-mqtt-retry-corrupt-{}.json", std::process::id()));
+        std::fs::write(&path, "not json").unwrap();
+        let error = RetryQueue::open(&config(Some(path.clone()))).await.err().unwrap();
+        assert!(error.to_string().contains("is not a valid retry queue file"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_parked_message_is_sent_again_until_it_goes_through() {
+        let recording = Arc::new(Recording::default());
+        let pipeline = Arc::new(Pipeline {
+            emitter: Arc::new(Flaky {
+                failures: AtomicU32::new(2),
+                inner: recording.clone(),
+            }),
+            retry_queue: Some(RetryQueue::open(&config(None)).await.unwrap()),
+            ..pipeline(&Config::default(), recording.clone())
+        });
+        pipeline.process(&reading()).await.unwrap();
+        assert_eq!(pipeline.metrics.parked.load(Ordering::Relaxed), 1);
+        let task = start(pipeline.clone()).unwrap();
+
+        // The second attempt is due after 100ms and fails, the third 200ms
+        // after that
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        assert!(recording.calls().is_empty());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(recording.calls(), ["emit a"]);
+        assert_eq!(pipeline.metrics.mapped.load(Ordering::Relaxed), 1);
+        assert!(pipeline.retry_queue.as_ref().unwrap().parked.lock().await.is_empty());
+        task.abort();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn changes_refused_by_an_open_circuit_wait_out_the_cooldown() {
+        use crate::config::CircuitBreakerConfig;
+        use crate::emit::CircuitBreakerEmitter;
+
+        let recording = Arc::new(Recording::default());
+        let breaker = CircuitBreakerEmitter::new(
+            Box::new(recording.clone()),
+            &CircuitBreakerConfig {
+                failure_threshold: 1,
+                cooldown_ms: 500,
+            },
+            Arc::new(Metrics::default()),
+        );
+        let pipeline = Arc::new(Pipeline {
+            emitter: Arc::new(breaker),
+            retry_queue: Some(RetryQueue::open(&config(None)).await.unwrap()),
+            ..pipeline(&Config::default(), recording.clone())
+        });
+        // Opens the circuit; the output is back right after
+        recording.failing.store(true, Ordering::Relaxed);
+        pipeline.process(&reading()).await.unwrap();
+        recording.failing.store(false, Ordering::Relaxed);
+        let task = start(pipeline.clone()).unwrap();
+
+        // Refused at 100ms and 300ms, let through as the probe at 700ms
+        tokio::time::sleep(Duration::from_millis(650)).await;
+        assert!(recording.calls().is_empty());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(recording.calls(), ["emit a"]);
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn what_is_still_parked_at_shutdown_is_dead_lettered() {
+        use crate::config::DeadLetterConfig;
+        use crate::deadletter::DeadLetterSink;
+
+        let path = std::env::temp_dir().join(format!("drasi-mqtt-retry-shutdown-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let recording = Arc::new(Recording::default());
+        let pipeline = Pipeline {
+            retry_queue: Some(RetryQueue::open(&config(None)).await.unwrap()),
+            dead_letters: Some(DeadLetterSink::build(&DeadLetterConfig::File { path: path.clone() }, None).await.unwrap()),
+            ..pipeline(&Config::default(), recording.clone())
+        };
+        recording.failing.store(true, Ordering::Relaxed);
+        pipeline.process(&reading()).await.unwrap();
+
+        shutdown(&pipeline).await;
+        assert_eq!(pipeline.metrics.undelivered.load(Ordering::Relaxed), 1);
+        let letter: serde_json::Value = serde_json::from_str(std::fs::read_to_string(&path).unwrap().trim()).unwrap();
+        assert_eq!(letter["topic"], "sensors/a");
+        assert_eq!(letter["payload"], r#"{"temperature": 21.5}"#);
+        assert_eq!(letter["category"], "shutdown");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,99 @@
+use anyhow::{anyhow, Context, Result};
+use jsonschema::Validator;
+use serde_json::Value;
+use std::fs;
+
+use crate::config::{self, Subscription};
+
+// --- SCHEMA VALIDATION ---
+// Subscriptions can point at a JSON Schema file. Each schema is compiled once
+// at startup and applied to payloads arriving on topics its subscription
+// matches, so garbage never becomes a graph node.
+#[derive(Default)]
+pub struct Schemas {
+    // (topic filter, compiled schema), in subscription order
+    entries: Vec<(String, Validator)>,
+}
+
+impl Schemas {
+    pub fn load(subscriptions: &[Subscription]) -> Result<Self> {
+        let mut entries = Vec::new();
+        for subscription in subscriptions {
+            let Some(path) = &subscription.schema else {
+                continue;
+            };
+            let text = fs::read_to_string(path)
+                .with_context(|| format!("Failed to read schema {}", path.display()))?;
+            let schema: Value = serde_json::from_str(&text)
+                .with_context(|| format!("Schema {} is not valid JSON", path.display()))?;
+            let validator = jsonschema::validator_for(&schema)
+                .map_err(|e| anyhow!("Schema {} is not a valid JSON Schema: {}", path.display(), e))?;
+            entries.push((subscription.topic.clone(), validator));
+        }
+        Ok(Schemas { entries })
+    }
+
+    // Validates against the schema of the first matching subscription that has
+    // one. Topics without a schema always pass.
+    pub fn validate(&self, topic: &str, value: &Value) -> Result<(), Vec<String>> {
+        let Some((_, validator)) = self.entries.iter().find(|(filter, _)| config::topic_matches_filter(topic, filter)) else {
+            return Ok(());
+        };
+        let errors: Vec<String> = validator
+            .iter_errors(value)
+            .map(|e| match e.instance_path.to_string() {
+                path if path.is_empty() => e.to_string(),
+                path => format!("{} at {}", e, path),
+            })
+            .collect();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn payloads_are_checked_against_their_subscriptions_schema() {
+        let path = std::env::temp_dir().join(format!("drasi-mqtt-schema-{}.json", std::process::id()));
+        let schema = json!({
+            "type": "object",
+            "required": ["temperature"],
+            "properties": { "temperature": { "type": "number" } }
+        });
+        std::fs::write(&path, schema.to_string()).unwrap();
+        let subscription = Subscription {
+            schema: Some(path.clone()),
+            ..Subscription::new("sensors/#")
+        };
+        let schemas = Schemas::load(&[subscription, Subscription::new("other/#")]).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(schemas.validate("sensors/a", &json!({ "temperature": 21.5 })).is_ok());
+        let errors = schemas.validate("sensors/a", &json!({ "temperature": "warm" })).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].ends_with("at /temperature"), "{}", errors[0]);
+        assert!(schemas.validate("sensors/a", &json!({})).is_err());
+        // No schema, no checks
+        assert!(schemas.validate("other/a", &json!({})).is_ok());
+    }
+
+    #[test]
+    fn a_schema_that_is_not_json_fails_to_load() {
+        let path = std::env::temp_dir().join(format!("drasi-mqtt-schema-bad-{}.json", std::process::id()));
+        std::fs::write(&path, "{").unwrap();
+        let subscription = Subscription {
+            schema: Some(path.clone()),
+            ..Subscription::new("sensors/#")
+        };
+        let error = Schemas::load(&[subscription]).err().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(error.to_string().contains("is not valid JSON"));
+    }
+}
@@ -0,0 +1,112 @@
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::config::SequenceConfig;
+use crate::model::DrasiElement;
+
+// --- SEQUENCE NUMBERS ---
+// Stamps every emitted element with `key`: 0 for the first emission of its
+// ID, one more for each after that, so a consumer can tell the latest
+// update even if delivery reorders them. The count survives a delete, so a
+// recreated element carries on from where it was. Counts are kept for up to
+// `max_entries` IDs; past that the least recently emitted are forgotten and
+// start over from 0 if they come back.
+pub struct Sequencer {
+    key: String,
+    max_entries: usize,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    // Bumped on every stamp, to order entries by recency
+    clock: u64,
+    // element ID -> (next sequence number, when last stamped)
+    next: HashMap<String, (u64, u64)>,
+}
+
+impl Sequencer {
+    pub fn new(config: &SequenceConfig) -> Self {
+        Sequencer {
+            key: config.key.clone(),
+            max_entries: config.max_entries,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    // Object properties only, like the ingestion timestamp
+    pub fn stamp(&self, element: &mut DrasiElement) {
+        let Value::Object(map) = &mut element.properties else {
+            return;
+        };
+        let mut state = self.state.lock().expect("sequence lock poisoned");
+        state.clock += 1;
+        let now = state.clock;
+        if !state.next.contains_key(&element.id) && state.next.len() >= self.max_entries {
+            evict(&mut state.next, self.max_entries);
+        }
+        let entry = state.next.entry(element.id.clone()).or_insert((0, now));
+        let seq = entry.0;
+        *entry = (seq + 1, now);
+        map.insert(self.key.clone(), json!(seq));
+    }
+}
+
+// Forgets the least recently stamped tenth at once
+fn evict(next: &mut HashMap<String, (u64, u64)>, max_entries: usize) {
+    let mut by_age: Vec<(u64, String)> = next.iter().map(|(id, (_, seen))| (*seen, id.clone())).collect();
+    by_age.sort_unstable();
+    let excess = next.len() + 1 - max_entries;
+    for (_, id) in by_age.into_iter().take(excess.max(max_entries / 10)) {
+        next.remove(&id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emit::tests::element;
+
+    fn stamped(sequencer: &Sequencer, id: &str) -> Value {
+        let mut element = element(id);
+        sequencer.stamp(&mut element);
+        element.properties["_seq"].clone()
+    }
+
+    fn sequencer(max_entries: usize) -> Sequencer {
+        Sequencer::new(&SequenceConfig {
+            key: "_seq".to_string(),
+            max_entries,
+        })
+    }
+
+    #[test]
+    fn each_id_counts_from_zero() {
+        let sequencer = sequencer(10);
+        assert_eq!(stamped(&sequencer, "a"), 0);
+        assert_eq!(stamped(&sequencer, "a"), 1);
+        assert_eq!(stamped(&sequencer, "b"), 0);
+        assert_eq!(stamped(&sequencer, "a"), 2);
+    }
+
+    #[test]
+    fn the_least_recently_stamped_start_over() {
+        let sequencer = sequencer(2);
+        stamped(&sequencer, "a");
+        stamped(&sequencer, "b");
+        stamped(&sequencer, "a");
+        stamped(&sequencer, "c");
+        assert_eq!(stamped(&sequencer, "a"), 2);
+        assert_eq!(stamped(&sequencer, "b"), 0);
+    }
+
+    #[test]
+    fn non_object_properties_are_left_alone() {
+        let sequencer = sequencer(10);
+        let mut element = element("a");
+        element.properties = json!(42);
+        sequencer.stamp(&mut element);
+        assert_eq!(element.properties, json!(42));
+    }
+}
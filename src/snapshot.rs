@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+use crate::config::SnapshotConfig;
+use crate::connection::ConnectionState;
+use crate::model::DrasiElement;
+use crate::pipeline::Pipeline;
+
+// --- SNAPSHOT ON RECONNECT ---
+// Keeps the last element emitted for each ID, and after every reconnect
+// (not the first connect) emits them all again, so a consumer that
+// restarted during the outage has the full picture before the sensors next
+// publish. Up to `max_entries` IDs are kept; past that the least recently
+// emitted are forgotten, like change detection. A deleted ID is dropped.
+pub struct SnapshotCache {
+    max_entries: usize,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    // Bumped on every update, to order entries by recency
+    clock: u64,
+    // element ID -> (last emitted element, when)
+    last: HashMap<String, (DrasiElement, u64)>,
+}
+
+impl SnapshotCache {
+    pub fn new(config: &SnapshotConfig) -> Self {
+        SnapshotCache {
+            max_entries: config.max_entries,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    pub fn remember(&self, element: &DrasiElement) {
+        let mut state = self.state.lock().expect("snapshot lock poisoned");
+        state.clock += 1;
+        let now = state.clock;
+        if !state.last.contains_key(&element.id) && state.last.len() >= self.max_entries {
+            evict(&mut state.last, self.max_entries);
+        }
+        state.last.insert(element.id.clone(), (element.clone(), now));
+    }
+
+    pub fn forget(&self, id: &str) {
+        self.state.lock().expect("snapshot lock poisoned").last.remove(id);
+    }
+
+    // Oldest first, so a consumer applying them in order ends up with the
+    // same state as one that saw them live
+    fn elements(&self) -> Vec<DrasiElement> {
+        let state = self.state.lock().expect("snapshot lock poisoned");
+        let mut elements: Vec<&(DrasiElement, u64)> = state.last.values().collect();
+        elements.sort_unstable_by_key(|(_, seen)| *seen);
+        elements.into_iter().map(|(element, _)| element.clone()).collect()
+    }
+}
+
+// Forgets the least recently emitted tenth at once
+fn evict(last: &mut HashMap<String, (DrasiElement, u64)>, max_entries: usize) {
+    let mut by_age: Vec<(u64, String)> = last.iter().map(|(id, (_, seen))| (*seen, id.clone())).collect();
+    by_age.sort_unstable();
+    let excess = last.len() + 1 - max_entries;
+    for (_, id) in by_age.into_iter().take(excess.max(max_entries / 10)) {
+        last.remove(&id);
+    }
+}
+
+// Follows one broker connection; nothing to do unless the pipeline keeps a
+// snapshot
+pub fn start(pipeline: Arc<Pipeline>, mut state: watch::Receiver<ConnectionState>) {
+    if pipeline.snapshot.is_none() {
+        return;
+    }
+    tokio::spawn(async move {
+        let Some(snapshot) = &pipeline.snapshot else {
+            return;
+        };
+        let mut connected_before = false;
+        let mut lost = false;
+        loop {
+            let current = *state.borrow_and_update();
+            match current {
+                ConnectionState::Connected => {
+                    if lost {
+                        lost = false;
+                        let elements = snapshot.elements();
+                        info!(
+                            event = "snapshot",
+                            "Reconnected; emitting the last known state of {} element(s)",
+                            elements.len()
+                        );
+                        for element in elements {
+                            let id = element.id.clone();
+                            if let Err(e) = pipeline.emitter.emit(element).await {
+                                warn!(event = "snapshot_failed", device_id = %id, "Failed to re-emit {}: {:#}", id, e);
+                            }
+                        }
+                    }
+                    connected_before = true;
+                }
+                _ if connected_before => lost = true,
+                _ => {}
+            }
+            if state.changed().await.is_err() {
+                break;
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::emit::tests::{element, Recording};
+    use crate::message::Message;
+    use rumqttc::QoS;
+    use std::time::Duration;
+
+    fn ids(cache: &SnapshotCache) -> Vec<String> {
+        cache.elements().into_iter().map(|element| element.id).collect()
+    }
+
+    #[test]
+    fn the_latest_of_each_element_is_kept_oldest_first() {
+        let cache = SnapshotCache::new(&SnapshotConfig { max_entries: 100 });
+        for id in ["a", "b", "c"] {
+            cache.remember(&element(id));
+        }
+        let mut updated = element("a");
+        updated.properties = serde_json::json!({ "temperature": 25.0 });
+        cache.remember(&updated);
+        cache.forget("b");
+        let elements = cache.elements();
+        assert_eq!(ids(&cache), ["c", "a"]);
+        assert_eq!(elements[1].properties["temperature"], 25.0);
+    }
+
+    #[test]
+    fn past_max_entries_the_least_recent_are_forgotten() {
+        let cache = SnapshotCache::new(&SnapshotConfig { max_entries: 3 });
+        for id in ["a", "b", "c"] {
+            cache.remember(&element(id));
+        }
+        cache.remember(&element("a"));
+        cache.remember(&element("d"));
+        assert_eq!(ids(&cache), ["c", "a", "d"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_reconnect_emits_every_cached_element_again() {
+        let recording = Arc::new(Recording::default());
+        let pipeline = Arc::new(Pipeline {
+            snapshot: Some(SnapshotCache::new(&SnapshotConfig { max_entries: 100 })),
+            ..crate::pipeline::tests::pipeline(&Config::default(), recording.clone())
+        });
+        let (sender, receiver) = watch::channel(ConnectionState::Connecting);
+        start(pipeline.clone(), receiver);
+        sender.send(ConnectionState::Connected).unwrap();
+        for id in ["temp-01", "temp-02", "temp-03"] {
+            let message = Message::from(rumqttc::Publish::new(format!("sensors/{}", id), QoS::AtLeastOnce, "{}"));
+            pipeline.process(&message).await.unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(recording.calls().len(), 3);
+
+        // Each state is given time to be seen before the next one
+        for state in [ConnectionState::Disconnected, ConnectionState::Reconnecting, ConnectionState::Connected] {
+            sender.send(state).unwrap();
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(recording.calls()[3..], ["emit temp-01", "emit temp-02", "emit temp-03"]);
+    }
+}
@@ -0,0 +1,157 @@
+use anyhow::{bail, Context, Result};
+use base64::Engine;
+use bytes::Bytes;
+use tracing::{info, warn};
+use rumqttc::QoS;
+use serde::Deserialize;
+use serde_json::Value;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use tokio::fs::File;
+use tokio::io::{AsyncBufReadExt, BufReader, Lines};
+
+use super::Dispatcher;
+use crate::config;
+use crate::health::Health;
+use crate::message::Message;
+
+// --- FILE SOURCE ---
+// Replays captured messages from a JSON Lines file, one message per line:
+//   {"topic": "lfx/drasi/sensors/t1", "payload": {"temp": 21.5}}
+// A string `payload` is sent as its raw text (for non-JSON formats), any
+// other JSON value as its serialization, and `payload_base64` carries binary
+// bodies. Leaving both out replays an empty (delete) message. `qos`,
+// `retain`, `content_type` and `user_properties` are optional.
+pub struct FileSource {
+    path: PathBuf,
+    lines: Lines<BufReader<File>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ReplayRecord {
+    topic: String,
+    #[serde(default)]
+    payload: Option<Value>,
+    #[serde(default)]
+    payload_base64: Option<String>,
+    #[serde(default = "default_replay_qos", deserialize_with = "config::deserialize_qos")]
+    qos: QoS,
+    #[serde(default)]
+    retain: bool,
+    #[serde(default)]
+    content_type: Option<String>,
+    // `[["key", "value"], ...]`, as in MQTT v5
+    #[serde(default)]
+    user_properties: Vec<(String, String)>,
+    // Written by `--record`; replay doesn't re-time messages, so it's ignored
+    #[serde(default, rename = "received_at")]
+    _received_at: Option<String>,
+}
+
+fn default_replay_qos() -> QoS {
+    QoS::AtLeastOnce
+}
+
+impl FileSource {
+    // Opened up front so a wrong path fails before anything starts
+    pub async fn open(path: &Path) -> Result<Self> {
+        let file = File::open(path)
+            .await
+            .with_context(|| format!("Failed to open replay file {}", path.display()))?;
+        Ok(FileSource {
+            path: path.to_path_buf(),
+            lines: BufReader::new(file).lines(),
+        })
+    }
+
+    // Feeds every line to the Dispatcher, stopping early on shutdown. Lines
+    // that can't be parsed are logged and skipped.
+    pub async fn run(
+        mut self,
+        dispatcher: &Dispatcher,
+        health: &Health,
+        mut shutdown: Pin<&mut impl Future<Output = ()>>,
+    ) -> Result<()> {
+        info!("Replaying messages from {}", self.path.display());
+        health.set_ready(true);
+        let mut line_number = 0;
+        loop {
+            let line = tokio::select! {
+                _ = &mut shutdown => break,
+                line = self.lines.next_line() => line,
+            };
+            let Some(line) = line.with_context(|| format!("Failed to read {}", self.path.display()))? else {
+                info!("Replay of {} finished after {} line(s)", self.path.display(), line_number);
+                break;
+            };
+            line_number += 1;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match parse_record(&line) {
+                Ok(message) => dispatcher.dispatch(message).await?,
+                Err(e) => warn!("Skipping line {} of {}: {:#}", line_number, self.path.display(), e),
+            }
+        }
+        health.set_ready(false);
+        Ok(())
+    }
+}
+
+fn parse_record(line: &str) -> Result<Message> {
+    let record: ReplayRecord = serde_json::from_str(line).context("not a valid replay record")?;
+    let payload = match (record.payload, record.payload_base64) {
+        (Some(_), Some(_)) => bail!("give either payload or payload_base64, not both"),
+        (Some(Value::String(text)), None) => Bytes::from(text),
+        (Some(value), None) => Bytes::from(serde_json::to_vec(&value)?),
+        (None, Some(encoded)) => Bytes::from(
+            base64::engine::general_purpose::STANDARD
+                .decode(encoded)
+                .context("payload_base64 is not valid base64")?,
+        ),
+        (None, None) => Bytes::new(),
+    };
+    Ok(Message {
+        topic: record.topic,
+        payload,
+        qos: record.qos,
+        retain: record.retain,
+        dup: false,
+        pkid: 0,
+        user_properties: record.user_properties,
+        content_type: record.content_type,
+        broker: None,
+        expires_at: None,
+        ack: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replay_records_become_messages() {
+        let json = parse_record(r#"{"topic": "sensors/a", "payload": {"t": 21.5}, "qos": 2, "retain": true}"#).unwrap();
+        assert_eq!(json.payload, Bytes::from(r#"{"t":21.5}"#));
+        assert_eq!((json.qos, json.retain), (QoS::ExactlyOnce, true));
+        let text = parse_record(r#"{"topic": "sensors/a", "payload": "on", "content_type": "text/plain"}"#).unwrap();
+        assert_eq!(text.payload, Bytes::from("on"));
+        assert_eq!((text.qos, text.content_type.as_deref()), (QoS::AtLeastOnce, Some("text/plain")));
+        let binary = parse_record(r#"{"topic": "sensors/a", "payload_base64": "/wA=", "user_properties": [["k", "v"]]}"#).unwrap();
+        assert_eq!(binary.payload.as_ref(), [0xff, 0x00]);
+        assert_eq!(binary.user_properties, [("k".to_string(), "v".to_string())]);
+        assert!(parse_record(r#"{"topic": "sensors/a"}"#).unwrap().payload.is_empty());
+    }
+
+    #[test]
+    fn ambiguous_or_unknown_records_are_refused() {
+        let error = parse_record(r#"{"topic": "a", "payload": "x", "payload_base64": "eA=="}"#).unwrap_err();
+        assert!(error.to_string().contains("not both"));
+        assert!(parse_record(r#"{"topic": "a", "qos": 3}"#).is_err());
+        assert!(parse_record(r#"{"topic": "a", "colour": "red"}"#).is_err());
+        assert!(parse_record("not json").is_err());
+    }
+}
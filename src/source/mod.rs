@@ -0,0 +1,607 @@
+use anyhow::{bail, Result};
+use tracing::{error, warn};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use std::task::Poll;
+use std::time::Duration;
+use tokio::sync::{mpsc, watch, Mutex};
+use tokio::task::JoinSet;
+
+use crate::config::{self, Config, OverflowPolicy};
+use crate::error::MappingError;
+use crate::message::Message;
+use crate::metrics::Metrics;
+use crate::pipeline::Pipeline;
+use crate::record::Recorder;
+use crate::summary::TopicSummary;
+
+// Kept from the end of the drain for dead-lettering what the workers still
+// hold, so a slow sink can't stretch it either
+const DEAD_LETTER_GRACE: Duration = Duration::from_secs(1);
+
+mod file;
+mod mqtt;
+
+pub use file::FileSource;
+pub use mqtt::MqttSource;
+
+// --- SOURCES ---
+// Where messages come from: live brokers (an `MqttSource` each) or a captured file
+// (`FileSource`, for `--replay`). Either way they end up in the Dispatcher,
+// so both drive exactly the same processing path.
+pub enum Source {
+    Mqtt(Vec<MqttSource>),
+    File(Box<FileSource>),
+}
+
+// --- DISPATCHER ---
+// Queues each message for a fixed pool of workers, so a slow payload never
+// stalls the source (for MQTT, its keepalives) and a burst can't grow memory
+// past the queue's capacity. A full queue is counted and, depending on
+// `queue_full`, either waited out or the message is dropped.
+//
+// With fair scheduling each broker gets a queue of its own and the workers
+// serve them round-robin: a full queue only holds up the broker filling it,
+// and a quiet broker's message waits for at most one turn of each other
+// queue rather than behind everything already queued.
+//
+// With `preserve_order` every worker has queues of its own and each topic
+// is always handed to the same worker, so messages on one topic (and thus,
+// unless its ID comes from the payload, for one element) are processed one
+// after the other in the order they arrived. Other topics still proceed
+// concurrently on the other workers.
+//
+// A subscription with `workers` of its own gets a pool of them, with its own
+// queue, so a flood on it can't hold up the other subscriptions (nor they
+// it). A message goes to the pool of the first subscription its topic
+// matches, as in the mapping; all the others share the main pool.
+//
+// Shutdown drains the queues for up to `drain_timeout_secs`. Past that
+// nothing more is attempted: what is still queued or being processed is
+// dead-lettered (category `shutdown`) and the workers stop.
+pub struct Dispatcher {
+    shared: Pool,
+    // Every subscription in order, with its own pool if it has one; empty
+    // when none has
+    isolated: Vec<(String, Option<Pool>)>,
+    queue_full: OverflowPolicy,
+    workers: JoinSet<CutOff>,
+    // Set when the drain stops waiting
+    cut_off: watch::Sender<bool>,
+    dead_letters: bool,
+    metrics: Arc<Metrics>,
+    recorder: Option<Arc<Recorder>>,
+    summary: Option<Arc<TopicSummary>>,
+}
+
+// The broker each queue is for; a single queue takes everything
+type Queues = Vec<(Option<Arc<str>>, mpsc::Sender<Message>)>;
+
+// The queues of a set of workers: one set shared by all of them, or one
+// set per worker with `preserve_order`
+struct Pool {
+    groups: Vec<Queues>,
+}
+
+impl Pool {
+    fn start(
+        pipeline: &Arc<Pipeline>,
+        names: &[Option<Arc<str>>],
+        workers: usize,
+        capacity: usize,
+        preserve_order: bool,
+        cut_off: &watch::Sender<bool>,
+        pool: &mut JoinSet<CutOff>,
+    ) -> Self {
+        let mut groups = Vec::new();
+        if preserve_order {
+            // The capacity is shared out, so memory stays bounded as before
+            let capacity = capacity.div_ceil(workers);
+            for _ in 0..workers {
+                let (queues, receiver) = open_queues(names, capacity);
+                groups.push(queues);
+                pool.spawn(work(pipeline.clone(), receiver, cut_off.subscribe()));
+            }
+        } else {
+            let (queues, receiver) = open_queues(names, capacity);
+            groups.push(queues);
+            for _ in 0..workers {
+                pool.spawn(work(pipeline.clone(), receiver.clone(), cut_off.subscribe()));
+            }
+        }
+        Pool { groups }
+    }
+
+    fn queue(&self, message: &Message) -> &mpsc::Sender<Message> {
+        let group = match self.groups.as_slice() {
+            [group] => group,
+            groups => {
+                let mut hasher = DefaultHasher::new();
+                message.topic.hash(&mut hasher);
+                &groups[hasher.finish() as usize % groups.len()]
+            }
+        };
+        match group.as_slice() {
+            [(_, queue)] => queue,
+            queues => queues
+                .iter()
+                .find(|(name, _)| name.as_deref() == message.broker.as_deref())
+                .map_or(&queues[0].1, |(_, queue)| queue),
+        }
+    }
+}
+
+impl Dispatcher {
+    // One queue per lane (broker) name, or a single one without any. The
+    // worker count, capacity and policies come from `config`.
+    pub fn new(
+        pipeline: Arc<Pipeline>,
+        config: &Config,
+        lanes: Vec<Arc<str>>,
+        recorder: Option<Arc<Recorder>>,
+        summary: Option<Arc<TopicSummary>>,
+    ) -> Self {
+        let names: Vec<Option<Arc<str>>> = if lanes.is_empty() {
+            vec![None]
+        } else {
+            lanes.into_iter().map(Some).collect()
+        };
+        let mut pool = JoinSet::new();
+        let cut_off = watch::Sender::new(false);
+        let shared = Pool::start(
+            &pipeline,
+            &names,
+            config.max_concurrency,
+            config.queue_capacity,
+            config.preserve_order,
+            &cut_off,
+            &mut pool,
+        );
+        let subscriptions = config.all_subscriptions();
+        let isolated = if subscriptions.iter().any(|subscription| subscription.workers.is_some()) {
+            subscriptions
+                .into_iter()
+                .map(|subscription| {
+                    let own = subscription.workers.map(|workers| {
+                        let capacity = subscription.queue_capacity.unwrap_or(config.queue_capacity);
+                        Pool::start(&pipeline, &names, workers, capacity, config.preserve_order, &cut_off, &mut pool)
+                    });
+                    (subscription.topic, own)
+                })
+                .collect()
+        } else {
+            Vec::new()
+        };
+        Dispatcher {
+            shared,
+            isolated,
+            queue_full: config.queue_full,
+            workers: pool,
+            cut_off,
+            dead_letters: pipeline.dead_letters.is_some(),
+            metrics: pipeline.metrics.clone(),
+            recorder,
+            summary,
+        }
+    }
+
+    pub async fn dispatch(&self, message: Message) -> Result<()> {
+        Metrics::inc(&self.metrics.received);
+        if let Some(summary) = &self.summary {
+            summary.record(&message.topic);
+        }
+        // A failing capture shouldn't stop processing
+        if let Some(recorder) = &self.recorder {
+            if let Err(e) = recorder.record(&message).await {
+                error!("{:#}", e);
+            }
+        }
+
+        let pool = self
+            .isolated
+            .iter()
+            .find(|(filter, _)| config::topic_matches_filter(&message.topic, filter))
+            .and_then(|(_, own)| own.as_ref())
+            .unwrap_or(&self.shared);
+        let queue = pool.queue(&message);
+        let message = match queue.try_send(message) {
+            Ok(()) => return Ok(()),
+            Err(mpsc::error::TrySendError::Full(message)) => message,
+            Err(mpsc::error::TrySendError::Closed(_)) => bail!("all dispatcher workers have stopped"),
+        };
+        Metrics::inc(&self.metrics.queue_full);
+        match self.queue_full {
+            OverflowPolicy::Block => queue.send(message).await?,
+            OverflowPolicy::Drop => {
+                warn!(event = "dropped", topic = %message.topic, "Processing queue is full; dropping message from {}", message.topic);
+                message.settle(true);
+            }
+        }
+        Ok(())
+    }
+
+    // Closing the queue lets each worker finish what's left in it and exit.
+    // The whole drain, dead letters included, takes at most `timeout`.
+    pub async fn drain(self, timeout: Duration) {
+        let Dispatcher {
+            shared,
+            isolated,
+            mut workers,
+            cut_off,
+            dead_letters,
+            metrics,
+            ..
+        } = self;
+        drop(shared);
+        drop(isolated);
+        let grace = DEAD_LETTER_GRACE.min(timeout / 2);
+        let mut cut = CutOff::default();
+        if tokio::time::timeout(timeout - grace, join_all(&mut workers, &mut cut)).await.is_ok() {
+            return;
+        }
+        warn!(
+            "Gave up waiting for {} busy worker(s) after {:?}; {} what they still hold",
+            workers.len(),
+            timeout - grace,
+            if dead_letters { "dead-lettering" } else { "dropping" }
+        );
+        cut_off.send_replace(true);
+        if tokio::time::timeout(grace, join_all(&mut workers, &mut cut)).await.is_err() {
+            warn!("Abandoning {} worker(s) stuck dead-lettering", workers.len());
+        }
+        let undelivered = metrics.undelivered.load(std::sync::atomic::Ordering::Relaxed);
+        if undelivered == 0 {
+            return;
+        }
+        if !dead_letters {
+            warn!(event = "undelivered", "{} message(s) were not delivered before shutdown and are lost", undelivered);
+            return;
+        }
+        // Those the abandoned workers were still dead-lettering never report back
+        let abandoned = undelivered.saturating_sub(cut.dead_lettered + cut.lost);
+        warn!(
+            event = "undelivered",
+            "{} message(s) were not delivered before shutdown: {} dead-lettered, {} could not be dead-lettered",
+            undelivered,
+            cut.dead_lettered,
+            cut.lost + abandoned
+        );
+    }
+}
+
+// What a worker did with the messages it was cut off from
+#[derive(Default)]
+struct CutOff {
+    dead_lettered: u64,
+    lost: u64,
+}
+
+async fn join_all(workers: &mut JoinSet<CutOff>, cut: &mut CutOff) {
+    while let Some(result) = workers.join_next().await {
+        if let Ok(worker) = result {
+            cut.dead_lettered += worker.dead_lettered;
+            cut.lost += worker.lost;
+        }
+    }
+}
+
+// Workers take turns on the receivers; the lock is only held while waiting
+// for the next message, not while processing it
+fn open_queues(names: &[Option<Arc<str>>], capacity: usize) -> (Queues, Arc<Mutex<Lanes>>) {
+    let (queues, receivers) = names
+        .iter()
+        .map(|name| {
+            let (queue, receiver) = mpsc::channel(capacity);
+            ((name.clone(), queue), receiver)
+        })
+        .unzip();
+    (queues, Arc::new(Mutex::new(Lanes { receivers, next: 0 })))
+}
+
+// The receiving ends of the queues, served in turn
+struct Lanes {
+    receivers: Vec<mpsc::Receiver<Message>>,
+    // Where the next look starts: just after the queue served last
+    next: usize,
+}
+
+impl Lanes {
+    // None once every queue is closed and empty
+    async fn recv(&mut self) -> Option<Message> {
+        std::future::poll_fn(|cx| {
+            let count = self.receivers.len();
+            let mut open = false;
+            for offset in 0..count {
+                let index = (self.next + offset) % count;
+                match self.receivers[index].poll_recv(cx) {
+                    Poll::Ready(Some(message)) => {
+                        self.next = (index + 1) % count;
+                        return Poll::Ready(Some(message));
+                    }
+                    Poll::Ready(None) => {}
+                    Poll::Pending => open = true,
+                }
+            }
+            if open {
+                Poll::Pending
+            } else {
+                Poll::Ready(None)
+            }
+        })
+        .await
+    }
+}
+
+async fn work(pipeline: Arc<Pipeline>, receiver: Arc<Mutex<Lanes>>, mut cut_off: watch::Receiver<bool>) -> CutOff {
+    let mut cut = CutOff::default();
+    loop {
+        let Some(message) = receiver.lock().await.recv().await else {
+            return cut;
+        };
+        // Once the drain stops waiting, what the worker holds and what is
+        // still queued is only dead-lettered
+        let result = if *cut_off.borrow() {
+            None
+        } else {
+            tokio::select! {
+                result = pipeline.process(&message) => Some(result),
+                _ = cut_off.wait_for(|cut| *cut) => None,
+            }
+        };
+        let Some(result) = result else {
+            Metrics::inc(&pipeline.metrics.undelivered);
+            Metrics::inc(&pipeline.metrics.failed);
+            if pipeline.dead_letter(&message.topic, &message.payload, &MappingError::Shutdown).await {
+                cut.dead_lettered += 1;
+            } else {
+                cut.lost += 1;
+            }
+            message.settle(false);
+            continue;
+        };
+        // Left unacknowledged when the output failed it, so the broker
+        // redelivers it; anything else would only fail the same way again
+        message.settle(!matches!(result, Err(MappingError::Emit(_))));
+        if let Err(e) = result {
+            let position = e.parse_position();
+            error!(
+                event = "failed",
+                topic = %message.topic,
+                category = e.category(),
+                line = position.map(|position| position.line),
+                column = position.map(|position| position.column),
+                parse_error = position.map(|position| position.kind),
+                "Failed to map payload from {}: {}",
+                message.topic,
+                e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DeadLetterConfig;
+    use crate::deadletter::DeadLetterSink;
+    use crate::emit::Emitter;
+    use crate::mapping::Mapper;
+    use crate::model::{DrasiDelete, DrasiElement, DrasiRelation};
+    use arc_swap::ArcSwap;
+    use async_trait::async_trait;
+    use rumqttc::QoS;
+    use std::sync::atomic::Ordering;
+
+    // An output that never answers
+    struct Stuck;
+
+    #[async_trait]
+    impl Emitter for Stuck {
+        async fn emit(&self, _element: DrasiElement) -> Result<()> {
+            std::future::pending().await
+        }
+
+        async fn delete(&self, _delete: DrasiDelete) -> Result<()> {
+            std::future::pending().await
+        }
+
+        async fn emit_relation(&self, _relation: DrasiRelation) -> Result<()> {
+            std::future::pending().await
+        }
+    }
+
+    fn pipeline(config: &Config, emitter: Arc<dyn Emitter>, dead_letters: Option<DeadLetterSink>) -> Arc<Pipeline> {
+        Arc::new(Pipeline {
+            mapper: ArcSwap::from_pointee(Mapper::new(config).unwrap()),
+            emitter,
+            metrics: Arc::new(Metrics::default()),
+            dead_letters,
+            dedup: None,
+            change_detection: None,
+            merge: None,
+            expiry: None,
+            reorder: None,
+            snapshot: None,
+            sequence: None,
+            checkpoints: None,
+            aggregator: None,
+            retry_queue: None,
+            max_payload_bytes: config.max_payload_bytes,
+            max_properties: None,
+            dead_letter_oversized: false,
+            canonicalize: false,
+            redact: Vec::new(),
+            slow_message: None,
+        })
+    }
+
+    fn message(id: &str) -> Message {
+        Message::from(rumqttc::Publish::new(format!("sensors/{}", id), QoS::AtLeastOnce, "{}"))
+    }
+
+    #[tokio::test]
+    async fn what_is_left_at_the_deadline_is_dead_lettered() {
+        let path = std::env::temp_dir().join(format!("drasi-mqtt-drain-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let config = Config {
+            max_concurrency: 1,
+            ..Config::default()
+        };
+        let sink = DeadLetterSink::build(&DeadLetterConfig::File { path: path.clone() }, None).await.unwrap();
+        let pipeline = pipeline(&config, Arc::new(Stuck), Some(sink));
+        let dispatcher = Dispatcher::new(pipeline.clone(), &config, Vec::new(), None, None);
+        for id in ["a", "b", "c"] {
+            dispatcher.dispatch(message(id)).await.unwrap();
+        }
+        dispatcher.drain(Duration::from_millis(200)).await;
+
+        assert_eq!(pipeline.metrics.undelivered.load(Ordering::Relaxed), 3);
+        let letters = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(letters.lines().count(), 3);
+        assert!(letters.lines().all(|line| line.contains("\"shutdown\"")), "{}", letters);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn without_dead_letters_they_are_counted_as_undelivered() {
+        let config = Config {
+            max_concurrency: 2,
+            ..Config::default()
+        };
+        let pipeline = pipeline(&config, Arc::new(Stuck), None);
+        let dispatcher = Dispatcher::new(pipeline.clone(), &config, Vec::new(), None, None);
+        for id in ["a", "b", "c"] {
+            dispatcher.dispatch(message(id)).await.unwrap();
+        }
+        dispatcher.drain(Duration::from_millis(200)).await;
+        assert_eq!(pipeline.metrics.undelivered.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn a_full_queue_drops_or_waits_as_configured() {
+        let config = Config {
+            max_concurrency: 1,
+            queue_capacity: 1,
+            queue_full: OverflowPolicy::Drop,
+            ..Config::default()
+        };
+        let pipeline = pipeline(&config, Arc::new(Stuck), None);
+        let dispatcher = Dispatcher::new(pipeline.clone(), &config, Vec::new(), None, None);
+        // The worker takes the first and is stuck with it; the second fills the queue
+        dispatcher.dispatch(message("a")).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        for id in ["b", "c"] {
+            dispatcher.dispatch(message(id)).await.unwrap();
+        }
+        assert_eq!(pipeline.metrics.queue_full.load(Ordering::Relaxed), 1);
+        dispatcher.drain(Duration::from_millis(20)).await;
+        assert_eq!(pipeline.metrics.undelivered.load(Ordering::Relaxed), 2);
+
+        let config = Config {
+            queue_full: OverflowPolicy::Block,
+            ..config
+        };
+        let pipeline = self::pipeline(&config, Arc::new(Stuck), None);
+        let dispatcher = Dispatcher::new(pipeline.clone(), &config, Vec::new(), None, None);
+        dispatcher.dispatch(message("a")).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        dispatcher.dispatch(message("b")).await.unwrap();
+        let waited = tokio::time::timeout(Duration::from_millis(50), dispatcher.dispatch(message("c"))).await;
+        assert!(waited.is_err());
+        assert_eq!(pipeline.metrics.queue_full.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn the_queues_are_served_in_turn() {
+        let (busy, busy_receiver) = mpsc::channel(8);
+        let (quiet, quiet_receiver) = mpsc::channel(8);
+        for id in ["b1", "b2", "b3"] {
+            busy.try_send(message(id)).unwrap();
+        }
+        quiet.try_send(message("q1")).unwrap();
+        let mut lanes = Lanes {
+            receivers: vec![busy_receiver, quiet_receiver],
+            next: 0,
+        };
+        let mut order = Vec::new();
+        for _ in 0..4 {
+            order.push(lanes.recv().await.unwrap().topic);
+        }
+        assert_eq!(order, ["sensors/b1", "sensors/q1", "sensors/b2", "sensors/b3"]);
+        drop((busy, quiet));
+        assert!(lanes.recv().await.is_none());
+    }
+
+    // Takes `delay_ms` over each reading, then notes its `n`
+    #[derive(Default)]
+    struct Delayed(std::sync::Mutex<Vec<String>>);
+
+    #[async_trait]
+    impl Emitter for Delayed {
+        async fn emit(&self, element: DrasiElement) -> Result<()> {
+            let delay = element.properties["delay_ms"].as_u64().unwrap_or_default();
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+            self.0.lock().unwrap().push(format!("{} {}", element.id, element.properties["n"]));
+            Ok(())
+        }
+
+        async fn delete(&self, _delete: DrasiDelete) -> Result<()> {
+            Ok(())
+        }
+
+        async fn emit_relation(&self, _relation: DrasiRelation) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn reading(topic: &str, n: u64, delay_ms: u64) -> Message {
+        let payload = serde_json::json!({ "n": n, "delay_ms": delay_ms }).to_string();
+        Message::from(rumqttc::Publish::new(topic, QoS::AtLeastOnce, payload))
+    }
+
+    #[tokio::test]
+    async fn preserve_order_keeps_each_topic_in_arrival_order() {
+        let config = Config {
+            max_concurrency: 4,
+            preserve_order: true,
+            ..Config::default()
+        };
+        let delayed = Arc::new(Delayed::default());
+        let dispatcher = Dispatcher::new(pipeline(&config, delayed.clone(), None), &config, Vec::new(), None, None);
+        // Earlier readings take longer, so any concurrency on the topic would
+        // let later ones overtake them
+        for n in 0..6 {
+            dispatcher.dispatch(reading("sensors/a", n, (6 - n) * 5)).await.unwrap();
+        }
+        dispatcher.drain(Duration::from_secs(5)).await;
+        assert_eq!(*delayed.0.lock().unwrap(), ["a 0", "a 1", "a 2", "a 3", "a 4", "a 5"]);
+    }
+
+    #[tokio::test]
+    async fn a_subscription_with_its_own_workers_is_not_held_up_by_the_others() {
+        let config = Config {
+            max_concurrency: 1,
+            queue_capacity: 1,
+            queue_full: OverflowPolicy::Drop,
+            subscriptions: vec![
+                config::Subscription::new("flood/#"),
+                config::Subscription {
+                    workers: Some(1),
+                    ..config::Subscription::new("alarms/#")
+                },
+            ],
+            ..Config::default()
+        };
+        let delayed = Arc::new(Delayed::default());
+        let dispatcher = Dispatcher::new(pipeline(&config, delayed.clone(), None), &config, Vec::new(), None, None);
+        // The shared worker is busy for a minute and its queue is full
+        for n in 0..3 {
+            dispatcher.dispatch(reading("flood/f", n, 60_000)).await.unwrap();
+        }
+        dispatcher.dispatch(reading("alarms/fire", 0, 0)).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(*delayed.0.lock().unwrap(), ["fire 0"]);
+        dispatcher.drain(Duration::from_millis(20)).await;
+    }
+}
@@ -0,0 +1,721 @@
+use anyhow::{anyhow, bail, Context, Result};
+use rumqttc::QoS;
+use tracing::{debug, error, info, info_span, warn, Instrument, Span};
+use std::future::Future;
+use std::pin::{pin, Pin};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{watch, Semaphore, SemaphorePermit};
+use tokio::task::JoinHandle;
+
+use super::Dispatcher;
+use crate::ack::AckQueue;
+use crate::backoff::Backoff;
+use crate::config::{Config, Subscription, TransportKind};
+use crate::connection::{self, ConnectionState, ErrorKind, MqttClient, MqttEventLoop, SourceEvent, Status};
+use crate::mapping::qos_level;
+use crate::metrics::Metrics;
+
+// --- MQTT SOURCE ---
+// The live source: subscribes, feeds every publish to the Dispatcher and
+// keeps the connection alive until shutdown.
+pub struct MqttSource {
+    // Identifies the connection in `_broker` and, with several brokers, logs
+    name: Arc<str>,
+    // 'client' is used to control the connection (subscribe/publish)
+    // 'eventloop' is the stream of incoming network packets
+    client: MqttClient,
+    eventloop: MqttEventLoop,
+    status: Option<Status>,
+    state: watch::Sender<ConnectionState>,
+    subscriptions: Vec<Subscription>,
+    exit_on_subscribe_failure: bool,
+    fail_on_qos_downgrade: bool,
+    max_reconnect_attempts: u32,
+    initial_connect_attempts: u32,
+    max_subscribe_attempts: u32,
+    subscribe_delay: Duration,
+    // Only with `manual_ack`
+    acks: Option<Arc<AckQueue>>,
+    connect_timeout: Option<Duration>,
+    exit_on_connect_timeout: bool,
+    idle_timeout: Option<Duration>,
+    // `host:port`, to name it when it can't be reached
+    proxy: Option<String>,
+}
+
+impl MqttSource {
+    pub fn new(name: &str, config: &Config) -> Result<Self> {
+        let transport = match config.transport {
+            TransportKind::Tcp if config.tls.enabled => " (TLS)".to_string(),
+            TransportKind::Tcp => String::new(),
+            TransportKind::Ws => format!(" (WebSocket {})", config.websocket_path),
+            TransportKind::Wss => format!(" (secure WebSocket {})", config.websocket_path),
+        };
+        let proxy = config.proxy.as_ref().map(|proxy| format!("{}:{}", proxy.host, proxy.port));
+        info!(
+            "Using broker {}:{} over MQTT {:?}{}{}",
+            config.broker_host,
+            config.port(),
+            config.protocol_version,
+            transport,
+            proxy.as_ref().map(|proxy| format!(" through proxy {}", proxy)).unwrap_or_default()
+        );
+        let (client, eventloop) = connection::create_client(config)?;
+        let acks = config.manual_ack.then(|| AckQueue::start(client.clone()));
+        Ok(MqttSource {
+            name: name.into(),
+            status: Status::new(&client, config),
+            client,
+            eventloop,
+            state: watch::Sender::new(ConnectionState::Connecting),
+            subscriptions: config.subscriptions.clone(),
+            exit_on_subscribe_failure: config.exit_on_subscribe_failure,
+            fail_on_qos_downgrade: config.fail_on_qos_downgrade,
+            max_reconnect_attempts: config.max_reconnect_attempts,
+            initial_connect_attempts: config.initial_connect_attempts,
+            max_subscribe_attempts: config.max_subscribe_attempts,
+            subscribe_delay: Duration::from_millis(config.subscribe_delay_ms),
+            acks,
+            connect_timeout: config.connect_timeout_secs.map(Duration::from_secs),
+            exit_on_connect_timeout: config.exit_on_connect_timeout,
+            idle_timeout: config.idle_timeout_secs.map(Duration::from_secs),
+            proxy,
+        })
+    }
+
+    pub fn name(&self) -> &Arc<str> {
+        &self.name
+    }
+
+    // Follows the connection from now on, starting with its current state
+    pub fn state(&self) -> watch::Receiver<ConnectionState> {
+        self.state.subscribe()
+    }
+
+    fn set_state(&self, state: ConnectionState) {
+        if self.state.send_replace(state) != state {
+            debug!(event = "connection_state", "Connection state: {:?}", state);
+        }
+    }
+
+    // One event loop per broker, all feeding the same dispatcher, until
+    // `shutdown` resolves. The first to fail stops the others, so its error
+    // becomes the outcome just as with a single broker.
+    pub async fn run_all(
+        sources: Vec<MqttSource>,
+        max_active: Option<usize>,
+        dispatcher: &Dispatcher,
+        shutdown: Pin<&mut impl Future<Output = ()>>,
+    ) -> Result<()> {
+        let stop = watch::Sender::new(false);
+        let several = sources.len() > 1;
+        let slots = max_active.filter(|max| *max < sources.len()).map(Semaphore::new);
+        if let Some(max) = max_active.filter(|_| slots.is_some()) {
+            info!("Connecting to at most {} of the {} brokers at a time", max, sources.len());
+        }
+        let slots = slots.as_ref();
+        let runs = sources.into_iter().map(|source| {
+            let span = if several {
+                info_span!("mqtt", broker = %source.name)
+            } else {
+                Span::none()
+            };
+            let name = source.name.clone();
+            let mut stopped = stop.subscribe();
+            let stop = &stop;
+            async move {
+                let stopped = pin!(async move {
+                    let _ = stopped.wait_for(|stopped| *stopped).await;
+                });
+                let result = source.run(dispatcher, slots, stopped).await;
+                if result.is_err() {
+                    stop.send_replace(true);
+                }
+                if several {
+                    result.with_context(|| format!("broker {}", name))
+                } else {
+                    result
+                }
+            }
+            .instrument(span)
+        });
+        let mut all = pin!(futures::future::join_all(runs));
+        let results = tokio::select! {
+            results = &mut all => results,
+            _ = shutdown => {
+                stop.send_replace(true);
+                all.await
+            }
+        };
+        results.into_iter().collect()
+    }
+
+    // Runs until `shutdown` resolves, then leaves the broker cleanly. Errors
+    // that retrying can't fix end the run early with that error. With
+    // `slots`, the event loop is only polled (so only connects) while holding
+    // one; it is handed back while waiting to reconnect.
+    pub async fn run(
+        mut self,
+        dispatcher: &Dispatcher,
+        slots: Option<&Semaphore>,
+        mut shutdown: Pin<&mut impl Future<Output = ()>>,
+    ) -> Result<()> {
+        let Some(mut slot) = self.take_slot(slots, shutdown.as_mut()).await else {
+            return Ok(());
+        };
+        // A. Main Event Loop
+        // Subscribing (the "Source" logic) happens on each ConnAck, below
+        let mut reconnect_backoff = Backoff::default();
+        // Connection errors since the last ConnAck
+        let mut failures: u32 = 0;
+        let mut connected_before = false;
+        // Armed until the first ConnAck, or until it has gone off once
+        let mut awaiting_connack = self.connect_timeout.is_some();
+        let connect_deadline = tokio::time::sleep(self.connect_timeout.unwrap_or_default());
+        let mut connect_deadline = pin!(connect_deadline);
+        // Pushed back on everything received but pings, and armed only while
+        // connected: a half-dead broker may well keep answering those
+        let mut connected = false;
+        let idle_deadline = tokio::time::sleep(self.idle_timeout.unwrap_or_default());
+        let mut idle_deadline = pin!(idle_deadline);
+        // Said goodbye after going idle; the connection is dropped as soon as
+        // the DISCONNECT is out, whether or not the broker closes it
+        let mut leaving_idle = false;
+        // Handing the subscriptions to the client after the latest ConnAck
+        let mut subscribing: Option<JoinHandle<Result<()>>> = None;
+        loop {
+            let event = tokio::select! {
+                _ = &mut shutdown => break,
+                _ = &mut connect_deadline, if awaiting_connack => {
+                    awaiting_connack = false;
+                    let timeout = self.connect_timeout.unwrap_or_default();
+                    if self.exit_on_connect_timeout {
+                        error!(event = "connect_timeout", "Giving up: no connection to the broker within {:?} of starting", timeout);
+                        return Err(anyhow!("no connection to the MQTT broker within {:?}; check its host and port", timeout));
+                    }
+                    error!(
+                        event = "connect_timeout",
+                        "No connection to the broker within {:?} of starting; check its host and port. Still retrying...",
+                        timeout
+                    );
+                    continue;
+                }
+                _ = &mut idle_deadline, if connected && !leaving_idle && self.idle_timeout.is_some() => {
+                    leaving_idle = true;
+                    Metrics::inc(&dispatcher.metrics.idle_reconnects);
+                    warn!(
+                        event = "idle_timeout",
+                        "Nothing received from the broker {} for {:?}; reconnecting",
+                        self.name,
+                        self.idle_timeout.unwrap_or_default()
+                    );
+                    // On its own task: the request channel is only drained
+                    // while this loop polls
+                    let client = self.client.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = client.disconnect().await {
+                            warn!("Failed to disconnect from the idle broker: {:#}", e);
+                        }
+                    });
+                    continue;
+                }
+                Some(result) = async { Some(subscribing.as_mut()?.await) }, if subscribing.is_some() => {
+                    subscribing = None;
+                    result??;
+                    for subscription in &self.subscriptions {
+                        info!("Subscribed to topic: {} ({:?})", subscription.topic, subscription.qos);
+                    }
+                    continue;
+                }
+                event = self.eventloop.poll() => event,
+            };
+            let heard = matches!(
+                event,
+                Ok(SourceEvent::Message(_) | SourceEvent::Connected { .. } | SourceEvent::SubAck(_) | SourceEvent::PubAck(_))
+            );
+            if let (true, Some(timeout)) = (heard, self.idle_timeout) {
+                idle_deadline.as_mut().reset(tokio::time::Instant::now() + timeout);
+            }
+            match event {
+                Ok(SourceEvent::Message(mut message)) => {
+                    message.broker = Some(self.name.clone());
+                    if let Some(acks) = &self.acks {
+                        message.ack = acks.ticket(message.qos, message.pkid);
+                    }
+                    dispatcher.dispatch(message).await?
+                }
+                Ok(SourceEvent::Connected { session_present }) => {
+                    info!("Successfully connected to MQTT Broker!");
+                    self.set_state(ConnectionState::Connected);
+                    connected = true;
+                    reconnect_backoff.reset();
+                    failures = 0;
+                    awaiting_connack = false;
+                    if let Some(acks) = &self.acks {
+                        acks.new_session();
+                    }
+                    // Subscribing only once the broker has accepted the
+                    // connection, and again after every reconnect: rumqttc
+                    // doesn't replay subscriptions, so a fresh session would
+                    // receive nothing. Spawned for the same reason as the
+                    // birth message below; giving up ends the run.
+                    if !connected_before || !session_present {
+                        if connected_before {
+                            info!("Re-subscribing to {} topic(s) after reconnecting", self.subscriptions.len());
+                        }
+                        if let Some(previous) = subscribing.take() {
+                            previous.abort();
+                        }
+                        let client = self.client.clone();
+                        let subscriptions = self.subscriptions.clone();
+                        let max_attempts = self.max_subscribe_attempts;
+                        let delay = self.subscribe_delay;
+                        subscribing = Some(tokio::spawn(async move {
+                            tokio::time::sleep(delay).await;
+                            subscribe_with_retry(|| client.subscribe(&subscriptions), max_attempts).await
+                        }));
+                    }
+                    connected_before = true;
+                    // On its own task: the request channel is only drained
+                    // while this loop polls
+                    if let Some(status) = self.status.clone() {
+                        tokio::spawn(async move {
+                            if let Err(e) = status.publish_birth().await {
+                                warn!("Failed to publish birth message: {:#}", e);
+                            }
+                        });
+                    }
+                }
+                Ok(SourceEvent::SubAck(results)) => self.check_suback(&results, &dispatcher.metrics)?,
+                Ok(SourceEvent::DisconnectSent) if leaving_idle => {
+                    leaving_idle = false;
+                    connected = false;
+                    self.eventloop.drop_connection();
+                    self.set_state(ConnectionState::Reconnecting);
+                }
+                Ok(_) => {} // Ignore Pings and Acks to keep logs clean
+                Err(e) => {
+                    self.set_state(ConnectionState::Disconnected);
+                    connected = false;
+                    leaving_idle = false;
+                    // A v5 broker may have told us why it closed the connection
+                    let disconnect_reason = connection::disconnect_reason(&e);
+                    if connection::classify(&e) == ErrorKind::Fatal {
+                        if let Some(reason) = disconnect_reason {
+                            error!(event = "broker_disconnect", "Giving up: the broker disconnected us ({})", reason);
+                            return Err(e.context(format!("the MQTT broker disconnected us: {}", reason)));
+                        }
+                        error!(event = "fatal", "Giving up on the broker: {:#}", e);
+                        return Err(e.context("cannot connect to the MQTT broker"));
+                    }
+                    failures += 1;
+                    // A misspelled host fails fast rather than retrying on
+                    // with nothing received
+                    if !connected_before && self.initial_connect_attempts > 0 && failures >= self.initial_connect_attempts {
+                        error!(
+                            event = "unreachable",
+                            "Giving up: could not reach the broker {} in {} attempt(s): {:#}",
+                            self.name,
+                            failures,
+                            e
+                        );
+                        return Err(e.context(format!(
+                            "could not reach the MQTT broker {} in {} attempt(s); check its host and port",
+                            self.name, failures
+                        )));
+                    }
+                    if self.max_reconnect_attempts > 0 && failures >= self.max_reconnect_attempts {
+                        error!(event = "fatal", "Giving up on the broker after {} failed attempt(s): {:#}", failures, e);
+                        return Err(e.context(format!(
+                            "cannot connect to the MQTT broker after {} attempt(s)",
+                            failures
+                        )));
+                    }
+                    // rumqttc reconnects on the next poll; we only decide how
+                    // long to wait, backing off while the broker stays unreachable
+                    let delay = reconnect_backoff.next_delay();
+                    match disconnect_reason {
+                        Some(reason) => warn!(
+                            event = "broker_disconnect",
+                            "Broker disconnected us ({}). Retrying in {:?}...",
+                            reason,
+                            delay
+                        ),
+                        None if connection::is_proxy_error(&e) => warn!(
+                            event = "proxy_unreachable",
+                            "Cannot reach the broker through proxy {}: {:#}. Retrying in {:?}...",
+                            self.proxy.as_deref().unwrap_or("?"),
+                            e,
+                            delay
+                        ),
+                        None if !connected_before => warn!("Cannot connect to the broker: {:#}. Retrying in {:?}...", e, delay),
+                        None => warn!("Connection lost: {:#}. Retrying in {:?}...", e, delay),
+                    }
+                    // The connect timeout cuts the wait short, so it goes off on time
+                    drop(slot);
+                    tokio::select! {
+                        _ = &mut shutdown => break,
+                        _ = tokio::time::sleep(delay) => {}
+                        _ = &mut connect_deadline, if awaiting_connack => {}
+                    }
+                    slot = match self.take_slot(slots, shutdown.as_mut()).await {
+                        Some(slot) => slot,
+                        None => break,
+                    };
+                    self.set_state(ConnectionState::Reconnecting);
+                }
+            }
+        }
+
+        // B. Leave the Broker
+        if let Some(subscribing) = subscribing {
+            subscribing.abort();
+        }
+        self.set_state(ConnectionState::Disconnected);
+        self.disconnect().await;
+        Ok(())
+    }
+
+    // Waits for a free slot, if connections are capped; None on shutdown
+    async fn take_slot<'a>(
+        &self,
+        slots: Option<&'a Semaphore>,
+        shutdown: Pin<&mut impl Future<Output = ()>>,
+    ) -> Option<Option<SemaphorePermit<'a>>> {
+        let Some(slots) = slots else {
+            return Some(None);
+        };
+        if let Ok(permit) = slots.try_acquire() {
+            return Some(Some(permit));
+        }
+        info!("Waiting for another broker to disconnect before connecting to {}", self.name);
+        tokio::select! {
+            permit = slots.acquire() => Some(Some(permit.expect("connection slots are never closed"))),
+            _ = shutdown => None,
+        }
+    }
+
+    // Without this a refused subscription looks exactly like a quiet topic,
+    // and a downgraded one like a working one
+    fn check_suback(&self, results: &[Result<QoS, String>], metrics: &Metrics) -> Result<()> {
+        let mut refused = Vec::new();
+        let mut downgraded = Vec::new();
+        for (subscription, result) in self.subscriptions.iter().zip(results) {
+            metrics.set_granted_qos(&self.name, &subscription.topic, result.as_ref().ok().map(|granted| qos_level(*granted)));
+            match result {
+                Ok(granted) if qos_level(*granted) < qos_level(subscription.qos) => {
+                    warn!(
+                        event = "qos_downgraded",
+                        topic = %subscription.topic,
+                        "Broker downgraded subscription to {} from {:?} to {:?}",
+                        subscription.topic,
+                        subscription.qos,
+                        granted
+                    );
+                    downgraded.push(format!("{} (QoS {} of {})", subscription.topic, qos_level(*granted), qos_level(subscription.qos)));
+                }
+                Ok(granted) => debug!("Broker granted {:?} for {}", granted, subscription.topic),
+                Err(reason) => {
+                    error!(event = "subscribe_failed", topic = %subscription.topic, "Broker refused subscription to {}: {}", subscription.topic, reason);
+                    refused.push(subscription.topic.as_str());
+                }
+            }
+        }
+        if self.exit_on_subscribe_failure && !refused.is_empty() {
+            bail!("broker refused subscription(s) to {}", refused.join(", "));
+        }
+        if self.fail_on_qos_downgrade && !downgraded.is_empty() {
+            bail!("broker granted a lower QoS than requested for {}", downgraded.join(", "));
+        }
+        Ok(())
+    }
+
+    async fn disconnect(mut self) {
+        // A clean DISCONNECT makes the broker drop our will, so announce going
+        // offline ourselves first
+        let goodbye = tokio::spawn({
+            let client = self.client.clone();
+            let status = self.status.take();
+            async move {
+                if let Some(status) = &status {
+                    if let Err(e) = status.publish_offline().await {
+                        warn!("Failed to publish offline status: {:#}", e);
+                    }
+                }
+                client.disconnect().await
+            }
+        });
+        // Both packets only go out while the event loop is polled
+        let _ = tokio::time::timeout(Duration::from_secs(1), async {
+            while let Ok(event) = self.eventloop.poll().await {
+                if let SourceEvent::DisconnectSent = event {
+                    break;
+                }
+            }
+        })
+        .await;
+        goodbye.abort();
+    }
+}
+
+// Hands the subscriptions to the client with `subscribe`, waiting out the
+// same backoff as reconnects between failures; gives up after `max_attempts`
+// (0 = never)
+async fn subscribe_with_retry<F, Fut>(mut subscribe: F, max_attempts: u32) -> Result<()>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let mut backoff = Backoff::default();
+    let mut failures: u32 = 0;
+    loop {
+        let Err(e) = subscribe().await else {
+            return Ok(());
+        };
+        failures += 1;
+        if max_attempts > 0 && failures >= max_attempts {
+            error!(event = "fatal", "Giving up on subscribing after {} failed attempt(s): {:#}", failures, e);
+            return Err(e.context(format!("cannot subscribe after {} attempt(s)", failures)));
+        }
+        let delay = backoff.next_delay();
+        warn!("Failed to subscribe: {:#}. Retrying in {:?}...", e, delay);
+        tokio::time::sleep(delay).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::tests::{MockBroker, Session};
+    use crate::emit::tests::Recording;
+    use rumqttc::mqttbytes::v4::Packet;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    // Two QoS 1 subscriptions; nothing is connected
+    fn source(exit_on_subscribe_failure: bool, fail_on_qos_downgrade: bool) -> MqttSource {
+        let subscription = |topic| Subscription {
+            qos: QoS::AtLeastOnce,
+            ..Subscription::new(topic)
+        };
+        let config = Config {
+            subscriptions: vec![subscription("sensors/#"), subscription("alarms/#")],
+            exit_on_subscribe_failure,
+            fail_on_qos_downgrade,
+            ..Config::default()
+        };
+        MqttSource::new("main", &config).unwrap()
+    }
+
+    #[test]
+    fn refused_and_downgraded_subscriptions_fail_only_when_asked_to() {
+        let results = [Ok(QoS::AtMostOnce), Err("NotAuthorized".to_string())];
+        let metrics = Metrics::default();
+        source(false, false).check_suback(&results, &metrics).unwrap();
+        let rendered = metrics.render();
+        assert!(rendered.contains("drasi_mqtt_subscription_granted_qos{broker=\"main\",topic=\"sensors/#\"} 0\n"));
+        assert!(!rendered.contains("topic=\"alarms/#\""));
+
+        let refused = source(true, false).check_suback(&results, &metrics).unwrap_err();
+        assert_eq!(refused.to_string(), "broker refused subscription(s) to alarms/#");
+        let downgraded = source(false, true).check_suback(&results, &metrics).unwrap_err();
+        assert_eq!(downgraded.to_string(), "broker granted a lower QoS than requested for sensors/# (QoS 0 of 1)");
+    }
+
+    // Maps into a `Recording`
+    fn dispatcher(config: &Config) -> (Dispatcher, Arc<Recording>) {
+        let recording = Arc::new(Recording::default());
+        let pipeline = crate::pipeline::tests::pipeline(config, recording.clone());
+        (Dispatcher::new(Arc::new(pipeline), config, Vec::new(), None, None), recording)
+    }
+
+    // The connections a SUBSCRIBE came in on, in order
+    fn subscribed(received: &[(usize, Packet)]) -> Vec<usize> {
+        received
+            .iter()
+            .filter(|(_, packet)| matches!(packet, Packet::Subscribe(_)))
+            .map(|(connection, _)| *connection)
+            .collect()
+    }
+
+    // Runs a source on `config` until the broker has received what `done`
+    // waits for, then signals shutdown through a channel
+    async fn run_until(broker: &MockBroker, config: &Config, done: impl Fn(&[(usize, Packet)]) -> bool) -> Result<()> {
+        let (dispatcher, _) = dispatcher(config);
+        let (signal, shutdown) = tokio::sync::oneshot::channel::<()>();
+        let shutdown = pin!(async {
+            let _ = shutdown.await;
+        });
+        let run = MqttSource::new("main", config).unwrap().run(&dispatcher, None, shutdown);
+        let signalled = async {
+            broker.until(done).await;
+            signal.send(()).unwrap();
+        };
+        let (result, ()) = tokio::join!(tokio::time::timeout(Duration::from_secs(5), run), signalled);
+        result.expect("the loop kept running after shutdown")
+    }
+
+    #[tokio::test]
+    async fn the_loop_leaves_the_broker_once_shutdown_is_signalled() {
+        let broker = MockBroker::start(vec![Session::Accept]).await;
+        run_until(&broker, &broker.config(), |received| !subscribed(received).is_empty()).await.unwrap();
+        broker.until(|received| matches!(received.last(), Some((0, Packet::Disconnect)))).await;
+    }
+
+    #[tokio::test]
+    async fn the_subscriptions_are_issued_again_after_a_reconnect() {
+        let broker = MockBroker::start(vec![Session::Close, Session::Accept]).await;
+        run_until(&broker, &broker.config(), |received| subscribed(received).len() == 2).await.unwrap();
+        assert_eq!(subscribed(&broker.received()), [0, 1]);
+    }
+
+    #[tokio::test]
+    async fn the_loop_gives_up_after_max_reconnect_attempts() {
+        // Connected once, so only the reconnect limit applies
+        let broker = MockBroker::start(vec![Session::Close, Session::Refuse, Session::Refuse]).await;
+        let config = Config {
+            max_reconnect_attempts: 2,
+            ..broker.config()
+        };
+        let (dispatcher, _) = dispatcher(&config);
+        let never = pin!(std::future::pending());
+        let run = MqttSource::new("main", &config).unwrap().run(&dispatcher, None, never);
+
+        let error = tokio::time::timeout(Duration::from_secs(5), run).await.expect("the loop kept retrying").unwrap_err();
+        assert_eq!(error.to_string(), "cannot connect to the MQTT broker after 2 attempt(s)");
+        assert_eq!(subscribed(&broker.received()), [0]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_failed_subscribe_is_retried_after_a_backoff() {
+        let attempts = AtomicU32::new(0);
+        let started = tokio::time::Instant::now();
+        let subscribe = || async {
+            match attempts.fetch_add(1, Ordering::Relaxed) {
+                0 => Err(anyhow!("request channel closed")),
+                _ => Ok(()),
+            }
+        };
+        subscribe_with_retry(subscribe, 3).await.unwrap();
+        assert_eq!(attempts.load(Ordering::Relaxed), 2);
+        // The first reconnect delay, less its jitter
+        assert!(started.elapsed() >= Duration::from_millis(400));
+
+        let error = subscribe_with_retry(|| async { Err(anyhow!("request channel closed")) }, 2).await.unwrap_err();
+        assert_eq!(error.to_string(), "cannot subscribe after 2 attempt(s)");
+    }
+
+    #[tokio::test]
+    async fn subscribing_waits_for_the_connack_and_the_delay_after_it() {
+        // A subscribe sent at startup would have waited out its delay during
+        // the failed first attempt
+        let broker = MockBroker::start(vec![Session::Refuse, Session::Accept]).await;
+        let config = Config {
+            subscribe_delay_ms: 300,
+            ..broker.config()
+        };
+        let connected = |received: &[(usize, Packet)]| received.iter().any(|(connection, _)| *connection == 1);
+        let (dispatcher, _) = dispatcher(&config);
+        let never = pin!(std::future::pending());
+        let run = MqttSource::new("main", &config).unwrap().run(&dispatcher, None, never);
+        let subscribed_after = async {
+            broker.until(connected).await;
+            let connack = tokio::time::Instant::now();
+            broker.until(|received| !subscribed(received).is_empty()).await;
+            connack.elapsed()
+        };
+        let elapsed = tokio::select! {
+            _ = run => panic!("the loop ended"),
+            elapsed = subscribed_after => elapsed,
+        };
+
+        assert_eq!(subscribed(&broker.received()), [1]);
+        assert!(elapsed >= Duration::from_millis(250), "subscribed {:?} after connecting", elapsed);
+    }
+
+    #[tokio::test]
+    async fn the_birth_message_is_published_once_connected() {
+        let broker = MockBroker::start(vec![Session::Refuse, Session::Accept]).await;
+        let config = Config {
+            birth_topic: Some("drasi/birth".to_string()),
+            birth_payload: "online".to_string(),
+            ..broker.config()
+        };
+        let births = |received: &[(usize, Packet)]| -> Vec<(usize, rumqttc::Publish)> {
+            received
+                .iter()
+                .filter_map(|(connection, packet)| match packet {
+                    Packet::Publish(publish) if publish.topic == "drasi/birth" => Some((*connection, publish.clone())),
+                    _ => None,
+                })
+                .collect()
+        };
+        run_until(&broker, &config, |received| !births(received).is_empty()).await.unwrap();
+
+        // Nothing before the broker accepted a connection
+        let births = births(&broker.received());
+        assert_eq!(births.len(), 1);
+        let (connection, birth) = &births[0];
+        assert_eq!(*connection, 1);
+        assert!(birth.retain);
+        assert_eq!(&birth.payload[..], b"online");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn no_connack_within_the_connect_timeout_ends_the_run() {
+        let broker = MockBroker::start(vec![Session::Silent]).await;
+        let config = Config {
+            connect_timeout_secs: Some(2),
+            exit_on_connect_timeout: true,
+            ..broker.config()
+        };
+        let (dispatcher, _) = dispatcher(&config);
+        let never = pin!(std::future::pending());
+        let started = tokio::time::Instant::now();
+        let error = MqttSource::new("main", &config).unwrap().run(&dispatcher, None, never).await.unwrap_err();
+
+        assert_eq!(error.to_string(), "no connection to the MQTT broker within 2s; check its host and port");
+        assert_eq!(started.elapsed(), Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn a_silent_broker_is_left_and_reconnected_to_after_the_idle_timeout() {
+        let broker = MockBroker::start(vec![Session::Mute, Session::Accept]).await;
+        let config = Config {
+            idle_timeout_secs: Some(1),
+            ..broker.config()
+        };
+        let (dispatcher, _) = dispatcher(&config);
+        let never = pin!(std::future::pending());
+        let run = MqttSource::new("main", &config).unwrap().run(&dispatcher, None, never);
+        tokio::select! {
+            _ = run => panic!("the loop ended"),
+            _ = broker.until(|received| subscribed(received).contains(&1)) => {}
+        }
+
+        let received = broker.received();
+        assert!(received.iter().any(|(connection, packet)| *connection == 0 && matches!(packet, Packet::Disconnect)));
+        assert_eq!(dispatcher.metrics.idle_reconnects.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn an_unreachable_broker_fails_the_run_after_the_initial_attempts() {
+        // Free a port so nothing listens on it
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let config = Config {
+            broker_host: "127.0.0.1".to_string(),
+            broker_port: Some(port),
+            initial_connect_attempts: 2,
+            ..Config::default()
+        };
+        let (dispatcher, _) = dispatcher(&config);
+        let never = pin!(std::future::pending());
+        let run = MqttSource::new("main", &config).unwrap().run(&dispatcher, None, never);
+
+        let error = tokio::time::timeout(Duration::from_secs(5), run).await.expect("the loop kept retrying").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "could not reach the MQTT broker main in 2 attempt(s); check its host and port"
+        );
+    }
+}
@@ -0,0 +1,108 @@
+use tracing::info;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::config::TopicSummaryConfig;
+
+// --- TOPIC SUMMARY ---
+// Counts incoming messages per topic (or per topic prefix of `levels`
+// levels) and logs the counts every interval, resetting them each time, so
+// chatty and silent devices stand out without a metrics stack. A key that
+// received nothing during an interval is reported once with 0, then
+// forgotten until it shows up again.
+pub struct TopicSummary {
+    levels: Option<usize>,
+    counts: Mutex<HashMap<String, u64>>,
+}
+
+impl TopicSummary {
+    // Starts the logging task; the summary is fed by `record`
+    pub fn start(config: &TopicSummaryConfig) -> Arc<Self> {
+        let summary = Arc::new(TopicSummary {
+            levels: config.levels,
+            counts: Mutex::new(HashMap::new()),
+        });
+        let period = Duration::from_secs(config.interval_secs);
+        info!("Logging message counts per topic every {:?}", period);
+        tokio::spawn({
+            let summary = summary.clone();
+            async move {
+                let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+                loop {
+                    interval.tick().await;
+                    summary.report(period);
+                }
+            }
+        });
+        summary
+    }
+
+    pub fn record(&self, topic: &str) {
+        let key = match self.levels {
+            Some(levels) => topic.split('/').take(levels).collect::<Vec<_>>().join("/"),
+            None => topic.to_string(),
+        };
+        *self.counts.lock().expect("topic summary lock poisoned").entry(key).or_default() += 1;
+    }
+
+    fn report(&self, period: Duration) {
+        let mut counts: Vec<(String, u64)> = {
+            let mut counts = self.counts.lock().expect("topic summary lock poisoned");
+            let snapshot = counts.iter().map(|(key, count)| (key.clone(), *count)).collect();
+            counts.retain(|_, count| *count > 0);
+            counts.values_mut().for_each(|count| *count = 0);
+            snapshot
+        };
+        if counts.is_empty() {
+            return;
+        }
+        // Busiest first, ties alphabetically
+        counts.sort_by(|(a_key, a), (b_key, b)| b.cmp(a).then_with(|| a_key.cmp(b_key)));
+        let total: u64 = counts.iter().map(|(_, count)| count).sum();
+        let listing: Vec<String> = counts.iter().map(|(key, count)| format!("{} ({})", key, count)).collect();
+        info!(
+            event = "topic_summary",
+            "{} message(s) in the last {:?}: {}",
+            total,
+            period,
+            listing.join(", ")
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(levels: Option<usize>) -> TopicSummary {
+        TopicSummary {
+            levels,
+            counts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn counts(summary: &TopicSummary) -> HashMap<String, u64> {
+        summary.counts.lock().unwrap().clone()
+    }
+
+    #[test]
+    fn messages_are_counted_per_topic_prefix() {
+        let summary = summary(Some(2));
+        summary.record("b1/sensors/temp-01");
+        summary.record("b1/sensors/temp-02");
+        summary.record("b2/sensors/temp-01");
+        summary.record("b3");
+        assert_eq!(counts(&summary), HashMap::from([("b1/sensors".to_string(), 2), ("b2/sensors".to_string(), 1), ("b3".to_string(), 1)]));
+    }
+
+    #[test]
+    fn a_silent_topic_is_reported_once_then_forgotten() {
+        let summary = summary(None);
+        summary.record("sensors/a");
+        summary.report(Duration::from_secs(60));
+        assert_eq!(counts(&summary), HashMap::from([("sensors/a".to_string(), 0)]));
+        summary.report(Duration::from_secs(60));
+        assert!(counts(&summary).is_empty());
+    }
+}
@@ -0,0 +1,136 @@
+use anyhow::{Context, Result};
+use opentelemetry::propagation::TextMapPropagator;
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use std::collections::HashMap;
+use tracing::{warn, Level, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+const DEFAULT_SERVICE_NAME: &str = "drasi-mqtt-source";
+// v5 user properties that carry the publisher's W3C trace context
+const TRACE_CONTEXT_KEYS: [&str; 2] = ["traceparent", "tracestate"];
+
+// --- DISTRIBUTED TRACING ---
+// Off unless OTEL_EXPORTER_OTLP_ENDPOINT (or _TRACES_ENDPOINT) names a
+// collector, e.g. http://localhost:4318. Each message then becomes an
+// `ingest` span with `map`, `parse` and `emit` children, exported in
+// batches over OTLP/HTTP. The spans are debug-level, so the text and JSON
+// logs at the default `info` look exactly as before.
+pub struct Telemetry {
+    provider: Option<SdkTracerProvider>,
+}
+
+impl Telemetry {
+    // Sends whatever is still batched; spans recorded afterwards are lost
+    pub fn shutdown(self) {
+        if let Some(provider) = self.provider {
+            if let Err(e) = provider.shutdown() {
+                warn!("Failed to flush trace spans: {}", e);
+            }
+        }
+    }
+}
+
+// The layer to add to the subscriber, or None when no collector is set
+pub fn layer<S>() -> Result<(Option<impl Layer<S>>, Telemetry)>
+where
+    S: tracing::Subscriber + for<'span> LookupSpan<'span>,
+{
+    let configured = ["OTEL_EXPORTER_OTLP_ENDPOINT", "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT"]
+        .iter()
+        .any(|var| std::env::var(var).is_ok_and(|value| !value.trim().is_empty()));
+    if !configured {
+        return Ok((None, Telemetry { provider: None }));
+    }
+
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .build()
+        .context("Invalid OpenTelemetry exporter settings")?;
+    let mut resource = Resource::builder();
+    if std::env::var("OTEL_SERVICE_NAME").is_err() {
+        resource = resource.with_service_name(DEFAULT_SERVICE_NAME);
+    }
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(resource.build())
+        .build();
+    Ok((
+        Some(spans(&provider)),
+        Telemetry {
+            provider: Some(provider),
+        },
+    ))
+}
+
+// Hands our spans to `provider`
+fn spans<S>(provider: &SdkTracerProvider) -> impl Layer<S>
+where
+    S: tracing::Subscriber + for<'span> LookupSpan<'span>,
+{
+    let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+    // Only our own spans, not every library's
+    let targets = Targets::new().with_target(env!("CARGO_CRATE_NAME"), Level::DEBUG);
+    tracing_opentelemetry::layer().with_tracer(tracer).with_filter(targets)
+}
+
+// Makes `span` part of the publisher's trace when the (v5) message carries
+// a `traceparent` user property; otherwise it starts a trace of its own
+pub fn continue_trace(span: &Span, user_properties: &[(String, String)]) {
+    let carrier: HashMap<String, String> = user_properties
+        .iter()
+        .filter(|(key, _)| TRACE_CONTEXT_KEYS.contains(&key.to_ascii_lowercase().as_str()))
+        .map(|(key, value)| (key.to_ascii_lowercase(), value.clone()))
+        .collect();
+    if !carrier.contains_key("traceparent") {
+        return;
+    }
+    let parent = TraceContextPropagator::new().extract(&carrier);
+    // Only fails when the span is disabled, i.e. tracing is off
+    let _ = span.set_parent(parent);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::emit::tests::Recording;
+    use crate::message::Message;
+    use opentelemetry_sdk::trace::InMemorySpanExporter;
+    use rumqttc::QoS;
+    use std::sync::Arc;
+    use tracing_subscriber::layer::SubscriberExt;
+    use tracing_subscriber::util::SubscriberInitExt;
+
+    #[tokio::test]
+    async fn every_processed_message_is_an_ingest_span_with_its_topic() {
+        let exporter = InMemorySpanExporter::default();
+        let provider = SdkTracerProvider::builder().with_simple_exporter(exporter.clone()).build();
+        let _guard = tracing_subscriber::registry().with(spans(&provider)).set_default();
+        let pipeline = crate::pipeline::tests::pipeline(&Config::default(), Arc::new(Recording::default()));
+        for topic in ["sensors/temp-01", "sensors/temp-02"] {
+            let message = Message::from(rumqttc::Publish::new(topic, QoS::AtLeastOnce, r#"{"temperature": 21.5}"#));
+            pipeline.process(&message).await.unwrap();
+        }
+        provider.force_flush().unwrap();
+
+        let finished = exporter.get_finished_spans().unwrap();
+        let ingests: Vec<_> = finished.iter().filter(|span| span.name == "ingest").collect();
+        let topics: Vec<String> = ingests
+            .iter()
+            .map(|span| {
+                let topic = span.attributes.iter().find(|attribute| attribute.key.as_str() == "topic").unwrap();
+                topic.value.to_string()
+            })
+            .collect();
+        assert_eq!(topics, ["sensors/temp-01", "sensors/temp-02"]);
+        // Mapping and emitting happen inside it
+        let emit = finished.iter().find(|span| span.name == "emit").unwrap();
+        assert_eq!(emit.parent_span_id, ingests[0].span_context.span_id());
+    }
+}
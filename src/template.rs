@@ -0,0 +1,198 @@
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+
+use crate::config::TopicTemplateRule;
+
+// --- TOPIC TEMPLATES ---
+// A pattern like `lfx/drasi/{type}/{id}`: literal segments must match
+// exactly, `{name}` binds one non-empty segment, `+` matches any single
+// segment and a trailing `#` matches whatever remains (including nothing).
+pub struct TopicTemplate {
+    pattern: String,
+    segments: Vec<Segment>,
+}
+
+enum Segment {
+    Literal(String),
+    // `{name}`
+    Capture(String),
+    // `+`, matches any single segment without capturing it
+    Any,
+    // `#`, only valid last; matches whatever remains
+    Rest,
+}
+
+impl TopicTemplate {
+    pub fn parse(pattern: &str) -> Result<Self> {
+        let parts: Vec<&str> = pattern.split('/').collect();
+        let mut segments = Vec::with_capacity(parts.len());
+        for (index, part) in parts.iter().enumerate() {
+            let segment = match *part {
+                "+" => Segment::Any,
+                "#" if index == parts.len() - 1 => Segment::Rest,
+                "#" => bail!("topic pattern {:?}: '#' must be the last segment", pattern),
+                capture if capture.starts_with('{') && capture.ends_with('}') && capture.len() > 2 => {
+                    let name = &capture[1..capture.len() - 1];
+                    let duplicate = segments
+                        .iter()
+                        .any(|segment| matches!(segment, Segment::Capture(existing) if existing == name));
+                    if duplicate {
+                        bail!("topic pattern {:?} captures {{{}}} more than once", pattern, name);
+                    }
+                    Segment::Capture(name.to_string())
+                }
+                literal => Segment::Literal(literal.to_string()),
+            };
+            segments.push(segment);
+        }
+        Ok(TopicTemplate {
+            pattern: pattern.to_string(),
+            segments,
+        })
+    }
+
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    pub fn has_capture(&self, name: &str) -> bool {
+        self.segments
+            .iter()
+            .any(|segment| matches!(segment, Segment::Capture(capture) if capture == name))
+    }
+
+    // Capture name -> topic segment, or None if the topic doesn't match
+    pub fn bind<'t>(&self, topic: &'t str) -> Option<HashMap<&str, &'t str>> {
+        let mut captures = HashMap::new();
+        let mut levels = topic.split('/');
+        for segment in &self.segments {
+            match segment {
+                Segment::Rest => return Some(captures),
+                Segment::Any => {
+                    levels.next()?;
+                }
+                Segment::Literal(literal) => {
+                    if levels.next()? != literal {
+                        return None;
+                    }
+                }
+                Segment::Capture(name) => {
+                    let level = levels.next().filter(|level| !level.is_empty())?;
+                    captures.insert(name.as_str(), level);
+                }
+            }
+        }
+        // Without a trailing '#' the topic must not be any deeper
+        levels.next().is_none().then_some(captures)
+    }
+}
+
+// --- ELEMENT BINDING ---
+// `mapping.topic_templates`: the first template matching a topic supplies
+// the element ID, labels and extra properties taken from its segments.
+pub struct TopicTemplates {
+    rules: Vec<CompiledRule>,
+}
+
+struct CompiledRule {
+    template: TopicTemplate,
+    id_from: Option<String>,
+    labels_from: Vec<String>,
+}
+
+pub struct TopicBinding {
+    pub id: Option<String>,
+    pub labels: Vec<String>,
+    // Captures not used as the ID or a label, in pattern order
+    pub properties: Vec<(String, String)>,
+}
+
+impl TopicTemplates {
+    // Like the topic hierarchy, capture names are checked at startup
+    pub fn compile(rules: &[TopicTemplateRule]) -> Result<Self> {
+        let mut compiled = Vec::with_capacity(rules.len());
+        for rule in rules {
+            let template = TopicTemplate::parse(&rule.pattern)?;
+            for name in rule.id_from.iter().chain(&rule.labels_from) {
+                if !template.has_capture(name) {
+                    bail!("topic_templates pattern {:?} has no {{{}}} segment", rule.pattern, name);
+                }
+            }
+            compiled.push(CompiledRule {
+                template,
+                id_from: rule.id_from.clone(),
+                labels_from: rule.labels_from.clone(),
+            });
+        }
+        Ok(TopicTemplates { rules: compiled })
+    }
+
+    pub fn bind(&self, topic: &str) -> Option<TopicBinding> {
+        self.rules.iter().find_map(|rule| {
+            let captures = rule.template.bind(topic)?;
+            let id = rule.id_from.as_deref().map(|name| captures[name].to_string());
+            let labels = rule.labels_from.iter().map(|name| captures[name.as_str()].to_string()).collect();
+            let properties = rule
+                .template
+                .segments
+                .iter()
+                .filter_map(|segment| match segment {
+                    Segment::Capture(name)
+                        if rule.id_from.as_ref() != Some(name) && !rule.labels_from.contains(name) =>
+                    {
+                        Some((name.clone(), captures[name.as_str()].to_string()))
+                    }
+                    _ => None,
+                })
+                .collect();
+            Some(TopicBinding { id, labels, properties })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn templates(pattern: &str) -> Result<TopicTemplates> {
+        TopicTemplates::compile(&[TopicTemplateRule {
+            pattern: pattern.to_string(),
+            id_from: Some("id".to_string()),
+            labels_from: vec!["type".to_string()],
+        }])
+    }
+
+    #[test]
+    fn captures_bind_the_id_labels_and_properties() {
+        let templates = templates("lfx/{site}/{type}/{id}").unwrap();
+        let binding = templates.bind("lfx/plant-a/Pump/p-1").unwrap();
+        assert_eq!(binding.id.as_deref(), Some("p-1"));
+        assert_eq!(binding.labels, ["Pump"]);
+        assert_eq!(binding.properties, [("site".to_string(), "plant-a".to_string())]);
+    }
+
+    #[test]
+    fn the_topic_must_have_the_patterns_depth() {
+        let templates = templates("lfx/{type}/{id}").unwrap();
+        assert!(templates.bind("lfx/Pump").is_none());
+        assert!(templates.bind("lfx/Pump/p-1/state").is_none());
+        assert!(templates.bind("lfx/Pump/").is_none());
+        assert!(templates.bind("other/Pump/p-1").is_none());
+    }
+
+    #[test]
+    fn wildcards_match_without_capturing() {
+        let template = TopicTemplate::parse("lfx/+/{id}/#").unwrap();
+        assert_eq!(template.bind("lfx/any/p-1").unwrap()["id"], "p-1");
+        assert_eq!(template.bind("lfx/any/p-1/state/now").unwrap()["id"], "p-1");
+        assert!(template.bind("lfx/p-1").is_none());
+    }
+
+    #[test]
+    fn bad_patterns_are_refused() {
+        assert!(TopicTemplate::parse("lfx/#/{id}").is_err());
+        assert!(TopicTemplate::parse("lfx/{id}/{id}").is_err());
+        let error = templates("lfx/{id}").err().unwrap();
+        assert_eq!(error.to_string(), "topic_templates pattern \"lfx/{id}\" has no {type} segment");
+    }
+}
@@ -0,0 +1,116 @@
+use anyhow::{bail, Context, Result};
+use rumqttc::TlsConfiguration;
+use std::fs;
+use std::io::BufReader;
+use std::path::Path;
+
+use crate::config::TlsConfig;
+
+// --- TLS TRANSPORT ---
+// rustls only looks at the certificates when the first connection is made,
+// which would turn a typo'd path into an endless reconnect loop. We read and
+// sanity-check every PEM file up front so that mistakes fail at startup with
+// the offending path in the message.
+pub fn load_tls_configuration(tls: &TlsConfig) -> Result<TlsConfiguration> {
+    let ca_path = match &tls.ca_cert {
+        Some(path) => path,
+        // No custom CA: trust the platform's root certificates
+        None => return Ok(TlsConfiguration::default()),
+    };
+
+    let ca = read_certificates(ca_path)?;
+    let client_auth = match (&tls.client_cert, &tls.client_key) {
+        (Some(cert_path), Some(key_path)) => Some((read_certificates(cert_path)?, read_private_key(key_path)?)),
+        _ => None,
+    };
+
+    Ok(TlsConfiguration::Simple {
+        ca,
+        alpn: None,
+        client_auth,
+    })
+}
+
+fn read_certificates(path: &Path) -> Result<Vec<u8>> {
+    let pem = read_pem(path)?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(pem.as_slice()))
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Failed to parse certificate file {}", path.display()))?;
+    if certs.is_empty() {
+        bail!("No PEM certificates found in {}", path.display());
+    }
+    Ok(pem)
+}
+
+fn read_private_key(path: &Path) -> Result<Vec<u8>> {
+    let pem = read_pem(path)?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(pem.as_slice()))
+        .with_context(|| format!("Failed to parse private key file {}", path.display()))?;
+    if key.is_none() {
+        bail!("No PEM private key found in {}", path.display());
+    }
+    Ok(pem)
+}
+
+fn read_pem(path: &Path) -> Result<Vec<u8>> {
+    fs::read(path).with_context(|| format!("Failed to read TLS file {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/tls").join(name)
+    }
+
+    fn tls(ca: &str, client: Option<(&str, &str)>) -> TlsConfig {
+        TlsConfig {
+            enabled: true,
+            ca_cert: Some(fixture(ca)),
+            client_cert: client.map(|(cert, _)| fixture(cert)),
+            client_key: client.map(|(_, key)| fixture(key)),
+        }
+    }
+
+    #[test]
+    fn builds_from_a_ca_fixture() {
+        match load_tls_configuration(&tls("ca.pem", None)).unwrap() {
+            TlsConfiguration::Simple { ca, client_auth, .. } => {
+                assert_eq!(ca, fs::read(fixture("ca.pem")).unwrap());
+                assert!(client_auth.is_none());
+            }
+            _ => panic!("expected a simple TLS configuration"),
+        }
+    }
+
+    #[test]
+    fn builds_with_a_client_certificate() {
+        match load_tls_configuration(&tls("ca.pem", Some(("client.pem", "client.key")))).unwrap() {
+            TlsConfiguration::Simple { client_auth, .. } => assert!(client_auth.is_some()),
+            _ => panic!("expected a simple TLS configuration"),
+        }
+    }
+
+    #[test]
+    fn without_a_ca_the_platform_roots_are_used() {
+        let config = TlsConfig {
+            enabled: true,
+            ..TlsConfig::default()
+        };
+        assert!(matches!(load_tls_configuration(&config).unwrap(), TlsConfiguration::Rustls(_)));
+    }
+
+    #[test]
+    fn a_missing_file_is_reported_with_its_path() {
+        let error = load_tls_configuration(&tls("missing.pem", None)).unwrap_err();
+        assert!(format!("{:#}", error).contains("missing.pem"), "{:#}", error);
+    }
+
+    #[test]
+    fn a_key_is_not_taken_for_a_certificate() {
+        let error = load_tls_configuration(&tls("client.key", None)).unwrap_err();
+        assert!(error.to_string().contains("No PEM certificates found"), "{:#}", error);
+    }
+}
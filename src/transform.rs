@@ -0,0 +1,109 @@
+use anyhow::{anyhow, bail, Context, Result};
+use rhai::{Dynamic, Engine, Scope, AST};
+use serde_json::Value;
+use tracing::debug;
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+use crate::config::ScriptConfig;
+
+thread_local! {
+    // When the script running on this thread must stop. Scripts never yield,
+    // so a run starts and ends on the same thread.
+    static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+// --- SCRIPTED TRANSFORM ---
+// A user-supplied Rhai script, compiled once at startup, that sees the
+// decoded payload as `payload` (a map for JSON objects) and the topic as
+// `topic`, and evaluates to the properties object, e.g.
+//
+//     #{ temperatureCelsius: payload.t, alarm: payload.t > 30.0 }
+//
+// Rhai has no file, network or process access to begin with; on top of
+// that each run is cut off after the configured timeout, and `print` and
+// `debug` go to the debug log.
+pub struct ScriptTransform {
+    engine: Engine,
+    ast: AST,
+    timeout: Duration,
+}
+
+impl ScriptTransform {
+    pub fn load(config: &ScriptConfig) -> Result<Self> {
+        let source = std::fs::read_to_string(&config.path)
+            .with_context(|| format!("Failed to read mapping script {}", config.path.display()))?;
+        let mut engine = Engine::new();
+        // Polled every operation; a non-unit value aborts the run
+        engine.on_progress(|_| match DEADLINE.get() {
+            Some(deadline) if Instant::now() >= deadline => Some(Dynamic::from("timed out")),
+            _ => None,
+        });
+        engine.on_print(|text| debug!(event = "script_print", "{}", text));
+        engine.on_debug(|text, _, position| debug!(event = "script_debug", "{:?}: {}", position, text));
+        let ast = engine
+            .compile(source)
+            .with_context(|| format!("Failed to compile mapping script {}", config.path.display()))?;
+        Ok(ScriptTransform {
+            engine,
+            ast,
+            timeout: Duration::from_millis(config.timeout_ms),
+        })
+    }
+
+    pub fn apply(&self, payload: &Value, topic: &str) -> Result<Value> {
+        let mut scope = Scope::new();
+        scope.push_dynamic("payload", rhai::serde::to_dynamic(payload)?);
+        scope.push("topic", topic.to_string());
+
+        DEADLINE.set(Some(Instant::now() + self.timeout));
+        let result = self.engine.eval_ast_with_scope::<Dynamic>(&mut scope, &self.ast);
+        DEADLINE.set(None);
+
+        let result = result.map_err(|e| match *e {
+            rhai::EvalAltResult::ErrorTerminated(..) => anyhow!("script ran longer than {:?}", self.timeout),
+            e => anyhow!("script failed: {}", e),
+        })?;
+        if !result.is_map() {
+            bail!("script must evaluate to an object map, got {}", result.type_name());
+        }
+        Ok(rhai::serde::from_dynamic(&result)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn script(name: &str, source: &str) -> Result<ScriptTransform> {
+        let path = std::env::temp_dir().join(format!("drasi-mqtt-{}-{}.rhai", name, std::process::id()));
+        std::fs::write(&path, source).unwrap();
+        let script = ScriptTransform::load(&ScriptConfig {
+            path: path.clone(),
+            timeout_ms: 50,
+        });
+        std::fs::remove_file(&path).unwrap();
+        script
+    }
+
+    #[test]
+    fn the_script_sees_the_payload_and_topic() {
+        let script = script("map", "#{ celsius: payload.t, alarm: payload.t > 30.0, from: topic }").unwrap();
+        let properties = script.apply(&json!({ "t": 31.5 }), "sensors/a").unwrap();
+        assert_eq!(properties, json!({ "celsius": 31.5, "alarm": true, "from": "sensors/a" }));
+    }
+
+    #[test]
+    fn a_script_must_return_a_map_in_time() {
+        let not_a_map = script("scalar", "payload.t").unwrap();
+        let error = not_a_map.apply(&json!({ "t": 1 }), "sensors/a").unwrap_err();
+        assert!(error.to_string().contains("must evaluate to an object map"), "{}", error);
+
+        let endless = script("loop", "loop {}").unwrap();
+        let error = endless.apply(&json!({}), "sensors/a").unwrap_err();
+        assert_eq!(error.to_string(), "script ran longer than 50ms");
+
+        assert!(script("broken", "#{ a: ").is_err());
+    }
+}